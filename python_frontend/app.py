@@ -108,6 +108,11 @@ def enqueue_job():
         "params": data.get("params", {})
     }
     
+    # Continue the caller's trace in the worker
+    traceparent = data.get("traceparent") or request.headers.get("traceparent")
+    if traceparent:
+        job_payload["traceparent"] = traceparent
+    
     from worker import execute_rust_worker
     
     job = queue.enqueue(
@@ -157,6 +162,10 @@ def process_file():
         "params": params_dict
     }
     
+    traceparent = request.headers.get("traceparent")
+    if traceparent:
+        job_payload["traceparent"] = traceparent
+    
     from worker import execute_rust_worker
     
     job = queue.enqueue(
@@ -185,6 +194,17 @@ def list_tasks():
             {"name": "sanitize_filename", "description": "Sanitize filename"},
             {"name": "create_file_manifest", "description": "Create file manifest"},
             {"name": "verify_file_integrity", "description": "Verify file integrity"},
+            {"name": "validate_media_type", "description": "Sniff magic bytes and check extension/expected types"},
+            {"name": "scan_for_malware", "description": "Scan file with ClamAV"},
+            {"name": "release_from_quarantine", "description": "Restore a quarantined file"},
+            {"name": "purge_quarantine", "description": "Delete old quarantine incidents"},
+            {"name": "verify_signature", "description": "Check minisign/ed25519/GPG detached signature"},
+            {"name": "sync_delta", "description": "rsync-style signature/delta/patch transfer"},
+            {"name": "detect_file_type", "description": "Identify container/codecs from magic bytes"},
+            {"name": "record_rtmp", "description": "Record a live RTMP stream into segments"},
+            {"name": "verify_manifest", "description": "Check a signed output manifest and the files it lists"},
+            {"name": "download_file_parallel", "description": "Download a URL over several byte-range connections"},
+            {"name": "upload_file", "description": "Upload to a presigned URL or S3/GCS bucket with checksum verification"},
         ],
         "video": [
             {"name": "transcode_h264_to_h265", "description": "Convert H.264 to H.265"},
@@ -192,10 +212,38 @@ def list_tasks():
             {"name": "get_video_info", "description": "Get video information"},
             {"name": "extract_frames", "description": "Extract frames as images"},
             {"name": "extract_thumbnails", "description": "Extract thumbnail images"},
-            {"name": "create_animated_gif", "description": "Create animated GIF"},
+            {"name": "create_animated_gif", "description": "Create animated GIF, WebP or AVIF"},
             {"name": "detect_scene_cuts", "description": "Detect scene changes"},
             {"name": "apply_watermark", "description": "Apply watermark overlay"},
             {"name": "extract_key_frame", "description": "Extract single frame"},
+            {"name": "images_to_video", "description": "Assemble an image sequence into a video"},
+            {"name": "export_image_sequence", "description": "Export frames as PNG/JPEG/WebP with a timestamp index"},
+            {"name": "stream_to_hls", "description": "Live input to a rolling HLS playlist"},
+            {"name": "push_rtmp", "description": "Simulcast a file or live input to RTMP endpoints"},
+            {"name": "live_thumbnails", "description": "Refresh a JPEG preview of a live input"},
+            {"name": "package_drm", "description": "CENC/cbcs encrypted fMP4 with DASH/HLS via Shaka Packager"},
+            {"name": "watermark_forensic", "description": "Embed a per-recipient payload into the picture"},
+            {"name": "detect_forensic_watermark", "description": "Recover a forensic watermark payload (JSON report)"},
+            {"name": "generate_hover_previews", "description": "Short muted preview clips across the timeline plus JSON index"},
+            {"name": "export_social", "description": "Platform-sized, trimmed, loudness-normalised export with safe-area warnings"},
+            {"name": "concatenate_videos", "description": "Join videos, normalising formats, with optional transitions"},
+            {"name": "generate_abr_ladder", "description": "Transcode one source into several renditions in a single decode pass"},
+            {"name": "transcode_parallel", "description": "Transcode in parallel keyframe-aligned segments and join them losslessly"},
+            {"name": "transcode_distributed", "description": "Split a transcode into chunk jobs for other workers and assemble the result"},
+            {"name": "encode_chunk", "description": "Encode one chunk of a transcode_distributed job (enqueued by it)"},
+            {"name": "assemble_chunks", "description": "Join the chunks of a transcode_distributed job with the source audio, checking A/V sync"},
+            {"name": "remux_container", "description": "Rewrite into another container (mkv to mp4, ts to mp4, ...) without re-encoding"},
+            {"name": "faststart_mp4", "description": "Move the MP4 index to the front for progressive playback, without re-encoding"},
+            {"name": "extract_clip", "description": "Cut a clip, stream-copied from the nearest keyframe or frame-accurate with smart cut"},
+            {"name": "render_edl", "description": "Render a JSON timeline of source clips with in/out points, transitions and audio gain into one video"},
+            {"name": "pad_video", "description": "Fit a video into a target size or aspect ratio over a solid or blurred background"},
+            {"name": "convert_frame_rate", "description": "Convert to another frame rate by dropping/duplicating, blending, motion interpolation or retiming"},
+            {"name": "tonemap_hdr_to_sdr", "description": "Tonemap HDR10/HLG video to SDR BT.709"},
+            {"name": "measure_quality", "description": "Score an encode against its reference with VMAF, PSNR and SSIM"},
+            {"name": "detect_freeze_frames", "description": "Report stretches of frozen picture as JSON"},
+            {"name": "detect_interlacing", "description": "Report whether video is progressive, interlaced (TFF/BFF) or telecined"},
+            {"name": "measure_av_sync", "description": "Measure audio/video offset and drift across the file as JSON"},
+            {"name": "run_qc_report", "description": "Check a file against a QC profile with a pass/fail verdict per rule"},
         ],
         "audio": [
             {"name": "resample_audio", "description": "Resample to different rate"},
@@ -203,15 +251,43 @@ def list_tasks():
             {"name": "get_audio_info", "description": "Get audio information"},
             {"name": "generate_waveform_json", "description": "Generate waveform data"},
             {"name": "mix_audio_tracks", "description": "Mix audio tracks"},
+            {"name": "downmix_audio", "description": "Downmix 5.1/7.1 to stereo"},
+            {"name": "remap_channels", "description": "Reorder, duplicate or drop channels"},
+            {"name": "concat_audio_crossfade", "description": "Join audio files with crossfades"},
+            {"name": "trim_audio", "description": "Sample-accurate audio trim"},
+            {"name": "adjust_audio_speed", "description": "Change tempo and pitch independently"},
+            {"name": "compress_dynamics", "description": "Dynamic range compression"},
+            {"name": "clean_audio_artifacts", "description": "De-hum and de-ess audio"},
+            {"name": "calculate_replaygain", "description": "Measure and tag ReplayGain"},
+            {"name": "detect_silence", "description": "Report silent intervals as JSON"},
+            {"name": "classify_audio_content", "description": "Label speech/music/other timeline"},
+            {"name": "detect_audio_language", "description": "Identify spoken language via whisper.cpp"},
+            {"name": "fingerprint_audio", "description": "Chromaprint fingerprint with optional AcoustID lookup"},
+            {"name": "analyze_music", "description": "Estimate BPM and musical key"},
+            {"name": "detect_clipping", "description": "Report clipped runs and true-peak overs"},
+            {"name": "fix_dc_offset", "description": "Measure and remove DC offset"},
+            {"name": "separate_stems", "description": "Split vocals/drums/bass/other via configured backend"},
+            {"name": "duck_music", "description": "Sidechain-duck a music bed under voice"},
+            {"name": "add_silent_audio", "description": "Mux a silent audio track into video-only files"},
+            {"name": "encode_audio_multi", "description": "Encode several formats from one decode"},
+            {"name": "convert_sample_format", "description": "Change bit depth/sample format with TPDF dither"},
+            {"name": "watermark_audio", "description": "Embed spread-spectrum watermark payload"},
+            {"name": "detect_audio_watermark", "description": "Recover watermark payload as JSON"},
+            {"name": "embed_podcast_chapters", "description": "Write ID3 CHAP/CTOC or MP4 chapters"},
         ],
         "binary": [
-            {"name": "calculate_sha256", "description": "Calculate SHA-256 hash"},
-            {"name": "compress_archive", "description": "Compress file"},
+            {"name": "calculate_sha256", "description": "Calculate file hash (SHA-256, MD5, BLAKE3, ...)"},
+            {"name": "compress_archive", "description": "Compress file or build tar/zip archive"},
             {"name": "extract_exif_metadata", "description": "Extract EXIF metadata"},
             {"name": "purge_original_file", "description": "Delete original file"},
             {"name": "validate_format_compliance", "description": "Validate file format"},
             {"name": "chain_job_trigger", "description": "Trigger next job"},
             {"name": "report_metrics", "description": "Report job metrics"},
+            {"name": "extract_archive", "description": "Safely extract zip/tar/7z archives"},
+            {"name": "encrypt_file", "description": "AES-256-GCM encrypt with metadata sidecar"},
+            {"name": "decrypt_file", "description": "Decrypt and verify an encrypted file"},
+            {"name": "strip_metadata", "description": "Remove EXIF/GPS/XMP and container tags"},
+            {"name": "run_pipeline", "description": "Run a graph of tasks with dependencies, independent steps in parallel"},
         ]
     }
     
@@ -3,6 +3,7 @@ import subprocess
 import sys
 from pathlib import Path
 import toml
+from rq import get_current_job
 
 config_path = Path(__file__).parent.parent / "config" / "settings.toml"
 config = toml.load(config_path)
@@ -22,6 +23,11 @@ def execute_rust_worker(job_payload):
             "Please build it first with: cd rust_worker && cargo build --release"
         )
     
+    # Lets the Rust worker publish the job's state under its queue id
+    current_job = get_current_job()
+    if current_job is not None:
+        job_payload = {**job_payload, "job_id": current_job.id}
+    
     job_json = json.dumps(job_payload)
     
     try:
@@ -33,9 +39,13 @@ def execute_rust_worker(job_payload):
             check=False
         )
 
+        # Progress events may precede the result, which is always the last line
+        lines = result.stdout.strip().splitlines()
+        last_line = lines[-1] if lines else ""
+
         if result.returncode == 0:
             try:
-                output = json.loads(result.stdout)
+                output = json.loads(last_line)
                 return output
             except json.JSONDecodeError:
                 return {
@@ -46,7 +56,7 @@ def execute_rust_worker(job_payload):
                 }
         else:
             try:
-                error_output = json.loads(result.stdout)
+                error_output = json.loads(last_line)
                 raise RuntimeError(f"Rust worker failed: {error_output.get('message', 'Unknown error')}")
             except json.JSONDecodeError:
                 raise RuntimeError(
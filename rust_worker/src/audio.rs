@@ -1,8 +1,14 @@
 use anyhow::{Context, Result};
 use ffmpeg_next as ffmpeg;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::{config::Config, JobPayload};
+use crate::{
+    config::Config,
+    follow::{FollowOptions, FollowedInput},
+    scratch::ScratchDir,
+    shutdown,
+    JobPayload,
+};
 
 pub async fn resample_audio_native(job: &JobPayload, _config: &Config) -> Result<String> {
     info!("Resampling audio using ffmpeg-next");
@@ -226,42 +232,30 @@ pub async fn generate_waveform_native(job: &JobPayload, _config: &Config) -> Res
         .and_then(|v| v.as_u64())
         .unwrap_or(1000) as usize;
     
-    // Open input
-    let mut ictx = ffmpeg::format::input(&job.input_path)?;
-    
-    let input_stream = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Audio)
-        .context("No audio stream found")?;
-    
-    let audio_stream_index = input_stream.index();
+    // A file still being written is read through a pipe that ends once it is complete
+    let followed = match FollowOptions::from_params(&job.params, &job.input_path) {
+        Some(options) => Some(FollowedInput::open(&job.input_path, &options)?),
+        None => None,
+    };
+    let input = followed.as_ref().map(|f| f.url()).unwrap_or(&job.input_path);
     
-    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
-    let mut decoder = context_decoder.decoder().audio()?;
+    // Resample to mono planar f32 so every codec, sample format and layout is handled alike
+    let mut reader = PcmReader::open(input, None, Some(ffmpeg::ChannelLayout::MONO))?;
     
     let mut all_samples: Vec<f32> = Vec::new();
     
     // Decode all audio
-    for (stream, packet) in ictx.packets() {
-        if stream.index() == audio_stream_index {
-            decoder.send_packet(&packet)?;
-            
-            let mut decoded = ffmpeg::util::frame::audio::Audio::empty();
-            while decoder.receive_frame(&mut decoded).is_ok() {
-                // Extract samples (assuming planar f32 format)
-                let data = decoded.data(0);
-                let sample_count = decoded.samples();
-                
-                for i in 0..sample_count {
-                    let offset = i * 4; // 4 bytes per f32
-                    if offset + 4 <= data.len() {
-                        let sample_bytes = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
-                        let sample = f32::from_le_bytes(sample_bytes);
-                        all_samples.push(sample.abs());
-                    }
-                }
-            }
+    loop {
+        let block = reader.read(65536)?;
+        if block[0].is_empty() {
+            break;
         }
+        all_samples.extend(block[0].iter().map(|sample| sample.abs()));
+    }
+    
+    drop(reader);
+    if let Some(followed) = followed {
+        followed.finish()?;
     }
     
     // Downsample to requested number of samples
@@ -285,6 +279,9 @@ pub async fn generate_waveform_native(job: &JobPayload, _config: &Config) -> Res
 }
 
 /// Mix multiple audio tracks
+///
+/// Every input is decoded in lockstep, converted to a common rate and layout, and summed
+/// sample by sample with its own `gain` and `offset_ms`. A peak limiter keeps the sum from clipping.
 pub async fn mix_audio_native(job: &JobPayload, _config: &Config) -> Result<String> {
     info!("Mixing audio tracks using ffmpeg-next");
     
@@ -296,109 +293,4159 @@ pub async fn mix_audio_native(job: &JobPayload, _config: &Config) -> Result<Stri
         anyhow::bail!("At least one input file required");
     }
     
-    // Open all input files
-    let mut inputs: Vec<ffmpeg::format::context::Input> = Vec::new();
-    let mut decoders: Vec<ffmpeg::decoder::Audio> = Vec::new();
+    let normalize = job.params.get("normalize")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
     
+    let ceiling = job.params.get("ceiling")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.98) as f32;
+    
+    // Each entry is either a path or {"path", "gain", "offset_ms"}
+    let mut specs = Vec::new();
     for file in input_files {
-        if let Some(path) = file.as_str() {
-            let ictx = ffmpeg::format::input(path)?;
-            let stream = ictx
-                .streams()
-                .best(ffmpeg::media::Type::Audio)
-                .context("No audio stream found")?;
+        let (path, gain, offset_ms) = match file {
+            serde_json::Value::String(path) => (path.as_str(), 1.0, 0.0),
+            serde_json::Value::Object(entry) => (
+                entry.get("path")
+                    .and_then(|v| v.as_str())
+                    .context("input_files entry requires a path")?,
+                entry.get("gain").and_then(|v| v.as_f64()).unwrap_or(1.0),
+                entry.get("offset_ms").and_then(|v| v.as_f64()).unwrap_or(0.0),
+            ),
+            _ => anyhow::bail!("input_files entries must be paths or objects"),
+        };
+        
+        if offset_ms < 0.0 {
+            anyhow::bail!("offset_ms must not be negative: {}", path);
+        }
+        
+        specs.push((path.to_string(), gain as f32, offset_ms));
+    }
+    
+    // The first track defines the common format unless overridden
+    let first = PcmReader::open(&specs[0].0, None, None)?;
+    let sample_rate = job.params.get("sample_rate")
+        .and_then(|v| v.as_u64())
+        .map(|v| v as u32)
+        .unwrap_or(first.sample_rate());
+    let channel_layout = match job.params.get("layout").and_then(|v| v.as_str()) {
+        Some(name) => parse_channel_layout(name)
+            .context(format!("Unsupported output layout: {}", name))?,
+        None => first.channel_layout(),
+    };
+    drop(first);
+    
+    let track_scale = if normalize { 1.0 / specs.len() as f32 } else { 1.0 };
+    
+    let mut tracks = Vec::new();
+    for (path, gain, offset_ms) in &specs {
+        tracks.push(MixTrack {
+            reader: PcmReader::open(path, Some(sample_rate), Some(channel_layout))?,
+            gain: gain * track_scale,
+            offset: (offset_ms / 1000.0 * sample_rate as f64).round() as u64,
+            finished: false,
+        });
+    }
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    let mut writer = PcmWriter::create(&job.output_path, &settings, channel_layout, sample_rate)?;
+    let mut limiter = PeakLimiter::new(ceiling, sample_rate);
+    
+    let channels = channel_layout.channels() as usize;
+    let block_size = 4096usize;
+    let mut position: u64 = 0;
+    
+    info!("Mixing {} audio tracks at {} Hz", tracks.len(), sample_rate);
+    
+    loop {
+        let mut mixed = vec![vec![0.0f32; block_size]; channels];
+        let mut produced = 0usize;
+        let mut active = false;
+        
+        for track in tracks.iter_mut().filter(|t| !t.finished) {
+            active = true;
+            
+            // Track hasn't started yet within this block
+            if track.offset >= position + block_size as u64 {
+                produced = block_size;
+                continue;
+            }
+            
+            let lead = track.offset.saturating_sub(position) as usize;
+            let wanted = block_size - lead;
+            let samples = track.reader.read(wanted)?;
+            let read = samples.first().map_or(0, |plane| plane.len());
+            
+            if read < wanted {
+                track.finished = true;
+            }
             
-            let context = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
-            let decoder = context.decoder().audio()?;
+            for (out_plane, in_plane) in mixed.iter_mut().zip(&samples) {
+                for (out, sample) in out_plane[lead..lead + read].iter_mut().zip(in_plane) {
+                    *out += sample * track.gain;
+                }
+            }
             
-            decoders.push(decoder);
-            inputs.push(ictx);
+            produced = produced.max(lead + read);
+        }
+        
+        if !active || produced == 0 {
+            break;
         }
+        
+        for plane in mixed.iter_mut() {
+            plane.truncate(produced);
+        }
+        
+        limiter.process(&mut mixed);
+        writer.write(&mixed)?;
+        position += produced as u64;
     }
     
-    // Use first decoder's properties for output
-    let reference_decoder = &decoders[0];
+    writer.finish()?;
     
-    // Create output
-    let mut octx = ffmpeg::format::output(&job.output_path)?;
+    info!(
+        "Audio mixing complete: {:.2}s, peak gain reduction {:.2} dB",
+        position as f64 / sample_rate as f64,
+        limiter.max_reduction_db()
+    );
+    Ok(job.output_path.clone())
+}
+
+struct MixTrack {
+    reader: PcmReader,
+    gain: f32,
+    offset: u64,
+    finished: bool,
+}
+
+/// Concatenate audio files with a crossfade between consecutive segments
+pub async fn concat_audio_crossfade(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Concatenating audio with crossfades using ffmpeg-next");
     
-    let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::MP3)
-        .or_else(|| ffmpeg::encoder::find(ffmpeg::codec::Id::AAC))
-        .context("No suitable audio encoder found")?;
+    let input_files: Vec<&str> = job.params.get("input_files")
+        .and_then(|v| v.as_array())
+        .context("input_files array parameter required")?
+        .iter()
+        .enumerate()
+        .map(|(index, v)| {
+            v.as_str()
+                .context(format!("input_files[{}] must be a path string, got {}", index, v))
+        })
+        .collect::<Result<_>>()?;
     
-    let mut ost = octx.add_stream(codec)?;
-    let mut encoder = ost.codec().encoder().audio()?;
+    if input_files.len() < 2 {
+        anyhow::bail!("At least two input files required for crossfade concatenation");
+    }
     
-    encoder.set_rate(reference_decoder.rate() as i32);
-    encoder.set_channel_layout(reference_decoder.channel_layout());
-    encoder.set_channels(reference_decoder.channels());
-    encoder.set_format(codec.audio()?.formats().unwrap().next().unwrap());
-    encoder.set_bit_rate(reference_decoder.bit_rate());
-    encoder.set_time_base((1, reference_decoder.rate() as i32));
+    let duration = job.params.get("crossfade_duration")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(1.0);
     
-    let encoder = encoder.open_as(codec)?;
-    ost.set_parameters(&encoder);
+    let curve = job.params.get("curve")
+        .and_then(|v| v.as_str())
+        .unwrap_or("tri");
     
-    octx.write_header()?;
+    // Validate the curve name before decoding anything
+    crossfade_gains(curve, 0.0)?;
+    
+    let first = PcmReader::open(input_files[0], None, None)?;
+    let sample_rate = first.sample_rate();
+    let channel_layout = first.channel_layout();
+    drop(first);
+    
+    let channels = channel_layout.channels() as usize;
+    let fade_samples = (duration * sample_rate as f64).round() as usize;
+    let block_size = 4096usize;
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    let mut writer = PcmWriter::create(&job.output_path, &settings, channel_layout, sample_rate)?;
+    
+    // Samples held back from the previous segment so they can be blended with the next one
+    let mut tail: Vec<Vec<f32>> = vec![Vec::new(); channels];
+    let mut total_samples = 0u64;
+    
+    for (index, path) in input_files.iter().enumerate() {
+        info!("Appending segment {}/{}: {}", index + 1, input_files.len(), path);
+        
+        let mut reader = PcmReader::open(path, Some(sample_rate), Some(channel_layout))?;
+        let is_last = index + 1 == input_files.len();
+        
+        // Blend the held tail with the head of this segment
+        let head = reader.read(tail[0].len())?;
+        let overlap = head[0].len().min(tail[0].len());
+        
+        if overlap > 0 {
+            let mut blended = vec![vec![0.0f32; overlap]; channels];
+            for i in 0..overlap {
+                let t = (i as f64 + 0.5) / overlap as f64;
+                let (fade_out, fade_in) = crossfade_gains(curve, t)?;
+                for channel in 0..channels {
+                    blended[channel][i] = tail[channel][tail[channel].len() - overlap + i] * fade_out as f32
+                        + head[channel][i] * fade_in as f32;
+                }
+            }
+            writer.write(&blended)?;
+            total_samples += overlap as u64;
+        }
+        
+        // Any head samples beyond the overlap (previous segment shorter than the fade) pass through
+        let mut held: Vec<std::collections::VecDeque<f32>> = head
+            .into_iter()
+            .map(|plane| plane.into_iter().skip(overlap).collect())
+            .collect();
+        
+        loop {
+            let block = reader.read(block_size)?;
+            let read = block[0].len();
+            
+            for (held_plane, plane) in held.iter_mut().zip(block) {
+                held_plane.extend(plane);
+            }
+            
+            let keep = if is_last { 0 } else { fade_samples };
+            let flush = held[0].len().saturating_sub(keep);
+            if flush > 0 {
+                let out: Vec<Vec<f32>> = held.iter_mut().map(|plane| plane.drain(..flush).collect()).collect();
+                writer.write(&out)?;
+                total_samples += flush as u64;
+            }
+            
+            if read < block_size {
+                break;
+            }
+        }
+        
+        tail = held.into_iter().map(|plane| plane.into_iter().collect()).collect();
+    }
     
-    info!("Mixing {} audio tracks", input_files.len());
+    writer.finish()?;
     
-    // Note: Actual mixing would require more complex sample-level processing
-    // This is a simplified version that concatenates rather than mixes
-    // For true mixing, you'd need to decode all tracks simultaneously and sum samples
+    info!(
+        "Crossfade concatenation complete: {} segments, {:.2}s",
+        input_files.len(),
+        total_samples as f64 / sample_rate as f64
+    );
+    Ok(job.output_path.clone())
+}
+
+/// Trim audio to a sample-accurate range, optionally fading at the cut points
+///
+/// Only ends that are actually cut are faded: the start when `start` is given, the end when
+/// `end` or `duration` is.
+pub async fn trim_audio(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Trimming audio using ffmpeg-next");
+    
+    let start = param_seconds(&job.params, "start")?.unwrap_or(0.0);
+    let end = match (param_seconds(&job.params, "end")?, param_seconds(&job.params, "duration")?) {
+        (Some(end), _) => Some(end),
+        (None, Some(duration)) => Some(start + duration),
+        (None, None) => None,
+    };
+    
+    if let Some(end) = end {
+        if end <= start {
+            anyhow::bail!("Trim end ({}s) must be after start ({}s)", end, start);
+        }
+    }
     
-    for (idx, mut input) in inputs.into_iter().enumerate() {
-        info!("Processing track {}/{}", idx + 1, input_files.len());
+    let fade_ms = job.params.get("fade_ms")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.0);
+    
+    let mut reader = PcmReader::open(&job.input_path, None, None)?;
+    let sample_rate = reader.sample_rate();
+    
+    let start_sample = (start * sample_rate as f64).round() as usize;
+    let end_sample = end.map(|end| (end * sample_rate as f64).round() as usize);
+    let fade_samples = (fade_ms / 1000.0 * sample_rate as f64).round() as usize;
+    let fade_in = if start_sample > 0 { fade_samples } else { 0 };
+    let fade_out = if end_sample.is_some() { fade_samples } else { 0 };
+    
+    reader.skip(start_sample)?;
+    // Checked before the output is created, so an empty range leaves no file behind
+    if reader.at_end()? {
+        anyhow::bail!("Trim range starts beyond the end of the input");
+    }
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    let mut writer = PcmWriter::create(&job.output_path, &settings, reader.channel_layout(), sample_rate)?;
+    
+    let block_size = 4096usize;
+    let mut position = 0usize;
+    let mut held: Vec<std::collections::VecDeque<f32>> =
+        vec![std::collections::VecDeque::new(); reader.channels()];
+    
+    loop {
+        let wanted = match end_sample {
+            Some(end) => block_size.min(end.saturating_sub(start_sample + position)),
+            None => block_size,
+        };
+        if wanted == 0 {
+            break;
+        }
         
-        let stream_index = input
+        let mut block = reader.read(wanted)?;
+        let read = block[0].len();
+        
+        // Fade in over the first samples of the range
+        for i in 0..read {
+            let offset = position + i;
+            if offset < fade_in {
+                let gain = offset as f32 / fade_in as f32;
+                for plane in block.iter_mut() {
+                    plane[i] *= gain;
+                }
+            }
+        }
+        position += read;
+        
+        for (held_plane, plane) in held.iter_mut().zip(block) {
+            held_plane.extend(plane);
+        }
+        
+        // Keep the last `fade_out` samples back until we know they are the final ones
+        let flush = held[0].len().saturating_sub(fade_out);
+        if flush > 0 {
+            let out: Vec<Vec<f32>> = held.iter_mut().map(|plane| plane.drain(..flush).collect()).collect();
+            writer.write(&out)?;
+        }
+        
+        if read < wanted {
+            break;
+        }
+    }
+    
+    // Fade out the held tail
+    let tail_len = held[0].len();
+    let tail: Vec<Vec<f32>> = held
+        .into_iter()
+        .map(|plane| {
+            plane
+                .into_iter()
+                .enumerate()
+                .map(|(i, sample)| sample * (tail_len - i) as f32 / tail_len as f32)
+                .collect()
+        })
+        .collect();
+    writer.write(&tail)?;
+    writer.finish()?;
+    
+    info!(
+        "Trim complete: {:.3}s from {:.3}s",
+        position as f64 / sample_rate as f64,
+        start
+    );
+    Ok(job.output_path.clone())
+}
+
+/// Change tempo and pitch independently
+///
+/// Tempo uses chained atempo filters so factors outside 0.5-2.0 keep their quality. Pitch uses
+/// rubberband when the linked FFmpeg has it, otherwise a resample + tempo compensation.
+pub async fn adjust_audio_speed(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Adjusting audio tempo/pitch using ffmpeg-next");
+    
+    let tempo = job.params.get("tempo")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(1.0);
+    
+    let semitones = job.params.get("pitch_semitones")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.0);
+    
+    if tempo <= 0.0 {
+        anyhow::bail!("tempo must be positive, got {}", tempo);
+    }
+    
+    let pitch = 2f64.powf(semitones / 12.0);
+    
+    let sample_rate = {
+        let ictx = ffmpeg::format::input(&job.input_path)?;
+        let stream = ictx
             .streams()
             .best(ffmpeg::media::Type::Audio)
-            .unwrap()
-            .index();
-        
-        for (stream, packet) in input.packets() {
-            if stream.index() == stream_index {
-                decoders[idx].send_packet(&packet)?;
-                
-                let mut decoded = ffmpeg::util::frame::audio::Audio::empty();
-                while decoders[idx].receive_frame(&mut decoded).is_ok() {
-                    encoder.send_frame(&decoded)?;
-                    
-                    let mut encoded = ffmpeg::Packet::empty();
-                    while encoder.receive_packet(&mut encoded).is_ok() {
-                        encoded.set_stream(0);
-                        encoded.write_interleaved(&mut octx)?;
-                    }
+            .context("No audio stream found")?;
+        ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?
+            .rate()
+    };
+    
+    let mut filters = Vec::new();
+    
+    if (pitch - 1.0).abs() > f64::EPSILON {
+        if ffmpeg::filter::find("rubberband").is_some() {
+            filters.push(format!("rubberband=pitch={:.6}:tempo={:.6}", pitch, tempo));
+        } else {
+            info!("rubberband filter not available, falling back to resample pitch shift");
+            filters.push(format!("asetrate={}", (sample_rate as f64 * pitch).round() as u32));
+            filters.push(format!("aresample={}", sample_rate));
+            filters.extend(atempo_chain(tempo / pitch));
+        }
+    } else if (tempo - 1.0).abs() > f64::EPSILON {
+        filters.extend(atempo_chain(tempo));
+    }
+    
+    if filters.is_empty() {
+        filters.push("anull".to_string());
+    }
+    
+    let filter_spec = filters.join(",");
+    info!(filter = %filter_spec, "Tempo {:.3}x, pitch {:+.2} semitones", tempo, semitones);
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    transcode_audio_filtered(&job.input_path, &job.output_path, &filter_spec, &settings)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Apply dynamic range compression (acompressor) with optional presets
+pub async fn compress_dynamics(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Compressing dynamic range using ffmpeg-next");
+    
+    let preset = job.params.get("preset")
+        .and_then(|v| v.as_str())
+        .unwrap_or("podcast");
+    
+    // (threshold dB, ratio, attack ms, release ms, makeup dB)
+    let (threshold_db, ratio, attack, release, makeup_db) = match preset {
+        "podcast" => (-20.0, 3.0, 10.0, 150.0, 4.0),
+        "broadcast" => (-24.0, 4.0, 5.0, 100.0, 6.0),
+        "gentle" => (-18.0, 2.0, 20.0, 250.0, 2.0),
+        // Unity ratio at full scale: nothing is compressed unless params say otherwise
+        "none" => (0.0, 1.0, 20.0, 250.0, 0.0),
+        _ => anyhow::bail!("Unknown compression preset: {}", preset),
+    };
+    
+    let param = |key: &str, default: f64| {
+        job.params.get(key).and_then(|v| v.as_f64()).unwrap_or(default)
+    };
+    
+    let threshold_db = param("threshold", threshold_db);
+    let ratio = param("ratio", ratio);
+    let attack = param("attack", attack);
+    let release = param("release", release);
+    let makeup_db = param("makeup", makeup_db);
+    
+    if !(1.0..=20.0).contains(&ratio) {
+        anyhow::bail!("ratio must be between 1 and 20, got {}", ratio);
+    }
+    
+    // acompressor takes linear threshold/makeup values
+    let threshold = 10f64.powf(threshold_db / 20.0).clamp(0.000976563, 1.0);
+    let makeup = 10f64.powf(makeup_db / 20.0).clamp(1.0, 64.0);
+    
+    let filter_spec = format!(
+        "acompressor=threshold={:.6}:ratio={}:attack={}:release={}:makeup={:.4}",
+        threshold, ratio, attack, release, makeup
+    );
+    
+    info!(filter = %filter_spec, preset = %preset, "Compressing dynamics");
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    transcode_audio_filtered(&job.input_path, &job.output_path, &filter_spec, &settings)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Remove mains hum and sibilance (plus optional FFT denoise) in a configurable stage order
+pub async fn clean_audio_artifacts(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Cleaning audio artifacts using ffmpeg-next");
+    
+    let stages: Vec<String> = match job.params.get("stages").and_then(|v| v.as_array()) {
+        Some(stages) => stages
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(String::from)
+            .collect(),
+        None => vec!["dehum".to_string(), "deess".to_string()],
+    };
+    
+    let hum_frequency = job.params.get("hum_frequency")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(50.0);
+    
+    let harmonics = job.params.get("harmonics")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(4);
+    
+    let notch_q = job.params.get("notch_q")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(30.0);
+    
+    let deess_intensity = job.params.get("deess_intensity")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.5);
+    
+    let deess_frequency = job.params.get("deess_frequency")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.5);
+    
+    let noise_reduction = job.params.get("noise_reduction_db")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(12.0);
+    
+    if hum_frequency != 50.0 && hum_frequency != 60.0 {
+        anyhow::bail!("hum_frequency must be 50 or 60, got {}", hum_frequency);
+    }
+    
+    let mut filters = Vec::new();
+    
+    for stage in &stages {
+        match stage.as_str() {
+            "dehum" => {
+                // Notch the fundamental and its harmonics
+                for harmonic in 1..=harmonics {
+                    filters.push(format!(
+                        "bandreject=f={}:t=q:w={}",
+                        hum_frequency * harmonic as f64,
+                        notch_q
+                    ));
                 }
             }
+            "deess" => filters.push(format!(
+                "deesser=i={:.3}:f={:.3}:m=0.5:s=o",
+                deess_intensity.clamp(0.0, 1.0),
+                deess_frequency.clamp(0.0, 1.0)
+            )),
+            "denoise" => filters.push(format!("afftdn=nr={}", noise_reduction.clamp(0.01, 97.0))),
+            other => anyhow::bail!("Unknown cleanup stage: {}", other),
         }
     }
     
-    // Flush encoder
-    encoder.send_eof()?;
-    let mut encoded = ffmpeg::Packet::empty();
-    while encoder.receive_packet(&mut encoded).is_ok() {
-        encoded.set_stream(0);
-        encoded.write_interleaved(&mut octx)?;
+    if filters.is_empty() {
+        anyhow::bail!("At least one cleanup stage required");
     }
     
-    octx.write_trailer()?;
+    let filter_spec = filters.join(",");
+    info!(filter = %filter_spec, "Applying cleanup stages: {}", stages.join(" -> "));
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    transcode_audio_filtered(&job.input_path, &job.output_path, &filter_spec, &settings)?;
     
-    info!("Audio mixing complete");
     Ok(job.output_path.clone())
 }
 
-// Helper function
-fn parse_bitrate(bitrate: &str) -> Result<usize> {
-    let bitrate = bitrate.to_uppercase();
+/// Measure ReplayGain 2.0 track gain/true peak (EBU R128 loudness) and tag the output
+///
+/// With `write_tags` (default) the input is stream-copied to `output_path` with ReplayGain
+/// tags added, or for Opus an `R128_TRACK_GAIN` tag; otherwise `output_path` receives the
+/// JSON measurement only.
+pub async fn calculate_replaygain(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Calculating ReplayGain using ffmpeg-next");
     
-    if bitrate.ends_with('K') {
-        let num: usize = bitrate.trim_end_matches('K').parse()?;
-        Ok(num * 1000)
-    } else if bitrate.ends_with('M') {
-        let num: usize = bitrate.trim_end_matches('M').parse()?;
-        Ok(num * 1_000_000)
-    } else {
-        Ok(bitrate.parse()?)
+    let write_tags = job.params.get("write_tags")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let reference_lufs = job.params.get("reference_lufs")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(-18.0);
+    
+    let measurement = measure_loudness(&job.input_path)?;
+    let integrated = measurement.integrated_lufs
+        .context("Input is too short or silent to measure loudness")?;
+    
+    let track_gain = reference_lufs - integrated;
+    let track_peak = measurement.true_peak;
+    
+    info!(
+        "Integrated loudness {:.2} LUFS, track gain {:+.2} dB, peak {:.6}",
+        integrated, track_gain, track_peak
+    );
+    
+    let report = serde_json::json!({
+        "integrated_lufs": integrated,
+        "loudness_range_lu": measurement.loudness_range,
+        "reference_lufs": reference_lufs,
+        "track_gain_db": track_gain,
+        "track_peak": track_peak,
+        "sample_peak": measurement.sample_peak,
+    });
+    
+    if !write_tags {
+        std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+        return Ok(job.output_path.clone());
     }
+    
+    let is_opus = {
+        let ictx = ffmpeg::format::input(&job.input_path)?;
+        ictx.streams()
+            .best(ffmpeg::media::Type::Audio)
+            .map_or(false, |stream| stream.parameters().id() == ffmpeg::codec::Id::OPUS)
+    };
+    
+    // Opus players ignore REPLAYGAIN_* and read R128 gain in Q7.8 relative to -23 LUFS
+    let tags = if is_opus {
+        let r128_gain = ((-23.0 - integrated) * 256.0).round() as i32;
+        vec![("R128_TRACK_GAIN".to_string(), r128_gain.to_string())]
+    } else {
+        vec![
+            ("REPLAYGAIN_TRACK_GAIN".to_string(), format!("{:+.2} dB", track_gain)),
+            ("REPLAYGAIN_TRACK_PEAK".to_string(), format!("{:.6}", track_peak)),
+            ("REPLAYGAIN_REFERENCE_LOUDNESS".to_string(), format!("{:.1} LUFS", reference_lufs)),
+        ]
+    };
+    
+    remux_with_metadata(&job.input_path, &job.output_path, &tags, &[])?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Detect silent intervals and write them as a JSON report
+///
+/// A window counts as silent when every channel's RMS level is below `threshold` (dBFS);
+/// runs shorter than `min_duration` seconds are ignored.
+pub async fn detect_silence(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Detecting silence using ffmpeg-next");
+    
+    let threshold_db = job.params.get("threshold")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(-50.0);
+    
+    let min_duration = job.params.get("min_duration")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.5);
+    
+    let window_ms = job.params.get("window_ms")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(10)
+        .max(1);
+    
+    let (silences, duration) = find_silences(&job.input_path, threshold_db, min_duration, window_ms)?;
+    let total_silence: f64 = silences.iter().map(|(start, end)| end - start).sum();
+    let leading_silence = silences.first()
+        .filter(|(start, _)| *start == 0.0)
+        .map_or(0.0, |(start, end)| end - start);
+    let trailing_silence = silences.last()
+        .filter(|(_, end)| (duration - end).abs() < 1e-9)
+        .map_or(0.0, |(start, end)| end - start);
+    
+    info!(
+        "Found {} silent intervals ({:.2}s of {:.2}s)",
+        silences.len(), total_silence, duration
+    );
+    
+    let report = serde_json::json!({
+        "threshold_db": threshold_db,
+        "min_duration": min_duration,
+        "duration": duration,
+        "total_silence": total_silence,
+        "silence_ratio": if duration > 0.0 { total_silence / duration } else { 0.0 },
+        "leading_silence": leading_silence,
+        "trailing_silence": trailing_silence,
+        "fully_silent": duration > 0.0 && (total_silence - duration).abs() < 1e-9,
+        "silences": silences.iter().map(|(start, end)| serde_json::json!({
+            "start": start,
+            "end": end,
+            "duration": end - start,
+        })).collect::<Vec<_>>(),
+    });
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Classify audio into a speech/music/other timeline written as JSON
+///
+/// Each `window` (seconds) is labelled from short-term energy, zero-crossing and spectral
+/// flatness statistics of its 20ms frames; adjacent windows with the same label are merged
+/// into segments.
+pub async fn classify_audio_content(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Classifying audio content using ffmpeg-next");
+    
+    let window_seconds = job.params.get("window")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(1.0)
+        .max(0.2);
+    
+    let silence_threshold_db = job.params.get("silence_threshold")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(-50.0);
+    
+    let (windows, segments, duration) =
+        classify_audio_timeline(&job.input_path, window_seconds, silence_threshold_db)?;
+    
+    let label_ratio = |label: &str| {
+        let seconds: f64 = segments.iter()
+            .filter(|segment| segment["label"] == label)
+            .map(|segment| segment["end"].as_f64().unwrap_or(0.0) - segment["start"].as_f64().unwrap_or(0.0))
+            .sum();
+        if duration > 0.0 { seconds / duration } else { 0.0 }
+    };
+    
+    let report = serde_json::json!({
+        "window": window_seconds,
+        "duration": duration,
+        "summary": {
+            "speech": label_ratio("speech"),
+            "music": label_ratio("music"),
+            "other": label_ratio("other"),
+        },
+        "segments": segments,
+        "windows": windows,
+    });
+    
+    info!("Classified {:.2}s of audio into {} segments", duration, segments.len());
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Detect the spoken language using whisper.cpp language identification
+///
+/// Up to `samples` clips of `clip_duration` seconds spread across the file are identified
+/// separately and their probabilities averaged, so a cold open or intro music doesn't decide
+/// the result on its own.
+pub async fn detect_audio_language(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Detecting spoken language using whisper.cpp");
+    
+    let samples = job.params.get("samples")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(3)
+        .max(1);
+    
+    let clip_duration = job.params.get("clip_duration")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(30.0);
+    
+    let duration = {
+        let ictx = ffmpeg::format::input(&job.input_path)?;
+        ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)
+    };
+    
+    // whisper.cpp only accepts 16 kHz mono PCM WAV
+    let scratch = ScratchDir::new(&config.scratch, "lid")?;
+    let wav_path = scratch.file("audio.wav");
+    let settings = AudioOutputSettings {
+        codec: Some("pcm_s16le".to_string()),
+        sample_rate: Some(16000),
+        channel_layout: Some(ffmpeg::ChannelLayout::MONO),
+        ..Default::default()
+    };
+    transcode_audio_filtered(&job.input_path, &wav_path, "anull", &settings)?;
+    scratch.check()?;
+    
+    let clip_count = if duration > clip_duration { samples } else { 1 };
+    let mut scores: std::collections::HashMap<String, f64> = std::collections::HashMap::new();
+    let mut clips = Vec::new();
+    
+    for i in 0..clip_count {
+        let offset = if clip_count == 1 {
+            0.0
+        } else {
+            (duration * (i + 1) as f64 / (clip_count + 1) as f64 - clip_duration / 2.0).max(0.0)
+        };
+        
+        let output = shutdown::output(
+            std::process::Command::new(&config.transcription.whisper_binary)
+                .args(&["-m", &config.transcription.model_path, "-f", &wav_path, "-l", "auto", "--detect-language"])
+                .args(&["--offset-t", &((offset * 1000.0) as u64).to_string()])
+                .args(&["--duration", &((clip_duration * 1000.0) as u64).to_string()]),
+        );
+        
+        let output = output.context("Failed to execute whisper.cpp")?;
+        
+        let log = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            anyhow::bail!("whisper.cpp failed: {}", log);
+        }
+    
+        // e.g. "whisper_full_with_state: auto-detected language: de (p = 0.912354)"
+        let detected = log.lines().find_map(|line| {
+            let rest = line.split("auto-detected language: ").nth(1)?;
+            let (language, probability) = rest.split_once(" (p = ")?;
+            let probability = probability.trim_end_matches(')').trim().parse::<f64>().ok()?;
+            Some((language.trim().to_string(), probability))
+        });
+    
+        if let Some((language, probability)) = detected {
+            *scores.entry(language.clone()).or_insert(0.0) += probability;
+            clips.push(serde_json::json!({
+                "offset": offset,
+                "language": language,
+                "probability": probability,
+            }));
+        }
+    }
+    
+    drop(scratch);
+    
+    if clips.is_empty() {
+        anyhow::bail!("whisper.cpp did not report a detected language");
+    }
+    
+    let mut languages: Vec<(String, f64)> = scores
+        .into_iter()
+        .map(|(language, score)| (language, score / clip_count as f64))
+        .collect();
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+    
+    info!("Detected language {} (confidence {:.2})", languages[0].0, languages[0].1);
+    
+    let report = serde_json::json!({
+        "language": languages[0].0,
+        "confidence": languages[0].1,
+        "languages": languages.iter().map(|(language, confidence)| serde_json::json!({
+            "language": language,
+            "confidence": confidence,
+        })).collect::<Vec<_>>(),
+        "clips": clips,
+    });
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Compute a Chromaprint fingerprint, optionally looking it up on AcoustID
+///
+/// Uses FFmpeg's libchromaprint muxer, so the fingerprint matches what `fpcalc` produces for
+/// the same `length`.
+pub async fn fingerprint_audio(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Fingerprinting audio using ffmpeg-next");
+    
+    let length = job.params.get("length")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(120.0);
+    
+    let algorithm = job.params.get("algorithm")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(1);
+    
+    let acoustid_client = job.params.get("acoustid_client")
+        .and_then(|v| v.as_str());
+    
+    let duration = {
+        let ictx = ffmpeg::format::input(&job.input_path)?;
+        ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)
+    };
+    
+    let scratch = ScratchDir::new(&config.scratch, "fingerprint")?;
+    let fingerprint_path = scratch.file("fingerprint.txt");
+    let octx = ffmpeg::format::output_as(&fingerprint_path, "chromaprint")
+        .context("FFmpeg was built without chromaprint support")?;
+    
+    let mut muxer_options = ffmpeg::Dictionary::new();
+    muxer_options.set("fp_format", "base64");
+    muxer_options.set("algorithm", &algorithm.to_string());
+    
+    // Chromaprint only accepts mono or stereo signed 16-bit PCM
+    let settings = AudioOutputSettings {
+        codec: Some("pcm_s16le".to_string()),
+        channel_layout: Some(ffmpeg::ChannelLayout::STEREO),
+        ..Default::default()
+    };
+    let filter_spec = if length > 0.0 {
+        format!("atrim=duration={}", length)
+    } else {
+        "anull".to_string()
+    };
+    
+    transcode_audio_filtered_into(
+        &job.input_path,
+        octx,
+        &fingerprint_path,
+        &filter_spec,
+        &settings,
+        muxer_options,
+    )?;
+    
+    let fingerprint = std::fs::read_to_string(&fingerprint_path)?.trim().to_string();
+    
+    let mut report = serde_json::json!({
+        "duration": duration.round() as u64,
+        "algorithm": algorithm,
+        "fingerprint": fingerprint,
+    });
+    
+    if let Some(client) = acoustid_client {
+        info!("Looking up fingerprint on AcoustID");
+        
+        // The client key goes in the request body, never on a command line
+        let url = reqwest::Url::parse("https://api.acoustid.org/v2/lookup")?;
+        let http = crate::acquisition::checked_client(&url, &config.download).await?;
+        let duration = (duration.round() as u64).to_string();
+        let response = http
+            .post(url)
+            .form(&[
+                ("client", client),
+                ("duration", duration.as_str()),
+                ("meta", "recordings releasegroups"),
+                ("fingerprint", fingerprint.as_str()),
+            ])
+            .send()
+            .await
+            .context("AcoustID lookup failed")?;
+        
+        let status = response.status();
+        let body = response.bytes().await.context("Failed to read AcoustID response")?;
+        if !status.is_success() {
+            anyhow::bail!("AcoustID lookup failed ({}): {}", status, String::from_utf8_lossy(&body));
+        }
+        
+        let lookup: serde_json::Value = serde_json::from_slice(&body)
+            .context("Invalid AcoustID response")?;
+        report["acoustid"] = lookup;
+    }
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Estimate tempo (BPM) and musical key, written as JSON
+///
+/// Tempo comes from autocorrelating a spectral-flux onset envelope; the key from correlating
+/// the track's average chroma vector with the Krumhansl-Kessler major/minor key profiles.
+pub async fn analyze_music(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Analyzing tempo and key using ffmpeg-next");
+    
+    let min_bpm = job.params.get("min_bpm")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(60.0);
+    
+    let max_bpm = job.params.get("max_bpm")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(200.0);
+    
+    if !(min_bpm > 0.0 && min_bpm < max_bpm) {
+        anyhow::bail!("min_bpm must be above 0 and below max_bpm (got {} - {})", min_bpm, max_bpm);
+    }
+    
+    const ANALYSIS_RATE: u32 = 22050;
+    const HOP: usize = 512;
+    const ONSET_FFT: usize = 2048;
+    const CHROMA_FFT: usize = 8192;
+    
+    let mut reader = PcmReader::open(
+        &job.input_path,
+        Some(ANALYSIS_RATE),
+        Some(ffmpeg::ChannelLayout::MONO),
+    )?;
+    let mut onset_analyzer = SpectrumAnalyzer::new(ONSET_FFT);
+    let mut chroma_analyzer = SpectrumAnalyzer::new(CHROMA_FFT);
+    
+    let mut window: Vec<f32> = Vec::with_capacity(CHROMA_FFT + HOP);
+    let mut previous_spectrum: Option<Vec<f32>> = None;
+    let mut onset_envelope = Vec::new();
+    let mut chroma = [0f64; 12];
+    let mut hops = 0usize;
+    
+    loop {
+        let block = reader.read(HOP)?;
+        if block[0].is_empty() {
+            break;
+        }
+    
+        window.extend_from_slice(&block[0]);
+        if window.len() > CHROMA_FFT {
+            window.drain(..window.len() - CHROMA_FFT);
+        }
+        hops += 1;
+    
+        if window.len() >= ONSET_FFT {
+            let spectrum: Vec<f32> = onset_analyzer
+                .magnitudes(&window[window.len() - ONSET_FFT..])
+                .iter()
+                .map(|m| (1.0 + 1000.0 * m).ln())
+                .collect();
+    
+            let flux = match &previous_spectrum {
+                Some(previous) => spectrum
+                    .iter()
+                    .zip(previous)
+                    .map(|(current, previous)| (current - previous).max(0.0) as f64)
+                    .sum(),
+                None => 0.0,
+            };
+            onset_envelope.push(flux);
+            previous_spectrum = Some(spectrum);
+        }
+    
+        // Chroma from non-overlapping long frames for enough low-frequency resolution
+        if window.len() == CHROMA_FFT && hops % (CHROMA_FFT / HOP) == 0 {
+            let magnitudes = chroma_analyzer.magnitudes(&window);
+            for (bin, magnitude) in magnitudes.iter().enumerate().skip(1) {
+                let frequency = bin as f64 * ANALYSIS_RATE as f64 / CHROMA_FFT as f64;
+                if !(55.0..=5000.0).contains(&frequency) {
+                    continue;
+                }
+                let midi_note = (12.0 * (frequency / 440.0).log2() + 69.0).round() as i64;
+                chroma[midi_note.rem_euclid(12) as usize] += (*magnitude as f64).powi(2);
+            }
+        }
+    }
+    
+    let duration = hops as f64 * HOP as f64 / ANALYSIS_RATE as f64;
+    let frame_rate = ANALYSIS_RATE as f64 / HOP as f64;
+    
+    let tempo = estimate_tempo(&onset_envelope, frame_rate, min_bpm, max_bpm);
+    let key = estimate_key(&chroma);
+    
+    let chroma_total: f64 = chroma.iter().sum();
+    let chroma_profile: Vec<f64> = chroma
+        .iter()
+        .map(|c| if chroma_total > 0.0 { c / chroma_total } else { 0.0 })
+        .collect();
+    
+    let report = serde_json::json!({
+        "duration": duration,
+        "bpm": tempo.map(|(bpm, _)| (bpm * 10.0).round() / 10.0),
+        "bpm_confidence": tempo.map(|(_, confidence)| confidence),
+        "key": key.map(|(tonic, _, _)| tonic),
+        "scale": key.map(|(_, scale, _)| scale),
+        "key_confidence": key.map(|(_, _, confidence)| confidence),
+        "chroma": chroma_profile,
+    });
+    
+    info!(
+        "Estimated tempo {:?} BPM, key {:?}",
+        report["bpm"].as_f64(),
+        key.map(|(tonic, scale, _)| format!("{} {}", tonic, scale))
+    );
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Report clipped sample runs and inter-sample true-peak overs as JSON
+///
+/// A clipped run is at least `min_run` consecutive samples at or above `clip_level`; an over
+/// is a sample whose 4x oversampled true peak exceeds `true_peak_limit` dBTP.
+pub async fn detect_clipping(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Detecting clipping using ffmpeg-next");
+    
+    let clip_level = job.params.get("clip_level")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.999) as f32;
+    
+    let min_run = job.params.get("min_run")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(3)
+        .max(1) as usize;
+    
+    let true_peak_limit_db = job.params.get("true_peak_limit")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(-1.0);
+    
+    let max_events = job.params.get("max_events")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(20) as usize;
+    
+    let mut reader = PcmReader::open(&job.input_path, None, None)?;
+    let sample_rate = reader.sample_rate() as f64;
+    let channels = reader.channels();
+    let true_peak_limit = 10f64.powf(true_peak_limit_db / 20.0);
+    
+    let mut meters: Vec<TruePeakMeter> = (0..channels).map(|_| TruePeakMeter::new()).collect();
+    let mut sample_peaks = vec![0f32; channels];
+    let mut true_peaks = vec![0f64; channels];
+    let mut overs = vec![0u64; channels];
+    let mut run_starts: Vec<Option<u64>> = vec![None; channels];
+    let mut clipped_samples = vec![0u64; channels];
+    // (channel, start sample, length)
+    let mut runs: Vec<(usize, u64, u64)> = Vec::new();
+    // Sample positions of all events, for grouping into time ranges
+    let mut event_positions: Vec<u64> = Vec::new();
+    let mut position = 0u64;
+    
+    let close_run = |channel: usize, start: u64, end: u64, runs: &mut Vec<(usize, u64, u64)>| {
+        if end - start >= min_run as u64 {
+            runs.push((channel, start, end - start));
+        }
+    };
+    
+    loop {
+        let block = reader.read(4096)?;
+        let count = block[0].len();
+        if count == 0 {
+            break;
+        }
+    
+        for (channel, plane) in block.iter().enumerate() {
+            for (i, sample) in plane.iter().enumerate() {
+                let sample_position = position + i as u64;
+                let level = sample.abs();
+                sample_peaks[channel] = sample_peaks[channel].max(level);
+    
+                let peak = meters[channel].process(*sample);
+                true_peaks[channel] = true_peaks[channel].max(peak);
+                if peak > true_peak_limit {
+                    overs[channel] += 1;
+                    event_positions.push(sample_position);
+                }
+    
+                if level >= clip_level {
+                    clipped_samples[channel] += 1;
+                    run_starts[channel].get_or_insert(sample_position);
+                } else if let Some(start) = run_starts[channel].take() {
+                    close_run(channel, start, sample_position, &mut runs);
+                }
+            }
+        }
+    
+        position += count as u64;
+    }
+    
+    for (channel, start) in run_starts.iter().enumerate() {
+        if let Some(start) = start {
+            close_run(channel, *start, position, &mut runs);
+        }
+    }
+    
+    event_positions.extend(runs.iter().map(|(_, start, _)| *start));
+    event_positions.sort_unstable();
+    
+    // Group events less than 100ms apart into affected ranges
+    let gap = (sample_rate * 0.1) as u64;
+    let mut ranges: Vec<(u64, u64, u64)> = Vec::new();
+    for event in event_positions {
+        match ranges.last_mut() {
+            Some((_, end, events)) if event <= *end + gap => {
+                *end = (*end).max(event);
+                *events += 1;
+            }
+            _ => ranges.push((event, event, 1)),
+        }
+    }
+    
+    let to_db = |level: f64| if level > 0.0 { 20.0 * level.log10() } else { f64::NEG_INFINITY };
+    let total_runs = runs.len();
+    let total_overs: u64 = overs.iter().sum();
+    
+    let mut worst_runs = runs.clone();
+    worst_runs.sort_by(|a, b| b.2.cmp(&a.2));
+    worst_runs.truncate(max_events);
+    
+    let report = serde_json::json!({
+        "clip_level": clip_level,
+        "min_run": min_run,
+        "true_peak_limit_db": true_peak_limit_db,
+        "clipped_runs": total_runs,
+        "true_peak_overs": total_overs,
+        "max_true_peak_db": to_db(true_peaks.iter().cloned().fold(0.0, f64::max)),
+        "channels": (0..channels).map(|channel| serde_json::json!({
+            "channel": channel,
+            "sample_peak_db": to_db(sample_peaks[channel] as f64),
+            "true_peak_db": to_db(true_peaks[channel]),
+            "clipped_samples": clipped_samples[channel],
+            "clipped_runs": runs.iter().filter(|run| run.0 == channel).count(),
+            "longest_run": runs.iter().filter(|run| run.0 == channel).map(|run| run.2).max().unwrap_or(0),
+            "true_peak_overs": overs[channel],
+        })).collect::<Vec<_>>(),
+        "worst_runs": worst_runs.iter().map(|(channel, start, length)| serde_json::json!({
+            "channel": channel,
+            "time": *start as f64 / sample_rate,
+            "sample": start,
+            "length": length,
+        })).collect::<Vec<_>>(),
+        "affected_ranges": ranges.iter().map(|(start, end, events)| serde_json::json!({
+            "start": *start as f64 / sample_rate,
+            "end": *end as f64 / sample_rate,
+            "events": events,
+        })).collect::<Vec<_>>(),
+    });
+    
+    info!(
+        "Found {} clipped runs and {} true-peak overs in {} ranges",
+        total_runs, total_overs, ranges.len()
+    );
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Measure per-channel DC offset and optionally remove it
+///
+/// `method` is `subtract` (remove the measured constant offset, default) or `highpass`
+/// (5 Hz high-pass, also removes slowly drifting offset). With `correct` disabled only the JSON
+/// measurement is written to `output_path`; otherwise the corrected audio goes to `output_path`
+/// and the before/after report to `report_path` (default: `<output_path>.json`).
+pub async fn fix_dc_offset(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Measuring DC offset using ffmpeg-next");
+    
+    let correct = job.params.get("correct")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let method = job.params.get("method")
+        .and_then(|v| v.as_str())
+        .unwrap_or("subtract");
+    
+    let threshold = job.params.get("threshold")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.0);
+    
+    let report_path = job.params.get("report_path")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}.json", job.output_path));
+    
+    let before = measure_dc_offset(&job.input_path)?;
+    let worst = before.iter().fold(0f64, |worst, offset| worst.max(offset.abs()));
+    
+    let offsets_json = |offsets: &[f64]| {
+        offsets.iter().enumerate().map(|(channel, offset)| serde_json::json!({
+            "channel": channel,
+            "offset": offset,
+            "offset_db": if *offset != 0.0 { Some(20.0 * offset.abs().log10()) } else { None },
+        })).collect::<Vec<_>>()
+    };
+    
+    info!("Measured DC offsets: {:?}", before);
+    
+    if !correct {
+        let report = serde_json::json!({ "before": offsets_json(&before) });
+        std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+        return Ok(job.output_path.clone());
+    }
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    
+    if worst <= threshold {
+        info!("DC offset {:.6} within threshold, copying audio unchanged", worst);
+        transcode_audio_filtered(&job.input_path, &job.output_path, "anull", &settings)?;
+    } else {
+        match method {
+            "subtract" => {
+                let mut reader = PcmReader::open(&job.input_path, settings.sample_rate, None)?;
+                let mut writer = PcmWriter::create(
+                    &job.output_path,
+                    &settings,
+                    reader.channel_layout(),
+                    reader.sample_rate(),
+                )?;
+    
+                loop {
+                    let mut block = reader.read(4096)?;
+                    if block[0].is_empty() {
+                        break;
+                    }
+                    for (plane, offset) in block.iter_mut().zip(&before) {
+                        plane.iter_mut().for_each(|sample| *sample -= *offset as f32);
+                    }
+                    writer.write(&block)?;
+                }
+    
+                writer.finish()?;
+            }
+            "highpass" => {
+                transcode_audio_filtered(&job.input_path, &job.output_path, "highpass=f=5:p=2", &settings)?;
+            }
+            _ => anyhow::bail!("Unsupported DC offset correction method: {}", method),
+        }
+    }
+    
+    let after = measure_dc_offset(&job.output_path)?;
+    info!("DC offsets after correction: {:?}", after);
+    
+    let report = serde_json::json!({
+        "method": method,
+        "corrected": worst > threshold,
+        "before": offsets_json(&before),
+        "after": offsets_json(&after),
+    });
+    std::fs::write(&report_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Separate a mix into stems with the backend configured under `[separation]`
+///
+/// Stems are written to `output_dir` (default: `<output_path>_stems`), re-encoded when
+/// `format` is given, and `output_path` receives a JSON index of the stem files.
+pub async fn separate_stems(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Separating stems with {}", config.separation.command);
+    
+    let format = job.params.get("format")
+        .and_then(|v| v.as_str())
+        .unwrap_or("wav");
+    
+    let output_dir = job.params.get("output_dir")
+        .and_then(|v| v.as_str())
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| {
+            let output = std::path::Path::new(&job.output_path);
+            output.with_file_name(format!(
+                "{}_stems",
+                output.file_stem().and_then(|s| s.to_str()).unwrap_or("output")
+            ))
+        });
+    std::fs::create_dir_all(&output_dir)
+        .context(format!("Failed to create stem directory {}", output_dir.display()))?;
+    
+    let requested: Vec<String> = match job.params.get("stems").and_then(|v| v.as_array()) {
+        Some(stems) => stems.iter().filter_map(|v| v.as_str()).map(String::from).collect(),
+        None => config.separation.stems.clone(),
+    };
+    
+    if let Some(unknown) = requested.iter().find(|stem| !config.separation.stems.contains(stem)) {
+        anyhow::bail!(
+            "Stem '{}' is not produced by the separation backend (available: {})",
+            unknown,
+            config.separation.stems.join(", ")
+        );
+    }
+    
+    let scratch = ScratchDir::new(&config.scratch, "separation")?;
+    let work_dir = scratch.path();
+    
+    let input_stem = std::path::Path::new(&job.input_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default()
+        .to_string();
+    let work_dir_str = work_dir.to_string_lossy().to_string();
+    let substitute = |template: &str| {
+        template
+            .replace("{input}", &job.input_path)
+            .replace("{input_stem}", &input_stem)
+            .replace("{output_dir}", &work_dir_str)
+    };
+    
+    let args: Vec<String> = config.separation.args.iter().map(|arg| substitute(arg)).collect();
+    let output = shutdown::output(std::process::Command::new(&config.separation.command).args(&args))
+        .context(format!("Failed to execute {}", config.separation.command))?;
+    scratch.check()?;
+    
+    if !output.status.success() {
+        anyhow::bail!("Stem separation failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    
+    let mut stems = serde_json::Map::new();
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    
+    for stem in &requested {
+        let separated = substitute(&config.separation.output_pattern).replace("{stem}", stem);
+        if !std::path::Path::new(&separated).exists() {
+            anyhow::bail!("Separation backend did not produce stem '{}' at {}", stem, separated);
+        }
+    
+        let stem_path = output_dir.join(format!("{}.{}", stem, format));
+        let stem_path_str = stem_path.to_string_lossy().to_string();
+    
+        if separated.ends_with(&format!(".{}", format)) && settings.codec.is_none() {
+            // The scratch directory may be on another filesystem
+            crate::acquisition::move_file(&separated, &stem_path_str)?;
+        } else {
+            transcode_audio_filtered(&separated, &stem_path_str, "anull", &settings)?;
+        }
+    
+        info!("Wrote {} stem to {}", stem, stem_path_str);
+        stems.insert(stem.clone(), serde_json::json!({
+            "path": stem_path_str,
+            "size_bytes": std::fs::metadata(&stem_path)?.len(),
+        }));
+    }
+    
+    drop(scratch);
+    
+    let index = serde_json::json!({
+        "backend": config.separation.command,
+        "output_dir": output_dir.to_string_lossy(),
+        "stems": stems,
+    });
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&index)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Duck a music bed under a voice track with sidechain compression
+///
+/// `input_path` is the voice track and `music` the bed; the voice drives `sidechaincompress`
+/// on the music. With `mix` (default) the output is voice plus ducked music, otherwise only
+/// the ducked music. `duration` is `voice` (default) or `longest`.
+pub async fn duck_music(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Ducking music under voice using ffmpeg-next");
+    
+    let music_path = job.params.get("music")
+        .and_then(|v| v.as_str())
+        .context("music parameter required")?;
+    
+    let threshold_db = job.params.get("threshold")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(-30.0);
+    
+    let ratio = job.params.get("ratio")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(8.0);
+    
+    let attack = job.params.get("attack")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(20.0);
+    
+    let release = job.params.get("release")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(400.0);
+    
+    let music_gain = job.params.get("music_gain")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.0);
+    
+    let mix = job.params.get("mix")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let duration = job.params.get("duration")
+        .and_then(|v| v.as_str())
+        .unwrap_or("voice");
+    
+    if duration != "voice" && duration != "longest" {
+        anyhow::bail!("Unsupported duration mode: {}", duration);
+    }
+    
+    let mut voice = PcmReader::open(&job.input_path, None, None)?;
+    let sample_rate = voice.sample_rate();
+    let channel_layout = voice.channel_layout();
+    let channels = voice.channels();
+    let mut music = PcmReader::open(music_path, Some(sample_rate), Some(channel_layout))?;
+    
+    let compressor = format!(
+        "sidechaincompress=threshold={}:ratio={}:attack={}:release={}",
+        10f64.powf(threshold_db / 20.0),
+        ratio.clamp(1.0, 20.0),
+        attack.clamp(0.01, 2000.0),
+        release.clamp(0.01, 9000.0)
+    );
+    let filter_spec = if mix {
+        format!(
+            "[in0]volume={}dB[music];[in1]asplit=2[sc][voice];[music][sc]{}[ducked];\
+             [ducked][voice]amix=inputs=2:normalize=0:duration=first",
+            music_gain, compressor
+        )
+    } else {
+        format!("[in0]volume={}dB[music];[music][in1]{}", music_gain, compressor)
+    };
+    
+    let source = AudioSourceFormat {
+        format: ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Planar),
+        channel_layout,
+        sample_rate,
+        time_base: ffmpeg::Rational::new(1, sample_rate as i32),
+    };
+    let mut graph = build_audio_filter_graph(
+        &[source, source],
+        &filter_spec,
+        &AudioSinkFormat::planar_f32(channel_layout, sample_rate),
+    )?;
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    let mut writer = PcmWriter::create(&job.output_path, &settings, channel_layout, sample_rate)?;
+    
+    let block_size = 4096usize;
+    let mut position = 0i64;
+    
+    loop {
+        let mut voice_block = voice.read(block_size)?;
+        let mut music_block = music.read(block_size)?;
+    
+        let count = match duration {
+            "voice" => voice_block[0].len(),
+            _ => voice_block[0].len().max(music_block[0].len()),
+        };
+        if count == 0 {
+            break;
+        }
+    
+        // Whichever track ran out first continues as silence
+        for plane in voice_block.iter_mut().chain(music_block.iter_mut()) {
+            plane.resize(count, 0.0);
+        }
+    
+        for (name, planes) in [("in0", &music_block), ("in1", &voice_block)] {
+            graph
+                .get(name)
+                .context("Filter source missing")?
+                .source()
+                .add(&planar_f32_frame(planes, channel_layout, sample_rate, position))?;
+        }
+        write_filtered_planes(&mut graph, &mut writer, channels)?;
+    
+        position += count as i64;
+        if count < block_size {
+            break;
+        }
+    }
+    
+    for name in ["in0", "in1"] {
+        graph.get(name).context("Filter source missing")?.source().flush()?;
+    }
+    write_filtered_planes(&mut graph, &mut writer, channels)?;
+    writer.finish()?;
+    
+    info!(
+        "Ducking complete: {:.2}s",
+        position as f64 / sample_rate as f64
+    );
+    Ok(job.output_path.clone())
+}
+
+/// Add a silent audio track to a video-only file
+///
+/// Video and subtitle streams are copied; the silent track (default: 48 kHz stereo AAC) is
+/// generated alongside the video packets so it spans the whole file. Files that already have
+/// audio are remuxed unchanged.
+pub async fn add_silent_audio(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Adding silent audio track using ffmpeg-next");
+    
+    let layout_name = job.params.get("channel_layout")
+        .and_then(|v| v.as_str())
+        .unwrap_or("stereo");
+    let channel_layout = parse_channel_layout(layout_name)
+        .context(format!("Unsupported channel layout: {}", layout_name))?;
+    
+    let mut settings = AudioOutputSettings::from_params(&job.params)?;
+    settings.codec.get_or_insert_with(|| "aac".to_string());
+    settings.bit_rate.get_or_insert(128_000);
+    let sample_rate = settings.sample_rate.unwrap_or(48000);
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    
+    if ictx.streams().best(ffmpeg::media::Type::Audio).is_some() {
+        info!("Input already has an audio stream, remuxing unchanged");
+        drop(ictx);
+        remux_with_metadata(&job.input_path, &job.output_path, &[], &[])?;
+        return Ok(job.output_path.clone());
+    }
+    
+    let mut octx = ffmpeg::format::output(&job.output_path)
+        .context("Failed to create output file")?;
+    
+    let mut stream_mapping = vec![-1i32; ictx.nb_streams() as usize];
+    let mut input_time_bases = vec![ffmpeg::Rational(0, 1); ictx.nb_streams() as usize];
+    let mut output_index = 0;
+    
+    for (input_index, ist) in ictx.streams().enumerate() {
+        let medium = ist.parameters().medium();
+        if medium != ffmpeg::media::Type::Video && medium != ffmpeg::media::Type::Subtitle {
+            continue;
+        }
+    
+        stream_mapping[input_index] = output_index;
+        input_time_bases[input_index] = ist.time_base();
+        output_index += 1;
+    
+        let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        ost.set_metadata(ist.metadata().to_owned());
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+    }
+    
+    let audio_index = output_index as usize;
+    let mut encoder = open_audio_encoder(&mut octx, &job.output_path, &settings, channel_layout, sample_rate)?;
+    let mut graph = build_audio_filter_graph(
+        &[AudioSourceFormat {
+            format: ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Planar),
+            channel_layout,
+            sample_rate,
+            time_base: ffmpeg::Rational::new(1, sample_rate as i32),
+        }],
+        "anull",
+        &AudioSinkFormat::for_encoder(&encoder),
+    )?;
+    
+    octx.set_metadata(ictx.metadata().to_owned());
+    octx.write_header()?;
+    
+    let audio_time_base = octx.stream(audio_index).context("Output stream missing")?.time_base();
+    let container_duration = ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE);
+    let mut silence_written = 0i64;
+    let mut media_end = 0f64;
+    
+    for (stream, mut packet) in ictx.packets() {
+        let input_index = stream.index();
+        let output_index = stream_mapping[input_index];
+        if output_index < 0 {
+            continue;
+        }
+    
+        // Keep the audio track level with the copied streams so the muxer can interleave
+        let time_base = input_time_bases[input_index];
+        if let Some(timestamp) = packet.dts().or(packet.pts()) {
+            let end = (timestamp + packet.duration()) as f64 * f64::from(time_base);
+            media_end = media_end.max(end);
+            let target = (end * sample_rate as f64) as i64;
+            write_silence(
+                &mut graph,
+                &mut encoder,
+                &mut octx,
+                audio_index,
+                audio_time_base,
+                &mut silence_written,
+                target,
+            )?;
+        }
+    
+        let output_time_base = octx
+            .stream(output_index as usize)
+            .context("Output stream missing")?
+            .time_base();
+        packet.rescale_ts(time_base, output_time_base);
+        packet.set_position(-1);
+        packet.set_stream(output_index as usize);
+        packet.write_interleaved(&mut octx)?;
+    }
+    
+    let target = (container_duration.max(media_end) * sample_rate as f64) as i64;
+    write_silence(
+        &mut graph,
+        &mut encoder,
+        &mut octx,
+        audio_index,
+        audio_time_base,
+        &mut silence_written,
+        target,
+    )?;
+    
+    graph.get("in").context("Filter source missing")?.source().flush()?;
+    encode_filtered_audio(&mut graph, &mut encoder, &mut octx, audio_index, audio_time_base)?;
+    encoder.send_eof()?;
+    write_encoded_audio(&mut encoder, &mut octx, audio_index, audio_time_base)?;
+    
+    octx.write_trailer()?;
+    
+    info!(
+        "Added {:.2}s of silent {} audio",
+        silence_written as f64 / sample_rate as f64,
+        layout_name
+    );
+    Ok(job.output_path.clone())
+}
+
+/// Decode the input once and encode it to several output targets
+///
+/// Each entry of `targets` takes `codec`, `bitrate`, `sample_rate`, `channel_layout` and either
+/// `path` or `extension` (written next to `output_path`). `output_path` receives a JSON index
+/// of the encoded files.
+pub async fn encode_audio_multi(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Encoding audio to multiple targets using ffmpeg-next");
+    
+    let targets = job.params.get("targets")
+        .and_then(|v| v.as_array())
+        .context("targets array parameter required")?;
+    
+    if targets.is_empty() {
+        anyhow::bail!("At least one target required");
+    }
+    
+    let output = std::path::Path::new(&job.output_path);
+    let base_name = output.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+    
+    let mut reader = PcmReader::open(&job.input_path, None, None)?;
+    let sample_rate = reader.sample_rate();
+    let channel_layout = reader.channel_layout();
+    
+    let mut writers = Vec::with_capacity(targets.len());
+    let mut paths = Vec::with_capacity(targets.len());
+    
+    for (index, target) in targets.iter().enumerate() {
+        let path = match (
+            target.get("path").and_then(|v| v.as_str()),
+            target.get("extension").and_then(|v| v.as_str()),
+        ) {
+            (Some(path), _) => path.to_string(),
+            (None, Some(extension)) => output
+                .with_file_name(format!("{}.{}", base_name, extension.trim_start_matches('.')))
+                .to_string_lossy()
+                .to_string(),
+            (None, None) => anyhow::bail!("Target {} needs a path or extension", index),
+        };
+    
+        if paths.contains(&path) {
+            anyhow::bail!("Duplicate target path: {}", path);
+        }
+    
+        let mut settings = AudioOutputSettings::from_params(target)?;
+        if let Some(layout_name) = target.get("channel_layout").and_then(|v| v.as_str()) {
+            let layout = parse_channel_layout(layout_name)
+                .context(format!("Unsupported channel layout: {}", layout_name))?;
+            settings = settings.with_channel_layout(layout);
+        }
+    
+        info!("Target {}: {}", index + 1, path);
+        writers.push(PcmWriter::create(&path, &settings, channel_layout, sample_rate)?);
+        paths.push(path);
+    }
+    
+    let mut total_samples = 0u64;
+    loop {
+        let block = reader.read(4096)?;
+        if block[0].is_empty() {
+            break;
+        }
+        for writer in writers.iter_mut() {
+            writer.write(&block)?;
+        }
+        total_samples += block[0].len() as u64;
+    }
+    
+    for writer in writers {
+        writer.finish()?;
+    }
+    
+    let mut outputs = Vec::with_capacity(paths.len());
+    for (path, target) in paths.iter().zip(targets) {
+        let ictx = ffmpeg::format::input(path)?;
+        let stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Audio)
+            .context(format!("No audio stream written to {}", path))?;
+        let codec_id = stream.parameters().id();
+        let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?;
+    
+        outputs.push(serde_json::json!({
+            "path": path,
+            "codec": codec_id.name(),
+            "bitrate": target.get("bitrate"),
+            "sample_rate": decoder.rate(),
+            "channels": decoder.channels(),
+            "duration": ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE),
+            "size_bytes": std::fs::metadata(path)?.len(),
+        }));
+    }
+    
+    let index = serde_json::json!({
+        "input": job.input_path,
+        "duration": total_samples as f64 / sample_rate as f64,
+        "outputs": outputs,
+    });
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&index)?)?;
+    
+    info!("Encoded {} targets from a single decode", paths.len());
+    Ok(job.output_path.clone())
+}
+
+/// Convert sample format / bit depth (16/24/32-bit integer, 32/64-bit float)
+///
+/// The PCM codec is chosen from the output container (`.wav` little-endian, `.aif(f)`
+/// big-endian, `.flac` for 16/24-bit). Reducing bit depth applies TPDF dither unless
+/// `dither` is `none`.
+pub async fn convert_sample_format(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Converting sample format using ffmpeg-next");
+    
+    let target = job.params.get("sample_format")
+        .and_then(|v| v.as_str())
+        .context("sample_format parameter required (s16, s24, s32, f32, f64)")?;
+    
+    let dither = job.params.get("dither")
+        .and_then(|v| v.as_str())
+        .unwrap_or("tpdf");
+    
+    if dither != "tpdf" && dither != "none" {
+        anyhow::bail!("Unsupported dither mode: {}", dither);
+    }
+    
+    let target_bits = match target {
+        "s16" => Some(16),
+        "s24" => Some(24),
+        "s32" => Some(32),
+        "f32" | "f64" => None,
+        _ => anyhow::bail!("Unsupported sample format: {}", target),
+    };
+    
+    let extension = std::path::Path::new(&job.output_path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    
+    let mut settings = AudioOutputSettings::from_params(&job.params)?;
+    if settings.codec.is_none() {
+        let (codec, sample_format, bits) = match (extension.as_str(), target) {
+            ("flac", "s16") => (
+                "flac".to_string(),
+                Some(ffmpeg::format::Sample::I16(ffmpeg::format::sample::Type::Packed)),
+                None,
+            ),
+            ("flac", "s24") => (
+                "flac".to_string(),
+                Some(ffmpeg::format::Sample::I32(ffmpeg::format::sample::Type::Packed)),
+                Some(24),
+            ),
+            ("flac", _) => anyhow::bail!("FLAC only supports s16 and s24 sample formats"),
+            ("aif" | "aiff", _) => (format!("pcm_{}be", target), None, None),
+            _ => (format!("pcm_{}le", target), None, None),
+        };
+        settings.codec = Some(codec);
+        settings.sample_format = sample_format;
+        settings.bits_per_raw_sample = bits;
+    }
+    
+    let (source_bits, source_float) = source_bit_depth(&job.input_path)?;
+    let reduces_depth = match target_bits {
+        Some(bits) => source_float || source_bits > bits,
+        None => false,
+    };
+    
+    info!(
+        "Source {}-bit {}, target {} ({})",
+        source_bits,
+        if source_float { "float" } else { "integer" },
+        target,
+        settings.codec.as_deref().unwrap_or("default codec")
+    );
+    
+    // f32 cannot carry more than 24 bits, so 32-bit targets never need dither
+    match target_bits {
+        Some(bits) if reduces_depth && bits <= 24 && dither == "tpdf" => {
+            let mut reader = PcmReader::open(&job.input_path, settings.sample_rate, None)?;
+            let mut writer = PcmWriter::create(
+                &job.output_path,
+                &settings,
+                reader.channel_layout(),
+                reader.sample_rate(),
+            )?;
+            let mut ditherer = TpdfDither::new(bits)?;
+    
+            loop {
+                let mut block = reader.read(4096)?;
+                if block[0].is_empty() {
+                    break;
+                }
+                for plane in block.iter_mut() {
+                    ditherer.process(plane);
+                }
+                writer.write(&block)?;
+            }
+    
+            writer.finish()?;
+            info!("Applied TPDF dither to {} bits", bits);
+        }
+        _ => {
+            transcode_audio_filtered(&job.input_path, &job.output_path, "anull", &settings)?;
+        }
+    }
+    
+    Ok(job.output_path.clone())
+}
+
+/// Embed an inaudible spread-spectrum watermark carrying up to 8 bytes of `payload`
+///
+/// Each payload bit (plus a CRC-8) modulates a pseudo-noise sequence derived from `key`,
+/// added at `strength` dB below the local signal level and repeated for the whole file.
+pub async fn watermark_audio(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Embedding audio watermark using ffmpeg-next");
+    
+    let payload = job.params.get("payload")
+        .and_then(|v| v.as_str())
+        .context("payload parameter required")?;
+    
+    let key = job.params.get("key")
+        .and_then(|v| v.as_str())
+        .context("key is required")?;
+    
+    let strength_db = job.params.get("strength")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(-35.0);
+    
+    let bits = watermark_bits(payload)?;
+    let sequence = watermark_sequence(key);
+    let gain = 10f64.powf(strength_db / 20.0) as f32;
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?;
+    let mut reader = PcmReader::open(&job.input_path, None, None)?;
+    let mut writer = PcmWriter::create(
+        &job.output_path,
+        &settings,
+        reader.channel_layout(),
+        reader.sample_rate(),
+    )?;
+    
+    let mut chip_blocks = 0usize;
+    loop {
+        let mut block = reader.read(WATERMARK_CHIPS)?;
+        let count = block[0].len();
+        if count == 0 {
+            break;
+        }
+    
+        // Scale to the block's level so the mark stays masked and silence stays silent
+        let energy: f64 = block.iter().flatten().map(|s| (*s as f64) * (*s as f64)).sum();
+        let rms = (energy / (count * block.len()) as f64).sqrt() as f32;
+        let amplitude = gain * rms * bits[chip_blocks % bits.len()];
+    
+        for plane in block.iter_mut() {
+            for (sample, chip) in plane.iter_mut().zip(&sequence) {
+                *sample += amplitude * chip;
+            }
+        }
+    
+        writer.write(&block)?;
+        chip_blocks += 1;
+    }
+    
+    writer.finish()?;
+    
+    info!(
+        "Embedded {}-bit watermark {} times",
+        bits.len(),
+        chip_blocks / bits.len()
+    );
+    Ok(job.output_path.clone())
+}
+
+/// Detect a watermark embedded by `watermark_audio` and write the recovered payload as JSON
+///
+/// The file must be at the sample rate it was marked at (or pass that as `sample_rate`).
+/// With `search` the chip alignment is scanned, so copies with a leading trim shorter than one
+/// bit period (4096 samples) are still detected.
+pub async fn detect_audio_watermark(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Detecting audio watermark using ffmpeg-next");
+    
+    let key = job.params.get("key")
+        .and_then(|v| v.as_str())
+        .context("key is required")?;
+    
+    let sample_rate = job.params.get("sample_rate")
+        .and_then(|v| v.as_u64())
+        .map(|v| v as u32);
+    
+    let search = job.params.get("search")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let sequence = watermark_sequence(key);
+    let reference: Vec<f32> = std::iter::once(sequence[0])
+        .chain(sequence.windows(2).map(|pair| pair[1] - pair[0]))
+        .collect();
+    
+    let offsets: Vec<usize> = if search {
+        (0..WATERMARK_CHIPS).step_by(16).collect()
+    } else {
+        vec![0]
+    };
+    
+    let frame_bits = WATERMARK_PAYLOAD_BYTES * 8 + 8;
+    
+    // Blocks are correlated as the input streams in, for every offset at once, so only the
+    // whitened samples a block still to come needs are kept
+    let mut sums = vec![vec![0f64; frame_bits]; offsets.len()];
+    let mut next_block = vec![0usize; offsets.len()];
+    let mut window: Vec<f32> = Vec::new();
+    let mut window_start = 0usize;
+    let mut previous: Option<f32> = None;
+    
+    let mut reader = PcmReader::open(&job.input_path, sample_rate, Some(ffmpeg::ChannelLayout::MONO))?;
+    loop {
+        let block = reader.read(65536)?;
+        if block[0].is_empty() {
+            break;
+        }
+        // Differencing whitens the host audio, which is dominated by low frequencies
+        for &sample in &block[0] {
+            window.push(previous.map_or(0.0, |previous| sample - previous));
+            previous = Some(sample);
+        }
+        
+        for (index, &offset) in offsets.iter().enumerate() {
+            loop {
+                let start = offset + next_block[index] * WATERMARK_CHIPS - window_start;
+                let Some(chips) = window.get(start..start + WATERMARK_CHIPS) else {
+                    break;
+                };
+                let energy: f64 = chips.iter().map(|s| (*s as f64) * (*s as f64)).sum();
+                if energy > f64::EPSILON {
+                    let correlation: f64 = chips.iter().zip(&reference).map(|(a, b)| (*a as f64) * (*b as f64)).sum();
+                    sums[index][next_block[index] % frame_bits] += correlation / energy.sqrt();
+                }
+                next_block[index] += 1;
+            }
+        }
+        
+        let needed = offsets
+            .iter()
+            .zip(&next_block)
+            .map(|(offset, block)| offset + block * WATERMARK_CHIPS)
+            .min()
+            .unwrap_or(window_start);
+        window.drain(..needed - window_start);
+        window_start = needed;
+    }
+    
+    if next_block[0] == 0 {
+        anyhow::bail!("Input too short to contain a watermark");
+    }
+    
+    let mut best: Option<(usize, Vec<f64>, f64)> = None;
+    for (offset, sums) in offsets.into_iter().zip(sums) {
+        let score = sums.iter().map(|s| s.abs()).sum::<f64>();
+        if best.as_ref().map_or(true, |(_, _, best_score)| score > *best_score) {
+            best = Some((offset, sums, score));
+        }
+    }
+    let (offset, sums, _) = best.context("Input too short to contain a watermark")?;
+    
+    let mut bytes = vec![0u8; WATERMARK_PAYLOAD_BYTES + 1];
+    for (bit, sum) in sums.iter().enumerate() {
+        if *sum > 0.0 {
+            bytes[bit / 8] |= 0x80 >> (bit % 8);
+        }
+    }
+    let checksum_ok = crc8(&bytes[..WATERMARK_PAYLOAD_BYTES]) == bytes[WATERMARK_PAYLOAD_BYTES];
+    
+    // Mean per-bit correlation relative to its spread, a rough detection margin
+    let mean = sums.iter().map(|s| s.abs()).sum::<f64>() / sums.len() as f64;
+    let deviation = (sums.iter().map(|s| (s.abs() - mean).powi(2)).sum::<f64>() / sums.len() as f64).sqrt();
+    let confidence = if deviation > 0.0 { mean / deviation } else { 0.0 };
+    
+    let payload_bytes: Vec<u8> = bytes[..WATERMARK_PAYLOAD_BYTES]
+        .iter()
+        .copied()
+        .take_while(|b| *b != 0)
+        .collect();
+    
+    let report = serde_json::json!({
+        "detected": checksum_ok,
+        "payload": if checksum_ok { Some(String::from_utf8_lossy(&payload_bytes).to_string()) } else { None },
+        "confidence": confidence,
+        "offset_samples": offset,
+    });
+    
+    info!("Watermark detected: {} (confidence {:.2})", checksum_ok, confidence);
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Write chapter markers into a podcast episode (ID3v2 CHAP/CTOC for MP3, chapter track for MP4)
+///
+/// `chapters` is a list of `{start, end, title}` (seconds or timestamps, `end` optional). Without
+/// it, chapter starts are derived from long silences and speech/music transitions, at least
+/// `min_chapter` seconds apart. Streams are copied without re-encoding.
+pub async fn embed_podcast_chapters(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Embedding podcast chapters using ffmpeg-next");
+    
+    let duration = {
+        let ictx = ffmpeg::format::input(&job.input_path)?;
+        ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)
+    };
+    
+    let mut starts: Vec<(f64, Option<f64>, String)> = Vec::new();
+    
+    if let Some(entries) = job.params.get("chapters").and_then(|v| v.as_array()) {
+        for (index, entry) in entries.iter().enumerate() {
+            let start = param_seconds(entry, "start")?
+                .context(format!("Chapter {} is missing a start time", index + 1))?;
+            let end = param_seconds(entry, "end")?;
+            let title = entry.get("title")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| format!("Chapter {}", index + 1));
+            starts.push((start, end, title));
+        }
+    } else {
+        let min_chapter = job.params.get("min_chapter")
+            .and_then(|v| v.as_f64())
+            .unwrap_or(60.0);
+        
+        let min_silence = job.params.get("min_silence")
+            .and_then(|v| v.as_f64())
+            .unwrap_or(1.5);
+        
+        let silence_threshold = job.params.get("silence_threshold")
+            .and_then(|v| v.as_f64())
+            .unwrap_or(-45.0);
+        
+        let (silences, _) = find_silences(&job.input_path, silence_threshold, min_silence, 10)?;
+        let mut boundaries: Vec<f64> = silences.iter().map(|(start, end)| (start + end) / 2.0).collect();
+        
+        let (_, segments, _) = classify_audio_timeline(&job.input_path, 1.0, silence_threshold)?;
+        boundaries.extend(
+            segments
+                .windows(2)
+                .filter(|pair| pair[0]["label"] != pair[1]["label"] && pair[1]["label"] != "other")
+                .filter_map(|pair| pair[1]["start"].as_f64()),
+        );
+        boundaries.sort_by(|a, b| a.total_cmp(b));
+        
+        let mut previous = 0.0;
+        starts.push((0.0, None, String::new()));
+        for boundary in boundaries {
+            if boundary - previous >= min_chapter && duration - boundary >= min_chapter {
+                starts.push((boundary, None, String::new()));
+                previous = boundary;
+            }
+        }
+        
+        for (index, chapter) in starts.iter_mut().enumerate() {
+            chapter.2 = format!("Chapter {}", index + 1);
+        }
+        
+        info!("Derived {} chapter candidates", starts.len());
+    }
+    
+    if starts.is_empty() {
+        anyhow::bail!("No chapters to embed");
+    }
+    
+    starts.sort_by(|a, b| a.0.total_cmp(&b.0));
+    
+    let mut chapters = Vec::with_capacity(starts.len());
+    for (index, (start, end, title)) in starts.iter().enumerate() {
+        let next_start = starts.get(index + 1).map(|next| next.0).unwrap_or(duration);
+        let end = end.unwrap_or(next_start).min(duration.max(*start));
+        if end < *start {
+            anyhow::bail!("Chapter '{}' ends before it starts", title);
+        }
+        chapters.push(Chapter {
+            start: *start,
+            end,
+            title: title.clone(),
+        });
+    }
+    
+    remux_with_metadata(&job.input_path, &job.output_path, &[], &chapters)?;
+    
+    info!("Embedded {} chapters", chapters.len());
+    Ok(job.output_path.clone())
+}
+
+/// Downmix 5.1/7.1 audio to stereo with ITU-R BS.775 coefficients
+pub async fn downmix_audio(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Downmixing surround audio using ffmpeg-next");
+    
+    let center_mix = job.params.get("center_mix_level")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.707);
+    
+    let surround_mix = job.params.get("surround_mix_level")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.707);
+    
+    let lfe_mix = job.params.get("lfe_mix_level")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.0);
+    
+    let night_mode = job.params.get("night_mode")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let prevent_clipping = job.params.get("prevent_clipping")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let source_layout = {
+        let ictx = ffmpeg::format::input(&job.input_path)?;
+        let stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Audio)
+            .context("No audio stream found")?;
+        let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?;
+        decoder_channel_layout(&decoder)
+    };
+    
+    if source_layout.channels() <= 2 {
+        anyhow::bail!(
+            "downmix_audio requires a multichannel source, got {} channel(s)",
+            source_layout.channels()
+        );
+    }
+    
+    let mask = source_layout.bits();
+    let has = |bit: u64| mask & bit != 0;
+    
+    // Build one pan expression per output side from whichever channels are present
+    let side_terms = |front: &str, side: &str, back: &str, front_bit: u64, side_bit: u64, back_bit: u64| {
+        let mut terms = Vec::new();
+        if has(front_bit) {
+            terms.push(front.to_string());
+        }
+        if has(ffmpeg::ffi::AV_CH_FRONT_CENTER) {
+            terms.push(format!("{:.4}*FC", center_mix));
+        }
+        if lfe_mix > 0.0 && has(ffmpeg::ffi::AV_CH_LOW_FREQUENCY) {
+            terms.push(format!("{:.4}*LFE", lfe_mix));
+        }
+        if has(side_bit) {
+            terms.push(format!("{:.4}*{}", surround_mix, side));
+        }
+        if has(back_bit) {
+            terms.push(format!("{:.4}*{}", surround_mix, back));
+        }
+        terms.join("+")
+    };
+    
+    let left = side_terms(
+        "FL", "SL", "BL",
+        ffmpeg::ffi::AV_CH_FRONT_LEFT,
+        ffmpeg::ffi::AV_CH_SIDE_LEFT,
+        ffmpeg::ffi::AV_CH_BACK_LEFT,
+    );
+    let right = side_terms(
+        "FR", "SR", "BR",
+        ffmpeg::ffi::AV_CH_FRONT_RIGHT,
+        ffmpeg::ffi::AV_CH_SIDE_RIGHT,
+        ffmpeg::ffi::AV_CH_BACK_RIGHT,
+    );
+    
+    let mut filter_spec = format!("pan=stereo|FL={}|FR={}", left, right);
+    
+    if night_mode {
+        // Reduce the gap between dialogue and effects for late-night listening
+        filter_spec.push_str(",acompressor=threshold=0.125:ratio=4:attack=10:release=250:makeup=2");
+    }
+    
+    if prevent_clipping {
+        filter_spec.push_str(",alimiter=limit=0.98:level=disabled");
+    }
+    
+    info!(filter = %filter_spec, "Downmixing {} channels to stereo", source_layout.channels());
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?
+        .with_channel_layout(ffmpeg::ChannelLayout::STEREO);
+    
+    let frame_count = transcode_audio_filtered(&job.input_path, &job.output_path, &filter_spec, &settings)?;
+    
+    info!("Downmix complete: {} frames", frame_count);
+    Ok(job.output_path.clone())
+}
+
+/// Reorder, duplicate or drop channels according to an explicit mapping
+pub async fn remap_channels(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Remapping audio channels using ffmpeg-next");
+    
+    let mapping = job.params.get("mapping")
+        .and_then(|v| v.as_array())
+        .context("mapping array parameter required")?;
+    
+    if mapping.is_empty() {
+        anyhow::bail!("mapping must contain at least one output channel");
+    }
+    
+    let source_layout = {
+        let ictx = ffmpeg::format::input(&job.input_path)?;
+        let stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Audio)
+            .context("No audio stream found")?;
+        let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?;
+        decoder_channel_layout(&decoder)
+    };
+    
+    let source_channels = source_layout.channels() as u64;
+    
+    // Resolve a single source reference (index or channel name) to a pan term
+    let resolve = |value: &serde_json::Value| -> Result<String> {
+        if let Some(index) = value.as_u64() {
+            if index >= source_channels {
+                anyhow::bail!(
+                    "Source channel {} out of range: input has {} channels",
+                    index,
+                    source_channels
+                );
+            }
+            return Ok(format!("c{}", index));
+        }
+        
+        let name = value.as_str()
+            .context("Channel reference must be an index or a channel name")?
+            .to_uppercase();
+        let bit = channel_bit(&name)
+            .context(format!("Unknown channel name: {}", name))?;
+        if source_layout.bits() & bit == 0 {
+            anyhow::bail!("Channel {} not present in input layout {:?}", name, source_layout);
+        }
+        Ok(name)
+    };
+    
+    let mut expressions = Vec::new();
+    
+    for (out_index, entry) in mapping.iter().enumerate() {
+        let expression = if let Some(object) = entry.as_object() {
+            let sources = object.get("channels")
+                .and_then(|v| v.as_array())
+                .context("mapping object requires a channels array")?;
+            let gain = object.get("gain")
+                .and_then(|v| v.as_f64())
+                .unwrap_or(1.0);
+            
+            let terms = sources
+                .iter()
+                .map(|source| resolve(source).map(|term| format!("{:.4}*{}", gain, term)))
+                .collect::<Result<Vec<_>>>()?;
+            
+            if terms.is_empty() {
+                anyhow::bail!("mapping entry {} has no source channels", out_index);
+            }
+            terms.join("+")
+        } else {
+            resolve(entry)?
+        };
+        
+        expressions.push(format!("c{}={}", out_index, expression));
+    }
+    
+    let output_layout = match job.params.get("layout").and_then(|v| v.as_str()) {
+        Some(name) => {
+            let layout = parse_channel_layout(name)
+                .context(format!("Unsupported output layout: {}", name))?;
+            if layout.channels() as usize != mapping.len() {
+                anyhow::bail!(
+                    "Layout {} has {} channels but mapping defines {}",
+                    name,
+                    layout.channels(),
+                    mapping.len()
+                );
+            }
+            layout
+        }
+        None => ffmpeg::ChannelLayout::default(mapping.len() as i32),
+    };
+    
+    let filter_spec = format!(
+        "pan=0x{:x}|{}",
+        output_layout.bits(),
+        expressions.join("|")
+    );
+    
+    info!(filter = %filter_spec, "Remapping {} source channels to {}", source_channels, mapping.len());
+    
+    let settings = AudioOutputSettings::from_params(&job.params)?
+        .with_channel_layout(output_layout);
+    
+    transcode_audio_filtered(&job.input_path, &job.output_path, &filter_spec, &settings)?;
+    
+    Ok(job.output_path.clone())
+}
+
+// Helper function
+fn parse_bitrate(bitrate: &str) -> Result<usize> {
+    let bitrate = bitrate.to_uppercase();
+    
+    if bitrate.ends_with('K') {
+        let num: usize = bitrate.trim_end_matches('K').parse()?;
+        Ok(num * 1000)
+    } else if bitrate.ends_with('M') {
+        let num: usize = bitrate.trim_end_matches('M').parse()?;
+        Ok(num * 1_000_000)
+    } else {
+        Ok(bitrate.parse()?)
+    }
+}
+
+/// Output encoder settings shared by the filter-graph based audio tasks
+#[derive(Default)]
+struct AudioOutputSettings {
+    codec: Option<String>,
+    bit_rate: Option<usize>,
+    sample_rate: Option<u32>,
+    channel_layout: Option<ffmpeg::ChannelLayout>,
+    sample_format: Option<ffmpeg::format::Sample>,
+    bits_per_raw_sample: Option<u32>,
+}
+
+impl AudioOutputSettings {
+    /// Read the common `codec`, `bitrate` and `sample_rate` params
+    fn from_params(params: &serde_json::Value) -> Result<Self> {
+        let bit_rate = match params.get("bitrate").and_then(|v| v.as_str()) {
+            Some(bitrate) => Some(parse_bitrate(bitrate)?),
+            None => None,
+        };
+        
+        Ok(Self {
+            codec: params.get("codec").and_then(|v| v.as_str()).map(String::from),
+            bit_rate,
+            sample_rate: params.get("sample_rate").and_then(|v| v.as_u64()).map(|v| v as u32),
+            ..Default::default()
+        })
+    }
+    
+    fn with_channel_layout(mut self, layout: ffmpeg::ChannelLayout) -> Self {
+        self.channel_layout = Some(layout);
+        self
+    }
+}
+
+/// Channel layout of a decoder, falling back to the default layout for its channel count
+fn decoder_channel_layout(decoder: &ffmpeg::decoder::Audio) -> ffmpeg::ChannelLayout {
+    let layout = decoder.channel_layout();
+    if layout.is_empty() || layout.bits() == 0 {
+        ffmpeg::ChannelLayout::default(decoder.channels() as i32)
+    } else {
+        layout
+    }
+}
+
+/// Add an audio stream to `octx` and open an encoder for it
+///
+/// The codec defaults to the container's preferred audio codec unless `settings.codec` is set.
+fn open_audio_encoder(
+    octx: &mut ffmpeg::format::context::Output,
+    output_path: &str,
+    settings: &AudioOutputSettings,
+    channel_layout: ffmpeg::ChannelLayout,
+    sample_rate: u32,
+) -> Result<ffmpeg::encoder::Audio> {
+    let codec = match &settings.codec {
+        Some(name) => ffmpeg::encoder::find_by_name(name)
+            .context(format!("Codec {} not found", name))?,
+        None => ffmpeg::encoder::find(octx.format().codec(output_path, ffmpeg::media::Type::Audio))
+            .context("No suitable audio encoder found")?,
+    };
+    
+    let mut supported_formats = codec
+        .audio()?
+        .formats()
+        .context("Audio encoder reports no supported sample formats")?;
+    let sample_format = match settings.sample_format {
+        Some(format) => supported_formats
+            .find(|supported| *supported == format)
+            .context(format!("Codec {} does not support sample format {}", codec.name(), format.name()))?,
+        None => supported_formats
+            .next()
+            .context("Audio encoder reports no supported sample formats")?,
+    };
+    
+    let global_header = octx
+        .format()
+        .flags()
+        .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+    
+    let mut ost = octx.add_stream(codec)?;
+    let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .audio()?;
+    
+    encoder.set_rate(sample_rate as i32);
+    encoder.set_channel_layout(channel_layout);
+    encoder.set_format(sample_format);
+    encoder.set_time_base((1, sample_rate as i32));
+    if let Some(bit_rate) = settings.bit_rate {
+        encoder.set_bit_rate(bit_rate);
+    }
+    if global_header {
+        encoder.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+    }
+    if let Some(bits) = settings.bits_per_raw_sample {
+        unsafe {
+            (*encoder.as_mut_ptr()).bits_per_raw_sample = bits as i32;
+        }
+    }
+    
+    let encoder = encoder.open_as(codec)?;
+    ost.set_parameters(&encoder);
+    ost.set_time_base((1, sample_rate as i32));
+    
+    Ok(encoder)
+}
+
+/// Format of a frame stream entering an audio filter graph
+#[derive(Clone, Copy)]
+struct AudioSourceFormat {
+    format: ffmpeg::format::Sample,
+    channel_layout: ffmpeg::ChannelLayout,
+    sample_rate: u32,
+    time_base: ffmpeg::Rational,
+}
+
+impl AudioSourceFormat {
+    fn from_decoder(decoder: &ffmpeg::decoder::Audio, time_base: ffmpeg::Rational) -> Self {
+        Self {
+            format: decoder.format(),
+            channel_layout: decoder_channel_layout(decoder),
+            sample_rate: decoder.rate(),
+            time_base,
+        }
+    }
+}
+
+/// Format the `abuffersink` of an audio filter graph converts to
+struct AudioSinkFormat {
+    format: ffmpeg::format::Sample,
+    channel_layout: ffmpeg::ChannelLayout,
+    sample_rate: u32,
+    frame_size: Option<u32>,
+}
+
+impl AudioSinkFormat {
+    fn for_encoder(encoder: &ffmpeg::encoder::Audio) -> Self {
+        let variable_frame_size = encoder.codec().map_or(true, |codec| {
+            codec
+                .capabilities()
+                .contains(ffmpeg::codec::capabilities::Capabilities::VARIABLE_FRAME_SIZE)
+        });
+        
+        Self {
+            format: encoder.format(),
+            channel_layout: encoder.channel_layout(),
+            sample_rate: encoder.rate(),
+            frame_size: if variable_frame_size { None } else { Some(encoder.frame_size()) },
+        }
+    }
+    
+    fn planar_f32(channel_layout: ffmpeg::ChannelLayout, sample_rate: u32) -> Self {
+        Self {
+            format: ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Planar),
+            channel_layout,
+            sample_rate,
+            frame_size: None,
+        }
+    }
+}
+
+/// Build an audio filter graph with one `abuffer` source per input and an `abuffersink` named "out"
+///
+/// A single source is named "in" so plain filter chains connect automatically; multiple
+/// sources are named "in0", "in1", ... and must be referenced by label in `filter_spec`.
+fn build_audio_filter_graph(
+    sources: &[AudioSourceFormat],
+    filter_spec: &str,
+    sink: &AudioSinkFormat,
+) -> Result<ffmpeg::filter::Graph> {
+    let mut graph = ffmpeg::filter::Graph::new();
+    
+    let abuffer = ffmpeg::filter::find("abuffer").context("abuffer filter not available")?;
+    let abuffersink = ffmpeg::filter::find("abuffersink").context("abuffersink filter not available")?;
+    
+    let names = audio_source_names(sources.len());
+    
+    for (source, name) in sources.iter().zip(&names) {
+        let args = format!(
+            "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            source.time_base,
+            source.sample_rate,
+            source.format.name(),
+            source.channel_layout.bits()
+        );
+        graph.add(&abuffer, name, &args)?;
+    }
+    
+    graph.add(&abuffersink, "out", "")?;
+    
+    {
+        let mut out = graph.get("out").context("Filter sink missing")?;
+        out.set_sample_format(sink.format);
+        out.set_channel_layout(sink.channel_layout);
+        out.set_sample_rate(sink.sample_rate);
+    }
+    
+    let mut parser = ffmpeg::filter::graph::Parser::new(&mut graph);
+    for name in &names {
+        parser = parser.output(name, 0)?;
+    }
+    parser.input("out", 0)?.parse(filter_spec)
+        .context(format!("Invalid audio filter: {}", filter_spec))?;
+    
+    graph.validate()?;
+    
+    if let Some(frame_size) = sink.frame_size {
+        graph
+            .get("out")
+            .context("Filter sink missing")?
+            .sink()
+            .set_frame_size(frame_size);
+    }
+    
+    Ok(graph)
+}
+
+fn audio_source_names(count: usize) -> Vec<String> {
+    if count == 1 {
+        vec!["in".to_string()]
+    } else {
+        (0..count).map(|i| format!("in{}", i)).collect()
+    }
+}
+
+/// Push every frame the decoder has ready into the named filter source
+fn send_decoded_audio(
+    decoder: &mut ffmpeg::decoder::Audio,
+    graph: &mut ffmpeg::filter::Graph,
+    source: &str,
+) -> Result<usize> {
+    let mut decoded = ffmpeg::util::frame::audio::Audio::empty();
+    let mut count = 0;
+    
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        let timestamp = decoded.timestamp();
+        decoded.set_pts(timestamp);
+        graph
+            .get(source)
+            .context("Filter source missing")?
+            .source()
+            .add(&decoded)?;
+        count += 1;
+    }
+    
+    Ok(count)
+}
+
+/// Pull filtered frames from the sink and encode them
+fn encode_filtered_audio(
+    graph: &mut ffmpeg::filter::Graph,
+    encoder: &mut ffmpeg::encoder::Audio,
+    octx: &mut ffmpeg::format::context::Output,
+    stream_index: usize,
+    output_time_base: ffmpeg::Rational,
+) -> Result<()> {
+    let mut filtered = ffmpeg::util::frame::audio::Audio::empty();
+    
+    while graph
+        .get("out")
+        .context("Filter sink missing")?
+        .sink()
+        .frame(&mut filtered)
+        .is_ok()
+    {
+        encoder.send_frame(&filtered)?;
+        write_encoded_audio(encoder, octx, stream_index, output_time_base)?;
+    }
+    
+    Ok(())
+}
+
+fn write_encoded_audio(
+    encoder: &mut ffmpeg::encoder::Audio,
+    octx: &mut ffmpeg::format::context::Output,
+    stream_index: usize,
+    output_time_base: ffmpeg::Rational,
+) -> Result<()> {
+    let encoder_time_base = ffmpeg::Rational::new(1, encoder.rate() as i32);
+    let mut encoded = ffmpeg::Packet::empty();
+    
+    while encoder.receive_packet(&mut encoded).is_ok() {
+        encoded.set_stream(stream_index);
+        encoded.rescale_ts(encoder_time_base, output_time_base);
+        encoded.write_interleaved(octx)?;
+    }
+    
+    Ok(())
+}
+
+/// Decode the best audio stream of `input_path`, run it through `filter_spec` and encode the result
+///
+/// Output sample rate and layout default to the source's unless overridden in `settings`.
+/// Returns the number of decoded frames.
+fn transcode_audio_filtered(
+    input_path: &str,
+    output_path: &str,
+    filter_spec: &str,
+    settings: &AudioOutputSettings,
+) -> Result<usize> {
+    let octx = ffmpeg::format::output(&output_path)
+        .context("Failed to create output file")?;
+    
+    transcode_audio_filtered_into(
+        input_path,
+        octx,
+        output_path,
+        filter_spec,
+        settings,
+        ffmpeg::Dictionary::new(),
+    )
+}
+
+/// Same as `transcode_audio_filtered`, writing into an already created output context and
+/// passing `muxer_options` to the muxer when writing the header
+fn transcode_audio_filtered_into(
+    input_path: &str,
+    mut octx: ffmpeg::format::context::Output,
+    output_path: &str,
+    filter_spec: &str,
+    settings: &AudioOutputSettings,
+    muxer_options: ffmpeg::Dictionary,
+) -> Result<usize> {
+    let _span = tracing::info_span!("encode").entered();
+    let mut ictx = ffmpeg::format::input(&input_path)
+        .context("Failed to open input file")?;
+    
+    let (audio_stream_index, input_time_base, mut decoder) = {
+        let input_stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Audio)
+            .context("No audio stream found")?;
+        let decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?
+            .decoder()
+            .audio()?;
+        (input_stream.index(), input_stream.time_base(), decoder)
+    };
+    
+    let channel_layout = settings
+        .channel_layout
+        .unwrap_or_else(|| decoder_channel_layout(&decoder));
+    let sample_rate = settings.sample_rate.unwrap_or_else(|| decoder.rate());
+    
+    let mut encoder = open_audio_encoder(&mut octx, output_path, settings, channel_layout, sample_rate)?;
+    let mut graph = build_audio_filter_graph(
+        &[AudioSourceFormat::from_decoder(&decoder, input_time_base)],
+        filter_spec,
+        &AudioSinkFormat::for_encoder(&encoder),
+    )?;
+    
+    octx.set_metadata(ictx.metadata().to_owned());
+    octx.write_header_with(muxer_options)?;
+    
+    let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
+    let mut frame_count = 0;
+    
+    for (stream, packet) in ictx.packets() {
+        if stream.index() == audio_stream_index {
+            decoder.send_packet(&packet)?;
+            frame_count += send_decoded_audio(&mut decoder, &mut graph, "in")?;
+            encode_filtered_audio(&mut graph, &mut encoder, &mut octx, 0, output_time_base)?;
+            
+            if frame_count > 0 && frame_count % 1000 == 0 {
+                info!("Processed {} audio frames", frame_count);
+            }
+        }
+    }
+    
+    // Flush decoder, filter graph and encoder in order
+    decoder.send_eof()?;
+    frame_count += send_decoded_audio(&mut decoder, &mut graph, "in")?;
+    
+    graph.get("in").context("Filter source missing")?.source().flush()?;
+    encode_filtered_audio(&mut graph, &mut encoder, &mut octx, 0, output_time_base)?;
+    
+    encoder.send_eof()?;
+    write_encoded_audio(&mut encoder, &mut octx, 0, output_time_base)?;
+    
+    octx.write_trailer()?;
+    
+    Ok(frame_count)
+}
+
+/// Decodes one input audio stream and re-encodes it into an output as packets arrive
+///
+/// For tasks that interleave audio with other streams of the same input, such as the live ones.
+pub(crate) struct AudioTranscoder {
+    decoder: ffmpeg::decoder::Audio,
+    encoder: ffmpeg::encoder::Audio,
+    graph: ffmpeg::filter::Graph,
+    output_index: usize,
+}
+
+impl AudioTranscoder {
+    /// Add an encoded copy of `stream` to `octx`; `codec` defaults to the container's audio codec
+    pub(crate) fn open(
+        stream: &ffmpeg::format::stream::Stream,
+        octx: &mut ffmpeg::format::context::Output,
+        output_path: &str,
+        codec: Option<&str>,
+        bit_rate: Option<usize>,
+    ) -> Result<Self> {
+        // async resampling pads or trims around timestamp gaps instead of drifting out of sync
+        Self::open_filtered(stream, octx, output_path, codec, bit_rate, "aresample=async=1")
+    }
+    
+    /// Like `open`, passing the decoded audio through `filter_spec` before encoding
+    pub(crate) fn open_filtered(
+        stream: &ffmpeg::format::stream::Stream,
+        octx: &mut ffmpeg::format::context::Output,
+        output_path: &str,
+        codec: Option<&str>,
+        bit_rate: Option<usize>,
+        filter_spec: &str,
+    ) -> Result<Self> {
+        let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?;
+        let settings = AudioOutputSettings {
+            codec: codec.map(String::from),
+            bit_rate,
+            ..Default::default()
+        };
+        
+        let output_index = octx.nb_streams() as usize;
+        let encoder = open_audio_encoder(
+            octx,
+            output_path,
+            &settings,
+            decoder_channel_layout(&decoder),
+            decoder.rate(),
+        )?;
+        let graph = build_audio_filter_graph(
+            &[AudioSourceFormat::from_decoder(&decoder, stream.time_base())],
+            filter_spec,
+            &AudioSinkFormat::for_encoder(&encoder),
+        )?;
+        
+        Ok(Self {
+            decoder,
+            encoder,
+            graph,
+            output_index,
+        })
+    }
+    
+    pub(crate) fn send_packet(
+        &mut self,
+        packet: &ffmpeg::Packet,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        // A corrupt packet in a long-running input costs a few milliseconds of audio, not the job
+        if let Err(e) = self.decoder.send_packet(packet) {
+            warn!(error = %e, "Dropping undecodable audio packet");
+            return Ok(());
+        }
+        send_decoded_audio(&mut self.decoder, &mut self.graph, "in")?;
+        self.encode(octx)
+    }
+    
+    /// Flush the decoder, filter graph and encoder
+    pub(crate) fn finish(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        self.decoder.send_eof()?;
+        send_decoded_audio(&mut self.decoder, &mut self.graph, "in")?;
+        self.graph.get("in").context("Filter source missing")?.source().flush()?;
+        self.encode(octx)?;
+        
+        self.encoder.send_eof()?;
+        let output_time_base = octx.stream(self.output_index).context("Output stream missing")?.time_base();
+        write_encoded_audio(&mut self.encoder, octx, self.output_index, output_time_base)
+    }
+    
+    fn encode(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let output_time_base = octx.stream(self.output_index).context("Output stream missing")?.time_base();
+        encode_filtered_audio(&mut self.graph, &mut self.encoder, octx, self.output_index, output_time_base)
+    }
+}
+
+/// Encodes the output of an audio filter graph fed from several decoders, for tasks that
+/// combine audio from more than one input; sources are named as by `build_audio_filter_graph`
+pub(crate) struct FilteredAudioEncoder {
+    encoder: ffmpeg::encoder::Audio,
+    graph: ffmpeg::filter::Graph,
+    output_index: usize,
+}
+
+impl FilteredAudioEncoder {
+    pub(crate) fn open(
+        octx: &mut ffmpeg::format::context::Output,
+        output_path: &str,
+        codec: Option<&str>,
+        bit_rate: Option<usize>,
+        channel_layout: ffmpeg::ChannelLayout,
+        sample_rate: u32,
+        sources: &[(&ffmpeg::decoder::Audio, ffmpeg::Rational)],
+        filter_spec: &str,
+    ) -> Result<Self> {
+        let settings = AudioOutputSettings {
+            codec: codec.map(String::from),
+            bit_rate,
+            ..Default::default()
+        };
+        
+        let output_index = octx.nb_streams() as usize;
+        let encoder = open_audio_encoder(octx, output_path, &settings, channel_layout, sample_rate)?;
+        let sources: Vec<AudioSourceFormat> = sources
+            .iter()
+            .map(|(decoder, time_base)| AudioSourceFormat::from_decoder(decoder, *time_base))
+            .collect();
+        let graph = build_audio_filter_graph(&sources, filter_spec, &AudioSinkFormat::for_encoder(&encoder))?;
+        
+        Ok(Self {
+            encoder,
+            graph,
+            output_index,
+        })
+    }
+    
+    /// Source names for a graph with `count` sources
+    pub(crate) fn source_names(count: usize) -> Vec<String> {
+        audio_source_names(count)
+    }
+    
+    /// Push the decoder's ready frames into `source` and encode what the graph produces
+    pub(crate) fn send_decoded(
+        &mut self,
+        decoder: &mut ffmpeg::decoder::Audio,
+        source: &str,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        send_decoded_audio(decoder, &mut self.graph, source)?;
+        self.encode(octx)
+    }
+    
+    /// Mark the end of one source's audio
+    pub(crate) fn close_source(&mut self, source: &str, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        self.graph.get(source).context("Filter source missing")?.source().flush()?;
+        self.encode(octx)
+    }
+    
+    /// Flush the encoder once every source is closed
+    pub(crate) fn finish(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        self.encode(octx)?;
+        self.encoder.send_eof()?;
+        let output_time_base = octx.stream(self.output_index).context("Output stream missing")?.time_base();
+        write_encoded_audio(&mut self.encoder, octx, self.output_index, output_time_base)
+    }
+    
+    fn encode(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let output_time_base = octx.stream(self.output_index).context("Output stream missing")?.time_base();
+        encode_filtered_audio(&mut self.graph, &mut self.encoder, octx, self.output_index, output_time_base)
+    }
+}
+
+/// Map an ffmpeg channel name (FL, FR, FC, ...) to its layout mask bit
+fn channel_bit(name: &str) -> Option<u64> {
+    let bit = match name {
+        "FL" => ffmpeg::ffi::AV_CH_FRONT_LEFT,
+        "FR" => ffmpeg::ffi::AV_CH_FRONT_RIGHT,
+        "FC" => ffmpeg::ffi::AV_CH_FRONT_CENTER,
+        "LFE" => ffmpeg::ffi::AV_CH_LOW_FREQUENCY,
+        "BL" => ffmpeg::ffi::AV_CH_BACK_LEFT,
+        "BR" => ffmpeg::ffi::AV_CH_BACK_RIGHT,
+        "FLC" => ffmpeg::ffi::AV_CH_FRONT_LEFT_OF_CENTER,
+        "FRC" => ffmpeg::ffi::AV_CH_FRONT_RIGHT_OF_CENTER,
+        "BC" => ffmpeg::ffi::AV_CH_BACK_CENTER,
+        "SL" => ffmpeg::ffi::AV_CH_SIDE_LEFT,
+        "SR" => ffmpeg::ffi::AV_CH_SIDE_RIGHT,
+        _ => return None,
+    };
+    Some(bit)
+}
+
+/// Parse common layout names ("mono", "stereo", "5.1", ...) into a channel layout
+fn parse_channel_layout(name: &str) -> Option<ffmpeg::ChannelLayout> {
+    let layout = match name.to_lowercase().as_str() {
+        "mono" => ffmpeg::ChannelLayout::MONO,
+        "stereo" => ffmpeg::ChannelLayout::STEREO,
+        "2.1" => ffmpeg::ChannelLayout::_2POINT1,
+        "quad" => ffmpeg::ChannelLayout::QUAD,
+        "5.0" => ffmpeg::ChannelLayout::_5POINT0,
+        "5.1" => ffmpeg::ChannelLayout::_5POINT1,
+        "5.1(back)" => ffmpeg::ChannelLayout::_5POINT1_BACK,
+        "7.1" => ffmpeg::ChannelLayout::_7POINT1,
+        other => {
+            let count: i32 = other.strip_suffix('c')?.parse().ok()?;
+            ffmpeg::ChannelLayout::default(count)
+        }
+    };
+    Some(layout)
+}
+
+/// Streaming decoder yielding planar f32 samples at a fixed rate and channel layout
+struct PcmReader {
+    ictx: ffmpeg::format::context::Input,
+    decoder: ffmpeg::decoder::Audio,
+    stream_index: usize,
+    graph: ffmpeg::filter::Graph,
+    sample_rate: u32,
+    channel_layout: ffmpeg::ChannelLayout,
+    pending: Vec<std::collections::VecDeque<f32>>,
+    eof: bool,
+}
+
+impl PcmReader {
+    /// Open the best audio stream of `path`, converting to `sample_rate`/`channel_layout` when given
+    fn open(
+        path: &str,
+        sample_rate: Option<u32>,
+        channel_layout: Option<ffmpeg::ChannelLayout>,
+    ) -> Result<Self> {
+        let ictx = ffmpeg::format::input(&path)
+            .context(format!("Failed to open audio input: {}", path))?;
+        
+        let (stream_index, time_base, decoder) = {
+            let stream = ictx
+                .streams()
+                .best(ffmpeg::media::Type::Audio)
+                .context(format!("No audio stream found in {}", path))?;
+            let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+                .decoder()
+                .audio()?;
+            (stream.index(), stream.time_base(), decoder)
+        };
+        
+        let sample_rate = sample_rate.unwrap_or_else(|| decoder.rate());
+        let channel_layout = channel_layout.unwrap_or_else(|| decoder_channel_layout(&decoder));
+        
+        let graph = build_audio_filter_graph(
+            &[AudioSourceFormat::from_decoder(&decoder, time_base)],
+            "anull",
+            &AudioSinkFormat::planar_f32(channel_layout, sample_rate),
+        )?;
+        
+        Ok(Self {
+            ictx,
+            decoder,
+            stream_index,
+            graph,
+            sample_rate,
+            channel_layout,
+            pending: vec![std::collections::VecDeque::new(); channel_layout.channels() as usize],
+            eof: false,
+        })
+    }
+    
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    
+    fn channel_layout(&self) -> ffmpeg::ChannelLayout {
+        self.channel_layout
+    }
+    
+    fn channels(&self) -> usize {
+        self.pending.len()
+    }
+    
+    /// Read up to `count` samples per channel; fewer are returned only at end of stream
+    fn read(&mut self, count: usize) -> Result<Vec<Vec<f32>>> {
+        while self.pending[0].len() < count && !self.eof {
+            self.fill()?;
+        }
+        
+        let available = count.min(self.pending[0].len());
+        Ok(self
+            .pending
+            .iter_mut()
+            .map(|plane| plane.drain(..available).collect())
+            .collect())
+    }
+    
+    /// Whether no samples are left, decoding ahead to find out
+    fn at_end(&mut self) -> Result<bool> {
+        while self.pending[0].is_empty() && !self.eof {
+            self.fill()?;
+        }
+        Ok(self.pending[0].is_empty())
+    }
+    
+    /// Discard `count` samples per channel
+    fn skip(&mut self, count: usize) -> Result<()> {
+        let mut remaining = count;
+        while remaining > 0 {
+            let skipped = self.read(remaining.min(65536))?;
+            let read = skipped[0].len();
+            if read == 0 {
+                break;
+            }
+            remaining -= read;
+        }
+        Ok(())
+    }
+    
+    /// Decode the next packet of the audio stream into the pending buffers
+    fn fill(&mut self) -> Result<()> {
+        let mut packet = ffmpeg::Packet::empty();
+        
+        loop {
+            match packet.read(&mut self.ictx) {
+                Ok(()) if packet.stream() == self.stream_index => {
+                    self.decoder.send_packet(&packet)?;
+                    send_decoded_audio(&mut self.decoder, &mut self.graph, "in")?;
+                    break;
+                }
+                Ok(()) => continue,
+                Err(ffmpeg::Error::Eof) => {
+                    self.decoder.send_eof()?;
+                    send_decoded_audio(&mut self.decoder, &mut self.graph, "in")?;
+                    self.graph.get("in").context("Filter source missing")?.source().flush()?;
+                    self.eof = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        
+        let mut filtered = ffmpeg::util::frame::audio::Audio::empty();
+        while self
+            .graph
+            .get("out")
+            .context("Filter sink missing")?
+            .sink()
+            .frame(&mut filtered)
+            .is_ok()
+        {
+            for (channel, plane) in self.pending.iter_mut().enumerate() {
+                plane.extend(filtered.plane::<f32>(channel));
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+/// Streaming encoder accepting planar f32 samples
+struct PcmWriter {
+    octx: ffmpeg::format::context::Output,
+    encoder: ffmpeg::encoder::Audio,
+    graph: ffmpeg::filter::Graph,
+    output_time_base: ffmpeg::Rational,
+    sample_rate: u32,
+    channel_layout: ffmpeg::ChannelLayout,
+    samples_written: i64,
+}
+
+impl PcmWriter {
+    /// Create an encoder accepting `channel_layout`/`sample_rate` input, converting to the
+    /// layout and rate in `settings` when those are set
+    fn create(
+        output_path: &str,
+        settings: &AudioOutputSettings,
+        channel_layout: ffmpeg::ChannelLayout,
+        sample_rate: u32,
+    ) -> Result<Self> {
+        let mut octx = ffmpeg::format::output(&output_path)
+            .context("Failed to create output file")?;
+        
+        let encoder = open_audio_encoder(
+            &mut octx,
+            output_path,
+            settings,
+            settings.channel_layout.unwrap_or(channel_layout),
+            settings.sample_rate.unwrap_or(sample_rate),
+        )?;
+        
+        let graph = build_audio_filter_graph(
+            &[AudioSourceFormat {
+                format: ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Planar),
+                channel_layout,
+                sample_rate,
+                time_base: ffmpeg::Rational::new(1, sample_rate as i32),
+            }],
+            "anull",
+            &AudioSinkFormat::for_encoder(&encoder),
+        )?;
+        
+        octx.write_header()?;
+        let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
+        
+        Ok(Self {
+            octx,
+            encoder,
+            graph,
+            output_time_base,
+            sample_rate,
+            channel_layout,
+            samples_written: 0,
+        })
+    }
+    
+    /// Encode one block of planar samples (all planes must have equal length)
+    fn write(&mut self, planes: &[Vec<f32>]) -> Result<()> {
+        let count = planes.first().map_or(0, |plane| plane.len());
+        if count == 0 {
+            return Ok(());
+        }
+        
+        let frame = planar_f32_frame(planes, self.channel_layout, self.sample_rate, self.samples_written);
+        
+        self.graph
+            .get("in")
+            .context("Filter source missing")?
+            .source()
+            .add(&frame)?;
+        encode_filtered_audio(&mut self.graph, &mut self.encoder, &mut self.octx, 0, self.output_time_base)?;
+        
+        self.samples_written += count as i64;
+        Ok(())
+    }
+    
+    fn finish(mut self) -> Result<()> {
+        self.graph.get("in").context("Filter source missing")?.source().flush()?;
+        encode_filtered_audio(&mut self.graph, &mut self.encoder, &mut self.octx, 0, self.output_time_base)?;
+        
+        self.encoder.send_eof()?;
+        write_encoded_audio(&mut self.encoder, &mut self.octx, 0, self.output_time_base)?;
+        
+        self.octx.write_trailer()?;
+        Ok(())
+    }
+}
+
+/// Instant-attack, smooth-release peak limiter applied across all channels
+struct PeakLimiter {
+    ceiling: f32,
+    gain: f32,
+    release: f32,
+    min_gain: f32,
+}
+
+impl PeakLimiter {
+    fn new(ceiling: f32, sample_rate: u32) -> Self {
+        // ~50ms release time constant
+        let release = 1.0 - (-1.0 / (0.05 * sample_rate as f32)).exp();
+        Self { ceiling, gain: 1.0, release, min_gain: 1.0 }
+    }
+    
+    fn process(&mut self, planes: &mut [Vec<f32>]) {
+        let count = planes.first().map_or(0, |plane| plane.len());
+        
+        for i in 0..count {
+            let peak = planes.iter().fold(0.0f32, |acc, plane| acc.max(plane[i].abs()));
+            let target = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+            
+            if target < self.gain {
+                self.gain = target;
+            } else {
+                self.gain += (target - self.gain) * self.release;
+            }
+            self.min_gain = self.min_gain.min(self.gain);
+            
+            for plane in planes.iter_mut() {
+                plane[i] *= self.gain;
+            }
+        }
+    }
+    
+    fn max_reduction_db(&self) -> f32 {
+        -20.0 * self.min_gain.log10()
+    }
+}
+
+/// Fade-out / fade-in gains at position `t` (0..1) through a crossfade
+fn crossfade_gains(curve: &str, t: f64) -> Result<(f64, f64)> {
+    let gains = match curve {
+        "tri" | "linear" => (1.0 - t, t),
+        "qsin" | "equal_power" => (
+            (t * std::f64::consts::FRAC_PI_2).cos(),
+            (t * std::f64::consts::FRAC_PI_2).sin(),
+        ),
+        "hsin" => (
+            (1.0 + (t * std::f64::consts::PI).cos()) / 2.0,
+            (1.0 - (t * std::f64::consts::PI).cos()) / 2.0,
+        ),
+        "exp" => ((1.0 - t).powi(2), 1.0 - (1.0 - t).powi(2)),
+        "log" => (1.0 - t * t, t * t),
+        _ => anyhow::bail!("Unsupported crossfade curve: {}", curve),
+    };
+    Ok(gains)
+}
+
+/// Read a time param given either as seconds or as an HH:MM:SS timestamp
+pub(crate) fn param_seconds(params: &serde_json::Value, key: &str) -> Result<Option<f64>> {
+    match params.get(key) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(timestamp)) => {
+            Ok(Some(crate::video::parse_timestamp(timestamp)?))
+        }
+        Some(value) => value
+            .as_f64()
+            .map(Some)
+            .context(format!("{} must be seconds or a timestamp string", key)),
+    }
+}
+
+/// Split a tempo factor into atempo stages that each stay within 0.5-2.0
+pub(crate) fn atempo_chain(factor: f64) -> Vec<String> {
+    let mut stages = Vec::new();
+    let mut remaining = factor;
+    
+    while remaining > 2.0 {
+        stages.push("atempo=2.0".to_string());
+        remaining /= 2.0;
+    }
+    while remaining < 0.5 {
+        stages.push("atempo=0.5".to_string());
+        remaining /= 0.5;
+    }
+    if (remaining - 1.0).abs() > f64::EPSILON {
+        stages.push(format!("atempo={:.6}", remaining));
+    }
+    
+    stages
+}
+
+/// Copy all streams of `input_path` into `output_path` without re-encoding, adding metadata tags
+/// and replacing the chapter list when `chapters` is non-empty
+///
+/// Tags are set on the container and on every audio stream, since muxers differ in where
+/// they read them from (ID3 for MP3, Vorbis comments for FLAC/Ogg).
+fn remux_with_metadata(
+    input_path: &str,
+    output_path: &str,
+    tags: &[(String, String)],
+    chapters: &[Chapter],
+) -> Result<()> {
+    let mut ictx = ffmpeg::format::input(&input_path)
+        .context("Failed to open input file")?;
+    let mut octx = ffmpeg::format::output(&output_path)
+        .context("Failed to create output file")?;
+    
+    let mut stream_mapping = vec![-1i32; ictx.nb_streams() as usize];
+    let mut input_time_bases = vec![ffmpeg::Rational(0, 1); ictx.nb_streams() as usize];
+    let mut output_index = 0;
+    
+    for (input_index, ist) in ictx.streams().enumerate() {
+        let medium = ist.parameters().medium();
+        if medium != ffmpeg::media::Type::Audio
+            && medium != ffmpeg::media::Type::Video
+            && medium != ffmpeg::media::Type::Subtitle
+        {
+            continue;
+        }
+        
+        stream_mapping[input_index] = output_index;
+        input_time_bases[input_index] = ist.time_base();
+        output_index += 1;
+        
+        let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        
+        let mut metadata = ist.metadata().to_owned();
+        if medium == ffmpeg::media::Type::Audio {
+            for (key, value) in tags {
+                metadata.set(key, value);
+            }
+        }
+        ost.set_metadata(metadata);
+    }
+    
+    let mut metadata = ictx.metadata().to_owned();
+    for (key, value) in tags {
+        metadata.set(key, value);
+    }
+    octx.set_metadata(metadata);
+    
+    if chapters.is_empty() {
+        for chapter in ictx.chapters() {
+            octx.add_chapter(
+                chapter.id(),
+                chapter.time_base(),
+                chapter.start(),
+                chapter.end(),
+                chapter.metadata().get("title").unwrap_or_default(),
+            )?;
+        }
+    } else {
+        for (index, chapter) in chapters.iter().enumerate() {
+            octx.add_chapter(
+                index as i64,
+                (1, 1000),
+                (chapter.start * 1000.0).round() as i64,
+                (chapter.end * 1000.0).round() as i64,
+                &chapter.title,
+            )?;
+        }
+    }
+    
+    octx.write_header()?;
+    
+    for (stream, mut packet) in ictx.packets() {
+        let input_index = stream.index();
+        let output_index = stream_mapping[input_index];
+        if output_index < 0 {
+            continue;
+        }
+        
+        let output_time_base = octx
+            .stream(output_index as usize)
+            .context("Output stream missing")?
+            .time_base();
+        packet.rescale_ts(input_time_bases[input_index], output_time_base);
+        packet.set_position(-1);
+        packet.set_stream(output_index as usize);
+        packet.write_interleaved(&mut octx)?;
+    }
+    
+    octx.write_trailer()?;
+    Ok(())
+}
+
+/// Result of an ITU-R BS.1770 / EBU R128 loudness measurement
+pub(crate) struct LoudnessMeasurement {
+    pub(crate) integrated_lufs: Option<f64>,
+    pub(crate) loudness_range: Option<f64>,
+    pub(crate) sample_peak: f64,
+    /// Highest 4x oversampled (BS.1770 true-peak) level across channels
+    pub(crate) true_peak: f64,
+}
+
+/// Measure integrated loudness, loudness range, sample peak and true peak of the best audio stream
+pub(crate) fn measure_loudness(path: &str) -> Result<LoudnessMeasurement> {
+    // The K-weighting coefficients below are defined for 48 kHz
+    let mut reader = PcmReader::open(path, Some(48000), None)?;
+    let mut meter = LoudnessMeter::new(reader.channel_layout());
+    
+    loop {
+        let block = reader.read(4800)?;
+        if block[0].is_empty() {
+            break;
+        }
+        meter.process(&block);
+    }
+    
+    Ok(meter.finish())
+}
+
+/// Streaming BS.1770 loudness meter operating on 48 kHz planar samples
+struct LoudnessMeter {
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    // Sum of squared K-weighted samples per channel in the current 100ms step
+    step_energy: Vec<f64>,
+    step_fill: usize,
+    // Mean-square per channel for each completed 100ms step
+    steps: Vec<Vec<f64>>,
+    sample_peak: f64,
+    peak_meters: Vec<TruePeakMeter>,
+    true_peak: f64,
+}
+
+impl LoudnessMeter {
+    const STEP: usize = 4800;
+    
+    fn new(layout: ffmpeg::ChannelLayout) -> Self {
+        let channels = layout.channels() as usize;
+        let mask = layout.bits();
+        
+        // Surround channels count +1.5 dB, LFE is excluded
+        let mut weights = Vec::with_capacity(channels);
+        let mut bit = 1u64;
+        while weights.len() < channels && bit != 0 {
+            if mask == 0 || mask & bit != 0 {
+                let weight = if bit == ffmpeg::ffi::AV_CH_LOW_FREQUENCY {
+                    0.0
+                } else if bit == ffmpeg::ffi::AV_CH_BACK_LEFT
+                    || bit == ffmpeg::ffi::AV_CH_BACK_RIGHT
+                    || bit == ffmpeg::ffi::AV_CH_SIDE_LEFT
+                    || bit == ffmpeg::ffi::AV_CH_SIDE_RIGHT
+                {
+                    1.41
+                } else {
+                    1.0
+                };
+                weights.push(weight);
+            }
+            bit = bit.wrapping_shl(1);
+        }
+        weights.resize(channels, 1.0);
+        
+        let filters = (0..channels)
+            .map(|_| {
+                [
+                    Biquad::new(
+                        [1.53512485958697, -2.69169618940638, 1.19839281085285],
+                        [-1.69065929318241, 0.73248077421585],
+                    ),
+                    Biquad::new([1.0, -2.0, 1.0], [-1.99004745483398, 0.99007225036621]),
+                ]
+            })
+            .collect();
+        
+        Self {
+            weights,
+            filters,
+            step_energy: vec![0.0; channels],
+            step_fill: 0,
+            steps: Vec::new(),
+            sample_peak: 0.0,
+            peak_meters: (0..channels).map(|_| TruePeakMeter::new()).collect(),
+            true_peak: 0.0,
+        }
+    }
+    
+    fn process(&mut self, planes: &[Vec<f32>]) {
+        let count = planes.first().map_or(0, |plane| plane.len());
+        
+        for i in 0..count {
+            for (channel, plane) in planes.iter().enumerate() {
+                let sample = plane[i] as f64;
+                self.sample_peak = self.sample_peak.max(sample.abs());
+                self.true_peak = self.true_peak.max(self.peak_meters[channel].process(plane[i]));
+                
+                let [shelf, highpass] = &mut self.filters[channel];
+                let weighted = highpass.process(shelf.process(sample));
+                self.step_energy[channel] += weighted * weighted;
+            }
+            
+            self.step_fill += 1;
+            if self.step_fill == Self::STEP {
+                self.steps.push(self.step_energy.iter().map(|e| e / Self::STEP as f64).collect());
+                self.step_energy.iter_mut().for_each(|e| *e = 0.0);
+                self.step_fill = 0;
+            }
+        }
+    }
+    
+    /// Loudness of the window made of `steps` consecutive 100ms steps ending at `end`
+    fn window_loudness(&self, end: usize, steps: usize) -> f64 {
+        let power: f64 = (0..self.weights.len())
+            .map(|channel| {
+                let mean: f64 = self.steps[end + 1 - steps..=end]
+                    .iter()
+                    .map(|step| step[channel])
+                    .sum::<f64>()
+                    / steps as f64;
+                self.weights[channel] * mean
+            })
+            .sum();
+        -0.691 + 10.0 * power.max(1e-20).log10()
+    }
+    
+    fn finish(self) -> LoudnessMeasurement {
+        // 400ms momentary blocks with 75% overlap
+        let blocks: Vec<f64> = (3..self.steps.len())
+            .map(|end| self.window_loudness(end, 4))
+            .collect();
+        
+        let integrated = gated_loudness(&blocks, -10.0);
+        
+        // 3s short-term blocks for loudness range
+        let short_term: Vec<f64> = (29..self.steps.len())
+            .map(|end| self.window_loudness(end, 30))
+            .collect();
+        let loudness_range = loudness_range(&short_term);
+        
+        LoudnessMeasurement {
+            integrated_lufs: integrated,
+            loudness_range,
+            sample_peak: self.sample_peak,
+            true_peak: self.true_peak.max(self.sample_peak),
+        }
+    }
+}
+
+/// Apply the absolute (-70 LUFS) and relative gates and return the gated mean loudness
+fn gated_loudness(blocks: &[f64], relative_gate: f64) -> Option<f64> {
+    let mean_loudness = |values: &mut dyn Iterator<Item = &f64>| {
+        let (sum, count) = values.fold((0.0, 0usize), |(sum, count), l| {
+            (sum + 10f64.powf((l + 0.691) / 10.0), count + 1)
+        });
+        if count == 0 {
+            None
+        } else {
+            Some(-0.691 + 10.0 * (sum / count as f64).log10())
+        }
+    };
+    
+    let absolute = mean_loudness(&mut blocks.iter().filter(|l| **l > -70.0))?;
+    let threshold = absolute + relative_gate;
+    mean_loudness(&mut blocks.iter().filter(|l| **l > -70.0 && **l > threshold))
+}
+
+/// EBU Tech 3342 loudness range from short-term loudness values
+fn loudness_range(short_term: &[f64]) -> Option<f64> {
+    let absolute: Vec<f64> = short_term.iter().copied().filter(|l| *l > -70.0).collect();
+    if absolute.is_empty() {
+        return None;
+    }
+    
+    let mean = -0.691
+        + 10.0
+            * (absolute.iter().map(|l| 10f64.powf((l + 0.691) / 10.0)).sum::<f64>()
+                / absolute.len() as f64)
+                .log10();
+    
+    let mut gated: Vec<f64> = absolute.into_iter().filter(|l| *l > mean - 20.0).collect();
+    if gated.is_empty() {
+        return None;
+    }
+    gated.sort_by(|a, b| a.total_cmp(b));
+    
+    let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    Some(percentile(0.95) - percentile(0.10))
+}
+
+/// Direct form I biquad section (a0 normalized to 1)
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+    
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// Hann-windowed FFT producing magnitude spectra of fixed-size frames
+struct SpectrumAnalyzer {
+    fft: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<rustfft::num_complex::Complex<f32>>,
+}
+
+impl SpectrumAnalyzer {
+    fn new(size: usize) -> Self {
+        let fft = rustfft::FftPlanner::new().plan_fft_forward(size);
+        let window = (0..size)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / size as f32).cos())
+            .collect();
+    
+        Self {
+            fft,
+            window,
+            buffer: vec![rustfft::num_complex::Complex::new(0.0, 0.0); size],
+        }
+    }
+    
+    fn size(&self) -> usize {
+        self.window.len()
+    }
+    
+    /// Magnitudes of the `size / 2 + 1` non-negative frequency bins; short frames are zero-padded
+    fn magnitudes(&mut self, samples: &[f32]) -> Vec<f32> {
+        for (i, bin) in self.buffer.iter_mut().enumerate() {
+            let sample = samples.get(i).copied().unwrap_or(0.0);
+            *bin = rustfft::num_complex::Complex::new(sample * self.window[i], 0.0);
+        }
+    
+        self.fft.process(&mut self.buffer);
+    
+        self.buffer[..self.size() / 2 + 1]
+            .iter()
+            .map(|bin| bin.norm())
+            .collect()
+    }
+}
+
+/// Ratio of geometric to arithmetic mean of a magnitude spectrum (1.0 = white noise)
+fn spectral_flatness(magnitudes: &[f32]) -> f64 {
+    let power: Vec<f64> = magnitudes.iter().map(|m| (*m as f64).powi(2) + 1e-12).collect();
+    let log_mean = power.iter().map(|p| p.ln()).sum::<f64>() / power.len() as f64;
+    let mean = power.iter().sum::<f64>() / power.len() as f64;
+    log_mean.exp() / mean
+}
+
+/// Pick the tempo whose beat period best autocorrelates with the onset envelope
+///
+/// Candidates are weighted toward 120 BPM (log-normal, one octave deviation) to reduce
+/// half/double tempo errors. Returns (bpm, confidence).
+fn estimate_tempo(onsets: &[f64], frame_rate: f64, min_bpm: f64, max_bpm: f64) -> Option<(f64, f64)> {
+    let min_lag = (60.0 * frame_rate / max_bpm).floor().max(1.0) as usize;
+    let max_lag = (60.0 * frame_rate / min_bpm).ceil() as usize;
+    // A tiny min_bpm saturates the cast, so the window check must not overflow
+    if onsets.len() <= max_lag.saturating_add(1) {
+        return None;
+    }
+    
+    let mean = onsets.iter().sum::<f64>() / onsets.len() as f64;
+    let centered: Vec<f64> = onsets.iter().map(|o| o - mean).collect();
+    
+    let autocorrelation = |lag: usize| {
+        centered[lag..]
+            .iter()
+            .zip(&centered)
+            .map(|(a, b)| a * b)
+            .sum::<f64>()
+            / (centered.len() - lag) as f64
+    };
+    
+    let energy = autocorrelation(0);
+    if energy <= 0.0 {
+        return None;
+    }
+    
+    let correlations: Vec<f64> = (min_lag - 1..=max_lag + 1).map(autocorrelation).collect();
+    let weighted = |lag: usize| {
+        let bpm = 60.0 * frame_rate / lag as f64;
+        let weight = (-0.5 * (bpm / 120.0).log2().powi(2)).exp();
+        correlations[lag + 1 - min_lag] * weight
+    };
+    
+    let best_lag = (min_lag..=max_lag).max_by(|a, b| weighted(*a).total_cmp(&weighted(*b)))?;
+    
+    // Parabolic interpolation between neighbouring lags for sub-frame precision
+    let (left, center, right) = (
+        correlations[best_lag - min_lag],
+        correlations[best_lag + 1 - min_lag],
+        correlations[best_lag + 2 - min_lag],
+    );
+    let denominator = left - 2.0 * center + right;
+    let offset = if denominator.abs() > f64::EPSILON {
+        (0.5 * (left - right) / denominator).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    
+    let bpm = 60.0 * frame_rate / (best_lag as f64 + offset);
+    Some((bpm, (center / energy).clamp(0.0, 1.0)))
+}
+
+/// Match a 12-bin chroma vector (C = 0) against rotated Krumhansl-Kessler key profiles
+///
+/// Returns (tonic, "major"/"minor", correlation).
+fn estimate_key(chroma: &[f64; 12]) -> Option<(&'static str, &'static str, f64)> {
+    const MAJOR: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+    const MINOR: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+    const NOTES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    
+    if chroma.iter().all(|c| *c <= 0.0) {
+        return None;
+    }
+    
+    let correlation = |profile: &[f64; 12], tonic: usize| {
+        let x: Vec<f64> = (0..12).map(|i| chroma[(i + tonic) % 12]).collect();
+        let mean_x = x.iter().sum::<f64>() / 12.0;
+        let mean_y = profile.iter().sum::<f64>() / 12.0;
+        let covariance: f64 = x.iter().zip(profile).map(|(a, b)| (a - mean_x) * (b - mean_y)).sum();
+        let variance_x: f64 = x.iter().map(|a| (a - mean_x).powi(2)).sum();
+        let variance_y: f64 = profile.iter().map(|b| (b - mean_y).powi(2)).sum();
+        covariance / (variance_x * variance_y).sqrt().max(f64::EPSILON)
+    };
+    
+    (0..12)
+        .flat_map(|tonic| {
+            [
+                (NOTES[tonic], "major", correlation(&MAJOR, tonic)),
+                (NOTES[tonic], "minor", correlation(&MINOR, tonic)),
+            ]
+        })
+        .max_by(|a, b| a.2.total_cmp(&b.2))
+}
+
+/// ITU-R BS.1770 style true-peak meter: 4x polyphase oversampling of a single channel
+struct TruePeakMeter {
+    phases: Vec<Vec<f64>>,
+    history: std::collections::VecDeque<f64>,
+}
+
+impl TruePeakMeter {
+    const OVERSAMPLING: usize = 4;
+    const TAPS_PER_PHASE: usize = 12;
+    
+    fn new() -> Self {
+        // Hann-windowed sinc low-pass at the original Nyquist frequency
+        let length = Self::OVERSAMPLING * Self::TAPS_PER_PHASE;
+        let center = (length - 1) as f64 / 2.0;
+        let coefficients: Vec<f64> = (0..length)
+            .map(|n| {
+                let x = (n as f64 - center) / Self::OVERSAMPLING as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+                };
+                let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * n as f64 / (length - 1) as f64).cos();
+                sinc * window
+            })
+            .collect();
+    
+        let phases = (0..Self::OVERSAMPLING)
+            .map(|phase| {
+                coefficients
+                    .iter()
+                    .skip(phase)
+                    .step_by(Self::OVERSAMPLING)
+                    .copied()
+                    .collect()
+            })
+            .collect();
+    
+        Self {
+            phases,
+            history: std::collections::VecDeque::from(vec![0.0; Self::TAPS_PER_PHASE]),
+        }
+    }
+    
+    /// Feed one sample and return the highest absolute interpolated value since the previous one
+    fn process(&mut self, sample: f32) -> f64 {
+        self.history.pop_back();
+        self.history.push_front(sample as f64);
+    
+        self.phases
+            .iter()
+            .map(|taps| {
+                taps.iter()
+                    .zip(&self.history)
+                    .map(|(tap, x)| tap * x)
+                    .sum::<f64>()
+                    .abs()
+            })
+            .fold((sample as f64).abs(), f64::max)
+    }
+}
+
+/// Mean sample value of each channel of the best audio stream
+fn measure_dc_offset(path: &str) -> Result<Vec<f64>> {
+    let mut reader = PcmReader::open(path, None, None)?;
+    let mut sums = vec![0f64; reader.channels()];
+    let mut count = 0u64;
+    
+    loop {
+        let block = reader.read(65536)?;
+        if block[0].is_empty() {
+            break;
+        }
+        for (sum, plane) in sums.iter_mut().zip(&block) {
+            *sum += plane.iter().map(|s| *s as f64).sum::<f64>();
+        }
+        count += block[0].len() as u64;
+    }
+    
+    Ok(sums.into_iter().map(|sum| if count > 0 { sum / count as f64 } else { 0.0 }).collect())
+}
+
+/// Build a planar f32 frame from per-channel sample buffers of equal length
+fn planar_f32_frame(
+    planes: &[Vec<f32>],
+    channel_layout: ffmpeg::ChannelLayout,
+    sample_rate: u32,
+    pts: i64,
+) -> ffmpeg::util::frame::audio::Audio {
+    let count = planes.first().map_or(0, |plane| plane.len());
+    let mut frame = ffmpeg::util::frame::audio::Audio::new(
+        ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Planar),
+        count,
+        channel_layout,
+    );
+    frame.set_rate(sample_rate);
+    frame.set_pts(Some(pts));
+    
+    for (channel, plane) in planes.iter().enumerate() {
+        frame.plane_mut::<f32>(channel).copy_from_slice(&plane[..count]);
+    }
+    
+    frame
+}
+
+/// Drain planar f32 frames from the graph's "out" sink into `writer`
+fn write_filtered_planes(graph: &mut ffmpeg::filter::Graph, writer: &mut PcmWriter, channels: usize) -> Result<()> {
+    let mut filtered = ffmpeg::util::frame::audio::Audio::empty();
+    while graph
+        .get("out")
+        .context("Filter sink missing")?
+        .sink()
+        .frame(&mut filtered)
+        .is_ok()
+    {
+        let planes: Vec<Vec<f32>> = (0..channels)
+            .map(|channel| filtered.plane::<f32>(channel).to_vec())
+            .collect();
+        writer.write(&planes)?;
+    }
+    Ok(())
+}
+
+/// Encode digital silence into `stream_index` until `written` reaches `target` samples
+fn write_silence(
+    graph: &mut ffmpeg::filter::Graph,
+    encoder: &mut ffmpeg::encoder::Audio,
+    octx: &mut ffmpeg::format::context::Output,
+    stream_index: usize,
+    output_time_base: ffmpeg::Rational,
+    written: &mut i64,
+    target: i64,
+) -> Result<()> {
+    let channels = encoder.channel_layout().channels() as usize;
+    
+    while *written < target {
+        let count = (target - *written).min(1024) as usize;
+        let silence = vec![vec![0.0f32; count]; channels];
+        let frame = planar_f32_frame(&silence, encoder.channel_layout(), encoder.rate(), *written);
+    
+        graph.get("in").context("Filter source missing")?.source().add(&frame)?;
+        encode_filtered_audio(graph, encoder, octx, stream_index, output_time_base)?;
+    
+        *written += count as i64;
+    }
+    
+    Ok(())
+}
+
+/// Bit depth of the best audio stream and whether its samples are floating point
+fn source_bit_depth(path: &str) -> Result<(u32, bool)> {
+    let ictx = ffmpeg::format::input(&path)?;
+    let stream = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Audio)
+        .context("No audio stream found")?;
+    
+    let raw_bits = unsafe { (*stream.parameters().as_ptr()).bits_per_raw_sample };
+    let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+        .decoder()
+        .audio()?;
+    
+    let format = decoder.format();
+    let is_float = matches!(
+        format,
+        ffmpeg::format::Sample::F32(_) | ffmpeg::format::Sample::F64(_)
+    );
+    let bits = if raw_bits > 0 { raw_bits as u32 } else { format.bytes() as u32 * 8 };
+    
+    Ok((bits, is_float))
+}
+
+/// Quantizes float samples to an integer bit depth with triangular (TPDF) dither of ±1 LSB
+struct TpdfDither {
+    scale: f32,
+    state: u64,
+}
+
+impl TpdfDither {
+    fn new(bits: u32) -> Result<Self> {
+        if !(1..=32).contains(&bits) {
+            anyhow::bail!("Dither bit depth must be between 1 and 32, got {}", bits);
+        }
+        Ok(Self {
+            scale: (1u64 << (bits - 1)) as f32,
+            state: 0x9E37_79B9_7F4A_7C15,
+        })
+    }
+    
+    /// Uniform value in [0, 1) from a xorshift64* generator
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 40) as f32 / (1u64 << 24) as f32
+    }
+    
+    fn process(&mut self, samples: &mut [f32]) {
+        let max = (self.scale - 1.0) / self.scale;
+        for sample in samples.iter_mut() {
+            let noise = self.uniform() - self.uniform();
+            let quantized = (*sample * self.scale + noise).round() / self.scale;
+            *sample = quantized.clamp(-1.0, max);
+        }
+    }
+}
+
+/// Samples per watermark bit
+const WATERMARK_CHIPS: usize = 4096;
+pub(crate) const WATERMARK_PAYLOAD_BYTES: usize = 8;
+
+/// Payload bytes (zero padded) followed by their CRC-8, as ±1.0 per bit, MSB first
+pub(crate) fn watermark_bits(payload: &str) -> Result<Vec<f32>> {
+    let mut bytes = payload.as_bytes().to_vec();
+    if bytes.len() > WATERMARK_PAYLOAD_BYTES {
+        anyhow::bail!("Watermark payload is limited to {} bytes", WATERMARK_PAYLOAD_BYTES);
+    }
+    bytes.resize(WATERMARK_PAYLOAD_BYTES, 0);
+    bytes.push(crc8(&bytes));
+    
+    Ok(bytes
+        .iter()
+        .flat_map(|byte| (0..8).map(move |bit| if byte & (0x80 >> bit) != 0 { 1.0 } else { -1.0 }))
+        .collect())
+}
+
+/// Pseudo-noise ±1.0 chip sequence seeded from the watermark key
+pub(crate) fn watermark_sequence(key: &str) -> Vec<f32> {
+    let digest = <sha2::Sha256 as sha2::Digest>::digest(key.as_bytes());
+    let mut state = u64::from_le_bytes(digest[..8].try_into().unwrap_or_default()) | 1;
+    
+    (0..WATERMARK_CHIPS)
+        .map(|_| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            if state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 63 == 0 { 1.0 } else { -1.0 }
+        })
+        .collect()
+}
+
+/// CRC-8 (polynomial 0x07)
+pub(crate) fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 }
+        })
+    })
+}
+
+/// Onset strength of the best audio stream of `path` for each `hop` seconds from its first
+/// sample: how far the level (log RMS) rose from the previous hop, zero where it fell
+pub(crate) fn onset_envelope(path: &str, hop: f64) -> Result<Vec<f64>> {
+    const ANALYSIS_RATE: u32 = 16000;
+    
+    let mut reader = PcmReader::open(path, Some(ANALYSIS_RATE), Some(ffmpeg::ChannelLayout::MONO))?;
+    let hop_samples = ((hop * ANALYSIS_RATE as f64).round() as usize).max(1);
+    
+    let mut envelope = Vec::new();
+    let mut previous_level: Option<f64> = None;
+    loop {
+        let block = reader.read(hop_samples)?;
+        if block[0].is_empty() {
+            break;
+        }
+        let mean_square = block[0].iter().map(|s| (*s as f64) * (*s as f64)).sum::<f64>() / block[0].len() as f64;
+        let level = 10.0 * (mean_square + 1e-10).log10();
+        envelope.push(previous_level.map_or(0.0, |previous| (level - previous).max(0.0)));
+        previous_level = Some(level);
+    }
+    Ok(envelope)
+}
+
+/// Silent intervals (start, end) in seconds plus the total duration of the best audio stream
+///
+/// A `window_ms` window is silent when every channel's RMS is below `threshold_db`.
+pub(crate) fn find_silences(
+    path: &str,
+    threshold_db: f64,
+    min_duration: f64,
+    window_ms: u64,
+) -> Result<(Vec<(f64, f64)>, f64)> {
+    let mut reader = PcmReader::open(path, None, None)?;
+    let sample_rate = reader.sample_rate() as f64;
+    let window = ((sample_rate * window_ms as f64 / 1000.0).round() as usize).max(1);
+    let threshold = 10f64.powf(threshold_db / 20.0);
+    
+    let mut silences = Vec::new();
+    let mut silence_start: Option<usize> = None;
+    let mut position = 0usize;
+    
+    let close_interval = |start: usize, end: usize, silences: &mut Vec<(f64, f64)>| {
+        let (start, end) = (start as f64 / sample_rate, end as f64 / sample_rate);
+        if end - start >= min_duration {
+            silences.push((start, end));
+        }
+    };
+    
+    loop {
+        let block = reader.read(window)?;
+        let count = block[0].len();
+        if count == 0 {
+            break;
+        }
+        
+        let silent = block.iter().all(|plane| {
+            let mean_square = plane.iter().map(|s| (*s as f64) * (*s as f64)).sum::<f64>() / count as f64;
+            mean_square.sqrt() < threshold
+        });
+        
+        match (silent, silence_start) {
+            (true, None) => silence_start = Some(position),
+            (false, Some(start)) => {
+                close_interval(start, position, &mut silences);
+                silence_start = None;
+            }
+            _ => {}
+        }
+        
+        position += count;
+    }
+    
+    if let Some(start) = silence_start {
+        close_interval(start, position, &mut silences);
+    }
+    
+    Ok((silences, position as f64 / sample_rate))
+}
+
+/// Label fixed-length windows of the best audio stream as speech/music/other
+///
+/// Returns the per-window feature records, the merged segments and the analysed duration.
+fn classify_audio_timeline(
+    path: &str,
+    window_seconds: f64,
+    silence_threshold_db: f64,
+) -> Result<(Vec<serde_json::Value>, Vec<serde_json::Value>, f64)> {
+    const ANALYSIS_RATE: u32 = 16000;
+    const FRAME: usize = 320;
+    
+    let mut reader = PcmReader::open(
+        path,
+        Some(ANALYSIS_RATE),
+        Some(ffmpeg::ChannelLayout::MONO),
+    )?;
+    let mut analyzer = SpectrumAnalyzer::new(512);
+    
+    let frames_per_window = ((window_seconds * ANALYSIS_RATE as f64) as usize / FRAME).max(1);
+    let silence_threshold = 10f64.powf(silence_threshold_db / 20.0);
+    
+    let mut windows = Vec::new();
+    let mut position = 0usize;
+    
+    loop {
+        let block = reader.read(frames_per_window * FRAME)?;
+        let samples = &block[0];
+        if samples.len() < FRAME {
+            break;
+        }
+        
+        let mut energies = Vec::new();
+        let mut zero_crossings = Vec::new();
+        let mut flatness = Vec::new();
+        
+        for frame in samples.chunks_exact(FRAME) {
+            let energy = frame.iter().map(|s| (*s as f64) * (*s as f64)).sum::<f64>() / FRAME as f64;
+            let crossings = frame.windows(2).filter(|pair| (pair[0] >= 0.0) != (pair[1] >= 0.0)).count();
+            energies.push(energy);
+            zero_crossings.push(crossings as f64 / FRAME as f64);
+            
+            if energy.sqrt() >= silence_threshold {
+                flatness.push(spectral_flatness(&analyzer.magnitudes(frame)));
+            }
+        }
+        
+        let frame_count = energies.len() as f64;
+        let mean_energy = energies.iter().sum::<f64>() / frame_count;
+        let mean_zcr = zero_crossings.iter().sum::<f64>() / frame_count;
+        
+        // Speech alternates voiced/unvoiced syllables and pauses, so many frames sit far
+        // below the mean energy and far above the mean zero-crossing rate
+        let low_energy_ratio = energies.iter().filter(|e| **e < 0.5 * mean_energy).count() as f64 / frame_count;
+        let high_zcr_ratio = zero_crossings.iter().filter(|z| **z > 1.5 * mean_zcr).count() as f64 / frame_count;
+        let mean_flatness = if flatness.is_empty() {
+            1.0
+        } else {
+            flatness.iter().sum::<f64>() / flatness.len() as f64
+        };
+        
+        let speech_score = 0.5 * (low_energy_ratio / 0.15 + high_zcr_ratio / 0.10);
+        let (label, confidence) = if mean_energy.sqrt() < silence_threshold {
+            ("other", 1.0)
+        } else if mean_flatness > 0.5 {
+            // Broadband noise has a flat spectrum; tones and voices do not
+            ("other", ((mean_flatness - 0.5) / 0.5).clamp(0.5, 1.0))
+        } else if speech_score >= 1.0 {
+            ("speech", (0.5 + (speech_score - 1.0) / 2.0).clamp(0.5, 1.0))
+        } else {
+            ("music", (0.5 + (1.0 - speech_score) / 2.0).clamp(0.5, 1.0))
+        };
+        
+        let start = position as f64 / ANALYSIS_RATE as f64;
+        position += samples.len();
+        
+        windows.push(serde_json::json!({
+            "start": start,
+            "end": position as f64 / ANALYSIS_RATE as f64,
+            "label": label,
+            "confidence": confidence,
+            "low_energy_ratio": low_energy_ratio,
+            "high_zcr_ratio": high_zcr_ratio,
+            "spectral_flatness": mean_flatness,
+        }));
+    }
+    
+    let mut segments: Vec<serde_json::Value> = Vec::new();
+    for window in &windows {
+        match segments.last_mut() {
+            Some(segment) if segment["label"] == window["label"] => {
+                segment["end"] = window["end"].clone();
+            }
+            _ => segments.push(serde_json::json!({
+                "start": window["start"],
+                "end": window["end"],
+                "label": window["label"],
+            })),
+        }
+    }
+    
+    let duration = position as f64 / ANALYSIS_RATE as f64;
+    
+    Ok((windows, segments, duration))
+}
+
+/// Chapter marker in seconds
+struct Chapter {
+    start: f64,
+    end: f64,
+    title: String,
 }
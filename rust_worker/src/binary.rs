@@ -1,76 +1,313 @@
+use aes_gcm::aead::Aead;
+use rand::RngCore;
 use anyhow::{Context, Result};
-use sha2::{Sha256, Digest};
+use ffmpeg_next as ffmpeg;
+use sha2::{Digest, Sha256, Sha512};
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::process::Command;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::{config::Config, JobPayload};
+use crate::{acquisition::move_file, config::Config, JobPayload};
 
-/// Calculate SHA-256 hash of a file
+/// Calculate a file hash (SHA-256 unless `algorithm` says otherwise)
+///
+/// Writes a `<hash>  <filename>` line, as produced by `sha256sum` and friends. With `parallel`,
+/// see `hash_file_parallel` for how the work is split.
 pub async fn calculate_sha256(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Calculating SHA-256 hash");
+    let algorithm = HashAlgorithm::from_params(&job.params)?;
+    info!("Calculating {} hash", algorithm.name());
     
-    let mut file = File::open(&job.input_path)
-        .context("Failed to open input file")?;
-    
-    let mut hasher = Sha256::new();
-    let mut buffer = [0u8; 8192];
-    
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-        hasher.update(&buffer[..bytes_read]);
-    }
+    let hash_hex = hash_file_with_params(&job.input_path, algorithm, &job.params)?;
     
-    let hash = hasher.finalize();
-    let hash_hex = hex::encode(hash);
+    let file_name = std::path::Path::new(&job.input_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| job.input_path.clone());
     
     // Write hash to output file
     let mut output_file = File::create(&job.output_path)?;
-    output_file.write_all(hash_hex.as_bytes())?;
+    writeln!(output_file, "{}  {}", hash_hex, file_name)?;
     
     Ok(job.output_path.clone())
 }
 
 /// Compress file using zstd or gzip
+///
+/// With `format` (`tar`, `tar.gz`, `tar.zst`, `zip`) or an `inputs` list of files and
+/// directories, builds a multi-file archive instead; see `create_archive`.
 pub async fn compress_archive(job: &JobPayload, _config: &Config) -> Result<String> {
+    if job.params.get("format").is_some() || job.params.get("inputs").is_some() {
+        return create_archive(job);
+    }
+    
     info!("Compressing archive");
     
     let compression = job.params.get("compression")
         .and_then(|v| v.as_str())
         .unwrap_or("gzip");
     
-    match compression {
+    let mut input_file = File::open(&job.input_path)
+        .context("Failed to open input file")?;
+    let output_file = File::create(&job.output_path)
+        .context("Failed to create output file")?;
+    
+    let bytes_in = match compression {
         "gzip" => {
-            let output = Command::new("gzip")
-                .args(&["-c", &job.input_path])
-                .output()
-                .context("Failed to execute gzip")?;
-            
-            if !output.status.success() {
-                anyhow::bail!("Gzip failed: {}", String::from_utf8_lossy(&output.stderr));
-            }
-            
-            fs::write(&job.output_path, output.stdout)?;
+            let level = archive_level(job, 6)?;
+            let mut encoder = flate2::write::GzEncoder::new(
+                std::io::BufWriter::new(output_file),
+                flate2::Compression::new(level.clamp(0, 9) as u32),
+            );
+            let bytes_in = std::io::copy(&mut input_file, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            bytes_in
         }
         "zstd" => {
-            let output = Command::new("zstd")
-                .args(&["-c", &job.input_path])
-                .output()
-                .context("Failed to execute zstd")?;
-            
-            if !output.status.success() {
-                anyhow::bail!("Zstd failed: {}", String::from_utf8_lossy(&output.stderr));
-            }
-            
-            fs::write(&job.output_path, output.stdout)?;
+            let level = archive_level(job, 3)?;
+            let mut encoder = zstd_encoder(std::io::BufWriter::new(output_file), level, job)?;
+            let bytes_in = std::io::copy(&mut input_file, &mut encoder)?;
+            encoder.finish()?.flush()?;
+            bytes_in
         }
         _ => anyhow::bail!("Unsupported compression type: {}", compression),
+    };
+    
+    let bytes_out = fs::metadata(&job.output_path)?.len();
+    info!(
+        bytes_in,
+        bytes_out,
+        "Compressed to {:.1}% of original",
+        bytes_out as f64 * 100.0 / bytes_in.max(1) as f64
+    );
+    
+    Ok(job.output_path.clone())
+}
+
+/// Extract a zip, tar, tar.gz, tar.zst or 7z archive into `output_dir`
+/// (default: `<output_path>_extracted`) and write a JSON listing to `output_path`
+///
+/// Entries that would land outside the output directory, and links, are skipped. Extraction
+/// stops once `max_entries` or `max_bytes` (capped by `[archive]` in settings) is exceeded,
+/// counting bytes actually written rather than trusting the archive headers.
+pub async fn extract_archive(job: &JobPayload, config: &Config) -> Result<String> {
+    let format = match job.params.get("format").and_then(|v| v.as_str()) {
+        Some(format) => format.to_ascii_lowercase(),
+        None => detect_archive_format(&job.input_path)?.to_string(),
+    };
+    info!("Extracting {} archive", format);
+    
+    let output_dir = job.params.get("output_dir")
+        .and_then(|v| v.as_str())
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| {
+            let output = std::path::Path::new(&job.output_path);
+            output.with_file_name(format!(
+                "{}_extracted",
+                output.file_stem().and_then(|s| s.to_str()).unwrap_or("output")
+            ))
+        });
+    fs::create_dir_all(&output_dir)
+        .context("Failed to create extraction directory")?;
+    
+    let max_bytes = job.params.get("max_bytes")
+        .and_then(|v| v.as_u64())
+        .map_or(config.archive.max_extracted_bytes, |v| v.min(config.archive.max_extracted_bytes));
+    
+    let max_entries = job.params.get("max_entries")
+        .and_then(|v| v.as_u64())
+        .map_or(config.archive.max_entries, |v| v.min(config.archive.max_entries));
+    
+    let mut extraction = Extraction::new(output_dir.clone(), max_bytes, max_entries);
+    
+    match format.as_str() {
+        "zip" => {
+            let mut archive = zip::ZipArchive::new(File::open(&job.input_path)?)
+                .context("Failed to read zip archive")?;
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                let name = entry.name().to_string();
+                if entry.is_dir() {
+                    extraction.directory(&name)?;
+                } else if entry.is_symlink() {
+                    extraction.skip(&name, "symbolic link")?;
+                } else {
+                    extraction.file(&name, &mut entry)?;
+                }
+            }
+        }
+        "tar" => extract_tar(File::open(&job.input_path)?, &mut extraction)?,
+        "tar.gz" | "tgz" => extract_tar(
+            flate2::read::MultiGzDecoder::new(File::open(&job.input_path)?),
+            &mut extraction,
+        )?,
+        "tar.zst" | "tzst" => extract_tar(
+            zstd::stream::read::Decoder::new(File::open(&job.input_path)?)?,
+            &mut extraction,
+        )?,
+        "7z" => {
+            let mut reader = sevenz_rust::SevenZReader::open(&job.input_path, sevenz_rust::Password::empty())
+                .context("Failed to read 7z archive")?;
+            let mut failure = None;
+            reader.for_each_entries(|entry, data| {
+                let result = if entry.is_directory() {
+                    extraction.directory(entry.name())
+                } else {
+                    extraction.file(entry.name(), data)
+                };
+                match result {
+                    Ok(()) => Ok(true),
+                    Err(e) => {
+                        failure = Some(e);
+                        Ok(false)
+                    }
+                }
+            })?;
+            if let Some(e) = failure {
+                return Err(e);
+            }
+        }
+        other => anyhow::bail!("Unsupported archive format: {}", other),
     }
     
+    let listing = serde_json::json!({
+        "archive": job.input_path,
+        "format": format,
+        "output_dir": output_dir.to_string_lossy(),
+        "file_count": extraction.files.len(),
+        "total_bytes": extraction.total_bytes,
+        "files": extraction.files,
+        "skipped": extraction.skipped,
+    });
+    
+    fs::write(&job.output_path, serde_json::to_string_pretty(&listing)?)?;
+    
+    info!(
+        "Extracted {} files ({} bytes), skipped {}",
+        listing["file_count"], extraction.total_bytes, listing["skipped"].as_array().map_or(0, |s| s.len())
+    );
+    Ok(job.output_path.clone())
+}
+
+/// Encrypt a file with AES-256-GCM using the `[encryption]` key named by `key_id`
+///
+/// The file is sealed in 1 MiB chunks (each with its own tag, the last one flagged so
+/// truncation is detected) and a `<output>.meta.json` sidecar records the key id, sizes and
+/// plaintext hash.
+pub async fn encrypt_file(job: &JobPayload, config: &Config) -> Result<String> {
+    let key_id = job.params.get("key_id")
+        .and_then(|v| v.as_str())
+        .unwrap_or(&config.encryption.default_key);
+    info!(key_id, "Encrypting file");
+    
+    let cipher = load_cipher(config, key_id)?;
+    
+    let mut nonce_prefix = [0u8; 7];
+    rand::rngs::OsRng.fill_bytes(&mut nonce_prefix);
+    
+    let mut header = Vec::with_capacity(ENCRYPTION_HEADER_LEN);
+    header.extend_from_slice(ENCRYPTION_MAGIC);
+    header.extend_from_slice(&(ENCRYPTION_CHUNK_SIZE as u32).to_be_bytes());
+    header.extend_from_slice(&nonce_prefix);
+    
+    let mut input = std::io::BufReader::new(
+        File::open(&job.input_path).context("Failed to open input file")?,
+    );
+    let mut output = std::io::BufWriter::new(File::create(&job.output_path)?);
+    output.write_all(&header)?;
+    
+    let mut hasher = Sha256::new();
+    let mut current = vec![0u8; ENCRYPTION_CHUNK_SIZE];
+    let mut next = vec![0u8; ENCRYPTION_CHUNK_SIZE];
+    let mut current_len = read_full(&mut input, &mut current)?;
+    let mut plaintext_size = 0u64;
+    let mut counter = 0u32;
+    
+    loop {
+        let next_len = read_full(&mut input, &mut next)?;
+        let last = next_len == 0;
+        
+        let chunk = &current[..current_len];
+        hasher.update(chunk);
+        plaintext_size += current_len as u64;
+        
+        let sealed = cipher
+            .encrypt(
+                &chunk_nonce(&nonce_prefix, counter, last),
+                aes_gcm::aead::Payload { msg: chunk, aad: &header },
+            )
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+        output.write_all(&sealed)?;
+        
+        if last {
+            break;
+        }
+        counter = counter
+            .checked_add(1)
+            .context("File too large to encrypt")?;
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+    }
+    output.flush()?;
+    
+    let file_name = std::path::Path::new(&job.input_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string());
+    let metadata = serde_json::json!({
+        "algorithm": "AES-256-GCM",
+        "key_id": key_id,
+        "chunk_size": ENCRYPTION_CHUNK_SIZE,
+        "original_name": file_name,
+        "plaintext_size": plaintext_size,
+        "plaintext_sha256": hex::encode(hasher.finalize()),
+        "ciphertext_size": fs::metadata(&job.output_path)?.len(),
+        "encrypted_at": chrono::Utc::now().to_rfc3339(),
+    });
+    fs::write(
+        format!("{}.meta.json", job.output_path),
+        serde_json::to_string_pretty(&metadata)?,
+    )?;
+    
+    info!(bytes = plaintext_size, "Encrypted file");
+    Ok(job.output_path.clone())
+}
+
+/// Decrypt a file produced by `encrypt_file`
+///
+/// The key comes from `key_id`, else the `<input>.meta.json` sidecar, else the configured
+/// default. Any tampering or truncation fails the job and removes the partial output.
+pub async fn decrypt_file(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Decrypting file");
+    
+    let sidecar: Option<serde_json::Value> = fs::read_to_string(format!("{}.meta.json", job.input_path))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok());
+    
+    let key_id = job.params.get("key_id")
+        .and_then(|v| v.as_str())
+        .or_else(|| sidecar.as_ref().and_then(|meta| meta["key_id"].as_str()))
+        .unwrap_or(&config.encryption.default_key)
+        .to_string();
+    
+    let cipher = load_cipher(config, &key_id)?;
+    
+    let result = decrypt_stream(&cipher, &job.input_path, &job.output_path);
+    let (plaintext_size, plaintext_hash) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = fs::remove_file(&job.output_path);
+            return Err(e);
+        }
+    };
+    
+    if let Some(expected) = sidecar.as_ref().and_then(|meta| meta["plaintext_sha256"].as_str()) {
+        if expected != plaintext_hash {
+            let _ = fs::remove_file(&job.output_path);
+            anyhow::bail!("Decrypted file does not match the recorded plaintext hash");
+        }
+    }
+    
+    info!(key_id = %key_id, bytes = plaintext_size, "Decrypted file");
     Ok(job.output_path.clone())
 }
 
@@ -92,20 +329,116 @@ pub async fn extract_exif_metadata(job: &JobPayload, _config: &Config) -> Result
     Ok(job.output_path.clone())
 }
 
-/// Safely delete the original input file
-pub async fn purge_original_file(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Purging original file");
+/// Remove identifying metadata before publishing
+///
+/// Images go through exiftool, which drops EXIF, GPS, XMP, IPTC and maker notes while keeping
+/// the `keep` tags (default: `ICC_Profile`, `Orientation`) so the picture still displays as
+/// shot. Audio and video are remuxed without container, stream and chapter tags except
+/// `keep_tags` (default: `language`), and without data streams such as GPS or telemetry
+/// tracks or attached cover art, whose image keeps its own EXIF. The cleaned file goes to `output_path`; a report of everything removed goes to
+/// `report_path` (default: `<output_path>.json`).
+pub async fn strip_metadata(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Stripping metadata");
+    
+    let report_path = job.params.get("report_path")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}.json", job.output_path));
+    
+    let is_image = infer::get_from_path(&job.input_path)
+        .context("Failed to read input file")?
+        .map(|kind| kind.matcher_type() == infer::MatcherType::Image)
+        .unwrap_or(false);
+    
+    let report = if is_image {
+        let keep: Vec<String> = job.params.get("keep")
+            .and_then(|v| v.as_array())
+            .map(|tags| tags.iter().filter_map(|t| t.as_str().map(String::from)).collect())
+            .unwrap_or_else(|| vec!["ICC_Profile".to_string(), "Orientation".to_string()]);
+        
+        strip_image_metadata(&job.input_path, &job.output_path, &keep)?
+    } else {
+        let keep_tags: Vec<String> = job.params.get("keep_tags")
+            .and_then(|v| v.as_array())
+            .map(|tags| tags.iter().filter_map(|t| t.as_str().map(|s| s.to_lowercase())).collect())
+            .unwrap_or_else(|| vec!["language".to_string()]);
+        
+        let keep_chapters = job.params.get("keep_chapters")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        
+        strip_container_metadata(&job.input_path, &job.output_path, &keep_tags, keep_chapters)?
+    };
+    
+    fs::write(&report_path, serde_json::to_string_pretty(&report)?)?;
+    
+    info!(report = %report_path, "Stripped metadata");
+    Ok(job.output_path.clone())
+}
+
+/// Remove the original input file
+///
+/// `mode` is `delete` (unlink, default), `soft` (move under the configured trash path, where it
+/// is kept for `ttl_days` and swept by later soft deletes) or `shred` (overwrite `passes` times
+/// with random data, then zeros, before unlinking; this does not reach old copies on
+/// copy-on-write filesystems or SSD spare blocks). `confirm_token` must repeat the input's file
+/// name, so a payload with the wrong input or a stray purge step fails instead of deleting.
+pub async fn purge_original_file(job: &JobPayload, config: &Config) -> Result<String> {
+    let mode = job.params.get("mode")
+        .and_then(|v| v.as_str())
+        .unwrap_or("delete");
+    
+    info!(mode, "Purging original file");
+    
+    let input = std::path::Path::new(&job.input_path);
     
     // Verify the file exists before attempting deletion
-    if !std::path::Path::new(&job.input_path).exists() {
+    if !input.is_file() {
         anyhow::bail!("Input file does not exist: {}", job.input_path);
     }
     
-    fs::remove_file(&job.input_path)
-        .context("Failed to delete file")?;
+    let file_name = input
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .context("Input path has no file name")?;
+    
+    match job.params.get("confirm_token").and_then(|v| v.as_str()) {
+        Some(token) if token == file_name => {}
+        Some(_) => anyhow::bail!("confirm_token does not match the input file name; nothing was purged"),
+        None => anyhow::bail!("purge_original_file requires confirm_token set to the input file name"),
+    }
+    
+    let confirmation = match mode {
+        "delete" => {
+            fs::remove_file(&job.input_path)
+                .context("Failed to delete file")?;
+            format!("File deleted: {}", job.input_path)
+        }
+        "soft" => {
+            let ttl_days = job.params.get("ttl_days")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(config.trash.ttl_days);
+            
+            let swept = sweep_trash(&config.trash.path)?;
+            if swept > 0 {
+                info!(swept, "Removed expired trash entries");
+            }
+            
+            let (trash_path, expires_at) = move_to_trash(&job.input_path, &file_name, &config.trash.path, ttl_days)?;
+            format!("File moved to trash: {} -> {} (expires {})", job.input_path, trash_path, expires_at)
+        }
+        "shred" => {
+            let passes = job.params.get("passes")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(config.trash.shred_passes as u64) as u32;
+            
+            shred_file(&job.input_path, passes)?;
+            format!("File shredded ({} passes): {}", passes, job.input_path)
+        }
+        other => anyhow::bail!("Unknown purge mode: {}", other),
+    };
     
     // Write confirmation to output
-    let confirmation = format!("File deleted: {}", job.input_path);
     fs::write(&job.output_path, confirmation.as_bytes())?;
     
     Ok(job.output_path.clone())
@@ -144,31 +477,49 @@ pub async fn validate_format_compliance(job: &JobPayload, _config: &Config) -> R
     Ok(job.output_path.clone())
 }
 
-/// Chain job trigger - enqueue next job in sequence
-pub async fn chain_job_trigger(job: &JobPayload, _config: &Config) -> Result<String> {
+/// Chain job trigger - enqueue or run the next jobs, with this job's input as theirs
+///
+/// Steps come from `next` (one object or a list of `{task, output_path, input_path, params}`)
+/// or from `next_task`/`next_output`/`next_input`/`next_params`. Steps without an
+/// `input_path` take this job's input, normally the previous job's output. `next_mode` is
+/// `enqueue` (default; onto the Redis queue run by `rust_worker consume`) or `execute` (each
+/// in a worker process of its own, in order, failing on the first failed step). The report lists the job ids or
+/// outputs.
+pub async fn chain_job_trigger(job: &JobPayload, config: &Config) -> Result<String> {
     info!("Triggering chained job");
     
-    let next_task = job.params.get("next_task")
-        .and_then(|v| v.as_str())
-        .context("next_task parameter required")?;
+    let mut steps = next_steps(&job.params)?;
     
-    let next_input = job.params.get("next_input")
-        .and_then(|v| v.as_str())
-        .unwrap_or(&job.input_path);
+    if let Some(next_task) = job.params.get("next_task").and_then(|v| v.as_str()) {
+        let next_output = job.params.get("next_output")
+            .and_then(|v| v.as_str())
+            .context("next_output parameter required")?;
+        
+        steps.push(NextStep {
+            task: next_task.to_string(),
+            input_path: job.params.get("next_input")
+                .and_then(|v| v.as_str())
+                .map(String::from),
+            output_path: next_output.to_string(),
+            params: job.params.get("next_params")
+                .cloned()
+                .unwrap_or_else(|| serde_json::json!({})),
+        });
+    }
     
-    let next_output = job.params.get("next_output")
-        .and_then(|v| v.as_str())
-        .context("next_output parameter required")?;
-    
-    // Create a trigger file with the next job details
-    let trigger_data = serde_json::json!({
-        "task": next_task,
-        "input_path": next_input,
-        "output_path": next_output,
-        "params": job.params.get("next_params").unwrap_or(&serde_json::json!({}))
+    if steps.is_empty() {
+        anyhow::bail!("next or next_task parameter required");
+    }
+    
+    let triggered = trigger_next_jobs(&steps, &job.input_path, &job.params, config).await?;
+    
+    let report = serde_json::json!({
+        "source": job.input_path,
+        "mode": next_mode(&job.params)?,
+        "jobs": triggered,
     });
     
-    fs::write(&job.output_path, serde_json::to_string_pretty(&trigger_data)?)?;
+    fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
     
     Ok(job.output_path.clone())
 }
@@ -190,3 +541,1084 @@ pub async fn report_metrics(job: &JobPayload, _config: &Config) -> Result<String
     
     Ok(job.output_path.clone())
 }
+
+/// A follow-up job from a `next` param
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct NextStep {
+    task: String,
+    #[serde(default)]
+    input_path: Option<String>,
+    output_path: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+/// Steps in the `next` param, which holds one step or a list of them
+pub(crate) fn next_steps(params: &serde_json::Value) -> Result<Vec<NextStep>> {
+    match params.get("next") {
+        None | Some(serde_json::Value::Null) => Ok(Vec::new()),
+        Some(serde_json::Value::Array(steps)) => steps
+            .iter()
+            .map(|step| serde_json::from_value(step.clone()).context("Invalid next step"))
+            .collect(),
+        Some(step) => Ok(vec![serde_json::from_value(step.clone()).context("Invalid next step")?]),
+    }
+}
+
+pub(crate) fn next_mode(params: &serde_json::Value) -> Result<&str> {
+    match params.get("next_mode").and_then(|v| v.as_str()).unwrap_or("enqueue") {
+        mode @ ("enqueue" | "execute") => Ok(mode),
+        other => anyhow::bail!("Unknown next_mode: {} (expected enqueue or execute)", other),
+    }
+}
+
+/// Enqueue or run `steps` with `current_output` as the input of those that name none,
+/// returning a summary of each
+pub(crate) async fn trigger_next_jobs(
+    steps: &[NextStep],
+    current_output: &str,
+    params: &serde_json::Value,
+    config: &Config,
+) -> Result<Vec<serde_json::Value>> {
+    let mode = next_mode(params)?;
+    let mut triggered = Vec::new();
+    
+    for step in steps {
+        let next = JobPayload {
+            job_id: None,
+            traceparent: crate::telemetry::traceparent(),
+            task: step.task.clone(),
+            input_path: step.input_path.clone().unwrap_or_else(|| current_output.to_string()),
+            output_path: step.output_path.clone(),
+            params: step.params.clone(),
+        };
+        
+        if mode == "execute" {
+            // A worker process stages, publishes and chains the step as any other job
+            info!(task = %next.task, "Running chained job");
+            match crate::queue::run_job_process(config, &next).await? {
+                crate::queue::Outcome::Succeeded => {}
+                crate::queue::Outcome::Failed(message) => {
+                    anyhow::bail!("Chained job '{}' failed: {}", next.task, message)
+                }
+                crate::queue::Outcome::Cancelled => anyhow::bail!("Chained job '{}' was cancelled", next.task),
+            }
+            triggered.push(serde_json::json!({
+                "task": next.task,
+                "input_path": next.input_path,
+                "output_path": next.output_path,
+                "status": "succeeded",
+            }));
+        } else {
+            let (task, input_path, output_path) = (next.task.clone(), next.input_path.clone(), next.output_path.clone());
+            let job_id = crate::queue::enqueue(config, next).await?;
+            info!(task = %task, job_id = %job_id, "Enqueued chained job");
+            triggered.push(serde_json::json!({
+                "task": task,
+                "job_id": job_id,
+                "input_path": input_path,
+                "output_path": output_path,
+                "status": "enqueued",
+            }));
+        }
+    }
+    
+    Ok(triggered)
+}
+
+/// Hash algorithms accepted by the `algorithm` param of the checksum tasks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+    Xxh64,
+    Crc32,
+}
+
+impl HashAlgorithm {
+    pub(crate) fn parse(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "md5" => Ok(Self::Md5),
+            "sha1" => Ok(Self::Sha1),
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            "blake3" | "b3" => Ok(Self::Blake3),
+            "xxh64" | "xxhash64" | "xxhash" => Ok(Self::Xxh64),
+            "crc32" => Ok(Self::Crc32),
+            _ => anyhow::bail!("Unsupported hash algorithm: {}", name),
+        }
+    }
+    
+    /// Reads `algorithm`, defaulting to SHA-256
+    pub(crate) fn from_params(params: &serde_json::Value) -> Result<Self> {
+        params.get("algorithm")
+            .and_then(|v| v.as_str())
+            .map_or(Ok(Self::Sha256), Self::parse)
+    }
+    
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+            Self::Xxh64 => "xxh64",
+            Self::Crc32 => "crc32",
+        }
+    }
+}
+
+enum FileHasher {
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+    Xxh64(xxhash_rust::xxh64::Xxh64),
+    Crc32(crc32fast::Hasher),
+}
+
+impl FileHasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Self::Md5(md5::Md5::new()),
+            HashAlgorithm::Sha1 => Self::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Self::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Xxh64 => Self::Xxh64(xxhash_rust::xxh64::Xxh64::new(0)),
+            HashAlgorithm::Crc32 => Self::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+    
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Self::Xxh64(hasher) => hasher.update(data),
+            Self::Crc32(hasher) => hasher.update(data),
+        }
+    }
+    
+    /// Lowercase hex digest; xxh64 and crc32 are printed big-endian like `xxhsum`/`crc32`
+    fn finalize_hex(self) -> String {
+        match self {
+            Self::Md5(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha1(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha512(hasher) => hex::encode(hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            Self::Xxh64(hasher) => format!("{:016x}", hasher.digest()),
+            Self::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Hash a file with `algorithm`, returning the lowercase hex digest
+pub(crate) fn hash_file(path: &str, algorithm: HashAlgorithm) -> Result<String> {
+    let mut file = File::open(path)
+        .context(format!("Failed to open {}", path))?;
+    
+    let mut hasher = FileHasher::new(algorithm);
+    let mut buffer = vec![0u8; 64 * 1024];
+    
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    
+    Ok(hasher.finalize_hex())
+}
+
+/// Hash a file sequentially, or with `hash_file_parallel` when `parallel` is set
+///
+/// `threads` (default: all cores) and `chunk_size` (bytes per SHA-256 leaf) tune parallel mode.
+pub(crate) fn hash_file_with_params(
+    path: &str,
+    algorithm: HashAlgorithm,
+    params: &serde_json::Value,
+) -> Result<String> {
+    let parallel = params.get("parallel")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    if !parallel {
+        return hash_file(path, algorithm);
+    }
+    
+    let threads = params.get("threads")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as usize;
+    
+    let chunk_size = params.get("chunk_size")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(64 * 1024 * 1024);
+    
+    hash_file_parallel(path, algorithm, threads, chunk_size)
+}
+
+/// Hash a file on `threads` worker threads (0 = one per core)
+///
+/// BLAKE3 uses its native tree parallelism over a memory map, giving the same digest as a
+/// sequential hash. SHA-256 hashes `chunk_size` pieces independently and reports the root of a
+/// Merkle tree over them (parent = SHA-256(left || right), an odd node is carried up unchanged),
+/// so it only matches other parallel SHA-256 hashes with the same chunk size.
+pub(crate) fn hash_file_parallel(
+    path: &str,
+    algorithm: HashAlgorithm,
+    threads: usize,
+    chunk_size: u64,
+) -> Result<String> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .context("Failed to start hashing threads")?;
+    
+    match algorithm {
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            pool.install(|| hasher.update_mmap_rayon(path).map(|_| ()))
+                .context(format!("Failed to hash {}", path))?;
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+        HashAlgorithm::Sha256 => {
+            if chunk_size == 0 {
+                anyhow::bail!("chunk_size must be greater than zero");
+            }
+            
+            let size = fs::metadata(path)
+                .context(format!("Failed to read metadata for {}", path))?
+                .len();
+            let chunk_count = size.div_ceil(chunk_size).max(1);
+            
+            let leaves = pool.install(|| {
+                use rayon::prelude::*;
+                
+                (0..chunk_count)
+                    .into_par_iter()
+                    .map(|index| hash_range(path, index * chunk_size, chunk_size))
+                    .collect::<Result<Vec<_>>>()
+            })?;
+            
+            info!(chunks = leaves.len(), "Computed SHA-256 Merkle leaves");
+            
+            let mut level = leaves;
+            while level.len() > 1 {
+                level = level
+                    .chunks(2)
+                    .map(|pair| match pair {
+                        [left, right] => {
+                            let mut hasher = Sha256::new();
+                            hasher.update(left);
+                            hasher.update(right);
+                            hasher.finalize().to_vec()
+                        }
+                        [single] => single.clone(),
+                        _ => unreachable!(),
+                    })
+                    .collect();
+            }
+            
+            Ok(hex::encode(&level[0]))
+        }
+        other => anyhow::bail!(
+            "Parallel hashing supports blake3 and sha256, not {}",
+            other.name()
+        ),
+    }
+}
+
+/// SHA-256 of `length` bytes starting at `offset` (less at the end of the file)
+fn hash_range(path: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+    let mut file = File::open(path)
+        .context(format!("Failed to open {}", path))?;
+    file.seek(SeekFrom::Start(offset))?;
+    
+    let mut reader = file.take(length);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Look up the expected hash for `file_name` in a checksum manifest
+///
+/// Accepts GNU coreutils lines (`<hash>  <name>`, `<hash> *<name>`) and BSD tagged lines
+/// (`SHA256 (<name>) = <hash>`); entries are matched on their final path component. The
+/// algorithm is returned when the line names it.
+pub(crate) fn find_manifest_entry(
+    manifest: &str,
+    file_name: &str,
+) -> Option<(String, Option<HashAlgorithm>)> {
+    let base_name = |name: &str| {
+        name.rsplit(['/', '\\']).next().unwrap_or(name).to_string()
+    };
+    
+    manifest.lines().find_map(|line| {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        
+        if let Some((tag, rest)) = line.split_once(" (") {
+            if let Some((name, hash)) = rest.rsplit_once(") = ") {
+                return (base_name(name) == file_name)
+                    .then(|| (hash.trim().to_ascii_lowercase(), HashAlgorithm::parse(tag).ok()));
+            }
+        }
+        
+        let (hash, name) = line.split_once(char::is_whitespace)?;
+        let name = name.trim_start().trim_start_matches('*');
+        (base_name(name) == file_name).then(|| (hash.to_ascii_lowercase(), None))
+    })
+}
+
+/// Archive format from magic bytes, falling back to plain tar
+fn detect_archive_format(path: &str) -> Result<&'static str> {
+    let mut header = [0u8; 262];
+    let read = File::open(path)
+        .context("Failed to open archive")?
+        .read(&mut header)?;
+    let header = &header[..read];
+    
+    Ok(if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+        "zip"
+    } else if header.starts_with(&[0x37, 0x7a, 0xbc, 0xaf, 0x27, 0x1c]) {
+        "7z"
+    } else if header.starts_with(&[0x1f, 0x8b]) {
+        "tar.gz"
+    } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        "tar.zst"
+    } else {
+        "tar"
+    })
+}
+
+fn extract_tar<R: Read>(reader: R, extraction: &mut Extraction) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().context("Failed to read tar archive")? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let entry_type = entry.header().entry_type();
+        
+        if entry_type.is_dir() {
+            extraction.directory(&name)?;
+        } else if entry_type.is_file() {
+            extraction.file(&name, &mut entry)?;
+        } else if entry_type.is_symlink() || entry_type.is_hard_link() {
+            extraction.skip(&name, "link")?;
+        } else if !entry_type.is_pax_global_extensions() && !entry_type.is_gnu_longname() {
+            extraction.skip(&name, "special file")?;
+        }
+    }
+    Ok(())
+}
+
+/// Bookkeeping and limits shared by every archive format
+struct Extraction {
+    root: std::path::PathBuf,
+    max_bytes: u64,
+    max_entries: u64,
+    entries: u64,
+    total_bytes: u64,
+    files: Vec<serde_json::Value>,
+    skipped: Vec<serde_json::Value>,
+}
+
+impl Extraction {
+    fn new(root: std::path::PathBuf, max_bytes: u64, max_entries: u64) -> Self {
+        Self {
+            root,
+            max_bytes,
+            max_entries,
+            entries: 0,
+            total_bytes: 0,
+            files: Vec::new(),
+            skipped: Vec::new(),
+        }
+    }
+    
+    fn count_entry(&mut self) -> Result<()> {
+        self.entries += 1;
+        if self.entries > self.max_entries {
+            anyhow::bail!("Archive has more than {} entries", self.max_entries);
+        }
+        Ok(())
+    }
+    
+    /// Destination for an entry name, or None if it is absolute or climbs out of the root
+    fn resolve(&self, name: &str) -> Option<(std::path::PathBuf, String)> {
+        let mut relative = std::path::PathBuf::new();
+        for component in std::path::Path::new(&name.replace('\\', "/")).components() {
+            match component {
+                std::path::Component::Normal(part) => relative.push(part),
+                std::path::Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        if relative.as_os_str().is_empty() {
+            return None;
+        }
+        let display = relative.to_string_lossy().replace('\\', "/");
+        Some((self.root.join(relative), display))
+    }
+    
+    fn directory(&mut self, name: &str) -> Result<()> {
+        self.count_entry()?;
+        match self.resolve(name) {
+            Some((path, _)) => fs::create_dir_all(&path)
+                .context(format!("Failed to create {}", path.display())),
+            None => self.skip_uncounted(name, "unsafe path"),
+        }
+    }
+    
+    fn file(&mut self, name: &str, reader: &mut dyn Read) -> Result<()> {
+        self.count_entry()?;
+        let (path, relative) = match self.resolve(name) {
+            Some(resolved) => resolved,
+            None => return self.skip_uncounted(name, "unsafe path"),
+        };
+        
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        
+        let remaining = self.max_bytes - self.total_bytes;
+        let mut output = File::create(&path)
+            .context(format!("Failed to create {}", path.display()))?;
+        let written = std::io::copy(&mut reader.take(remaining + 1), &mut output)?;
+        
+        if written > remaining {
+            drop(output);
+            let _ = fs::remove_file(&path);
+            anyhow::bail!("Archive expands beyond the {} byte limit", self.max_bytes);
+        }
+        
+        self.total_bytes += written;
+        self.files.push(serde_json::json!({
+            "path": relative,
+            "size": written,
+        }));
+        Ok(())
+    }
+    
+    fn skip(&mut self, name: &str, reason: &str) -> Result<()> {
+        self.count_entry()?;
+        self.skip_uncounted(name, reason)
+    }
+    
+    fn skip_uncounted(&mut self, name: &str, reason: &str) -> Result<()> {
+        warn!(entry = name, reason, "Skipping archive entry");
+        self.skipped.push(serde_json::json!({
+            "name": name,
+            "reason": reason,
+        }));
+        Ok(())
+    }
+}
+
+/// Build a tar, tar.gz, tar.zst or zip from `inputs` (default: the job input)
+///
+/// Directories are walked recursively; `include`/`exclude` globs are matched against archive
+/// paths. Entries are sorted and written with fixed timestamps and ownership so the same
+/// inputs always produce the same bytes.
+fn create_archive(job: &JobPayload) -> Result<String> {
+    let format = job.params.get("format")
+        .and_then(|v| v.as_str())
+        .unwrap_or("tar.gz")
+        .to_ascii_lowercase();
+    
+    let inputs: Vec<String> = match job.params.get("inputs").and_then(|v| v.as_array()) {
+        Some(inputs) => inputs.iter().filter_map(|v| v.as_str()).map(String::from).collect(),
+        None => vec![job.input_path.clone()],
+    };
+    
+    let include = glob_set(job.params.get("include"))?;
+    let exclude = glob_set(job.params.get("exclude"))?;
+    
+    let mtime = job.params.get("mtime")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    
+    let mut entries = Vec::new();
+    for input in &inputs {
+        let path = std::path::Path::new(input);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .context(format!("Input has no file name: {}", input))?;
+        collect_archive_entries(path, &name, &mut entries)?;
+    }
+    
+    entries.retain(|(_, name)| {
+        include.as_ref().map_or(true, |set| set.is_match(name))
+            && !exclude.as_ref().is_some_and(|set| set.is_match(name))
+    });
+    entries.sort_by(|a, b| a.1.cmp(&b.1));
+    entries.dedup_by(|a, b| a.1 == b.1);
+    
+    info!("Creating {} archive with {} files", format, entries.len());
+    
+    let output = File::create(&job.output_path)
+        .context("Failed to create archive")?;
+    
+    match format.as_str() {
+        "tar" => {
+            write_tar(output, &entries, mtime)?;
+        }
+        "tar.gz" | "tgz" => {
+            let level = archive_level(job, 6)?;
+            let encoder = flate2::GzBuilder::new()
+                .mtime(mtime as u32)
+                .write(output, flate2::Compression::new(level.clamp(0, 9) as u32));
+            write_tar(encoder, &entries, mtime)?.finish()?;
+        }
+        "tar.zst" | "tzst" => {
+            let level = archive_level(job, 3)?;
+            let encoder = zstd_encoder(output, level, job)?;
+            write_tar(encoder, &entries, mtime)?.finish()?;
+        }
+        "zip" => {
+            let level = archive_level(job, 6)?;
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                .compression_level(Some(level.clamp(0, 9).into()))
+                .last_modified_time(zip::DateTime::default())
+                .unix_permissions(0o644)
+                // Entries over 4 GiB need ZIP64 headers, which must be chosen up front
+                .large_file(true);
+            
+            let mut writer = zip::ZipWriter::new(output);
+            for (source, name) in &entries {
+                writer.start_file(name.as_str(), options)?;
+                std::io::copy(&mut File::open(source)?, &mut writer)
+                    .context(format!("Failed to add {}", source.display()))?;
+            }
+            writer.finish()?;
+        }
+        other => anyhow::bail!("Unsupported archive format: {}", other),
+    }
+    
+    Ok(job.output_path.clone())
+}
+
+/// `level` param, defaulting per format
+fn archive_level(job: &JobPayload, default: i32) -> Result<i32> {
+    Ok(job.params.get("level")
+        .and_then(|v| v.as_i64())
+        .map_or(default, |v| v as i32))
+}
+
+/// zstd encoder using `threads` worker threads (default: all cores, 0 = single-threaded)
+fn zstd_encoder<W: Write>(
+    output: W,
+    level: i32,
+    job: &JobPayload,
+) -> Result<zstd::stream::write::Encoder<'static, W>> {
+    let threads = job.params.get("threads")
+        .and_then(|v| v.as_u64())
+        .map_or_else(
+            || std::thread::available_parallelism().map_or(1, |n| n.get() as u32),
+            |v| v as u32,
+        );
+    
+    let mut encoder = zstd::stream::write::Encoder::new(output, level)?;
+    if threads > 0 {
+        encoder.multithread(threads)
+            .context("Failed to enable multithreaded zstd")?;
+    }
+    Ok(encoder)
+}
+
+pub(crate) fn glob_set(patterns: Option<&serde_json::Value>) -> Result<Option<globset::GlobSet>> {
+    let patterns = match patterns.and_then(|v| v.as_array()) {
+        Some(patterns) if !patterns.is_empty() => patterns,
+        _ => return Ok(None),
+    };
+    
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in patterns.iter().filter_map(|v| v.as_str()) {
+        builder.add(globset::Glob::new(pattern).context(format!("Invalid glob: {}", pattern))?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Regular files under `path`, named by their archive path (`/`-separated under `name`)
+pub(crate) fn collect_archive_entries(
+    path: &std::path::Path,
+    name: &str,
+    entries: &mut Vec<(std::path::PathBuf, String)>,
+) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)
+        .context(format!("Failed to read {}", path.display()))?;
+    
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let child_name = format!("{}/{}", name, entry.file_name().to_string_lossy());
+            collect_archive_entries(&entry.path(), &child_name, entries)?;
+        }
+    } else if metadata.is_file() {
+        entries.push((path.to_path_buf(), name.to_string()));
+    } else {
+        warn!(path = %path.display(), "Skipping non-regular file");
+    }
+    
+    Ok(())
+}
+
+/// Write a tar stream with normalised headers and hand back the underlying writer
+fn write_tar<W: Write>(writer: W, entries: &[(std::path::PathBuf, String)], mtime: u64) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    
+    for (source, name) in entries {
+        let file = File::open(source)
+            .context(format!("Failed to open {}", source.display()))?;
+        let metadata = file.metadata()?;
+        
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(metadata.len());
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mode(if is_executable(&metadata) { 0o755 } else { 0o644 });
+        
+        builder.append_data(&mut header, name, file)
+            .context(format!("Failed to add {}", source.display()))?;
+    }
+    
+    Ok(builder.into_inner()?)
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+const ENCRYPTION_MAGIC: &[u8; 8] = b"RMPENC1\0";
+/// Magic, chunk size (u32 BE) and 7-byte nonce prefix; authenticated with every chunk
+const ENCRYPTION_HEADER_LEN: usize = 8 + 4 + 7;
+const ENCRYPTION_CHUNK_SIZE: usize = 1024 * 1024;
+const ENCRYPTION_TAG_LEN: usize = 16;
+
+/// Load a 256-bit key from its `[encryption.keys]` reference
+fn load_cipher(config: &Config, key_id: &str) -> Result<aes_gcm::Aes256Gcm> {
+    use aes_gcm::KeyInit;
+    
+    let reference = config.encryption.keys.get(key_id)
+        .context(format!("Unknown encryption key: {}", key_id))?;
+    let key = load_key_reference(reference, key_id)?;
+    
+    aes_gcm::Aes256Gcm::new_from_slice(&key)
+        .map_err(|_| anyhow::anyhow!("Key {} is {} bytes, expected 32", key_id, key.len()))
+}
+
+/// Read a 32-byte key from a config reference: `env:VAR` (hex) or `file:/path` (32 raw bytes
+/// or hex)
+pub(crate) fn load_key_reference(reference: &str, key_id: &str) -> Result<Vec<u8>> {
+    if let Some(var) = reference.strip_prefix("env:") {
+        let value = std::env::var(var)
+            .context(format!("Key environment variable {} is not set", var))?;
+        hex::decode(value.trim()).context("Key must be hex encoded")
+    } else if let Some(path) = reference.strip_prefix("file:") {
+        let bytes = fs::read(path).context(format!("Failed to read key file {}", path))?;
+        if bytes.len() == 32 {
+            Ok(bytes)
+        } else {
+            hex::decode(String::from_utf8_lossy(&bytes).trim()).context("Key file must be 32 bytes or hex")
+        }
+    } else {
+        anyhow::bail!("Key {} must reference env: or file:", key_id);
+    }
+}
+
+/// 7-byte prefix, 32-bit chunk counter and a final-chunk flag
+fn chunk_nonce(prefix: &[u8; 7], counter: u32, last: bool) -> aes_gcm::Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0u8; 12];
+    nonce[..7].copy_from_slice(prefix);
+    nonce[7..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = u8::from(last);
+    nonce.into()
+}
+
+/// Fill `buffer` as far as the reader allows, returning the byte count
+pub(crate) fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Returns the plaintext size and SHA-256
+fn decrypt_stream(cipher: &aes_gcm::Aes256Gcm, input_path: &str, output_path: &str) -> Result<(u64, String)> {
+    let mut input = std::io::BufReader::new(
+        File::open(input_path).context("Failed to open input file")?,
+    );
+    
+    let mut header = [0u8; ENCRYPTION_HEADER_LEN];
+    if read_full(&mut input, &mut header)? != ENCRYPTION_HEADER_LEN || &header[..8] != ENCRYPTION_MAGIC {
+        anyhow::bail!("Not a file produced by encrypt_file");
+    }
+    let chunk_size = u32::from_be_bytes(header[8..12].try_into()?) as usize;
+    let nonce_prefix: [u8; 7] = header[12..19].try_into()?;
+    if chunk_size == 0 || chunk_size > 64 * 1024 * 1024 {
+        anyhow::bail!("Invalid chunk size in encrypted file header: {}", chunk_size);
+    }
+    
+    let mut output = std::io::BufWriter::new(File::create(output_path)?);
+    let mut hasher = Sha256::new();
+    let mut current = vec![0u8; chunk_size + ENCRYPTION_TAG_LEN];
+    let mut next = vec![0u8; chunk_size + ENCRYPTION_TAG_LEN];
+    let mut current_len = read_full(&mut input, &mut current)?;
+    let mut plaintext_size = 0u64;
+    let mut counter = 0u32;
+    
+    loop {
+        let next_len = read_full(&mut input, &mut next)?;
+        let last = next_len == 0;
+        
+        let plaintext = cipher
+            .decrypt(
+                &chunk_nonce(&nonce_prefix, counter, last),
+                aes_gcm::aead::Payload { msg: &current[..current_len], aad: &header },
+            )
+            .map_err(|_| anyhow::anyhow!("Chunk {} failed authentication (wrong key, tampered or truncated)", counter))?;
+        
+        output.write_all(&plaintext)?;
+        hasher.update(&plaintext);
+        plaintext_size += plaintext.len() as u64;
+        
+        if last {
+            break;
+        }
+        counter = counter.checked_add(1).context("Too many chunks")?;
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+    }
+    output.flush()?;
+    
+    Ok((plaintext_size, hex::encode(hasher.finalize())))
+}
+
+/// exiftool tags of a file keyed by `Group:Tag`, minus the file-system pseudo tags
+fn exiftool_tags(path: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let output = Command::new("exiftool")
+        .args(&["-json", "-G1", "-a", "-s", path])
+        .output()
+        .context("Failed to execute exiftool")?;
+    
+    if !output.status.success() {
+        anyhow::bail!("Exiftool failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    
+    let mut parsed: Vec<serde_json::Map<String, serde_json::Value>> = serde_json::from_slice(&output.stdout)?;
+    let mut tags = parsed.pop().unwrap_or_default();
+    tags.retain(|key, _| {
+        !["SourceFile", "ExifTool:", "System:", "File:", "Composite:"]
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+    });
+    Ok(tags)
+}
+
+fn strip_image_metadata(input_path: &str, output_path: &str, keep: &[String]) -> Result<serde_json::Value> {
+    let before = exiftool_tags(input_path)?;
+    
+    // exiftool refuses to overwrite with -o
+    if std::path::Path::new(output_path).exists() {
+        fs::remove_file(output_path)?;
+    }
+    
+    let mut command = Command::new("exiftool");
+    command.arg("-all=");
+    if !keep.is_empty() {
+        command.args(&["-tagsFromFile", "@"]);
+        command.args(keep.iter().map(|tag| format!("-{}", tag)));
+    }
+    let output = command
+        .args(&["-o", output_path, input_path])
+        .output()
+        .context("Failed to execute exiftool")?;
+    
+    if !output.status.success() {
+        anyhow::bail!("Exiftool failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    
+    let after = exiftool_tags(output_path)?;
+    let removed: serde_json::Map<String, serde_json::Value> = before
+        .into_iter()
+        .filter(|(key, _)| !after.contains_key(key))
+        .collect();
+    let gps_removed = removed.keys().any(|key| key.contains("GPS"));
+    
+    Ok(serde_json::json!({
+        "input": input_path,
+        "output": output_path,
+        "kind": "image",
+        "removed_count": removed.len(),
+        "gps_removed": gps_removed,
+        "removed": removed,
+        "kept": after,
+    }))
+}
+
+fn metadata_json(metadata: &ffmpeg::DictionaryRef) -> serde_json::Map<String, serde_json::Value> {
+    metadata
+        .iter()
+        .map(|(key, value)| (key.to_string(), serde_json::Value::String(value.to_string())))
+        .collect()
+}
+
+/// Tags to keep, and the removed ones as JSON
+fn split_metadata(
+    metadata: &ffmpeg::DictionaryRef,
+    keep_tags: &[String],
+) -> (ffmpeg::Dictionary<'static>, serde_json::Map<String, serde_json::Value>) {
+    let mut kept = ffmpeg::Dictionary::new();
+    let mut removed = serde_json::Map::new();
+    for (key, value) in metadata.iter() {
+        if keep_tags.contains(&key.to_lowercase()) {
+            kept.set(key, value);
+        } else {
+            removed.insert(key.to_string(), serde_json::Value::String(value.to_string()));
+        }
+    }
+    (kept, removed)
+}
+
+/// Copy audio, video and subtitle streams, dropping tags not in `keep_tags` and all data streams
+fn strip_container_metadata(
+    input_path: &str,
+    output_path: &str,
+    keep_tags: &[String],
+    keep_chapters: bool,
+) -> Result<serde_json::Value> {
+    let mut ictx = ffmpeg::format::input(&input_path)
+        .context("Failed to open input file")?;
+    let mut octx = ffmpeg::format::output(&output_path)
+        .context("Failed to create output file")?;
+    
+    let mut stream_mapping = vec![-1i32; ictx.nb_streams() as usize];
+    let mut input_time_bases = vec![ffmpeg::Rational(0, 1); ictx.nb_streams() as usize];
+    let mut output_index = 0;
+    let mut removed_streams = Vec::new();
+    let mut dropped_streams = Vec::new();
+    
+    for (input_index, ist) in ictx.streams().enumerate() {
+        let medium = ist.parameters().medium();
+        // Cover art is a copied image file, EXIF and GPS included
+        let attached_picture = ist.disposition().contains(ffmpeg::format::stream::Disposition::ATTACHED_PIC);
+        if attached_picture
+            || (medium != ffmpeg::media::Type::Audio
+                && medium != ffmpeg::media::Type::Video
+                && medium != ffmpeg::media::Type::Subtitle)
+        {
+            dropped_streams.push(serde_json::json!({
+                "index": input_index,
+                "type": if attached_picture { "attached_picture".to_string() } else { format!("{:?}", medium).to_lowercase() },
+                "codec": format!("{:?}", ist.parameters().id()).to_lowercase(),
+                "tags": metadata_json(&ist.metadata()),
+            }));
+            continue;
+        }
+        
+        stream_mapping[input_index] = output_index;
+        input_time_bases[input_index] = ist.time_base();
+        output_index += 1;
+        
+        let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        
+        let (kept, removed) = split_metadata(&ist.metadata(), keep_tags);
+        if !removed.is_empty() {
+            removed_streams.push(serde_json::json!({ "index": input_index, "tags": removed }));
+        }
+        ost.set_metadata(kept);
+    }
+    
+    let (kept, removed_format) = split_metadata(&ictx.metadata(), keep_tags);
+    octx.set_metadata(kept);
+    
+    let mut removed_chapter_tags = 0;
+    if keep_chapters {
+        for chapter in ictx.chapters() {
+            removed_chapter_tags += chapter.metadata().iter().filter(|(key, _)| *key != "title").count();
+            octx.add_chapter(
+                chapter.id(),
+                chapter.time_base(),
+                chapter.start(),
+                chapter.end(),
+                chapter.metadata().get("title").unwrap_or_default(),
+            )?;
+        }
+    }
+    let dropped_chapters = if keep_chapters { 0 } else { ictx.chapters().count() };
+    
+    // Keep the muxer from stamping its own encoder tag
+    unsafe {
+        (*octx.as_mut_ptr()).flags |= ffmpeg::ffi::AVFMT_FLAG_BITEXACT as i32;
+    }
+    
+    octx.write_header()?;
+    
+    for (stream, mut packet) in ictx.packets() {
+        let input_index = stream.index();
+        let output_index = stream_mapping[input_index];
+        if output_index < 0 {
+            continue;
+        }
+        
+        let output_time_base = octx
+            .stream(output_index as usize)
+            .context("Output stream missing")?
+            .time_base();
+        packet.rescale_ts(input_time_bases[input_index], output_time_base);
+        packet.set_position(-1);
+        packet.set_stream(output_index as usize);
+        packet.write_interleaved(&mut octx)?;
+    }
+    
+    octx.write_trailer()?;
+    
+    Ok(serde_json::json!({
+        "input": input_path,
+        "output": output_path,
+        "kind": "media",
+        "removed": {
+            "format": removed_format,
+            "streams": removed_streams,
+            "dropped_streams": dropped_streams,
+            "chapter_tags": removed_chapter_tags,
+            "chapters": dropped_chapters,
+        },
+        "kept_tags": keep_tags,
+    }))
+}
+
+/// Move a file to `{trash}/{timestamp}-{name}` with a `.trash.json` record next to it
+fn move_to_trash(path: &str, file_name: &str, trash: &str, ttl_days: u64) -> Result<(String, String)> {
+    let now = chrono::Utc::now();
+    let expires_at = (now + chrono::Duration::days(ttl_days as i64)).to_rfc3339();
+    let trash_path = std::path::Path::new(trash)
+        .join(format!("{}-{}", now.format("%Y%m%dT%H%M%S%.3fZ"), file_name))
+        .to_string_lossy()
+        .to_string();
+    
+    move_file(path, &trash_path)?;
+    
+    let record = serde_json::json!({
+        "original_path": path,
+        "trash_path": trash_path,
+        "deleted_at": now.to_rfc3339(),
+        "expires_at": expires_at,
+    });
+    fs::write(format!("{}.trash.json", trash_path), serde_json::to_string_pretty(&record)?)?;
+    
+    Ok((trash_path, expires_at))
+}
+
+/// Delete trash entries whose record has expired, returning how many were removed
+fn sweep_trash(trash: &str) -> Result<usize> {
+    let entries = match fs::read_dir(trash) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    
+    let now = chrono::Utc::now();
+    let mut swept = 0;
+    
+    for entry in entries.flatten() {
+        let record_path = entry.path();
+        let Some(trash_path) = record_path.to_str().and_then(|p| p.strip_suffix(".trash.json")) else {
+            continue;
+        };
+        
+        let expired = fs::read_to_string(&record_path)
+            .ok()
+            .and_then(|record| serde_json::from_str::<serde_json::Value>(&record).ok())
+            .and_then(|record| record["expires_at"].as_str().map(String::from))
+            .and_then(|expires_at| chrono::DateTime::parse_from_rfc3339(&expires_at).ok())
+            .map(|expires_at| expires_at < now)
+            .unwrap_or(false);
+        
+        if expired {
+            if let Err(e) = fs::remove_file(trash_path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!(path = %trash_path, error = %e, "Failed to remove expired trash entry");
+                    continue;
+                }
+            }
+            fs::remove_file(&record_path)?;
+            swept += 1;
+        }
+    }
+    
+    Ok(swept)
+}
+
+/// Overwrite a file in place with random passes and a final zero pass, then unlink it
+fn shred_file(path: &str, passes: u32) -> Result<()> {
+    let size = fs::metadata(path)?.len();
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .context(format!("Failed to open {} for overwriting", path))?;
+    let mut buffer = vec![0u8; 1024 * 1024];
+    
+    for pass in 0..=passes {
+        let random = pass < passes;
+        file.seek(SeekFrom::Start(0))?;
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = remaining.min(buffer.len() as u64) as usize;
+            if random {
+                rand::rngs::OsRng.fill_bytes(&mut buffer[..len]);
+            } else {
+                buffer[..len].fill(0);
+            }
+            file.write_all(&buffer[..len])?;
+            remaining -= len as u64;
+        }
+        // Each pass has to reach the disk, not just the page cache
+        file.sync_all()?;
+    }
+    
+    drop(file);
+    fs::remove_file(path).context("Failed to delete file")?;
+    Ok(())
+}
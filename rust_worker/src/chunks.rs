@@ -0,0 +1,362 @@
+use anyhow::{Context, Result};
+use ffmpeg_next as ffmpeg;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    queue, telemetry,
+    video::{self, EncodedSegment},
+    JobPayload,
+};
+
+/// Params of the coordinating job passed on to every chunk encode
+const ENCODE_PARAMS: &[&str] = &[
+    "bitrate",
+    "codec",
+    "quality_mode",
+    "quality",
+    "max_bitrate",
+    "preset",
+    "cpu_used",
+    "row_mt",
+    "tile_columns",
+    "tile_rows",
+    "gop",
+    "closed_gop",
+    "scene_cut",
+    "force_keyframes",
+    "keyframe_seconds",
+    "video_stream",
+    "pixel_format",
+    "color_primaries",
+    "color_transfer",
+    "color_space",
+    "color_range",
+    "hdr_metadata",
+];
+
+/// Plan of a distributed encode, written next to the output as `<output>.chunks.json`
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkManifest {
+    group: String,
+    input_path: String,
+    output_path: String,
+    /// Time base of the input's video stream, as (numerator, denominator)
+    time_base: (i32, i32),
+    /// Timestamp just past the input's last video packet
+    end_pts: i64,
+    /// Input audio streams copied into the output
+    #[serde(default)]
+    audio_streams: Vec<usize>,
+    chunks: Vec<ChunkPlan>,
+    /// Params for `assemble_chunks`
+    #[serde(default)]
+    assemble_params: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkPlan {
+    index: usize,
+    start_pts: i64,
+    end_pts: Option<i64>,
+    output_path: String,
+    job_id: String,
+}
+
+/// Split the input at keyframes and enqueue an `encode_chunk` job per chunk
+///
+/// Takes the rate control, keyframe and stream selection params of `transcode_video_native`
+/// (audio is copied or left out), `chunks` (default: 8) and, for the final `assemble_chunks`
+/// job, `max_av_drift` and `keep_chunks`. Chunks go to `<output>.chunks/`; the chunk encode
+/// that finishes last enqueues `assemble_chunks`, which writes the output. Every worker must
+/// see the same paths, so this needs local storage on a shared filesystem. Returns the path
+/// of the chunk manifest.
+pub async fn transcode_distributed(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Planning distributed transcode");
+    
+    if config.storage.storage_type != "local" {
+        anyhow::bail!("transcode_distributed needs workers sharing a filesystem (storage.type = \"local\")");
+    }
+    
+    let chunk_count = job.params.get("chunks")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(8)
+        .max(1) as usize;
+    
+    let video_stream = video::video_stream_param(&job.params);
+    let time_base = {
+        let ictx = ffmpeg::format::input(&job.input_path)
+            .context("Failed to open input file")?;
+        let index = video::select_video_stream(&ictx, video_stream)?;
+        ictx.stream(index).context("No video stream found")?.time_base()
+    };
+    let audio_streams = video::StreamPassthrough::from_params(&job.params)?.segment_audio(&job.input_path)?;
+    let (keyframes, end_pts) = video::keyframe_timestamps(&job.input_path, video_stream)?;
+    let ranges = video::split_points(&keyframes, end_pts, chunk_count);
+    
+    let chunk_dir = format!("{}.chunks", job.output_path);
+    std::fs::create_dir_all(&chunk_dir)?;
+    let manifest_path = format!("{}.chunks.json", job.output_path);
+    
+    let mut encode_params = serde_json::Map::new();
+    for name in ENCODE_PARAMS {
+        if let Some(value) = job.params.get(*name) {
+            encode_params.insert(name.to_string(), value.clone());
+        }
+    }
+    let mut assemble_params = serde_json::Map::new();
+    for name in ["max_av_drift", "keep_chunks", "faststart", "fragmented", "fragment_duration", "cmaf", "split_init"] {
+        if let Some(value) = job.params.get(name) {
+            assemble_params.insert(name.to_string(), value.clone());
+        }
+    }
+    
+    let group = queue::new_job_id();
+    let manifest = ChunkManifest {
+        group: group.clone(),
+        input_path: job.input_path.clone(),
+        output_path: job.output_path.clone(),
+        time_base: (time_base.numerator(), time_base.denominator()),
+        end_pts,
+        audio_streams,
+        chunks: ranges
+            .iter()
+            .enumerate()
+            .map(|(index, &(start_pts, end_pts))| ChunkPlan {
+                index,
+                start_pts,
+                end_pts,
+                output_path: Path::new(&chunk_dir)
+                    .join(format!("chunk_{:05}.mkv", index))
+                    .to_string_lossy()
+                    .to_string(),
+                job_id: format!("{}-chunk-{}", group, index),
+            })
+            .collect(),
+        assemble_params: serde_json::Value::Object(assemble_params),
+    };
+    // The manifest must exist before any chunk can finish and look for it
+    std::fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+    
+    for chunk in &manifest.chunks {
+        let mut params = encode_params.clone();
+        params.insert("manifest".to_string(), serde_json::json!(manifest_path));
+        params.insert("index".to_string(), serde_json::json!(chunk.index));
+        params.insert("start_pts".to_string(), serde_json::json!(chunk.start_pts));
+        params.insert("end_pts".to_string(), serde_json::json!(chunk.end_pts));
+        
+        queue::enqueue(config, JobPayload {
+            job_id: Some(chunk.job_id.clone()),
+            traceparent: telemetry::traceparent(),
+            task: "encode_chunk".to_string(),
+            input_path: job.input_path.clone(),
+            output_path: chunk.output_path.clone(),
+            params: serde_json::Value::Object(params),
+        })
+        .await
+        .context(format!("Failed to enqueue chunk {}", chunk.index))?;
+    }
+    
+    info!(group = %group, chunks = manifest.chunks.len(), "Enqueued chunk encodes");
+    Ok(manifest_path)
+}
+
+/// Encode one chunk of a `transcode_distributed` job
+///
+/// `start_pts` and `end_pts` bound the chunk in the input's video time base. The result is
+/// recorded under the group in Redis, and the encode that completes the group enqueues
+/// `assemble_chunks`.
+pub async fn encode_chunk(job: &JobPayload, config: &Config) -> Result<String> {
+    let manifest_path = job.params.get("manifest")
+        .and_then(|v| v.as_str())
+        .context("manifest parameter required")?;
+    
+    let index = job.params.get("index")
+        .and_then(|v| v.as_u64())
+        .context("index parameter required")? as usize;
+    
+    let start_pts = job.params.get("start_pts")
+        .and_then(|v| v.as_i64())
+        .context("start_pts parameter required")?;
+    
+    let end_pts = job.params.get("end_pts")
+        .and_then(|v| v.as_i64());
+    
+    let codec_name = job.params.get("codec")
+        .and_then(|v| v.as_str())
+        .unwrap_or("libx265");
+    
+    let quality_mode = job.params.get("quality_mode")
+        .and_then(|v| v.as_str())
+        .unwrap_or("bitrate");
+    
+    let bitrate = job.params.get("bitrate")
+        .and_then(|v| v.as_str())
+        .unwrap_or("1M");
+    
+    info!(index, "Encoding chunk");
+    
+    let bit_rate = (quality_mode == "bitrate").then(|| video::parse_bitrate(bitrate)).transpose()?;
+    let options = video::transcode_options(job, codec_name, quality_mode)?;
+    let keyframes = video::ForcedKeyframes::from_params(&job.params)?;
+    let picture = video::PictureFormat::from_params(&job.params)?;
+    let segment = video::encode_segment_range(
+        &job.input_path,
+        video::video_stream_param(&job.params),
+        &job.output_path,
+        start_pts,
+        end_pts,
+        codec_name,
+        bit_rate,
+        &options,
+        &keyframes,
+        &picture,
+        || {},
+    )?;
+    
+    let manifest = read_manifest(manifest_path)?;
+    let mut connection = redis_connection(config).await?;
+    let key = group_key(config, &manifest.group);
+    let (done,): (usize,) = redis::pipe()
+        .atomic()
+        .hset(&key, index, serde_json::to_string(&segment)?)
+        .ignore()
+        .expire(&key, config.redis.result_ttl_seconds as i64)
+        .ignore()
+        .hlen(&key)
+        .query_async(&mut connection)
+        .await
+        .context("Failed to record chunk")?;
+    info!(index, done, total = manifest.chunks.len(), "Chunk encoded");
+    
+    if done == manifest.chunks.len() {
+        // A retried chunk can complete the group twice; only the first enqueues the assembly
+        let first: bool = connection
+            .set_nx(format!("{}:assembling", key), chrono::Utc::now().to_rfc3339())
+            .await?;
+        if first {
+            connection.expire::<_, ()>(format!("{}:assembling", key), config.redis.result_ttl_seconds as i64).await?;
+            let assemble_id = queue::enqueue(config, JobPayload {
+                job_id: Some(format!("{}-assemble", manifest.group)),
+                traceparent: telemetry::traceparent(),
+                task: "assemble_chunks".to_string(),
+                input_path: manifest_path.to_string(),
+                output_path: manifest.output_path.clone(),
+                params: manifest.assemble_params.clone(),
+            })
+            .await?;
+            info!(job_id = %assemble_id, "All chunks encoded; enqueued assembly");
+        }
+    }
+    
+    Ok(job.output_path.clone())
+}
+
+/// Join the chunks of a `transcode_distributed` job, with the input's audio, into the output
+///
+/// The input is the chunk manifest. Chunks must cover the input without gaps, and the joined
+/// video must end within `max_av_drift` seconds (default: 0.1) of the source's video, so
+/// that the copied audio stays in sync. Chunk files are removed afterwards unless
+/// `keep_chunks` is set.
+pub async fn assemble_chunks(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Assembling chunks");
+    
+    let max_av_drift = job.params.get("max_av_drift")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.1);
+    
+    let keep_chunks = job.params.get("keep_chunks")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let manifest = read_manifest(&job.input_path)?;
+    let mut connection = redis_connection(config).await?;
+    let key = group_key(config, &manifest.group);
+    let recorded: std::collections::HashMap<usize, String> = connection
+        .hgetall(&key)
+        .await
+        .context("Failed to read chunk results")?;
+    
+    let mut segments: Vec<EncodedSegment> = Vec::with_capacity(manifest.chunks.len());
+    for (position, chunk) in manifest.chunks.iter().enumerate() {
+        let segment: EncodedSegment = serde_json::from_str(
+            recorded
+                .get(&chunk.index)
+                .with_context(|| format!("Chunk {} has not been encoded", chunk.index))?,
+        )?;
+        if segment.frames == 0 {
+            anyhow::bail!("Chunk {} contains no frames", chunk.index);
+        }
+        if let Some(previous) = position.checked_sub(1).map(|i| &manifest.chunks[i]) {
+            if previous.end_pts != Some(chunk.start_pts) {
+                anyhow::bail!("Chunks {} and {} are not contiguous", previous.index, chunk.index);
+            }
+        }
+        if chunk.end_pts.map_or(false, |end| segment.end_pts != end) {
+            anyhow::bail!("Chunk {} ends at {} instead of {:?}", chunk.index, segment.end_pts, chunk.end_pts);
+        }
+        segments.push(segment);
+    }
+    
+    let times = video::concat_segments(
+        &segments,
+        &job.output_path,
+        Some((&manifest.input_path, &manifest.audio_streams)),
+        &job.params,
+    )?;
+    
+    let time_base = ffmpeg::Rational(manifest.time_base.0, manifest.time_base.1);
+    let source_video_end = manifest.end_pts as f64 * f64::from(time_base);
+    let drift = times.video_end - source_video_end;
+    info!(
+        video_end = times.video_end,
+        source_video_end,
+        audio_end = times.audio_end,
+        frames = segments.iter().map(|segment| segment.frames).sum::<usize>(),
+        "Chunks assembled"
+    );
+    if drift.abs() > max_av_drift {
+        anyhow::bail!(
+            "Assembled video ends {:.3}s {} the source, beyond max_av_drift {}s; audio would be out of sync",
+            drift.abs(),
+            if drift > 0.0 { "after" } else { "before" },
+            max_av_drift
+        );
+    }
+    
+    if !keep_chunks {
+        for chunk in &manifest.chunks {
+            let _ = std::fs::remove_file(&chunk.output_path);
+        }
+        if let Some(dir) = manifest.chunks.first().and_then(|chunk| Path::new(&chunk.output_path).parent()) {
+            let _ = std::fs::remove_dir(dir);
+        }
+        let _ = std::fs::remove_file(&job.input_path);
+        if let Err(e) = connection.del::<_, ()>([key.clone(), format!("{}:assembling", key)]).await {
+            warn!(error = %e, "Failed to remove chunk results");
+        }
+    }
+    
+    Ok(job.output_path.clone())
+}
+
+fn read_manifest(path: &str) -> Result<ChunkManifest> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("Failed to read chunk manifest {}", path))?;
+    serde_json::from_str(&contents).context(format!("Invalid chunk manifest {}", path))
+}
+
+fn group_key(config: &Config, group: &str) -> String {
+    format!("{}chunks:{}", config.redis.result_key_prefix, group)
+}
+
+async fn redis_connection(config: &Config) -> Result<redis::aio::MultiplexedConnection> {
+    redis::Client::open(config.redis.url.as_str())
+        .context("Invalid redis.url")?
+        .get_multiplexed_async_connection()
+        .await
+        .context("Failed to connect to Redis")
+}
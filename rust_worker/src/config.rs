@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -8,12 +9,95 @@ pub struct Config {
     pub storage: StorageConfig,
     pub processing: ProcessingConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub transcription: TranscriptionConfig,
+    #[serde(default)]
+    pub separation: SeparationConfig,
+    #[serde(default)]
+    pub download: DownloadConfig,
+    #[serde(default)]
+    pub antivirus: AntivirusConfig,
+    #[serde(default)]
+    pub quarantine: QuarantineConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub signatures: SignaturesConfig,
+    #[serde(default)]
+    pub trash: TrashConfig,
+    #[serde(default)]
+    pub drm: DrmConfig,
+    #[serde(default)]
+    pub watch: WatchConfig,
+    #[serde(default)]
+    pub scratch: ScratchConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct RedisConfig {
     pub url: String,
     pub queue_name: String,
+    /// Publish state changes of jobs that carry a `job_id`
+    #[serde(default = "default_publish_events")]
+    pub publish_events: bool,
+    #[serde(default = "default_events_channel")]
+    pub events_channel: String,
+    /// Final results are pushed to `<prefix><job_id>`
+    #[serde(default = "default_result_key_prefix")]
+    pub result_key_prefix: String,
+    #[serde(default = "default_result_ttl_seconds")]
+    pub result_ttl_seconds: u64,
+    /// Attempts a job gets in `consume` mode before it is dead-lettered
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_dead_letter_queue")]
+    pub dead_letter_queue: String,
+    /// A failed job waits this long before it is requeued, doubling with each attempt
+    #[serde(default = "default_retry_backoff_seconds")]
+    pub retry_backoff_seconds: u64,
+    /// Jobs being run are kept in `<prefix><worker_id>` until they finish
+    #[serde(default = "default_processing_key_prefix")]
+    pub processing_key_prefix: String,
+    /// Names this consumer's processing list; defaults to the hostname, so consumers sharing
+    /// a host need their own
+    #[serde(default)]
+    pub worker_id: Option<String>,
+}
+
+fn default_publish_events() -> bool {
+    true
+}
+
+fn default_events_channel() -> String {
+    "media_processing:events".to_string()
+}
+
+fn default_result_key_prefix() -> String {
+    "media_processing:result:".to_string()
+}
+
+fn default_result_ttl_seconds() -> u64 {
+    86400
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_dead_letter_queue() -> String {
+    "media_processing:dead".to_string()
+}
+
+fn default_retry_backoff_seconds() -> u64 {
+    10
+}
+
+fn default_processing_key_prefix() -> String {
+    "media_processing:processing:".to_string()
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -24,18 +108,75 @@ pub struct StorageConfig {
     pub output_path: String,
     #[serde(default)]
     pub s3: S3Config,
+    #[serde(default)]
+    pub gcs: GcsConfig,
+    #[serde(default)]
+    pub azure: AzureConfig,
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct S3Config {
     pub bucket: String,
     pub region: String,
+    /// Endpoint of an S3-compatible service (MinIO, R2, ...); empty for AWS
+    #[serde(default)]
+    pub endpoint: String,
+}
+
+/// Google Cloud Storage through the JSON API
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct GcsConfig {
+    pub bucket: String,
+    pub endpoint: String,
+    /// Environment variable holding an OAuth access token; without it tokens come from the
+    /// GCE/GKE metadata server
+    pub token_env: String,
+}
+
+impl Default for GcsConfig {
+    fn default() -> Self {
+        Self {
+            bucket: String::new(),
+            endpoint: "https://storage.googleapis.com".to_string(),
+            token_env: "GOOGLE_OAUTH_ACCESS_TOKEN".to_string(),
+        }
+    }
+}
+
+/// Azure Blob Storage, authorised with a SAS token
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AzureConfig {
+    pub account: String,
+    pub container: String,
+    /// Defaults to `https://<account>.blob.core.windows.net`
+    pub endpoint: String,
+    pub sas_token_env: String,
+}
+
+impl Default for AzureConfig {
+    fn default() -> Self {
+        Self {
+            account: String::new(),
+            container: String::new(),
+            endpoint: String::new(),
+            sas_token_env: "AZURE_STORAGE_SAS_TOKEN".to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ProcessingConfig {
     pub max_workers: usize,
     pub timeout_seconds: u64,
+    /// How long a job may keep running after SIGTERM/SIGINT before the worker exits anyway
+    #[serde(default = "default_shutdown_grace_seconds")]
+    pub shutdown_grace_seconds: u64,
+}
+
+fn default_shutdown_grace_seconds() -> u64 {
+    20
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -44,6 +185,341 @@ pub struct LoggingConfig {
     pub format: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TranscriptionConfig {
+    pub whisper_binary: String,
+    pub model_path: String,
+}
+
+impl Default for TranscriptionConfig {
+    fn default() -> Self {
+        Self {
+            whisper_binary: "whisper-cli".to_string(),
+            model_path: "./models/ggml-base.bin".to_string(),
+        }
+    }
+}
+
+/// External stem separation backend; `{input}`, `{input_stem}` and `{output_dir}` are
+/// substituted in `args` and `output_pattern`, plus `{stem}` in `output_pattern`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SeparationConfig {
+    pub command: String,
+    pub args: Vec<String>,
+    pub output_pattern: String,
+    pub stems: Vec<String>,
+}
+
+impl Default for SeparationConfig {
+    fn default() -> Self {
+        Self {
+            command: "demucs".to_string(),
+            args: ["-n", "htdemucs", "-o", "{output_dir}", "{input}"]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect(),
+            output_pattern: "{output_dir}/htdemucs/{input_stem}/{stem}.wav".to_string(),
+            stems: ["vocals", "drums", "bass", "other"]
+                .iter()
+                .map(|stem| stem.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Defaults for `download_file`; jobs can override most of these through params
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DownloadConfig {
+    pub connect_timeout_seconds: u64,
+    pub read_timeout_seconds: u64,
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+    pub max_redirects: usize,
+    pub user_agent: String,
+    /// 0 = unlimited
+    pub max_bandwidth_bytes_per_sec: u64,
+    /// Parallel byte-range connections; 1 disables segmented downloads
+    pub segments: usize,
+    pub min_segment_bytes: u64,
+    pub allowed_schemes: Vec<String>,
+    /// Empty allows any host not denied; entries also match subdomains
+    pub allowed_hosts: Vec<String>,
+    pub denied_hosts: Vec<String>,
+    /// Refuse loopback, private, link-local and other reserved addresses (SSRF protection)
+    pub block_private_networks: bool,
+    /// 0 = unlimited
+    pub max_download_bytes: u64,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_seconds: 30,
+            read_timeout_seconds: 60,
+            max_retries: 3,
+            retry_backoff_ms: 1000,
+            max_redirects: 10,
+            user_agent: concat!("rust-media-pipeline/", env!("CARGO_PKG_VERSION")).to_string(),
+            max_bandwidth_bytes_per_sec: 0,
+            segments: 1,
+            min_segment_bytes: 8 * 1024 * 1024,
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            block_private_networks: true,
+            max_download_bytes: 0,
+        }
+    }
+}
+
+/// clamd endpoint used by `scan_for_malware`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AntivirusConfig {
+    /// `tcp://host:port` or `unix:///path/to/clamd.sock`
+    pub address: String,
+    pub timeout_seconds: u64,
+    /// Must stay below clamd's StreamMaxLength per chunk
+    pub chunk_size: usize,
+}
+
+impl Default for AntivirusConfig {
+    fn default() -> Self {
+        Self {
+            address: "tcp://127.0.0.1:3310".to_string(),
+            timeout_seconds: 300,
+            chunk_size: 64 * 1024,
+        }
+    }
+}
+
+/// Where failed validation/scan inputs are moved, one directory per incident
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct QuarantineConfig {
+    pub path: String,
+    /// Quarantine automatically when validate_checksum, validate_media_type or
+    /// scan_for_malware fails; jobs can override with the `quarantine` param
+    pub on_failure: bool,
+    /// Default age for purge_quarantine
+    pub retention_days: u64,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self {
+            path: "./data/quarantine".to_string(),
+            on_failure: false,
+            retention_days: 30,
+        }
+    }
+}
+
+/// Upper bounds for `extract_archive`; job params may only lower them
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub max_extracted_bytes: u64,
+    pub max_entries: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            max_extracted_bytes: 10 * 1024 * 1024 * 1024,
+            max_entries: 10_000,
+        }
+    }
+}
+
+/// Named keys for `encrypt_file`/`decrypt_file`, each a reference rather than the key itself:
+/// `env:VAR` (hex in an environment variable) or `file:/path` (raw or hex key file)
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub default_key: String,
+    pub keys: HashMap<String, String>,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            default_key: "default".to_string(),
+            keys: HashMap::from([("default".to_string(), "env:MEDIA_PIPELINE_KEY".to_string())]),
+        }
+    }
+}
+
+/// Trusted public keys for `verify_signature`, by key id
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SignaturesConfig {
+    /// Base64 minisign public keys (the second line of a `.pub` file)
+    pub minisign_keys: HashMap<String, String>,
+    /// Hex-encoded 32-byte ed25519 public keys
+    pub ed25519_keys: HashMap<String, String>,
+    pub gpg_keyring: String,
+    pub gpgv_binary: String,
+    /// Write a signed manifest of every job's output; jobs can override with `sign_manifest`
+    pub sign_manifests: bool,
+    /// Recorded in signed manifests; consumers list its public key under `ed25519_keys`
+    pub manifest_key_id: String,
+    /// ed25519 secret key (32-byte seed): `env:VAR` (hex) or `file:/path` (raw or hex)
+    pub manifest_signing_key: String,
+}
+
+impl Default for SignaturesConfig {
+    fn default() -> Self {
+        Self {
+            minisign_keys: HashMap::new(),
+            ed25519_keys: HashMap::new(),
+            gpg_keyring: "./config/trusted-keys.gpg".to_string(),
+            gpgv_binary: "gpgv".to_string(),
+            sign_manifests: false,
+            manifest_key_id: "pipeline".to_string(),
+            manifest_signing_key: "env:MEDIA_PIPELINE_SIGNING_KEY".to_string(),
+        }
+    }
+}
+
+/// Soft-delete and shredding settings for `purge_original_file`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TrashConfig {
+    pub path: String,
+    /// How long soft-deleted files are kept before being swept
+    pub ttl_days: u64,
+    /// Random overwrite passes in `shred` mode (a zero pass always follows)
+    pub shred_passes: u32,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            path: "./data/trash".to_string(),
+            ttl_days: 7,
+            shred_passes: 3,
+        }
+    }
+}
+
+/// Shaka Packager used by `package_drm`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DrmConfig {
+    pub packager_binary: String,
+    /// The packager is killed if it runs longer than this
+    pub timeout_seconds: u64,
+}
+
+impl Default for DrmConfig {
+    fn default() -> Self {
+        Self {
+            packager_binary: "packager".to_string(),
+            timeout_seconds: 3600,
+        }
+    }
+}
+
+/// Where tasks keep intermediate files while they run
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ScratchConfig {
+    pub path: String,
+    /// New scratch directories are refused, and tasks still writing to one fail, once this
+    /// much is in use; 0 = unlimited
+    pub max_size_mb: u64,
+}
+
+impl Default for ScratchConfig {
+    fn default() -> Self {
+        Self {
+            path: "./data/scratch".to_string(),
+            max_size_mb: 0,
+        }
+    }
+}
+
+/// OpenTelemetry trace export; needs the `otel` feature
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// OTLP/gRPC collector, e.g. `http://localhost:4317`; empty = no export
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: String::new(),
+            service_name: "rust-media-pipeline".to_string(),
+        }
+    }
+}
+
+/// Hot folders for `rust_worker watch`
+#[derive(Debug, Deserialize, Clone)]
+pub struct WatchConfig {
+    #[serde(default)]
+    pub folders: Vec<WatchFolderConfig>,
+    /// Files already processed, so that a restart does not process them again
+    #[serde(default = "default_ledger_path")]
+    pub ledger_path: String,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            folders: Vec::new(),
+            ledger_path: default_ledger_path(),
+        }
+    }
+}
+
+fn default_ledger_path() -> String {
+    "./data/watch_ledger.json".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WatchFolderConfig {
+    pub path: String,
+    #[serde(default)]
+    pub recursive: bool,
+    /// File name suffixes to pick up; empty = every file
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Seconds a file's size and mtime must stay unchanged before it is processed
+    #[serde(default = "default_stable_seconds")]
+    pub stable_seconds: u64,
+    pub output_dir: String,
+    /// Where to move the source once its pipeline succeeds; left in place if unset
+    #[serde(default)]
+    pub move_processed_to: Option<String>,
+    pub pipeline: Vec<WatchStepConfig>,
+}
+
+fn default_stable_seconds() -> u64 {
+    10
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WatchStepConfig {
+    pub task: String,
+    #[serde(default = "default_step_input")]
+    pub input: String,
+    pub output: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+fn default_step_input() -> String {
+    "{input}".to_string()
+}
+
 impl Config {
     pub fn load(path: &str) -> Result<Self> {
         let contents = fs::read_to_string(path)
@@ -0,0 +1,141 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::{config::RedisConfig, JobPayload, JobResult, JobStatus};
+
+static PUBLISHER: OnceLock<Publisher> = OnceLock::new();
+
+/// Job state published to Redis for jobs that carry a `job_id`
+///
+/// Every transition (`started`, `progress`, `finished`, `failed`, `cancelled`) is published as
+/// JSON on `redis.events_channel`, and the final `JobResult` is pushed to the list
+/// `<redis.result_key_prefix><job_id>`, which expires after `redis.result_ttl_seconds`.
+/// Consumers can subscribe to the channel or BLPOP the result list. Redis is written from a
+/// background thread so that a slow or unreachable server never stalls the job.
+struct Publisher {
+    job_id: String,
+    task: String,
+    sender: Mutex<Option<Sender<Message>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+enum Message {
+    Event(String),
+    Result(String),
+}
+
+/// Start publishing for `job`; does nothing without a `job_id` or with publishing disabled
+pub fn init(config: &RedisConfig, job: &JobPayload) {
+    let Some(job_id) = &job.job_id else {
+        return;
+    };
+    if !config.publish_events {
+        return;
+    }
+    
+    let (sender, receiver) = mpsc::channel();
+    let config = config.clone();
+    let result_key = format!("{}{}", config.result_key_prefix, job_id);
+    let thread = std::thread::spawn(move || run(&config, &result_key, receiver));
+    
+    let _ = PUBLISHER.set(Publisher {
+        job_id: job_id.clone(),
+        task: job.task.clone(),
+        sender: Mutex::new(Some(sender)),
+        thread: Mutex::new(Some(thread)),
+    });
+}
+
+pub fn enabled() -> bool {
+    PUBLISHER.get().is_some()
+}
+
+/// Publish `event` with `fields` merged into the common job fields
+pub fn publish(event: &str, fields: serde_json::Value) {
+    let Some(publisher) = PUBLISHER.get() else {
+        return;
+    };
+    
+    let mut message = serde_json::json!({
+        "event": event,
+        "job_id": publisher.job_id,
+        "task": publisher.task,
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+    });
+    if let (Some(message), serde_json::Value::Object(fields)) = (message.as_object_mut(), fields) {
+        for (name, value) in fields {
+            message.entry(name).or_insert(value);
+        }
+    }
+    publisher.send(Message::Event(message.to_string()));
+}
+
+/// Publish the job's final state, store its result and wait for Redis to be written
+pub fn finish(result: &JobResult) {
+    let Some(publisher) = PUBLISHER.get() else {
+        return;
+    };
+    let Ok(value) = serde_json::to_value(result) else {
+        return;
+    };
+    
+    let event = match result.status {
+        JobStatus::Succeeded => "finished",
+        JobStatus::Failed => "failed",
+        JobStatus::Cancelled => "cancelled",
+    };
+    publish(event, serde_json::json!({ "result": value }));
+    publisher.send(Message::Result(value.to_string()));
+    
+    // Closing the channel lets the thread drain it and exit
+    publisher.sender.lock().unwrap().take();
+    if let Some(thread) = publisher.thread.lock().unwrap().take() {
+        let _ = thread.join();
+    }
+}
+
+impl Publisher {
+    fn send(&self, message: Message) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            let _ = sender.send(message);
+        }
+    }
+}
+
+fn run(config: &RedisConfig, result_key: &str, receiver: Receiver<Message>) {
+    let timeout = Duration::from_secs(5);
+    let connection = redis::Client::open(config.url.as_str())
+        .and_then(|client| client.get_connection_with_timeout(timeout))
+        .and_then(|connection| {
+            connection.set_read_timeout(Some(timeout))?;
+            connection.set_write_timeout(Some(timeout))?;
+            Ok(connection)
+        });
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!(error = %e, "Failed to connect to Redis; job events will not be published");
+            return;
+        }
+    };
+    
+    for message in receiver {
+        let outcome = match &message {
+            Message::Event(event) => redis::cmd("PUBLISH")
+                .arg(&config.events_channel)
+                .arg(event)
+                .query::<()>(&mut connection),
+            Message::Result(result) => redis::pipe()
+                .atomic()
+                .cmd("RPUSH").arg(result_key).arg(result).ignore()
+                .cmd("EXPIRE").arg(result_key).arg(config.result_ttl_seconds).ignore()
+                .query::<()>(&mut connection),
+        };
+        if let Err(e) = outcome {
+            warn!(error = %e, "Failed to publish job event");
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How to tail a file that is still being written (`follow: true` in job params)
+#[derive(Debug, Clone)]
+pub struct FollowOptions {
+    /// The file is complete once this path exists (default: `<input>.done`)
+    pub completion_marker: String,
+    /// Treat the file as complete after this long without growth
+    pub inactivity_timeout: Duration,
+    pub poll_interval: Duration,
+}
+
+impl FollowOptions {
+    /// None unless the job asked for `follow`
+    pub fn from_params(params: &serde_json::Value, input_path: &str) -> Option<Self> {
+        let follow = params.get("follow")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        
+        if !follow {
+            return None;
+        }
+        
+        Some(Self {
+            completion_marker: params.get("completion_marker")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| format!("{}.done", input_path)),
+            inactivity_timeout: Duration::from_secs_f64(
+                params.get("inactivity_timeout")
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(30.0),
+            ),
+            poll_interval: Duration::from_millis(
+                params.get("poll_interval_ms")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(500),
+            ),
+        })
+    }
+}
+
+/// A growing file exposed to FFmpeg as a pipe
+///
+/// A background thread copies the file into the pipe as it grows and closes it once the
+/// completion marker appears or the file stops growing, which FFmpeg sees as end of input.
+/// The input is non-seekable, so the container must be streamable (MPEG-TS, fragmented MP4,
+/// Matroska, WAV, FLAC, ...).
+pub struct FollowedInput {
+    url: String,
+    reader: Option<std::io::PipeReader>,
+    tail: Option<JoinHandle<Result<u64>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl FollowedInput {
+    pub fn open(path: &str, options: &FollowOptions) -> Result<Self> {
+        let mut file = File::open(path)
+            .context(format!("Failed to open growing file: {}", path))?;
+        let (reader, mut writer) = std::io::pipe().context("Failed to create pipe")?;
+        let options = options.clone();
+        let path = path.to_string();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        
+        let tail = std::thread::spawn(move || -> Result<u64> {
+            let mut buffer = vec![0u8; 256 * 1024];
+            let mut copied = 0u64;
+            let mut last_growth = Instant::now();
+            
+            loop {
+                let bytes_read = file.read(&mut buffer)?;
+                if bytes_read > 0 {
+                    match writer.write_all(&buffer[..bytes_read]) {
+                        Ok(()) => {}
+                        // The consumer has everything it wants
+                        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(copied),
+                        Err(e) => return Err(e.into()),
+                    }
+                    copied += bytes_read as u64;
+                    last_growth = Instant::now();
+                    continue;
+                }
+                
+                // At the current end: finish if the writer is done, otherwise wait for more
+                if stopped.load(Ordering::Relaxed) {
+                    return Ok(copied);
+                }
+                if Path::new(&options.completion_marker).exists() {
+                    if file.metadata()?.len() > copied {
+                        continue;
+                    }
+                    info!(bytes = copied, "Completion marker found, finishing followed input");
+                    return Ok(copied);
+                }
+                if last_growth.elapsed() >= options.inactivity_timeout {
+                    info!(bytes = copied, path = %path, "Followed file stopped growing");
+                    return Ok(copied);
+                }
+                std::thread::sleep(options.poll_interval);
+            }
+        });
+        
+        Ok(Self {
+            url: format!("pipe:{}", reader.as_raw_fd()),
+            reader: Some(reader),
+            tail: Some(tail),
+            stop,
+        })
+    }
+    
+    /// `pipe:<fd>` URL for `ffmpeg::format::input`, valid while `self` is alive
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+    
+    /// Hand the read end to a child process (as its stdin) instead of FFmpeg in-process
+    pub fn take_reader(&mut self) -> Option<std::io::PipeReader> {
+        self.reader.take()
+    }
+    
+    /// Close the pipe and wait for the tail thread, returning the bytes copied
+    pub fn finish(mut self) -> Result<u64> {
+        self.stop.store(true, Ordering::Relaxed);
+        drop(self.reader.take());
+        match self.tail.take() {
+            Some(tail) => tail
+                .join()
+                .map_err(|_| anyhow::anyhow!("Follow thread panicked"))?,
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for FollowedInput {
+    fn drop(&mut self) {
+        // Closing the read end makes a writing tail thread stop with a broken pipe
+        self.stop.store(true, Ordering::Relaxed);
+        drop(self.reader.take());
+    }
+}
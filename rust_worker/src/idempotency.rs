@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+use tracing::info;
+
+use crate::{
+    acquisition::canonical_json,
+    binary::{hash_file, HashAlgorithm},
+    storage::Storage,
+    JobPayload,
+};
+
+/// Params that change how a job reports or what follows it, not what it writes
+const UNKEYED_PARAMS: &[&str] = &[
+    "progress",
+    "progress_path",
+    "progress_interval",
+    "skip_if_exists",
+    "idempotent",
+    "idempotency_key",
+    "keep_partial_output",
+    "next",
+    "next_mode",
+];
+
+/// Whether a job needs to run
+pub enum Decision {
+    /// Run it, then record the idempotency key if there is one
+    Run(Option<String>),
+    /// Its output is already in place
+    Skip,
+}
+
+/// Decide whether `job` can be skipped because its output already exists; runs before the
+/// input is staged, so `job` still holds storage keys
+///
+/// With `skip_if_exists` an existing output is enough. With `idempotent` (a key derived from
+/// the input's SHA-256, the task and its params) or an explicit `idempotency_key`, the output
+/// must also have a `<output>.idempotency.json` record with the same key, and still hash to
+/// what was recorded, so that a changed input, different params or a truncated output all
+/// cause the job to run again.
+pub async fn check(storage: &dyn Storage, job: &JobPayload, output_key: &str) -> Result<Decision> {
+    let skip_if_exists = job.params.get("skip_if_exists")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let key = match job.params.get("idempotency_key").and_then(|v| v.as_str()) {
+        Some(key) => Some(key.to_string()),
+        None if job.params.get("idempotent").and_then(|v| v.as_bool()).unwrap_or(false) => {
+            Some(derive_key(storage, job).await?)
+        }
+        None => None,
+    };
+    
+    if key.is_none() && !skip_if_exists {
+        return Ok(Decision::Run(None));
+    }
+    if !storage.exists(output_key).await? {
+        return Ok(Decision::Run(key));
+    }
+    let Some(key) = key else {
+        return Ok(Decision::Skip);
+    };
+    
+    let record_key = record_key(output_key);
+    if !storage.exists(&record_key).await? {
+        return Ok(Decision::Run(Some(key)));
+    }
+    let mut record = String::new();
+    storage.stream(&record_key).await?.read_to_string(&mut record).await?;
+    let record: serde_json::Value = serde_json::from_str(&record)
+        .context(format!("Invalid idempotency record {}", record_key))?;
+    
+    if record.get("key").and_then(|v| v.as_str()) != Some(key.as_str()) {
+        info!(output = output_key, "Idempotency key changed; running the job");
+        return Ok(Decision::Run(Some(key)));
+    }
+    if let Some(expected) = record.get("output_sha256").and_then(|v| v.as_str()) {
+        let actual = stream_sha256(storage, output_key).await?;
+        if actual != expected {
+            info!(output = output_key, "Output no longer matches its idempotency record; running the job");
+            return Ok(Decision::Run(Some(key)));
+        }
+    }
+    
+    Ok(Decision::Skip)
+}
+
+/// Write the idempotency record for a finished job's output
+pub async fn record(storage: &dyn Storage, key: &str, output_key: &str, local_output: &str) -> Result<()> {
+    let output = Path::new(local_output);
+    // Directories are recorded without a hash; only their existence is checked
+    let (output_sha256, output_size) = if output.is_file() {
+        (Some(hash_file(local_output, HashAlgorithm::Sha256)?), Some(output.metadata()?.len()))
+    } else {
+        (None, None)
+    };
+    
+    let record = serde_json::json!({
+        "key": key,
+        "output_sha256": output_sha256,
+        "output_size": output_size,
+        "recorded_at": chrono::Utc::now().to_rfc3339(),
+    });
+    let local_record = record_key(local_output);
+    std::fs::write(&local_record, serde_json::to_string_pretty(&record)?)?;
+    storage.put(Path::new(&local_record), &record_key(output_key)).await
+}
+
+fn record_key(output: &str) -> String {
+    format!("{}.idempotency.json", output.trim_end_matches('/'))
+}
+
+/// SHA-256 of the input, the task and the params that affect the output
+async fn derive_key(storage: &dyn Storage, job: &JobPayload) -> Result<String> {
+    let mut params = job.params.clone();
+    if let Some(params) = params.as_object_mut() {
+        for name in UNKEYED_PARAMS {
+            params.remove(*name);
+        }
+    }
+    // Remote inputs are hashed as they stream, without staging them
+    let input_sha256 = if storage.name() == "local" {
+        if Path::new(&job.input_path).is_file() {
+            hash_file(&job.input_path, HashAlgorithm::Sha256)?
+        } else {
+            String::new()
+        }
+    } else if !job.input_path.is_empty() && storage.exists(&job.input_path).await? {
+        stream_sha256(storage, &job.input_path).await?
+    } else {
+        String::new()
+    };
+    
+    // Canonical form, so equal params give the same key whatever order they arrived in
+    let material = serde_json::json!({
+        "task": job.task,
+        "input_sha256": input_sha256,
+        "params": params,
+    });
+    Ok(hex::encode(Sha256::digest(canonical_json(&material).as_bytes())))
+}
+
+async fn stream_sha256(storage: &dyn Storage, key: &str) -> Result<String> {
+    let mut stream = storage.stream(key).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
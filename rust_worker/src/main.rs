@@ -4,18 +4,37 @@ use std::env;
 use std::fs;
 use std::path::PathBuf;
 use tracing::{error, info, warn};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::Instrument;
 
 mod binary;
 mod acquisition;
 mod video;
 mod audio;
+mod chunks;
 mod config;
+mod events;
+mod follow;
+mod idempotency;
+mod metrics;
+mod pipeline;
+mod progress;
+mod queue;
+mod scratch;
+mod shutdown;
+mod storage;
+mod telemetry;
+mod watch;
 
 use config::Config;
 
 #[derive(Debug, Deserialize, Serialize)]
 struct JobPayload {
+    /// Queue job id; state changes are published to Redis when set
+    #[serde(default)]
+    job_id: Option<String>,
+    /// W3C trace context of the enqueuing service; the job's trace continues from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    traceparent: Option<String>,
     task: String,
     input_path: String,
     output_path: String,
@@ -26,11 +45,27 @@ struct JobPayload {
 #[derive(Debug, Serialize)]
 struct JobResult {
     success: bool,
+    status: JobStatus,
     message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     output_path: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    manifest_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     metrics: Option<JobMetrics>,
+    /// The output was already valid, so the task did not run
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    skipped: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobStatus {
+    Succeeded,
+    Failed,
+    /// Stopped by SIGTERM/SIGINT; the partial output has been removed unless the job set
+    /// `keep_partial_output`
+    Cancelled,
 }
 
 #[derive(Debug, Serialize)]
@@ -38,88 +73,321 @@ struct JobMetrics {
     duration_ms: u64,
     input_size_bytes: u64,
     output_size_bytes: u64,
+    /// Input bytes processed per second of job time
+    throughput_bytes_per_sec: f64,
+    /// Highest resident set size of the worker or any tool it ran
+    peak_rss_bytes: u64,
+    /// CPU time of the worker and the tools it ran
+    user_cpu_ms: u64,
+    system_cpu_ms: u64,
+    /// Video frames encoded, for tasks that encode video
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames_processed: Option<u64>,
+    /// Frames encoded per second of job time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    average_fps: Option<f64>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Load configuration
+    let config = Config::load("./config/settings.toml")
+        .context("Failed to load configuration")?;
+    
     // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer().json())
-        .init();
+    telemetry::init(&config.telemetry);
     
     // Initialize FFmpeg
     ffmpeg_video::init_ffmpeg()
         .context("Failed to initialize FFmpeg")?;
     info!("FFmpeg initialized successfully");
 
-    // Load configuration
-    let config = Config::load("./config/settings.toml")
-        .context("Failed to load configuration")?;
-
     info!("Rust worker started");
+    scratch::clean_stale(&config.scratch);
+    
+    let grace = std::time::Duration::from_secs(config.processing.shutdown_grace_seconds);
 
     // Parse command line arguments
     let args: Vec<String> = env::args().collect();
     
     if args.len() < 2 {
-        error!("Usage: rust_worker <job_payload_json>");
+        error!("Usage: rust_worker <job_payload_json> | rust_worker watch | rust_worker consume");
         std::process::exit(1);
     }
+    
+    if args[1] == "watch" {
+        tokio::spawn(async move {
+            shutdown::request(shutdown::signal().await);
+            tokio::time::sleep(grace).await;
+            warn!("Jobs still running after the shutdown grace period; exiting");
+            std::process::exit(143);
+        });
+        let result = watch::run(config).await;
+        telemetry::shutdown();
+        return result;
+    }
+    
+    if args[1] == "consume" {
+        tokio::spawn(async move {
+            shutdown::request(shutdown::signal().await);
+            // Each job process has `grace` of its own; the margin lets its result come back
+            // and the job be requeued before the worker gives up on it
+            tokio::time::sleep(grace + std::time::Duration::from_secs(10)).await;
+            warn!("Jobs still running after the shutdown grace period; exiting");
+            std::process::exit(143);
+        });
+        let result = queue::run(config).await;
+        telemetry::shutdown();
+        return result;
+    }
 
     let job_payload_str = &args[1];
-    let job: JobPayload = serde_json::from_str(job_payload_str)
+    let mut job: JobPayload = serde_json::from_str(job_payload_str)
         .context("Failed to parse job payload")?;
+    
+    events::init(&config.redis, &job);
+    events::publish("started", serde_json::json!({
+        "input_path": job.input_path,
+        "output_path": job.output_path,
+    }));
+    let job_span = telemetry::job_span(&job);
+    
+    // With remote storage the job's paths are object keys; tasks work on local copies
+    let output_key = job.output_path.clone();
+    let storage = storage::from_config(&config.storage).await?;
+    
+    // Checked before staging, so that a skipped job does not download its input
+    let idempotency = idempotency::check(storage.as_ref(), &job, &output_key)
+        .await
+        .unwrap_or_else(|e| {
+            warn!(error = %e, "Idempotency check failed; running the job");
+            idempotency::Decision::Run(None)
+        });
+    let idempotency_key = match idempotency {
+        idempotency::Decision::Run(key) => key,
+        idempotency::Decision::Skip => {
+            info!(output = %output_key, "Output already valid; skipping job");
+            // A requeued chain carries on from the output already stored
+            let result = match trigger_next(&job, &output_key, &config).await {
+                Ok(()) => JobResult {
+                    success: true,
+                    status: JobStatus::Succeeded,
+                    message: format!("Job '{}' skipped: output already exists", job.task),
+                    output_path: Some(output_key),
+                    manifest_path: None,
+                    metrics: None,
+                    skipped: true,
+                },
+                Err(e) => {
+                    error!(error = %e, "Failed to start next steps");
+                    JobResult {
+                        success: false,
+                        status: JobStatus::Failed,
+                        message: format!("Job failed: {:#}", e),
+                        output_path: None,
+                        manifest_path: None,
+                        metrics: None,
+                        skipped: false,
+                    }
+                }
+            };
+            println!("{}", serde_json::to_string(&result)?);
+            events::finish(&result);
+            telemetry::finish(job_span, &result);
+            if !result.success {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+    };
+    
+    let staging = storage::Staging::prepare(storage.as_ref(), &mut job, &config.storage)
+        .instrument(tracing::info_span!(parent: &job_span, "download"))
+        .await;
+    let staging = match staging {
+        Ok(staging) => staging,
+        Err(e) => {
+            error!(error = %e, "Failed to stage job input");
+            let result = JobResult {
+                success: false,
+                status: JobStatus::Failed,
+                message: format!("Job failed: {}", e),
+                output_path: None,
+                manifest_path: None,
+                metrics: None,
+                skipped: false,
+            };
+            println!("{}", serde_json::to_string(&result)?);
+            events::finish(&result);
+            telemetry::finish(job_span, &result);
+            std::process::exit(1);
+        }
+    };
+    
+    // Jobs poll for cancellation between units of work; one stuck in a single long call is
+    // abandoned once the grace period runs out
+    let output_path = job.output_path.clone();
+    let keep_partial_output = keep_partial_output(&job);
+    tokio::spawn(async move {
+        shutdown::request(shutdown::signal().await);
+        tokio::time::sleep(grace).await;
+        warn!("Job still running after the shutdown grace period; abandoning it");
+        if !keep_partial_output {
+            remove_partial_output(&output_path);
+        }
+        let result = JobResult {
+            success: false,
+            status: JobStatus::Cancelled,
+            message: "Job cancelled: shutdown grace period expired".to_string(),
+            output_path: None,
+            manifest_path: None,
+            metrics: None,
+            skipped: false,
+        };
+        if let Ok(line) = serde_json::to_string(&result) {
+            println!("{}", line);
+        }
+        events::finish(&result);
+        // The job span is still open, but its finished stages can be exported
+        telemetry::shutdown();
+        std::process::exit(143);
+    });
 
     info!(task = %job.task, input = %job.input_path, "Processing job");
 
     let start = std::time::Instant::now();
+    let usage_before = metrics::ResourceUsage::now();
     
     // Execute the job
-    let result = match execute_job(&job, &config).await {
+    let outcome = execute_job(&job, &config)
+        .instrument(tracing::info_span!(parent: &job_span, "process", task = %job.task))
+        .await;
+    let result = match outcome {
         Ok(output_path) => {
             let duration_ms = start.elapsed().as_millis() as u64;
+            let usage = metrics::ResourceUsage::now().since(&usage_before);
+            let frames = Some(metrics::frames()).filter(|&frames| frames > 0);
+            info!(
+                task = %job.task,
+                duration_ms,
+                peak_rss_bytes = usage.peak_rss_bytes,
+                user_cpu_ms = usage.user_cpu.as_millis() as u64,
+                system_cpu_ms = usage.system_cpu.as_millis() as u64,
+                frames_processed = frames,
+                "Job metrics"
+            );
             
             let input_size = get_file_size(&job.input_path).unwrap_or(0);
             let output_size = get_file_size(&output_path).unwrap_or(0);
+            let manifest_path = acquisition::manifest_requested(&job, &config)
+                .then(|| acquisition::manifest_path(&job, &output_path));
             
+            let local_output = output_path.clone();
+            let published = publish_output(staging.as_ref(), storage.as_ref(), output_path, manifest_path)
+                .instrument(tracing::info_span!(parent: &job_span, "upload"))
+                .await;
+            match published {
+                Ok((output_path, manifest_path)) => {
+                    if let Some(key) = &idempotency_key {
+                        if let Err(e) = idempotency::record(storage.as_ref(), key, &output_path, &local_output).await {
+                            warn!(error = %e, "Failed to record idempotency key");
+                        }
+                    }
+                    // Next steps get the stored output, so they start only once it is published
+                    if let Err(e) = trigger_next(&job, &output_path, &config).await {
+                        error!(error = %e, "Failed to start next steps");
+                        JobResult {
+                            success: false,
+                            status: JobStatus::Failed,
+                            message: format!("Job failed: {:#}", e),
+                            output_path: None,
+                            manifest_path: None,
+                            metrics: None,
+                            skipped: false,
+                        }
+                    } else {
+                        JobResult {
+                            success: true,
+                            status: JobStatus::Succeeded,
+                            message: format!("Job '{}' completed successfully", job.task),
+                            manifest_path,
+                            output_path: Some(output_path),
+                            metrics: Some(JobMetrics {
+                                duration_ms,
+                                input_size_bytes: input_size,
+                                output_size_bytes: output_size,
+                                throughput_bytes_per_sec: input_size as f64 * 1000.0 / duration_ms.max(1) as f64,
+                                peak_rss_bytes: usage.peak_rss_bytes,
+                                user_cpu_ms: usage.user_cpu.as_millis() as u64,
+                                system_cpu_ms: usage.system_cpu.as_millis() as u64,
+                                frames_processed: frames,
+                                average_fps: frames.map(|frames| {
+                                    (frames as f64 * 100_000.0 / duration_ms.max(1) as f64).round() / 100.0
+                                }),
+                            }),
+                            skipped: false,
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!(error = %e, "Failed to store job output");
+                    JobResult {
+                        success: false,
+                        status: JobStatus::Failed,
+                        message: format!("Job failed: {}", e),
+                        output_path: None,
+                        manifest_path: None,
+                        metrics: None,
+                        skipped: false,
+                    }
+                }
+            }
+        }
+        Err(e) if shutdown::requested() => {
+            warn!(error = %e, "Job cancelled");
+            if !keep_partial_output {
+                remove_partial_output(&job.output_path);
+            }
             JobResult {
-                success: true,
-                message: format!("Job '{}' completed successfully", job.task),
-                output_path: Some(output_path),
-                metrics: Some(JobMetrics {
-                    duration_ms,
-                    input_size_bytes: input_size,
-                    output_size_bytes: output_size,
-                }),
+                success: false,
+                status: JobStatus::Cancelled,
+                message: format!("Job cancelled: {}", e),
+                output_path: None,
+                manifest_path: None,
+                metrics: None,
+                skipped: false,
             }
         }
         Err(e) => {
             error!(error = %e, "Job failed");
             JobResult {
                 success: false,
+                status: JobStatus::Failed,
                 message: format!("Job failed: {}", e),
                 output_path: None,
+                manifest_path: None,
                 metrics: None,
+                skipped: false,
             }
         }
     };
 
     // Output result as JSON
     println!("{}", serde_json::to_string(&result)?);
+    events::finish(&result);
+    telemetry::finish(job_span, &result);
+    // Exiting skips destructors
+    drop(staging);
 
-    if result.success {
-        Ok(())
-    } else {
-        std::process::exit(1);
+    match result.status {
+        JobStatus::Succeeded => Ok(()),
+        JobStatus::Failed => std::process::exit(1),
+        JobStatus::Cancelled => std::process::exit(143),
     }
 }
 
 async fn execute_job(job: &JobPayload, config: &Config) -> Result<String> {
-    match job.task.as_str() {
+    let output_path = match job.task.as_str() {
         "download_file" => acquisition::download_file(job, config).await,
         "validate_checksum" => acquisition::validate_checksum(job, config).await,
         "probe_media_file" => acquisition::probe_media_file(job, config).await,
@@ -128,6 +396,17 @@ async fn execute_job(job: &JobPayload, config: &Config) -> Result<String> {
         "sanitize_filename" => acquisition::sanitize_filename(job, config).await,
         "create_file_manifest" => acquisition::create_file_manifest(job, config).await,
         "verify_file_integrity" => acquisition::verify_file_integrity(job, config).await,
+        "validate_media_type" => acquisition::validate_media_type(job, config).await,
+        "scan_for_malware" => acquisition::scan_for_malware(job, config).await,
+        "release_from_quarantine" => acquisition::release_from_quarantine(job, config).await,
+        "purge_quarantine" => acquisition::purge_quarantine(job, config).await,
+        "verify_signature" => acquisition::verify_signature(job, config).await,
+        "sync_delta" => acquisition::sync_delta(job, config).await,
+        "detect_file_type" => acquisition::detect_file_type(job, config).await,
+        "record_rtmp" => acquisition::record_rtmp(job, config).await,
+        "verify_manifest" => acquisition::verify_manifest(job, config).await,
+        "download_file_parallel" => acquisition::download_file_parallel(job, config).await,
+        "upload_file" => acquisition::upload_file(job, config).await,
         
         "transcode_h264_to_h265" => ffmpeg_video::transcode_video_native(job, config).await,
         "resize_to_720p" => ffmpeg_video::resize_video_native(job, config).await,
@@ -138,12 +417,63 @@ async fn execute_job(job: &JobPayload, config: &Config) -> Result<String> {
         "detect_scene_cuts" => ffmpeg_video::detect_scene_cuts(job, config).await,
         "apply_watermark" => ffmpeg_video::apply_watermark(job, config).await,
         "extract_key_frame" => ffmpeg_video::extract_key_frame(job, config).await,
+        "images_to_video" => ffmpeg_video::images_to_video(job, config).await,
+        "export_image_sequence" => ffmpeg_video::export_image_sequence(job, config).await,
+        "stream_to_hls" => ffmpeg_video::stream_to_hls(job, config).await,
+        "push_rtmp" => ffmpeg_video::push_rtmp(job, config).await,
+        "live_thumbnails" => ffmpeg_video::live_thumbnails(job, config).await,
+        "package_drm" => ffmpeg_video::package_drm(job, config).await,
+        "watermark_forensic" => ffmpeg_video::watermark_forensic(job, config).await,
+        "detect_forensic_watermark" => ffmpeg_video::detect_forensic_watermark(job, config).await,
+        "generate_hover_previews" => ffmpeg_video::generate_hover_previews(job, config).await,
+        "export_social" => ffmpeg_video::export_social(job, config).await,
+        "concatenate_videos" => ffmpeg_video::concatenate_videos(job, config).await,
+        "generate_abr_ladder" => ffmpeg_video::generate_abr_ladder(job, config).await,
+        "transcode_parallel" => ffmpeg_video::transcode_parallel(job, config).await,
+        "transcode_distributed" => chunks::transcode_distributed(job, config).await,
+        "encode_chunk" => chunks::encode_chunk(job, config).await,
+        "assemble_chunks" => chunks::assemble_chunks(job, config).await,
+        "remux_container" => ffmpeg_video::remux_container(job, config).await,
+        "faststart_mp4" => ffmpeg_video::faststart_mp4(job, config).await,
+        "extract_clip" => ffmpeg_video::extract_clip(job, config).await,
+        "render_edl" => ffmpeg_video::render_edl(job, config).await,
+        "pad_video" => ffmpeg_video::pad_video(job, config).await,
+        "convert_frame_rate" => ffmpeg_video::convert_frame_rate(job, config).await,
+        "tonemap_hdr_to_sdr" => ffmpeg_video::tonemap_hdr_to_sdr(job, config).await,
+        "measure_quality" => ffmpeg_video::measure_quality(job, config).await,
+        "detect_freeze_frames" => ffmpeg_video::detect_freeze_frames(job, config).await,
+        "detect_interlacing" => ffmpeg_video::detect_interlacing(job, config).await,
+        "measure_av_sync" => ffmpeg_video::measure_av_sync(job, config).await,
+        "run_qc_report" => ffmpeg_video::run_qc_report(job, config).await,
         
         "resample_audio" => ffmpeg_audio::resample_audio_native(job, config).await,
         "extract_audio_from_video" => ffmpeg_audio::extract_audio_native(job, config).await,
         "get_audio_info" => ffmpeg_audio::get_audio_info_native(job, config).await,
         "generate_waveform_json" => ffmpeg_audio::generate_waveform_native(job, config).await,
         "mix_audio_tracks" => ffmpeg_audio::mix_audio_native(job, config).await,
+        "downmix_audio" => ffmpeg_audio::downmix_audio(job, config).await,
+        "remap_channels" => ffmpeg_audio::remap_channels(job, config).await,
+        "concat_audio_crossfade" => ffmpeg_audio::concat_audio_crossfade(job, config).await,
+        "trim_audio" => ffmpeg_audio::trim_audio(job, config).await,
+        "adjust_audio_speed" => ffmpeg_audio::adjust_audio_speed(job, config).await,
+        "compress_dynamics" => ffmpeg_audio::compress_dynamics(job, config).await,
+        "clean_audio_artifacts" => ffmpeg_audio::clean_audio_artifacts(job, config).await,
+        "calculate_replaygain" => ffmpeg_audio::calculate_replaygain(job, config).await,
+        "detect_silence" => ffmpeg_audio::detect_silence(job, config).await,
+        "classify_audio_content" => ffmpeg_audio::classify_audio_content(job, config).await,
+        "detect_audio_language" => ffmpeg_audio::detect_audio_language(job, config).await,
+        "fingerprint_audio" => ffmpeg_audio::fingerprint_audio(job, config).await,
+        "analyze_music" => ffmpeg_audio::analyze_music(job, config).await,
+        "detect_clipping" => ffmpeg_audio::detect_clipping(job, config).await,
+        "fix_dc_offset" => ffmpeg_audio::fix_dc_offset(job, config).await,
+        "separate_stems" => ffmpeg_audio::separate_stems(job, config).await,
+        "duck_music" => ffmpeg_audio::duck_music(job, config).await,
+        "add_silent_audio" => ffmpeg_audio::add_silent_audio(job, config).await,
+        "encode_audio_multi" => ffmpeg_audio::encode_audio_multi(job, config).await,
+        "convert_sample_format" => ffmpeg_audio::convert_sample_format(job, config).await,
+        "watermark_audio" => ffmpeg_audio::watermark_audio(job, config).await,
+        "detect_audio_watermark" => ffmpeg_audio::detect_audio_watermark(job, config).await,
+        "embed_podcast_chapters" => ffmpeg_audio::embed_podcast_chapters(job, config).await,
         
         "calculate_sha256" => binary::calculate_sha256(job, config).await,
         "compress_archive" => binary::compress_archive(job, config).await,
@@ -152,11 +482,81 @@ async fn execute_job(job: &JobPayload, config: &Config) -> Result<String> {
         "validate_format_compliance" => binary::validate_format_compliance(job, config).await,
         "chain_job_trigger" => binary::chain_job_trigger(job, config).await,
         "report_metrics" => binary::report_metrics(job, config).await,
+        "extract_archive" => binary::extract_archive(job, config).await,
+        "encrypt_file" => binary::encrypt_file(job, config).await,
+        "decrypt_file" => binary::decrypt_file(job, config).await,
+        "strip_metadata" => binary::strip_metadata(job, config).await,
+        "run_pipeline" => pipeline::run_pipeline(job, config).await,
         
         _ => {
             warn!(task = %job.task, "Unknown task type");
             anyhow::bail!("Unknown task type: {}", job.task)
         }
+    }?;
+    
+    if acquisition::manifest_requested(job, config) {
+        acquisition::write_signed_manifest(job, &output_path, config)?;
+    }
+    
+    Ok(output_path)
+}
+
+/// Enqueue or run the job's `next` steps with its published output as their input
+///
+/// A step run in `execute` mode that fails is logged but does not fail the job, whose output
+/// is already stored; a step that could not be enqueued does, so that a retry enqueues it.
+async fn trigger_next(job: &JobPayload, output_path: &str, config: &Config) -> Result<()> {
+    // chain_job_trigger reads `next` itself
+    if job.task == "chain_job_trigger" {
+        return Ok(());
+    }
+    let steps = binary::next_steps(&job.params)?;
+    if steps.is_empty() {
+        return Ok(());
+    }
+    
+    match binary::trigger_next_jobs(&steps, output_path, &job.params, config).await {
+        Err(e) if binary::next_mode(&job.params)? == "execute" => {
+            error!(error = %e, "Next step failed");
+            Ok(())
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+/// Upload a staged job's output and manifest, returning their keys; unstaged paths are
+/// returned as they are
+async fn publish_output(
+    staging: Option<&storage::Staging>,
+    storage: &dyn storage::Storage,
+    output_path: String,
+    manifest_path: Option<String>,
+) -> Result<(String, Option<String>)> {
+    let Some(staging) = staging else {
+        return Ok((output_path, manifest_path));
+    };
+    
+    let output_path = staging.publish(storage, &output_path).await?;
+    let manifest_path = match manifest_path {
+        Some(path) => Some(staging.publish(storage, &path).await?),
+        None => None,
+    };
+    Ok((output_path, manifest_path))
+}
+
+fn keep_partial_output(job: &JobPayload) -> bool {
+    job.params.get("keep_partial_output")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// Delete a cancelled job's half-written output file; directories are left alone
+fn remove_partial_output(path: &str) {
+    if std::path::Path::new(path).is_file() {
+        match fs::remove_file(path) {
+            Ok(()) => info!(path, "Removed partial output"),
+            Err(e) => warn!(path, error = %e, "Failed to remove partial output"),
+        }
     }
 }
 
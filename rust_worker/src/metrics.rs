@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+static FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// CPU time and peak memory of the worker and the tools it has run, from `getrusage`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceUsage {
+    pub user_cpu: Duration,
+    pub system_cpu: Duration,
+    pub peak_rss_bytes: u64,
+}
+
+impl ResourceUsage {
+    /// Usage so far; external tools (whisper.cpp, the packager, ...) count once they have
+    /// been waited for
+    pub fn now() -> Self {
+        let own = rusage(libc::RUSAGE_SELF);
+        let children = rusage(libc::RUSAGE_CHILDREN);
+        Self {
+            user_cpu: own.user_cpu + children.user_cpu,
+            system_cpu: own.system_cpu + children.system_cpu,
+            peak_rss_bytes: own.peak_rss_bytes.max(children.peak_rss_bytes),
+        }
+    }
+    
+    /// CPU time used since `earlier`; the peak is for the whole process
+    pub fn since(&self, earlier: &ResourceUsage) -> Self {
+        Self {
+            user_cpu: self.user_cpu.saturating_sub(earlier.user_cpu),
+            system_cpu: self.system_cpu.saturating_sub(earlier.system_cpu),
+            peak_rss_bytes: self.peak_rss_bytes,
+        }
+    }
+}
+
+fn rusage(who: libc::c_int) -> ResourceUsage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(who, &mut usage) } != 0 {
+        return ResourceUsage::default();
+    }
+    let duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec.max(0) as u64) + Duration::from_micros(time.tv_usec.max(0) as u64)
+    };
+    // ru_maxrss is in kilobytes on Linux and bytes on macOS
+    let peak_rss_bytes = if cfg!(target_os = "macos") {
+        usage.ru_maxrss.max(0) as u64
+    } else {
+        usage.ru_maxrss.max(0) as u64 * 1024
+    };
+    ResourceUsage {
+        user_cpu: duration(usage.ru_utime),
+        system_cpu: duration(usage.ru_stime),
+        peak_rss_bytes,
+    }
+}
+
+/// Count `frames` encoded by the current job
+pub fn add_frames(frames: u64) {
+    FRAMES.fetch_add(frames, Ordering::Relaxed);
+}
+
+/// Frames encoded so far, across every task the job ran
+pub fn frames() -> u64 {
+    FRAMES.load(Ordering::Relaxed)
+}
@@ -0,0 +1,403 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::{config::Config, execute_job, scratch::ScratchDir, shutdown, watch::expand_params, JobPayload};
+
+/// One node of a `run_pipeline` graph
+#[derive(Debug, Clone, Deserialize)]
+struct PipelineStep {
+    id: String,
+    task: String,
+    #[serde(default)]
+    input: Option<String>,
+    #[serde(default)]
+    output: Option<String>,
+    #[serde(default)]
+    params: serde_json::Value,
+    #[serde(default)]
+    depends_on: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+impl StepStatus {
+    fn name(self) -> &'static str {
+        match self {
+            StepStatus::Pending => "pending",
+            StepStatus::Running => "running",
+            StepStatus::Succeeded => "succeeded",
+            StepStatus::Failed => "failed",
+            StepStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Progress of one step, reported per step at the end
+struct StepState {
+    status: StepStatus,
+    input: Option<String>,
+    output: Option<String>,
+    started_at: Option<String>,
+    duration_ms: Option<u64>,
+    error: Option<String>,
+}
+
+/// Run a graph of steps in one job, starting each as soon as its `depends_on` steps succeed
+///
+/// `steps` is a list of `{id, task, input, output, params, depends_on}`. A step's input
+/// defaults to the output of its only dependency, or the job's input for steps without
+/// dependencies; its output defaults to `<scratch>/<id>.<input extension>`. `{input}`,
+/// `{stem}`, `{ext}`, `{scratch}` and `{steps.<id>}` (the output of `<id>`, which must be one
+/// of the step's `depends_on`) are expanded in inputs, outputs and string params. Up to `max_parallel` (default:
+/// `processing.max_workers`) independent steps run at once.
+///
+/// After a failure no new steps start unless `continue_on_error` is set, in which case only
+/// the failed step's dependents are skipped. Intermediate files go to `scratch_dir`, or to a
+/// managed scratch directory that is removed afterwards unless `keep_scratch` is set. The
+/// per-step report is written to `output_path` before any failure is returned.
+pub async fn run_pipeline(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Running pipeline");
+    
+    let steps: Vec<PipelineStep> = serde_json::from_value(
+        job.params.get("steps")
+            .cloned()
+            .context("steps parameter required")?,
+    )
+    .context("Invalid pipeline steps")?;
+    
+    let max_parallel = job.params.get("max_parallel")
+        .and_then(|v| v.as_u64())
+        .map_or(config.processing.max_workers, |v| v as usize)
+        .max(1);
+    
+    let continue_on_error = job.params.get("continue_on_error")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let scratch_dir = job.params.get("scratch_dir")
+        .and_then(|v| v.as_str());
+    
+    let keep_scratch = job.params.get("keep_scratch")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(scratch_dir.is_some());
+    
+    let order = topological_order(&steps)?;
+    
+    let (scratch, managed) = match scratch_dir {
+        Some(dir) => (PathBuf::from(dir), None),
+        None => {
+            let managed = ScratchDir::new(&config.scratch, "pipeline")?;
+            (managed.path().to_path_buf(), Some(managed))
+        }
+    };
+    fs::create_dir_all(&scratch)
+        .context(format!("Failed to create scratch directory {}", scratch.display()))?;
+    
+    let started = Instant::now();
+    let outcome = run_steps(job, config, &steps, &order, &scratch, managed.as_ref(), max_parallel, continue_on_error).await;
+    
+    if keep_scratch {
+        info!(path = %scratch.display(), "Keeping pipeline scratch directory");
+        if let Some(managed) = managed {
+            if let Err(e) = managed.keep() {
+                warn!(error = %e, "Kept pipeline scratch directory may be removed by a later worker");
+            }
+        }
+    } else if managed.is_none() {
+        if let Err(e) = fs::remove_dir_all(&scratch) {
+            warn!(path = %scratch.display(), error = %e, "Failed to remove pipeline scratch directory");
+        }
+    }
+    
+    let states = outcome?;
+    let count = |status: StepStatus| states.values().filter(|state| state.status == status).count();
+    let failed = count(StepStatus::Failed);
+    
+    let report = serde_json::json!({
+        "steps": order.iter().map(|&index| {
+            let step = &steps[index];
+            let state = &states[&step.id];
+            serde_json::json!({
+                "id": step.id,
+                "task": step.task,
+                "status": state.status.name(),
+                "input": state.input,
+                "output": state.output,
+                "started_at": state.started_at,
+                "duration_ms": state.duration_ms,
+                "error": state.error,
+            })
+        }).collect::<Vec<_>>(),
+        "succeeded": count(StepStatus::Succeeded),
+        "failed": failed,
+        "skipped": count(StepStatus::Skipped),
+        "duration_ms": started.elapsed().as_millis() as u64,
+    });
+    fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    if failed > 0 {
+        let first_error = order
+            .iter()
+            .filter_map(|&index| states[&steps[index].id].error.as_ref().map(|e| (&steps[index].id, e)))
+            .next();
+        if let Some((id, e)) = first_error {
+            anyhow::bail!("Pipeline step '{}' failed: {}", id, e);
+        }
+    }
+    
+    info!(steps = steps.len(), "Pipeline complete");
+    Ok(job.output_path.clone())
+}
+
+/// Schedule the steps, returning every step's final state
+async fn run_steps(
+    job: &JobPayload,
+    config: &Config,
+    steps: &[PipelineStep],
+    order: &[usize],
+    scratch: &Path,
+    managed: Option<&ScratchDir>,
+    max_parallel: usize,
+    continue_on_error: bool,
+) -> Result<HashMap<String, StepState>> {
+    let config = Arc::new(config.clone());
+    let mut states: HashMap<String, StepState> = steps
+        .iter()
+        .map(|step| {
+            (step.id.clone(), StepState {
+                status: StepStatus::Pending,
+                input: None,
+                output: None,
+                started_at: None,
+                duration_ms: None,
+                error: None,
+            })
+        })
+        .collect();
+    let mut running = tokio::task::JoinSet::new();
+    let mut stopping = false;
+    
+    loop {
+        // Skip everything downstream of a failure
+        for &index in order {
+            let step = &steps[index];
+            let blocked = step.depends_on.iter().any(|dep| {
+                matches!(states[dep].status, StepStatus::Failed | StepStatus::Skipped)
+            });
+            if blocked && states[&step.id].status == StepStatus::Pending {
+                states.get_mut(&step.id).unwrap().status = StepStatus::Skipped;
+            }
+        }
+        
+        if shutdown::requested() {
+            stopping = true;
+        }
+        
+        if !stopping {
+            for &index in order {
+                if running.len() >= max_parallel {
+                    break;
+                }
+                let step = &steps[index];
+                let ready = states[&step.id].status == StepStatus::Pending
+                    && step.depends_on.iter().all(|dep| states[dep].status == StepStatus::Succeeded);
+                if !ready {
+                    continue;
+                }
+                
+                let step_job = match resolve_step(job, step, &states, scratch) {
+                    Ok(step_job) => step_job,
+                    Err(e) => {
+                        let state = states.get_mut(&step.id).unwrap();
+                        state.status = StepStatus::Failed;
+                        state.error = Some(format!("{:#}", e));
+                        stopping |= !continue_on_error;
+                        continue;
+                    }
+                };
+                
+                let state = states.get_mut(&step.id).unwrap();
+                state.status = StepStatus::Running;
+                state.input = Some(step_job.input_path.clone());
+                state.output = Some(step_job.output_path.clone());
+                state.started_at = Some(chrono::Utc::now().to_rfc3339());
+                info!(step = %step.id, task = %step.task, "Starting pipeline step");
+                
+                let id = step.id.clone();
+                let config = config.clone();
+                // Tasks do blocking media work, so each step gets its own thread
+                let handle = tokio::runtime::Handle::current();
+                running.spawn_blocking(move || {
+                    let start = Instant::now();
+                    let result = handle.block_on(Box::pin(execute_job(&step_job, &config)));
+                    (id, result, start.elapsed().as_millis() as u64)
+                });
+            }
+        }
+        
+        let Some(finished) = running.join_next().await else {
+            break;
+        };
+        let (id, result, duration_ms) = finished.context("Pipeline step panicked")?;
+        // A step that filled the scratch space fails even though its task succeeded
+        let result = result.and_then(|output| managed.map_or(Ok(()), ScratchDir::check).map(|()| output));
+        let state = states.get_mut(&id).unwrap();
+        state.duration_ms = Some(duration_ms);
+        match result {
+            Ok(output) => {
+                info!(step = %id, duration_ms, "Pipeline step succeeded");
+                state.status = StepStatus::Succeeded;
+                state.output = Some(output);
+            }
+            Err(e) => {
+                error!(step = %id, error = %e, "Pipeline step failed");
+                state.status = StepStatus::Failed;
+                state.error = Some(format!("{:#}", e));
+                stopping |= !continue_on_error;
+            }
+        }
+    }
+    
+    // Steps never started because the pipeline stopped early
+    for state in states.values_mut() {
+        if state.status == StepStatus::Pending {
+            state.status = StepStatus::Skipped;
+        }
+    }
+    
+    shutdown::check()?;
+    Ok(states)
+}
+
+/// The job for `step`, with defaults filled in and placeholders expanded
+fn resolve_step(
+    job: &JobPayload,
+    step: &PipelineStep,
+    states: &HashMap<String, StepState>,
+    scratch: &Path,
+) -> Result<JobPayload> {
+    let input_path = Path::new(&job.input_path);
+    let stem = input_path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = input_path.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_default();
+    let scratch = scratch.to_string_lossy().to_string();
+    
+    let expand = |s: &str| {
+        let mut expanded = s
+            .replace("{input}", &job.input_path)
+            .replace("{stem}", &stem)
+            .replace("{ext}", &ext)
+            .replace("{scratch}", &scratch);
+        // Only dependencies are expanded, so the result never depends on what else has run
+        for dep in &step.depends_on {
+            if let Some(output) = &states[dep].output {
+                expanded = expanded.replace(&format!("{{steps.{}}}", dep), output);
+            }
+        }
+        expanded
+    };
+    
+    let input = match (&step.input, step.depends_on.as_slice()) {
+        (Some(input), _) => expand(input),
+        (None, []) => job.input_path.clone(),
+        (None, [dep]) => states[dep].output.clone().unwrap_or_default(),
+        (None, _) => anyhow::bail!("Step '{}' has several dependencies and needs an input", step.id),
+    };
+    
+    let output = match &step.output {
+        Some(output) => expand(output),
+        None => {
+            let ext = Path::new(&input)
+                .extension()
+                .map(|e| format!(".{}", e.to_string_lossy()))
+                .unwrap_or_default();
+            format!("{}/{}{}", scratch, step.id, ext)
+        }
+    };
+    if let Some(parent) = Path::new(&output).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    
+    Ok(JobPayload {
+        job_id: None,
+        traceparent: None,
+        task: step.task.clone(),
+        input_path: input,
+        output_path: output,
+        params: expand_params(&step.params, &expand),
+    })
+}
+
+/// Indices of `steps` in an order that runs dependencies first, rejecting unknown ids and
+/// cycles
+fn topological_order(steps: &[PipelineStep]) -> Result<Vec<usize>> {
+    let mut index_of = HashMap::new();
+    for (index, step) in steps.iter().enumerate() {
+        if index_of.insert(step.id.as_str(), index).is_some() {
+            anyhow::bail!("Duplicate pipeline step id: {}", step.id);
+        }
+    }
+    for step in steps {
+        if let Some(dep) = step.depends_on.iter().find(|dep| !index_of.contains_key(dep.as_str())) {
+            anyhow::bail!("Step '{}' depends on unknown step '{}'", step.id, dep);
+        }
+        check_step_references(step)?;
+    }
+    
+    let mut order = Vec::with_capacity(steps.len());
+    let mut placed = HashSet::new();
+    while order.len() < steps.len() {
+        let next: Vec<usize> = (0..steps.len())
+            .filter(|index| !placed.contains(index))
+            .filter(|&index| steps[index].depends_on.iter().all(|dep| placed.contains(&index_of[dep.as_str()])))
+            .collect();
+        if next.is_empty() {
+            anyhow::bail!("Pipeline steps have a dependency cycle");
+        }
+        placed.extend(next.iter().copied());
+        order.extend(next);
+    }
+    
+    Ok(order)
+}
+
+/// Reject `{steps.<id>}` references in a step's input, output or params to anything but
+/// its own dependencies
+fn check_step_references(step: &PipelineStep) -> Result<()> {
+    fn strings<'a>(value: &'a serde_json::Value, found: &mut Vec<&'a str>) {
+        match value {
+            serde_json::Value::String(s) => found.push(s),
+            serde_json::Value::Array(items) => items.iter().for_each(|v| strings(v, found)),
+            serde_json::Value::Object(map) => map.values().for_each(|v| strings(v, found)),
+            _ => {}
+        }
+    }
+    
+    let mut found: Vec<&str> = step.input.iter().chain(&step.output).map(String::as_str).collect();
+    strings(&step.params, &mut found);
+    
+    for text in found {
+        for (start, _) in text.match_indices("{steps.") {
+            let rest = &text[start + "{steps.".len()..];
+            let id = rest.find('}').map(|end| &rest[..end]);
+            match id {
+                Some(id) if step.depends_on.iter().any(|dep| dep == id) => {}
+                Some(id) => anyhow::bail!("Step '{}' refers to the output of '{}', which it does not depend on", step.id, id),
+                None => anyhow::bail!("Step '{}' has an unterminated {{steps. reference", step.id),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Periodic progress events for long-running loops, counted in frames or bytes
+///
+/// Enabled per job with `progress: true` (NDJSON lines on stdout, ahead of the final
+/// `JobResult` line) and/or `progress_path` (NDJSON appended to that file, truncated when the
+/// job starts), and always for jobs that publish their events to Redis. Events are emitted at
+/// most every `progress_interval` seconds (default: 1) and once more when the loop finishes.
+pub struct ProgressReporter {
+    task: String,
+    stage: Option<String>,
+    stdout: bool,
+    file: Option<File>,
+    interval: Duration,
+    unit: ProgressUnit,
+    total: Option<u64>,
+    started: Instant,
+    last_report: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProgressUnit {
+    Frames,
+    Bytes,
+}
+
+#[derive(Debug, Serialize)]
+struct ProgressEvent<'a> {
+    event: &'static str,
+    task: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_frames: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fps: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_per_sec: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent: Option<f64>,
+    elapsed_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta_seconds: Option<f64>,
+    done: bool,
+}
+
+impl ProgressReporter {
+    /// None unless the job asked for progress
+    pub fn from_params(task: &str, params: &serde_json::Value) -> Result<Option<Self>> {
+        let stdout = params.get("progress")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        
+        let path = params.get("progress_path")
+            .and_then(|v| v.as_str());
+        
+        // Jobs publishing to Redis always report progress there
+        if !stdout && path.is_none() && !crate::events::enabled() {
+            return Ok(None);
+        }
+        
+        let file = match path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(path)
+                    .context(format!("Failed to open progress file {}", path))?,
+            ),
+            None => None,
+        };
+        
+        Ok(Some(Self {
+            task: task.to_string(),
+            stage: None,
+            stdout,
+            file,
+            interval: Duration::from_secs_f64(
+                params.get("progress_interval")
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(1.0)
+                    .max(0.1),
+            ),
+            unit: ProgressUnit::Frames,
+            total: None,
+            started: Instant::now(),
+            last_report: None,
+        }))
+    }
+    
+    /// Start a new stage (e.g. a second encoding pass), restarting the rate and ETA
+    pub fn start_stage(&mut self, stage: &str, total_frames: Option<u64>) {
+        self.stage = Some(stage.to_string());
+        self.set_total_frames(total_frames);
+        self.started = Instant::now();
+        self.last_report = None;
+    }
+    
+    pub fn set_total_frames(&mut self, total_frames: Option<u64>) {
+        self.unit = ProgressUnit::Frames;
+        self.total = total_frames.filter(|&total| total > 0);
+    }
+    
+    /// Count progress in bytes rather than frames
+    pub fn set_total_bytes(&mut self, total_bytes: Option<u64>) {
+        self.unit = ProgressUnit::Bytes;
+        self.total = total_bytes.filter(|&total| total > 0);
+    }
+    
+    /// Report `done` frames or bytes so far if the interval has passed
+    pub fn update(&mut self, done: u64) {
+        if self.last_report.map_or(false, |last| last.elapsed() < self.interval) {
+            return;
+        }
+        self.emit(done, false);
+    }
+    
+    pub fn finish(&mut self, done: u64) {
+        self.emit(done, true);
+    }
+    
+    fn emit(&mut self, count: u64, done: bool) {
+        self.last_report = Some(Instant::now());
+        
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { count as f64 / elapsed } else { 0.0 };
+        // Frame counts from container headers are estimates; never report past 100%
+        let percent = self.total.map(|total| {
+            if done { 100.0 } else { (count as f64 * 100.0 / total as f64).min(99.9) }
+        });
+        let eta_seconds = match (self.total, done) {
+            (_, true) => Some(0.0),
+            (Some(total), false) if rate > 0.0 => Some(total.saturating_sub(count) as f64 / rate),
+            _ => None,
+        };
+        let frames = self.unit == ProgressUnit::Frames;
+        
+        let event = ProgressEvent {
+            event: "progress",
+            task: &self.task,
+            stage: self.stage.as_deref(),
+            frames: frames.then_some(count),
+            total_frames: self.total.filter(|_| frames),
+            fps: frames.then(|| (rate * 100.0).round() / 100.0),
+            bytes: (!frames).then_some(count),
+            total_bytes: self.total.filter(|_| !frames),
+            bytes_per_sec: (!frames).then(|| rate as u64),
+            percent: percent.map(|percent| (percent * 10.0).round() / 10.0),
+            elapsed_seconds: (elapsed * 1000.0).round() / 1000.0,
+            eta_seconds: eta_seconds.map(|eta| eta.round()),
+            done,
+        };
+        
+        if crate::events::enabled() {
+            crate::events::publish("progress", serde_json::to_value(&event).unwrap_or_default());
+        }
+        
+        if let Err(e) = self.write(&event) {
+            // Progress is advisory; losing it must not fail the job
+            warn!(error = %e, "Failed to report progress");
+        }
+    }
+    
+    fn write(&mut self, event: &ProgressEvent) -> Result<()> {
+        let line = serde_json::to_string(event)?;
+        if self.stdout {
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{}", line)?;
+            stdout.flush()?;
+        }
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", line)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
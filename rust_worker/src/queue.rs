@@ -0,0 +1,338 @@
+use anyhow::{Context, Result};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::{config::Config, shutdown, JobPayload};
+
+/// A job in the Redis queue: the worker payload plus its delivery history
+#[derive(Debug, Deserialize, Serialize)]
+pub struct QueuedJob {
+    #[serde(flatten)]
+    pub job: JobPayload,
+    /// Failed attempts so far
+    #[serde(default)]
+    pub attempts: u32,
+    /// Overrides `redis.max_attempts` for this job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enqueued_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<AttemptFailure>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AttemptFailure {
+    pub attempt: u32,
+    pub error: String,
+    pub started_at: String,
+    pub failed_at: String,
+}
+
+/// How a job process ended
+pub(crate) enum Outcome {
+    Succeeded,
+    Failed(String),
+    /// Stopped by shutdown; the job goes back on the queue without using up an attempt
+    Cancelled,
+}
+
+/// Longest wait before a failed job is requeued, however many attempts it has had
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Consume JSON job payloads from the Redis list `redis.queue_name` until shutdown
+///
+/// Producers LPUSH payloads (optionally with a `job_id`); up to `processing.max_workers` run
+/// at once, each in its own worker process. A job that fails is pushed back onto the queue,
+/// after a backoff, until it has failed `redis.max_attempts` times (or its own
+/// `max_attempts`), then moved to the list `redis.dead_letter_queue` with every attempt's
+/// error and timestamps.
+///
+/// Jobs are moved, not popped, into this worker's processing list and removed from it only
+/// once they have been requeued, dead-lettered or have succeeded, so that a worker killed
+/// mid-job loses nothing: whatever is left in the list goes back on the queue at the next
+/// start.
+pub async fn run(config: Config) -> Result<()> {
+    let client = redis::Client::open(config.redis.url.as_str())
+        .context("Invalid redis.url")?;
+    // BLMOVE blocks its connection, so jobs get their own for requeueing
+    let mut queue_connection = client.get_multiplexed_async_connection()
+        .await
+        .context("Failed to connect to Redis")?;
+    let connection = client.get_multiplexed_async_connection()
+        .await
+        .context("Failed to connect to Redis")?;
+    
+    let processing = format!(
+        "{}{}",
+        config.redis.processing_key_prefix,
+        config.redis.worker_id.clone().unwrap_or_else(hostname)
+    );
+    recover(&config, &mut queue_connection, &processing).await?;
+    
+    let config = Arc::new(config);
+    let processing = Arc::new(processing);
+    let slots = Arc::new(tokio::sync::Semaphore::new(config.processing.max_workers.max(1)));
+    let mut running = tokio::task::JoinSet::new();
+    info!(queue = %config.redis.queue_name, processing = %processing, workers = config.processing.max_workers, "Consuming jobs");
+    
+    while !shutdown::requested() {
+        let permit = slots.clone().acquire_owned().await?;
+        
+        // The short timeout keeps the loop responsive to shutdown
+        let moved: Option<String> = queue_connection
+            .blmove(
+                &config.redis.queue_name,
+                processing.as_str(),
+                redis::Direction::Right,
+                redis::Direction::Left,
+                1.0,
+            )
+            .await
+            .context("Failed to read from the job queue")?;
+        let Some(entry) = moved else {
+            continue;
+        };
+        
+        let config = config.clone();
+        let processing = processing.clone();
+        let mut connection = connection.clone();
+        running.spawn(async move {
+            if let Err(e) = process(&config, &mut connection, &processing, entry, permit).await {
+                error!(error = %e, "Failed to process queued job");
+            }
+        });
+        while running.try_join_next().is_some() {}
+    }
+    
+    info!("Waiting for running jobs before shutting down");
+    while running.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Push a job onto the queue, returning its id
+pub async fn enqueue(config: &Config, mut job: JobPayload) -> Result<String> {
+    let job_id = job.job_id.get_or_insert_with(new_job_id).clone();
+    let queued = QueuedJob {
+        job,
+        attempts: 0,
+        max_attempts: None,
+        enqueued_at: Some(now()),
+        failures: Vec::new(),
+    };
+    
+    let client = redis::Client::open(config.redis.url.as_str())
+        .context("Invalid redis.url")?;
+    let mut connection = client.get_multiplexed_async_connection()
+        .await
+        .context("Failed to connect to Redis")?;
+    connection.lpush::<_, _, ()>(&config.redis.queue_name, serde_json::to_string(&queued)?)
+        .await
+        .context("Failed to enqueue job")?;
+    
+    Ok(job_id)
+}
+
+/// Put back the jobs a killed worker left in its processing list, at the consuming end so
+/// that they run first
+async fn recover(config: &Config, connection: &mut MultiplexedConnection, processing: &str) -> Result<()> {
+    loop {
+        let moved: Option<String> = connection
+            .lmove(processing, &config.redis.queue_name, redis::Direction::Right, redis::Direction::Right)
+            .await
+            .context("Failed to recover interrupted jobs")?;
+        if moved.is_none() {
+            return Ok(());
+        }
+        warn!(processing = %processing, "Requeueing a job interrupted by an earlier worker exit");
+    }
+}
+
+/// Push `record` onto `list` and drop `entry` from the processing list in one transaction
+async fn hand_off(
+    connection: &mut MultiplexedConnection,
+    processing: &str,
+    entry: &str,
+    list: &str,
+    record: String,
+    consuming_end: bool,
+) -> Result<()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if consuming_end {
+        pipe.rpush(list, record).ignore();
+    } else {
+        pipe.lpush(list, record).ignore();
+    }
+    pipe.lrem(processing, 1, entry).ignore();
+    pipe.query_async::<()>(connection).await?;
+    Ok(())
+}
+
+async fn process(
+    config: &Config,
+    connection: &mut MultiplexedConnection,
+    processing: &str,
+    entry: String,
+    permit: tokio::sync::OwnedSemaphorePermit,
+) -> Result<()> {
+    let mut queued: QueuedJob = match serde_json::from_str(&entry) {
+        Ok(queued) => queued,
+        Err(e) => {
+            warn!(error = %e, "Dead-lettering unreadable job");
+            let record = serde_json::json!({
+                "payload": &entry,
+                "error": format!("Invalid job payload: {}", e),
+                "dead_lettered_at": now(),
+            });
+            hand_off(connection, processing, &entry, &config.redis.dead_letter_queue, record.to_string(), false).await?;
+            return Ok(());
+        }
+    };
+    
+    let job_id = queued.job.job_id.get_or_insert_with(new_job_id).clone();
+    let attempt = queued.attempts + 1;
+    let started_at = now();
+    info!(job_id = %job_id, task = %queued.job.task, attempt, "Running queued job");
+    
+    let error = match run_job_process(config, &queued.job).await {
+        Ok(Outcome::Succeeded) => {
+            info!(job_id = %job_id, "Queued job succeeded");
+            connection.lrem::<_, _, ()>(processing, 1, &entry).await?;
+            return Ok(());
+        }
+        Ok(Outcome::Cancelled) => {
+            // Back to the consuming end, so that it runs first after a restart
+            info!(job_id = %job_id, "Queued job cancelled; returning it to the queue");
+            let record = serde_json::to_string(&queued)?;
+            hand_off(connection, processing, &entry, &config.redis.queue_name, record, true).await?;
+            return Ok(());
+        }
+        Ok(Outcome::Failed(message)) => message,
+        Err(e) => format!("{:#}", e),
+    };
+    
+    queued.attempts = attempt;
+    queued.failures.push(AttemptFailure {
+        attempt,
+        error: error.clone(),
+        started_at,
+        failed_at: now(),
+    });
+    
+    let max_attempts = queued.max_attempts.unwrap_or(config.redis.max_attempts).max(1);
+    if attempt < max_attempts {
+        let backoff = retry_backoff(config.redis.retry_backoff_seconds, attempt);
+        warn!(
+            job_id = %job_id,
+            attempt,
+            max_attempts,
+            backoff_seconds = backoff.as_secs(),
+            error = %error,
+            "Queued job failed; requeueing"
+        );
+        // The job stays in the processing list while it waits, but frees its slot
+        drop(permit);
+        let deadline = tokio::time::Instant::now() + backoff;
+        while !shutdown::requested() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        let record = serde_json::to_string(&queued)?;
+        hand_off(connection, processing, &entry, &config.redis.queue_name, record, false).await?;
+    } else {
+        error!(job_id = %job_id, attempts = attempt, error = %error, "Queued job failed; dead-lettering");
+        let mut record = serde_json::to_value(&queued)?;
+        record["error"] = serde_json::json!(error);
+        record["dead_lettered_at"] = serde_json::json!(now());
+        hand_off(connection, processing, &entry, &config.redis.dead_letter_queue, record.to_string(), false).await?;
+    }
+    
+    Ok(())
+}
+
+/// `base_seconds` doubled for each attempt after the first, capped at `MAX_RETRY_BACKOFF`
+fn retry_backoff(base_seconds: u64, attempt: u32) -> Duration {
+    let factor = 1u64 << attempt.saturating_sub(1).min(20);
+    Duration::from_secs(base_seconds.saturating_mul(factor)).min(MAX_RETRY_BACKOFF)
+}
+
+/// Run one job in a child worker process, which stages storage and publishes events exactly
+/// as a job run from the command line does
+pub(crate) async fn run_job_process(config: &Config, job: &JobPayload) -> Result<Outcome> {
+    let binary = std::env::current_exe().context("Failed to locate the worker binary")?;
+    let child = tokio::process::Command::new(binary)
+        .arg(serde_json::to_string(job)?)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to start job process")?;
+    let _guard = child.id().map(shutdown::ChildGuard::register);
+    
+    let timeout = Duration::from_secs(config.processing.timeout_seconds);
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => output.context("Failed to wait for job process")?,
+        // Dropping the child kills it
+        Err(_) => {
+            return Ok(Outcome::Failed(format!(
+                "Job timed out after {} seconds",
+                config.processing.timeout_seconds
+            )))
+        }
+    };
+    
+    // Progress events may precede the result, which is always the last line
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let result: Option<serde_json::Value> = stdout
+        .lines()
+        .last()
+        .and_then(|line| serde_json::from_str(line).ok());
+    let field = |name: &str| {
+        result.as_ref()
+            .and_then(|result| result.get(name))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+    
+    Ok(match field("status").as_deref() {
+        Some("succeeded") if output.status.success() => Outcome::Succeeded,
+        Some("cancelled") => Outcome::Cancelled,
+        _ => Outcome::Failed(
+            field("message").unwrap_or_else(|| format!("Job process exited with {}", output.status)),
+        ),
+    })
+}
+
+/// Unique enough for jobs enqueued by this host: time, process and a counter
+pub(crate) fn new_job_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}-{}-{}",
+        chrono::Utc::now().format("%Y%m%d%H%M%S%6f"),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Default `redis.worker_id`
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, which is what gethostname is told
+    let status = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    let length = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+    if status != 0 || length == 0 {
+        return "worker".to_string();
+    }
+    String::from_utf8_lossy(&buffer[..length]).to_string()
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
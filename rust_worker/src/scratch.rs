@@ -0,0 +1,223 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Once;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::ScratchConfig;
+
+/// Set by the usage monitor while `scratch.path` holds more than `scratch.max_size_mb`
+static OVER_LIMIT: AtomicBool = AtomicBool::new(false);
+static MONITOR: Once = Once::new();
+
+/// How often the usage monitor measures scratch space
+const MONITOR_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Directories younger than this are never stale; they may not be locked yet
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// File marking a directory that was kept on purpose, which `clean_stale` leaves alone
+const KEEP_MARKER: &str = ".keep";
+
+/// A private directory under `scratch.path` for a task's intermediate files
+///
+/// The directory and everything in it is removed when the `ScratchDir` is dropped, so
+/// intermediates go away whether the task succeeds, fails or panics. Directories are named
+/// `<prefix>-<pid>-<nanos>-<n>` and held under an exclusive flock for as long as they live;
+/// those left behind by a process that was killed are unlocked, and `clean_stale` removes
+/// them when the next worker starts, whichever host or container it runs in.
+pub struct ScratchDir {
+    path: PathBuf,
+    keep: bool,
+    limit_mb: u64,
+    /// Open handle on the directory holding its flock
+    _lease: fs::File,
+}
+
+impl ScratchDir {
+    /// Create a scratch directory, failing if `scratch.max_size_mb` is already used up
+    pub fn new(config: &ScratchConfig, prefix: &str) -> Result<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        
+        let root = Path::new(&config.path);
+        fs::create_dir_all(root)
+            .context(format!("Failed to create scratch directory {}", root.display()))?;
+        
+        if config.max_size_mb > 0 {
+            let used = directory_size(root);
+            let limit = config.max_size_mb * 1024 * 1024;
+            if used >= limit {
+                anyhow::bail!(
+                    "Scratch space {} is full ({} MB used of {} MB)",
+                    root.display(),
+                    used / (1024 * 1024),
+                    config.max_size_mb
+                );
+            }
+            start_monitor(root.to_path_buf(), limit);
+        }
+        
+        // Pids repeat across containers, so the creation time keeps names apart
+        let path = root.join(format!(
+            "{}-{}-{}-{}",
+            prefix,
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&path)
+            .context(format!("Failed to create scratch directory {}", path.display()))?;
+        let lease = fs::File::open(&path)
+            .context(format!("Failed to open scratch directory {}", path.display()))?;
+        if !try_lock(&lease) {
+            anyhow::bail!("Failed to lock scratch directory {}", path.display());
+        }
+        
+        Ok(Self {
+            path,
+            keep: false,
+            limit_mb: config.max_size_mb,
+            _lease: lease,
+        })
+    }
+    
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    
+    /// Fail once scratch space has grown past `scratch.max_size_mb`; tasks call this between
+    /// units of work that write here, and after external tools that do
+    pub fn check(&self) -> Result<()> {
+        if self.limit_mb > 0 && OVER_LIMIT.load(Ordering::Relaxed) {
+            anyhow::bail!(
+                "Scratch space {} is over its {} MB limit",
+                self.path.parent().unwrap_or(&self.path).display(),
+                self.limit_mb
+            );
+        }
+        Ok(())
+    }
+    
+    /// Path of `name` inside the directory
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().to_string()
+    }
+    
+    /// Leave the directory in place when dropped, returning its path
+    ///
+    /// The flock goes with `self`, so a marker file keeps later workers from sweeping it up.
+    pub fn keep(mut self) -> Result<PathBuf> {
+        self.keep = true;
+        fs::write(self.path.join(KEEP_MARKER), b"")
+            .context(format!("Failed to mark scratch directory {} as kept", self.path.display()))?;
+        Ok(self.path.clone())
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(path = %self.path.display(), error = %e, "Failed to remove scratch directory");
+            }
+        }
+    }
+}
+
+/// Remove scratch directories that no running worker holds a lock on
+///
+/// Drop does not run when a worker is killed or exits through `process::exit`, so each
+/// worker sweeps up after earlier ones when it starts. The lock, unlike a pid, is seen by
+/// workers in other pid namespaces sharing the directory.
+pub fn clean_stale(config: &ScratchConfig) {
+    // Without flock there is no telling whether another worker still uses a directory
+    if !cfg!(unix) {
+        return;
+    }
+    
+    let Ok(entries) = fs::read_dir(&config.path) else {
+        return;
+    };
+    
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Only names ScratchDir gives: a prefix then numeric parts
+        let mut parts = name.rsplitn(3, '-');
+        if !parts.by_ref().take(2).all(|part| part.parse::<u64>().is_ok()) || parts.next().is_none() {
+            continue;
+        }
+        let young = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map_or(true, |modified| modified.elapsed().map_or(true, |age| age < STALE_AFTER));
+        let path = entry.path();
+        if young || path.join(KEEP_MARKER).exists() {
+            continue;
+        }
+        
+        // Held until the directory is gone, so no worker can take it over meanwhile
+        let Ok(lease) = fs::File::open(&path) else {
+            continue;
+        };
+        if !try_lock(&lease) {
+            continue;
+        }
+        match fs::remove_dir_all(&path) {
+            Ok(()) => info!(path = %path.display(), "Removed stale scratch directory"),
+            Err(e) => warn!(path = %path.display(), error = %e, "Failed to remove stale scratch directory"),
+        }
+    }
+}
+
+/// Take an exclusive flock without waiting; false when another process holds one
+#[cfg(unix)]
+fn try_lock(file: &fs::File) -> bool {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: flock only reads the descriptor, which `file` keeps open for the call
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &fs::File) -> bool {
+    true
+}
+
+/// Measure scratch space in the background for the life of the process, so that `check`
+/// can fail tasks that are still writing once the limit is passed
+fn start_monitor(root: PathBuf, limit: u64) {
+    MONITOR.call_once(|| {
+        let spawned = std::thread::Builder::new()
+            .name("scratch-monitor".to_string())
+            .spawn(move || loop {
+                let over = directory_size(&root) > limit;
+                if over && !OVER_LIMIT.swap(true, Ordering::Relaxed) {
+                    warn!(path = %root.display(), limit_mb = limit / (1024 * 1024), "Scratch space is over its limit");
+                } else if !over {
+                    OVER_LIMIT.store(false, Ordering::Relaxed);
+                }
+                std::thread::sleep(MONITOR_INTERVAL);
+            });
+        if let Err(e) = spawned {
+            warn!(error = %e, "Failed to start scratch usage monitor");
+        }
+    });
+}
+
+fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
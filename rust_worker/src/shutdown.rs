@@ -0,0 +1,91 @@
+use anyhow::Result;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Wait for SIGTERM or SIGINT, returning the signal's name
+pub async fn signal() -> &'static str {
+    let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!(error = %e, "Failed to install SIGTERM handler");
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+/// Ask the running job to stop and terminate the child processes it started
+pub fn request(signal: &str) {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    info!(signal, "Shutdown requested");
+    
+    for pid in CHILDREN.lock().unwrap().iter() {
+        info!(pid, "Terminating child process");
+        // SAFETY: kill has no memory-safety preconditions; a stale pid at worst gets ESRCH
+        unsafe {
+            libc::kill(*pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Fail with a cancellation error once shutdown has been requested; long loops call this
+/// between units of work so that the job stops at a clean point
+pub fn check() -> Result<()> {
+    if requested() {
+        anyhow::bail!("Job cancelled by shutdown");
+    }
+    Ok(())
+}
+
+/// Like `Command::output`, but the child is terminated if the worker shuts down
+pub fn output(command: &mut Command) -> std::io::Result<Output> {
+    check().map_err(|e| std::io::Error::new(std::io::ErrorKind::Interrupted, e.to_string()))?;
+    
+    let child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    let pid = child.id();
+    
+    let _guard = ChildGuard::register(pid);
+    child.wait_with_output()
+}
+
+/// Registers a running child process for termination on shutdown until dropped
+pub struct ChildGuard(u32);
+
+impl ChildGuard {
+    pub fn register(pid: u32) -> Self {
+        CHILDREN.lock().unwrap().push(pid);
+        // A signal that arrived while the child was starting would otherwise miss it
+        if requested() {
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGTERM);
+            }
+        }
+        Self(pid)
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        CHILDREN.lock().unwrap().retain(|pid| *pid != self.0);
+    }
+}
@@ -0,0 +1,620 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::io::AsyncRead;
+use tracing::{info, warn};
+
+use crate::{
+    acquisition::base64_encode,
+    config::{AzureConfig, GcsConfig, StorageConfig},
+    JobPayload,
+};
+
+/// Body of a stored object, read as it arrives
+pub type ObjectStream = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Where job inputs are read from and outputs written to, selected by `storage.type`
+///
+/// Keys are file paths for `local` storage and object names in the configured bucket or
+/// container otherwise.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Name used in logs and errors
+    fn name(&self) -> &'static str;
+    
+    /// Read the object at `key` without staging it on disk
+    async fn stream(&self, key: &str) -> Result<ObjectStream>;
+    
+    /// Store the local file `path` as `key`, replacing any existing object
+    async fn put(&self, path: &Path, key: &str) -> Result<()>;
+    
+    async fn exists(&self, key: &str) -> Result<bool>;
+    
+    /// Remove `key`; a missing object is not an error
+    async fn delete(&self, key: &str) -> Result<()>;
+    
+    /// Copy the object at `key` to the local file `path`
+    async fn get(&self, key: &str, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut stream = self.stream(key).await?;
+        let mut file = tokio::fs::File::create(path)
+            .await
+            .context(format!("Failed to create {}", path.display()))?;
+        tokio::io::copy(&mut stream, &mut file)
+            .await
+            .context(format!("Failed to read {} from {} storage", key, self.name()))?;
+        Ok(())
+    }
+}
+
+pub async fn from_config(config: &StorageConfig) -> Result<Box<dyn Storage>> {
+    match config.storage_type.as_str() {
+        "local" => Ok(Box::new(LocalStorage)),
+        "s3" => s3_storage(config).await,
+        "gcs" => Ok(Box::new(GcsStorage::new(&config.gcs)?)),
+        "azure" => Ok(Box::new(AzureStorage::new(&config.azure)?)),
+        other => anyhow::bail!("Unknown storage type: {} (expected local, s3, gcs or azure)", other),
+    }
+}
+
+/// Local copies of a job's remote input and output while it runs
+///
+/// Tasks only ever see local paths: the input object is downloaded under
+/// `storage.input_path` and the output written under `storage.output_path`, then uploaded to
+/// the job's output key. Both directories are removed when this is dropped.
+pub struct Staging {
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    output_key: String,
+}
+
+impl Staging {
+    /// Fetch the job's input and point its paths at the staging directories; None for local
+    /// storage, where jobs use their paths as given
+    pub async fn prepare(
+        storage: &dyn Storage,
+        job: &mut JobPayload,
+        config: &StorageConfig,
+    ) -> Result<Option<Self>> {
+        if config.storage_type == "local" {
+            return Ok(None);
+        }
+        
+        let id = format!(
+            "job-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        );
+        let staging = Self {
+            input_dir: Path::new(&config.input_path).join(&id),
+            output_dir: Path::new(&config.output_path).join(&id),
+            output_key: job.output_path.clone(),
+        };
+        std::fs::create_dir_all(&staging.input_dir)?;
+        std::fs::create_dir_all(&staging.output_dir)?;
+        
+        // Tasks such as download_file have no input object
+        if !job.input_path.is_empty() {
+            if !storage.exists(&job.input_path).await? {
+                anyhow::bail!("Input not found in {} storage: {}", storage.name(), job.input_path);
+            }
+            let local_input = staging.input_dir.join(key_file_name(&job.input_path));
+            storage.get(&job.input_path, &local_input).await?;
+            info!(key = %job.input_path, path = %local_input.display(), "Staged input");
+            job.input_path = local_input.to_string_lossy().into_owned();
+        }
+        
+        job.output_path = staging
+            .output_dir
+            .join(key_file_name(&job.output_path))
+            .to_string_lossy()
+            .into_owned();
+        
+        Ok(Some(staging))
+    }
+    
+    /// Upload a file or directory the job wrote under the output staging directory, returning
+    /// its key; paths outside it are left where they are
+    pub async fn publish(&self, storage: &dyn Storage, path: &str) -> Result<String> {
+        let Ok(relative) = Path::new(path).strip_prefix(&self.output_dir) else {
+            warn!(path, "Output is outside the staging directory; leaving it local");
+            return Ok(path.to_string());
+        };
+        let key = self.key_for(relative);
+        
+        if Path::new(path).is_dir() {
+            for file in files_under(Path::new(path))? {
+                let relative = file.strip_prefix(&self.output_dir)?;
+                storage.put(&file, &self.key_for(relative)).await?;
+            }
+        } else {
+            storage.put(Path::new(path), &key).await?;
+        }
+        
+        info!(key = %key, storage = storage.name(), "Stored output");
+        Ok(key)
+    }
+    
+    /// The output key's directory joined with `relative`; the output file itself maps back to
+    /// the output key
+    fn key_for(&self, relative: &Path) -> String {
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        match self.output_key.rsplit_once('/') {
+            Some((parent, _)) => format!("{}/{}", parent, relative),
+            None => relative,
+        }
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        for dir in [&self.input_dir, &self.output_dir] {
+            if let Err(e) = std::fs::remove_dir_all(dir) {
+                warn!(path = %dir.display(), error = %e, "Failed to remove staging directory");
+            }
+        }
+    }
+}
+
+/// Files given as paths on the worker's filesystem
+pub struct LocalStorage;
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+    
+    async fn stream(&self, key: &str) -> Result<ObjectStream> {
+        let file = tokio::fs::File::open(key)
+            .await
+            .context(format!("Failed to open {}", key))?;
+        Ok(Box::pin(file))
+    }
+    
+    async fn put(&self, path: &Path, key: &str) -> Result<()> {
+        if path == Path::new(key) {
+            return Ok(());
+        }
+        if let Some(parent) = Path::new(key).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::copy(path, key)
+            .await
+            .context(format!("Failed to copy {} to {}", path.display(), key))?;
+        Ok(())
+    }
+    
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(key).await?)
+    }
+    
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(key).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).context(format!("Failed to delete {}", key))
+            }
+            _ => Ok(()),
+        }
+    }
+    
+    async fn get(&self, key: &str, path: &Path) -> Result<()> {
+        self.put(Path::new(key), &path.to_string_lossy()).await
+    }
+}
+
+/// Objects in `storage.s3.bucket`, with credentials from the environment
+#[cfg(feature = "s3")]
+pub struct S3Storage {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    region: String,
+    endpoint: Option<String>,
+}
+
+#[cfg(feature = "s3")]
+async fn s3_storage(config: &StorageConfig) -> Result<Box<dyn Storage>> {
+    if config.s3.bucket.is_empty() {
+        anyhow::bail!("storage.s3.bucket must be set");
+    }
+    let endpoint = (!config.s3.endpoint.is_empty()).then(|| config.s3.endpoint.clone());
+    
+    let sdk_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .region(aws_config::Region::new(config.s3.region.clone()))
+        .load()
+        .await;
+    let mut builder = aws_sdk_s3::config::Builder::from(&sdk_config);
+    if let Some(endpoint) = &endpoint {
+        builder = builder.endpoint_url(endpoint).force_path_style(true);
+    }
+    
+    Ok(Box::new(S3Storage {
+        client: aws_sdk_s3::Client::from_conf(builder.build()),
+        bucket: config.s3.bucket.clone(),
+        region: config.s3.region.clone(),
+        endpoint,
+    }))
+}
+
+#[cfg(not(feature = "s3"))]
+async fn s3_storage(_config: &StorageConfig) -> Result<Box<dyn Storage>> {
+    anyhow::bail!("storage.type = \"s3\" needs the worker built with --features s3")
+}
+
+#[cfg(feature = "s3")]
+#[async_trait]
+impl Storage for S3Storage {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+    
+    async fn stream(&self, key: &str) -> Result<ObjectStream> {
+        let object = self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .context(format!("Failed to get s3://{}/{}", self.bucket, key))?;
+        Ok(Box::pin(object.body.into_async_read()))
+    }
+    
+    async fn put(&self, path: &Path, key: &str) -> Result<()> {
+        // Shares upload_file's multipart upload and ETag check
+        let size = tokio::fs::metadata(path).await?.len();
+        let path = path.to_string_lossy();
+        let upload = crate::acquisition::upload_to_bucket(
+            &path,
+            &self.bucket,
+            key,
+            &self.region,
+            self.endpoint.clone(),
+            "application/octet-stream",
+            (size > MULTIPART_THRESHOLD).then_some(PART_SIZE),
+        )
+        .await?;
+        upload.verify()?;
+        Ok(())
+    }
+    
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match self.client.head_object().bucket(&self.bucket).key(key).send().await {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().map_or(false, |e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(e).context(format!("Failed to stat s3://{}/{}", self.bucket, key)),
+        }
+    }
+    
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .context(format!("Failed to delete s3://{}/{}", self.bucket, key))?;
+        Ok(())
+    }
+}
+
+/// Objects in `storage.gcs.bucket`, through the JSON API
+pub struct GcsStorage {
+    client: reqwest::Client,
+    bucket: String,
+    endpoint: String,
+    token_env: String,
+}
+
+impl GcsStorage {
+    fn new(config: &GcsConfig) -> Result<Self> {
+        if config.bucket.is_empty() {
+            anyhow::bail!("storage.gcs.bucket must be set");
+        }
+        Ok(Self {
+            client: reqwest::Client::new(),
+            bucket: config.bucket.clone(),
+            endpoint: config.endpoint.trim_end_matches('/').to_string(),
+            token_env: config.token_env.clone(),
+        })
+    }
+    
+    /// Access token from `token_env`, or from the metadata server of the VM or pod, which
+    /// refreshes it before it expires
+    async fn token(&self) -> Result<String> {
+        if let Ok(token) = std::env::var(&self.token_env) {
+            return Ok(token);
+        }
+        
+        #[derive(serde::Deserialize)]
+        struct MetadataToken {
+            access_token: String,
+        }
+        let response = self.client
+            .get("http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token")
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .context(format!("{} is not set and the GCE metadata server is unreachable", self.token_env))?;
+        let body = check_response(response, "GCS token request").await?.text().await?;
+        let token: MetadataToken = serde_json::from_str(&body).context("Invalid token response")?;
+        Ok(token.access_token)
+    }
+    
+    fn object_url(&self, key: &str) -> Result<reqwest::Url> {
+        let mut url = reqwest::Url::parse(&self.endpoint).context("Invalid storage.gcs.endpoint")?;
+        // Object names go in a single path segment, with their slashes escaped
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid storage.gcs.endpoint"))?
+            .extend(["storage", "v1", "b", &self.bucket, "o", key]);
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl Storage for GcsStorage {
+    fn name(&self) -> &'static str {
+        "gcs"
+    }
+    
+    async fn stream(&self, key: &str) -> Result<ObjectStream> {
+        let mut url = self.object_url(key)?;
+        url.query_pairs_mut().append_pair("alt", "media");
+        let response = self.client
+            .get(url)
+            .bearer_auth(self.token().await?)
+            .send()
+            .await
+            .context(format!("Failed to get gs://{}/{}", self.bucket, key))?;
+        Ok(response_stream(check_response(response, "GCS download").await?))
+    }
+    
+    async fn put(&self, path: &Path, key: &str) -> Result<()> {
+        let mut url = reqwest::Url::parse(&format!("{}/upload/storage/v1/b/{}/o", self.endpoint, self.bucket))
+            .context("Invalid storage.gcs.endpoint")?;
+        url.query_pairs_mut()
+            .append_pair("uploadType", "media")
+            .append_pair("name", key);
+        
+        let file = tokio::fs::File::open(path)
+            .await
+            .context(format!("Failed to open {}", path.display()))?;
+        let size = file.metadata().await?.len();
+        let response = self.client
+            .post(url)
+            .bearer_auth(self.token().await?)
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .body(reqwest::Body::from(file))
+            .send()
+            .await
+            .context(format!("Failed to upload gs://{}/{}", self.bucket, key))?;
+        check_response(response, "GCS upload").await?;
+        Ok(())
+    }
+    
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let response = self.client
+            .get(self.object_url(key)?)
+            .bearer_auth(self.token().await?)
+            .send()
+            .await
+            .context(format!("Failed to stat gs://{}/{}", self.bucket, key))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check_response(response, "GCS metadata request").await?;
+        Ok(true)
+    }
+    
+    async fn delete(&self, key: &str) -> Result<()> {
+        let response = self.client
+            .delete(self.object_url(key)?)
+            .bearer_auth(self.token().await?)
+            .send()
+            .await
+            .context(format!("Failed to delete gs://{}/{}", self.bucket, key))?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            check_response(response, "GCS delete").await?;
+        }
+        Ok(())
+    }
+}
+
+/// Block blobs in `storage.azure.container`, authorised by a SAS token
+pub struct AzureStorage {
+    client: reqwest::Client,
+    endpoint: String,
+    container: String,
+    sas_token: String,
+}
+
+/// Blobs up to this size are uploaded in a single Put Blob request
+const AZURE_SINGLE_PUT_LIMIT: u64 = 256 * 1024 * 1024;
+const AZURE_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+const AZURE_API_VERSION: &str = "2021-08-06";
+
+impl AzureStorage {
+    fn new(config: &AzureConfig) -> Result<Self> {
+        if config.account.is_empty() || config.container.is_empty() {
+            anyhow::bail!("storage.azure.account and storage.azure.container must be set");
+        }
+        let sas_token = std::env::var(&config.sas_token_env)
+            .context(format!("{} must hold a SAS token for Azure storage", config.sas_token_env))?;
+        let endpoint = if config.endpoint.is_empty() {
+            format!("https://{}.blob.core.windows.net", config.account)
+        } else {
+            config.endpoint.trim_end_matches('/').to_string()
+        };
+        
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint,
+            container: config.container.clone(),
+            sas_token: sas_token.trim_start_matches('?').to_string(),
+        })
+    }
+    
+    fn blob_url(&self, key: &str) -> Result<reqwest::Url> {
+        let mut url = reqwest::Url::parse(&self.endpoint).context("Invalid storage.azure.endpoint")?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid storage.azure.endpoint"))?
+            .push(&self.container)
+            .extend(key.split('/'));
+        url.set_query(Some(&self.sas_token));
+        Ok(url)
+    }
+    
+    fn request(&self, method: reqwest::Method, url: reqwest::Url) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, url)
+            .header("x-ms-version", AZURE_API_VERSION)
+    }
+    
+    /// Upload in blocks and commit them, for blobs too large for one request
+    async fn put_blocks(&self, path: &Path, key: &str) -> Result<()> {
+        let mut file = std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+        let mut buffer = vec![0u8; AZURE_BLOCK_SIZE];
+        let mut block_list = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>");
+        
+        for index in 0u32.. {
+            let length = crate::binary::read_full(&mut file, &mut buffer)?;
+            if length == 0 {
+                break;
+            }
+            // Block ids must all have the same length
+            let block_id = base64_encode(format!("{:08}", index).as_bytes());
+            
+            let mut url = self.blob_url(key)?;
+            url.query_pairs_mut()
+                .append_pair("comp", "block")
+                .append_pair("blockid", &block_id);
+            let response = self.request(reqwest::Method::PUT, url)
+                .body(buffer[..length].to_vec())
+                .send()
+                .await
+                .context(format!("Failed to upload block {} of {}", index, key))?;
+            check_response(response, "Azure block upload").await?;
+            
+            block_list.push_str(&format!("<Latest>{}</Latest>", block_id));
+            if length < AZURE_BLOCK_SIZE {
+                break;
+            }
+        }
+        block_list.push_str("</BlockList>");
+        
+        let mut url = self.blob_url(key)?;
+        url.query_pairs_mut().append_pair("comp", "blocklist");
+        let response = self.request(reqwest::Method::PUT, url)
+            .header(reqwest::header::CONTENT_TYPE, "application/xml")
+            .body(block_list)
+            .send()
+            .await
+            .context(format!("Failed to commit blocks of {}", key))?;
+        check_response(response, "Azure block list commit").await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for AzureStorage {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+    
+    async fn stream(&self, key: &str) -> Result<ObjectStream> {
+        let response = self.request(reqwest::Method::GET, self.blob_url(key)?)
+            .send()
+            .await
+            .context(format!("Failed to get blob {}", key))?;
+        Ok(response_stream(check_response(response, "Azure download").await?))
+    }
+    
+    async fn put(&self, path: &Path, key: &str) -> Result<()> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .context(format!("Failed to open {}", path.display()))?;
+        let size = file.metadata().await?.len();
+        if size > AZURE_SINGLE_PUT_LIMIT {
+            return self.put_blocks(path, key).await;
+        }
+        
+        let response = self.request(reqwest::Method::PUT, self.blob_url(key)?)
+            .header("x-ms-blob-type", "BlockBlob")
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .body(reqwest::Body::from(file))
+            .send()
+            .await
+            .context(format!("Failed to upload blob {}", key))?;
+        check_response(response, "Azure upload").await?;
+        Ok(())
+    }
+    
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let response = self.request(reqwest::Method::HEAD, self.blob_url(key)?)
+            .send()
+            .await
+            .context(format!("Failed to stat blob {}", key))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check_response(response, "Azure metadata request").await?;
+        Ok(true)
+    }
+    
+    async fn delete(&self, key: &str) -> Result<()> {
+        let response = self.request(reqwest::Method::DELETE, self.blob_url(key)?)
+            .send()
+            .await
+            .context(format!("Failed to delete blob {}", key))?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            check_response(response, "Azure delete").await?;
+        }
+        Ok(())
+    }
+}
+
+/// S3 objects above this size are uploaded in parts
+#[cfg(feature = "s3")]
+const MULTIPART_THRESHOLD: u64 = 64 * 1024 * 1024;
+#[cfg(feature = "s3")]
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Fail with the status and body of an unsuccessful response
+async fn check_response(response: reqwest::Response, what: &str) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    anyhow::bail!("{} failed: HTTP {}: {}", what, status, body.trim())
+}
+
+fn response_stream(response: reqwest::Response) -> ObjectStream {
+    let body = response.bytes_stream().map_err(std::io::Error::other);
+    Box::pin(tokio_util::io::StreamReader::new(body))
+}
+
+/// Last component of a key, used to name its staged copy
+fn key_file_name(key: &str) -> &str {
+    key.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or("object")
+}
+
+fn files_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(files_under(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,139 @@
+use tracing::Span;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{config::TelemetryConfig, JobPayload, JobResult, JobStatus};
+
+/// Install the log subscriber, with OTLP trace export when `telemetry.otlp_endpoint` is set
+///
+/// Each job becomes a `job` span with `download`, `process` and `upload` children; media
+/// tasks add `decode` and `encode` spans inside `process`. A job's `traceparent` (W3C trace
+/// context) makes its span a child of the enqueuing service's span.
+pub fn init(config: &TelemetryConfig) {
+    let registry = tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "info".into()),
+        )
+        .with(tracing_subscriber::fmt::layer().json());
+    
+    #[cfg(feature = "otel")]
+    {
+        let (layer, error) = match otel::layer(config) {
+            Ok(layer) => (layer, None),
+            Err(e) => (None, Some(e)),
+        };
+        registry.with(layer).init();
+        if let Some(e) = error {
+            tracing::warn!(error = %e, "Failed to start OTLP trace export");
+        }
+    }
+    
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        if !config.otlp_endpoint.is_empty() {
+            tracing::warn!("telemetry.otlp_endpoint is set but the worker was built without the otel feature");
+        }
+    }
+}
+
+/// The root span of one job, continuing the trace in its `traceparent` if there is one
+pub fn job_span(job: &JobPayload) -> Span {
+    let span = tracing::info_span!(
+        "job",
+        task = %job.task,
+        job_id = job.job_id.as_deref().unwrap_or_default(),
+        job.status = tracing::field::Empty,
+        otel.status_code = tracing::field::Empty,
+    );
+    
+    #[cfg(feature = "otel")]
+    if let Some(traceparent) = &job.traceparent {
+        otel::set_parent(&span, traceparent);
+    }
+    
+    span
+}
+
+/// Record the job's outcome on its span, end it and flush pending spans
+pub fn finish(span: Span, result: &JobResult) {
+    let status = match result.status {
+        JobStatus::Succeeded => "succeeded",
+        JobStatus::Failed => "failed",
+        JobStatus::Cancelled => "cancelled",
+    };
+    span.record("job.status", status);
+    span.record("otel.status_code", if result.success { "OK" } else { "ERROR" });
+    drop(span);
+    shutdown();
+}
+
+/// Flush pending spans; call before exiting
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// W3C trace context of the current span, for jobs this one enqueues
+pub fn traceparent() -> Option<String> {
+    #[cfg(feature = "otel")]
+    {
+        otel::traceparent(&Span::current())
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        None
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use anyhow::Result;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+    use std::collections::HashMap;
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::registry::LookupSpan;
+    
+    use crate::config::TelemetryConfig;
+    
+    pub fn layer<S>(config: &TelemetryConfig) -> Result<Option<impl tracing_subscriber::Layer<S>>>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        if config.otlp_endpoint.is_empty() {
+            return Ok(None);
+        }
+        
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&config.otlp_endpoint),
+            )
+            .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                KeyValue::new("service.name", config.service_name.clone()),
+            ])))
+            .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+        
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+    
+    pub fn set_parent(span: &Span, traceparent: &str) {
+        let carrier = HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+        let context = opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&carrier));
+        span.set_parent(context);
+    }
+    
+    pub fn traceparent(span: &Span) -> Option<String> {
+        let mut carrier = HashMap::new();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&span.context(), &mut carrier)
+        });
+        carrier.remove("traceparent")
+    }
+}
@@ -1,16 +1,45 @@
+use rand::RngCore;
 use anyhow::{Context, Result};
 use ffmpeg_next as ffmpeg;
+use serde::{Deserialize, Serialize};
 use std::path::Path;
-use tracing::info;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
-use crate::{config::Config, JobPayload};
+use crate::{
+    acquisition::redact_url,
+    audio::AudioTranscoder,
+    config::Config,
+    follow::{FollowOptions, FollowedInput},
+    metrics,
+    progress::ProgressReporter,
+    scratch::ScratchDir,
+    shutdown,
+    JobPayload,
+};
 
 pub fn init_ffmpeg() -> Result<()> {
     ffmpeg::init().context("Failed to initialize FFmpeg")?;
     Ok(())
 }
 
-pub async fn transcode_video_native(job: &JobPayload, _config: &Config) -> Result<String> {
+/// Transcode the video stream, by default to H.265
+///
+/// `quality_mode` picks the rate control: `bitrate` (default) targets `bitrate`, while `crf`
+/// and `cq` target a constant `quality` (default: the encoder's usual middle value, e.g. 28
+/// for libx265), optionally capped at `max_bitrate`. `preset` takes the x264 names (ultrafast
+/// to placebo) and is mapped onto the speed controls of the AV1 and VP9 encoders (libsvtav1,
+/// libaom-av1, libvpx-vp9), which also take `cpu_used`, `row_mt` and tile settings (see
+/// `speed_options`).
+/// `passes: 2` runs a first analysis pass and a second pass using its statistics (bitrate
+/// mode only), for tighter bitrate targets than a single pass achieves. The pixel format,
+/// colour tags and HDR metadata of the output follow the source unless overridden (see
+/// `PictureFormat`).
+///
+/// With `resume: true` a single-pass encode checkpoints every `checkpoint_seconds` (default:
+/// 60) of video, and a rerun of the same job continues from the last checkpoint instead of
+/// starting over.
+pub async fn transcode_video_native(job: &JobPayload, config: &Config) -> Result<String> {
     info!("Transcoding video using ffmpeg-next");
     
     let bitrate = job.params.get("bitrate")
@@ -21,28 +50,887 @@ pub async fn transcode_video_native(job: &JobPayload, _config: &Config) -> Resul
         .and_then(|v| v.as_str())
         .unwrap_or("libx265");
     
+    let quality_mode = job.params.get("quality_mode")
+        .and_then(|v| v.as_str())
+        .unwrap_or("bitrate");
+    
     // Parse bitrate (e.g., "1M" -> 1000000)
     let bitrate_value = parse_bitrate(bitrate)?;
     
+    let mut options = ffmpeg::Dictionary::new();
+    for (key, value) in transcode_options(job, codec_name, quality_mode)? {
+        options.set(&key, &value);
+    }
+    
+    let passes = job.params.get("passes")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(1);
+    
+    let resume = job.params.get("resume")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let checkpoint_seconds = job.params.get("checkpoint_seconds")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(60.0);
+    
+    let keyframes = ForcedKeyframes::from_params(&job.params)?;
+    let passthrough = StreamPassthrough::from_params(&job.params)?;
+    let mut progress = ProgressReporter::from_params(&job.task, &job.params)?;
+    let bit_rate = (quality_mode == "bitrate").then_some(bitrate_value);
+    let frame_index = match passes {
+        1 if resume => {
+            if checkpoint_seconds <= 0.0 {
+                anyhow::bail!("checkpoint_seconds must be positive");
+            }
+            encode_video_resumable(
+                job, codec_name, bit_rate, &options, &keyframes, &passthrough.segment_audio(&job.input_path)?,
+                checkpoint_seconds, &mut progress,
+            )?
+        }
+        2 if resume => anyhow::bail!("resume is only supported for single-pass encodes"),
+        1 => encode_video_pass(job, codec_name, bit_rate, &options, &keyframes, &passthrough, None, &mut progress)?,
+        2 => {
+            if bit_rate.is_none() {
+                anyhow::bail!("Two-pass encoding targets a bitrate; use quality_mode bitrate");
+            }
+            // x264 and x265 also leave macroblock-tree files next to their stats; the
+            // scratch directory takes them all with it
+            let scratch = ScratchDir::new(&config.scratch, "passlog")?;
+            let stats_path = scratch.file("passlog");
+            encode_video_pass(job, codec_name, bit_rate, &options, &keyframes, &passthrough, Some((1, &stats_path)), &mut progress)?;
+            encode_video_pass(job, codec_name, bit_rate, &options, &keyframes, &passthrough, Some((2, &stats_path)), &mut progress)?
+        }
+        _ => anyhow::bail!("passes must be 1 or 2"),
+    };
+    
+    info!("Transcoding complete: {} frames processed", frame_index);
+    Ok(job.output_path.clone())
+}
+
+/// Encoder options for the rate control and preset params of the transcode tasks
+pub(crate) fn transcode_options(job: &JobPayload, codec_name: &str, quality_mode: &str) -> Result<Vec<(String, String)>> {
+    let mut options = Vec::new();
+    if quality_mode != "bitrate" {
+        let quality = job.params.get("quality").and_then(|v| v.as_f64());
+        options.extend(constant_quality_options(codec_name, quality_mode, quality)?);
+        if let Some(max_bitrate) = job.params.get("max_bitrate").and_then(|v| v.as_str()) {
+            let max_bitrate = parse_bitrate(max_bitrate)?;
+            options.push(("maxrate".to_string(), max_bitrate.to_string()));
+            options.push(("bufsize".to_string(), (max_bitrate * 2).to_string()));
+        }
+    }
+    options.extend(speed_options(&job.params, codec_name)?);
+    options.extend(keyframe_options(&job.params, codec_name)?);
+    Ok(options)
+}
+
+/// Encoder options for the `preset`, `cpu_used`, `row_mt`, `tile_columns` and `tile_rows` params
+///
+/// `preset` takes the x264 names (ultrafast to placebo) for every software encoder and maps
+/// them onto each encoder's own speed control: SVT-AV1's numbered presets (a number from 0 to
+/// 13 is also accepted), libaom's `cpu-used`, and libvpx's `deadline` with `cpu-used`, going
+/// to realtime mode for the two fastest. `cpu_used` overrides the mapped value for libaom and
+/// libvpx. The AV1 and VP9 encoders get row-based multithreading unless `row_mt` is false, and
+/// `tile_columns`/`tile_rows` (log2, so 2 means four columns) split the picture into tiles
+/// that encode and decode in parallel. Other encoders receive `preset` unchanged.
+fn speed_options(params: &serde_json::Value, codec_name: &str) -> Result<Vec<(String, String)>> {
+    let mut options = Vec::new();
+    let preset = match params.get("preset") {
+        Some(serde_json::Value::Number(number)) => Some(number.to_string()),
+        Some(value) => Some(value.as_str().context("preset must be a string")?.to_string()),
+        None => None,
+    };
+    let x264_index = |preset: &str| -> Result<usize> {
+        X264_PRESETS.iter().position(|&name| name == preset).with_context(|| {
+            format!("Unknown {} preset: {} (expected one of {})", codec_name, preset, X264_PRESETS.join(", "))
+        })
+    };
+    
+    match codec_name {
+        "libx264" | "libx265" => {
+            if let Some(preset) = preset {
+                x264_index(&preset)?;
+                options.push(("preset".to_string(), preset));
+            }
+        }
+        "libsvtav1" => {
+            if let Some(preset) = preset {
+                let number = match preset.parse::<u32>() {
+                    Ok(number) if number <= 13 => number,
+                    Ok(number) => anyhow::bail!("libsvtav1 preset {} is outside 0..=13", number),
+                    Err(_) => SVTAV1_PRESETS[x264_index(&preset)?],
+                };
+                options.push(("preset".to_string(), number.to_string()));
+            }
+        }
+        "libaom-av1" => {
+            let cpu_used = match preset {
+                Some(preset) => Some(AOM_CPU_USED[x264_index(&preset)?]),
+                None => None,
+            };
+            if let Some(cpu_used) = cpu_used_param(params, (0, 8))?.or(cpu_used) {
+                options.push(("cpu-used".to_string(), cpu_used.to_string()));
+            }
+        }
+        "libvpx" | "libvpx-vp9" => {
+            let (deadline, cpu_used) = match preset {
+                Some(preset) => {
+                    let (deadline, cpu_used) = VPX_SPEEDS[x264_index(&preset)?];
+                    (Some(deadline), Some(cpu_used))
+                }
+                // libvpx otherwise runs at its slowest good-quality setting
+                None => (Some("good"), Some(2)),
+            };
+            let range = if codec_name == "libvpx" { (-16, 16) } else { (-8, 8) };
+            if let Some(deadline) = deadline {
+                options.push(("deadline".to_string(), deadline.to_string()));
+            }
+            if let Some(cpu_used) = cpu_used_param(params, range)?.or(cpu_used) {
+                options.push(("cpu-used".to_string(), cpu_used.to_string()));
+            }
+        }
+        _ => {
+            if let Some(preset) = preset {
+                options.push(("preset".to_string(), preset));
+            }
+        }
+    }
+    
+    let tiles = |name: &str| -> Result<Option<u64>> {
+        match params.get(name) {
+            Some(value) => Ok(Some(
+                value.as_u64()
+                    .filter(|&tiles| tiles <= 6)
+                    .context(format!("{} must be a log2 tile count from 0 to 6", name))?,
+            )),
+            None => Ok(None),
+        }
+    };
+    let tile_columns = tiles("tile_columns")?;
+    let tile_rows = tiles("tile_rows")?;
+    let row_mt = params.get("row_mt").and_then(|v| v.as_bool()).unwrap_or(true);
+    match codec_name {
+        "libaom-av1" | "libvpx-vp9" => {
+            options.push(("row-mt".to_string(), (row_mt as u8).to_string()));
+            if let Some(columns) = tile_columns {
+                options.push(("tile-columns".to_string(), columns.to_string()));
+            }
+            if let Some(rows) = tile_rows {
+                options.push(("tile-rows".to_string(), rows.to_string()));
+            }
+        }
+        "libsvtav1" => {
+            let svt_params: Vec<String> = [("tile-columns", tile_columns), ("tile-rows", tile_rows)]
+                .into_iter()
+                .filter_map(|(key, value)| Some(format!("{}={}", key, value?)))
+                .collect();
+            if !svt_params.is_empty() {
+                options.push(("svtav1-params".to_string(), svt_params.join(":")));
+            }
+        }
+        _ => {}
+    }
+    Ok(options)
+}
+
+fn cpu_used_param(params: &serde_json::Value, range: (i64, i64)) -> Result<Option<i64>> {
+    match params.get("cpu_used") {
+        Some(value) => Ok(Some(
+            value.as_i64()
+                .filter(|cpu_used| (range.0..=range.1).contains(cpu_used))
+                .context(format!("cpu_used must be a whole number from {} to {}", range.0, range.1))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Encoder options for the `gop`, `closed_gop` and `scene_cut` params of the transcode tasks
+///
+/// HLS/DASH players only switch cleanly between renditions whose keyframes line up, which
+/// takes the same fixed GOP in each: set `gop` with `scene_cut: false` (or force keyframes
+/// at the same times with `keyframe_seconds`), and `closed_gop: true` so that no frame of a
+/// segment refers to the one before it.
+fn keyframe_options(params: &serde_json::Value, codec_name: &str) -> Result<Vec<(String, String)>> {
+    let mut options = Vec::new();
+    // x264 and x265 take their own settings through one `-params` option
+    let mut x26x_params = Vec::new();
+    
+    let gop = match params.get("gop") {
+        Some(gop) => Some(
+            gop.as_u64()
+                .filter(|&gop| gop > 0)
+                .context("gop must be a positive number of frames")?,
+        ),
+        None => None,
+    };
+    if let Some(gop) = gop {
+        options.push(("g".to_string(), gop.to_string()));
+    }
+    
+    match params.get("closed_gop").and_then(|v| v.as_bool()) {
+        Some(true) => {
+            options.push(("flags".to_string(), "+cgop".to_string()));
+            x26x_params.push("open-gop=0");
+        }
+        Some(false) => x26x_params.push("open-gop=1"),
+        None => {}
+    }
+    
+    if !params.get("scene_cut").and_then(|v| v.as_bool()).unwrap_or(true) {
+        x26x_params.push("scenecut=0");
+        // Without scene cuts a fixed GOP also needs its minimum raised to the maximum
+        if let Some(gop) = gop {
+            options.push(("keyint_min".to_string(), gop.to_string()));
+        }
+    }
+    
+    if params.get("force_keyframes").is_some() || params.get("keyframe_seconds").is_some() {
+        // Make forced keyframes IDR frames, which segments can start on
+        if matches!(codec_name, "libx264" | "libx265") {
+            options.push(("forced-idr".to_string(), "1".to_string()));
+        }
+    }
+    
+    match codec_name {
+        "libx264" | "libx265" if !x26x_params.is_empty() => {
+            options.push((format!("{}-params", &codec_name[3..]), x26x_params.join(":")));
+        }
+        _ => {}
+    }
+    Ok(options)
+}
+
+/// Keyframes forced by the `force_keyframes` (times in seconds) and `keyframe_seconds`
+/// (interval) params of the transcode tasks
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ForcedKeyframes {
+    times: Vec<f64>,
+    interval: Option<f64>,
+    /// Time of the previous frame, in seconds
+    previous: Option<f64>,
+}
+
+impl ForcedKeyframes {
+    pub fn from_params(params: &serde_json::Value) -> Result<Self> {
+        let mut times = Vec::new();
+        if let Some(value) = params.get("force_keyframes") {
+            let values = value.as_array().context("force_keyframes must be an array of seconds")?;
+            for time in values {
+                times.push(time.as_f64().context("force_keyframes must be an array of seconds")?);
+            }
+            times.sort_by(|a, b| a.total_cmp(b));
+        }
+        
+        let interval = params.get("keyframe_seconds").and_then(|v| v.as_f64());
+        if interval.map_or(false, |interval| interval <= 0.0) {
+            anyhow::bail!("keyframe_seconds must be positive");
+        }
+        
+        Ok(Self { times, interval, previous: None })
+    }
+    
+    /// Set the picture type the encoder gets for `frame`: a keyframe if a forced time has
+    /// been reached since the previous frame, otherwise the encoder's choice
+    ///
+    /// Decoded frames carry the source's picture types, which encoders such as x264 would
+    /// otherwise copy, so every frame is reset.
+    pub fn apply(&mut self, frame: &mut ffmpeg::util::frame::video::Video, time_base: ffmpeg::Rational) {
+        // Allow for timestamps that land a hair before an exact multiple of the interval
+        let seconds = frame.pts().unwrap_or(0) as f64 * f64::from(time_base) + 1e-6;
+        let crossed = |time: f64| time <= seconds && self.previous.map_or(true, |previous| time > previous);
+        let due = self.times.iter().any(|&time| crossed(time))
+            || self.interval.map_or(false, |interval| {
+                self.previous.map_or(true, |previous| (seconds / interval).floor() > (previous / interval).floor())
+            });
+        
+        frame.set_kind(if due { ffmpeg::picture::Type::I } else { ffmpeg::picture::Type::None });
+        self.previous = Some(seconds);
+    }
+}
+
+/// Pixel format, colour tags and HDR metadata of the transcode tasks' video output
+///
+/// `pixel_format` picks the encoded pixel format (default: the source's), e.g. `yuv420p10le`
+/// for 10-bit output. `color_primaries` (`bt709`, `bt2020`, `smpte432`, ...), `color_transfer`
+/// (`bt709`, `smpte2084`, `arib-std-b67`, ...), `color_space` (`bt709`, `bt2020nc`, ...) and
+/// `color_range` (`tv` or `pc`) override the colour tags, which are otherwise carried over from
+/// the source. Unless `hdr_metadata` is false, the source's HDR10 mastering display and
+/// content light level metadata is carried over as well: into the bitstream for libx265 and
+/// libsvtav1, and into the container for every codec.
+#[derive(Clone, Default)]
+pub(crate) struct PictureFormat {
+    pixel_format: Option<ffmpeg::format::Pixel>,
+    primaries: Option<ffmpeg::ffi::AVColorPrimaries>,
+    transfer: Option<ffmpeg::ffi::AVColorTransferCharacteristic>,
+    space: Option<ffmpeg::ffi::AVColorSpace>,
+    range: Option<ffmpeg::ffi::AVColorRange>,
+    hdr_metadata: bool,
+    /// Colour tags and HDR metadata of the source, once known
+    source: Option<ColorTags>,
+    mastering: Option<ffmpeg::ffi::AVMasteringDisplayMetadata>,
+    light_level: Option<ffmpeg::ffi::AVContentLightMetadata>,
+}
+
+impl PictureFormat {
+    pub(crate) fn from_params(params: &serde_json::Value) -> Result<Self> {
+        use ffmpeg::ffi::{AVColorPrimaries as P, AVColorRange as R, AVColorSpace as S, AVColorTransferCharacteristic as T};
+        
+        let pixel_format = match params.get("pixel_format").and_then(|v| v.as_str()) {
+            Some(name) => Some(
+                name.parse::<ffmpeg::format::Pixel>()
+                    .map_err(|_| anyhow::anyhow!("Unknown pixel format: {}", name))?,
+            ),
+            None => None,
+        };
+        
+        let name = |key: &str| params.get(key).and_then(|v| v.as_str());
+        let unknown = |key: &str, value: &str| anyhow::anyhow!("Unknown {}: {}", key, value);
+        
+        let primaries = name("color_primaries")
+            .map(|value| match value {
+                "bt709" => Ok(P::AVCOL_PRI_BT709),
+                "bt2020" => Ok(P::AVCOL_PRI_BT2020),
+                "smpte432" | "p3" => Ok(P::AVCOL_PRI_SMPTE432),
+                "smpte431" => Ok(P::AVCOL_PRI_SMPTE431),
+                "smpte170m" => Ok(P::AVCOL_PRI_SMPTE170M),
+                "bt470bg" => Ok(P::AVCOL_PRI_BT470BG),
+                other => Err(unknown("color_primaries", other)),
+            })
+            .transpose()?;
+        let transfer = name("color_transfer")
+            .map(|value| match value {
+                "bt709" => Ok(T::AVCOL_TRC_BT709),
+                "smpte2084" | "pq" => Ok(T::AVCOL_TRC_SMPTE2084),
+                "arib-std-b67" | "hlg" => Ok(T::AVCOL_TRC_ARIB_STD_B67),
+                "bt2020-10" => Ok(T::AVCOL_TRC_BT2020_10),
+                "bt2020-12" => Ok(T::AVCOL_TRC_BT2020_12),
+                "smpte170m" => Ok(T::AVCOL_TRC_SMPTE170M),
+                "iec61966-2-1" | "srgb" => Ok(T::AVCOL_TRC_IEC61966_2_1),
+                "linear" => Ok(T::AVCOL_TRC_LINEAR),
+                other => Err(unknown("color_transfer", other)),
+            })
+            .transpose()?;
+        let space = name("color_space")
+            .map(|value| match value {
+                "bt709" => Ok(S::AVCOL_SPC_BT709),
+                "bt2020nc" => Ok(S::AVCOL_SPC_BT2020_NCL),
+                "bt2020c" => Ok(S::AVCOL_SPC_BT2020_CL),
+                "smpte170m" => Ok(S::AVCOL_SPC_SMPTE170M),
+                "bt470bg" => Ok(S::AVCOL_SPC_BT470BG),
+                other => Err(unknown("color_space", other)),
+            })
+            .transpose()?;
+        let range = name("color_range")
+            .map(|value| match value {
+                "tv" | "limited" => Ok(R::AVCOL_RANGE_MPEG),
+                "pc" | "full" => Ok(R::AVCOL_RANGE_JPEG),
+                other => Err(unknown("color_range", other)),
+            })
+            .transpose()?;
+        
+        Ok(Self {
+            pixel_format,
+            primaries,
+            transfer,
+            space,
+            range,
+            hdr_metadata: params.get("hdr_metadata").and_then(|v| v.as_bool()).unwrap_or(true),
+            ..Default::default()
+        })
+    }
+    
+    /// Take the source's colour tags and HDR metadata from its video stream
+    pub(crate) fn with_source(mut self, stream: &ffmpeg::format::stream::Stream) -> Self {
+        use ffmpeg::ffi::AVPacketSideDataType as Kind;
+        
+        self.source = Some(ColorTags::of_stream(stream));
+        if self.hdr_metadata {
+            self.mastering = stream_side_data(stream, Kind::AV_PKT_DATA_MASTERING_DISPLAY_METADATA);
+            self.light_level = stream_side_data(stream, Kind::AV_PKT_DATA_CONTENT_LIGHT_LEVEL);
+        }
+        self
+    }
+    
+    /// Set the pixel format and colour tags of `encoder`, which gets frames from `decoder`, and
+    /// add the HDR options `codec_name` understands to `options`
+    fn configure(
+        &self,
+        decoder: &ffmpeg::decoder::Video,
+        codec_name: &str,
+        encoder: &mut ffmpeg::encoder::video::Video,
+        options: &mut ffmpeg::Dictionary,
+    ) -> Result<()> {
+        let format = self.pixel_format.unwrap_or(decoder.format());
+        let supported: Vec<ffmpeg::format::Pixel> = ffmpeg::encoder::find_by_name(codec_name)
+            .context(format!("Codec {} not found", codec_name))?
+            .video()?
+            .formats()
+            .map(|formats| formats.collect())
+            .unwrap_or_default();
+        if !supported.is_empty() && !supported.contains(&format) {
+            anyhow::bail!(
+                "Codec {} cannot encode {:?}; set pixel_format to one of {:?}",
+                codec_name,
+                format,
+                supported
+            );
+        }
+        encoder.set_format(format);
+        
+        let Some(source) = self.source else {
+            return Ok(());
+        };
+        let tags = ColorTags {
+            primaries: self.primaries.unwrap_or(source.primaries),
+            transfer: self.transfer.unwrap_or(source.transfer),
+            space: self.space.unwrap_or(source.space),
+            range: self.range.unwrap_or(source.range),
+        };
+        let depth = format.descriptor().map_or(8, |descriptor| unsafe { (*descriptor.as_ptr()).comp[0].depth });
+        if tags.is_hdr() && depth < 10 {
+            warn!(?format, "Encoding HDR video with 8-bit components will band; use a 10-bit pixel_format");
+        }
+        tags.apply(encoder);
+        
+        if self.mastering.is_none() && self.light_level.is_none() {
+            return Ok(());
+        }
+        let (key, params) = match codec_name {
+            "libx265" => ("x265-params", self.x265_hdr_params()),
+            "libsvtav1" => ("svtav1-params", self.svtav1_hdr_params()),
+            _ => {
+                info!(codec = codec_name, "HDR metadata goes into the container only");
+                return Ok(());
+            }
+        };
+        if !params.is_empty() {
+            let params = match options.get(key) {
+                Some(existing) => format!("{}:{}", existing, params.join(":")),
+                None => params.join(":"),
+            };
+            options.set(key, &params);
+        }
+        Ok(())
+    }
+    
+    /// Add the source's HDR metadata to output stream `output_index`
+    fn add_side_data(&self, octx: &mut ffmpeg::format::context::Output, output_index: usize) -> Result<()> {
+        use ffmpeg::ffi::AVPacketSideDataType as Kind;
+        
+        if let Some(mastering) = &self.mastering {
+            add_stream_side_data(octx, output_index, Kind::AV_PKT_DATA_MASTERING_DISPLAY_METADATA, mastering)?;
+        }
+        if let Some(light_level) = &self.light_level {
+            add_stream_side_data(octx, output_index, Kind::AV_PKT_DATA_CONTENT_LIGHT_LEVEL, light_level)?;
+        }
+        Ok(())
+    }
+    
+    /// Mastering display primaries (green, blue, red), white point and luminance range
+    fn mastering_values(&self) -> Option<([(f64, f64); 4], (f64, f64))> {
+        let mastering = self.mastering.filter(|mastering| mastering.has_primaries != 0 && mastering.has_luminance != 0)?;
+        let value = |rational: ffmpeg::ffi::AVRational| f64::from(ffmpeg::Rational::from(rational));
+        let point = |xy: [ffmpeg::ffi::AVRational; 2]| (value(xy[0]), value(xy[1]));
+        let [red, green, blue] = mastering.display_primaries;
+        Some((
+            [point(green), point(blue), point(red), point(mastering.white_point)],
+            (value(mastering.max_luminance), value(mastering.min_luminance)),
+        ))
+    }
+    
+    /// x265 takes chromaticities in units of 0.00002 and luminance in units of 0.0001 nits
+    fn x265_hdr_params(&self) -> Vec<String> {
+        let mut params = vec!["hdr10=1".to_string()];
+        if let Some((points, (max, min))) = self.mastering_values() {
+            let [g, b, r, wp] = points.map(|(x, y)| ((x * 50000.0).round(), (y * 50000.0).round()));
+            params.push(format!(
+                "master-display=G({},{})B({},{})R({},{})WP({},{})L({},{})",
+                g.0, g.1, b.0, b.1, r.0, r.1, wp.0, wp.1,
+                (max * 10000.0).round(),
+                (min * 10000.0).round()
+            ));
+        }
+        if let Some(light_level) = &self.light_level {
+            params.push(format!("max-cll={},{}", light_level.MaxCLL, light_level.MaxFALL));
+        }
+        params
+    }
+    
+    /// SVT-AV1 takes chromaticities and luminance as plain numbers
+    fn svtav1_hdr_params(&self) -> Vec<String> {
+        let mut params = Vec::new();
+        if let Some((points, (max, min))) = self.mastering_values() {
+            let [g, b, r, wp] = points;
+            params.push(format!(
+                "mastering-display=G({:.4},{:.4})B({:.4},{:.4})R({:.4},{:.4})WP({:.4},{:.4})L({:.4},{:.4})",
+                g.0, g.1, b.0, b.1, r.0, r.1, wp.0, wp.1, max, min
+            ));
+        }
+        if let Some(light_level) = &self.light_level {
+            params.push(format!("content-light={},{}", light_level.MaxCLL, light_level.MaxFALL));
+        }
+        params
+    }
+}
+
+/// Converts decoded frames to an encoder's pixel format when it differs from the decoder's
+struct PixelConverter {
+    scaler: Option<ffmpeg::software::scaling::Context>,
+    converted: ffmpeg::util::frame::video::Video,
+}
+
+impl PixelConverter {
+    fn new(decoder: &ffmpeg::decoder::Video, format: ffmpeg::format::Pixel) -> Result<Self> {
+        let scaler = if decoder.format() == format {
+            None
+        } else {
+            Some(ffmpeg::software::scaling::context::Context::get(
+                decoder.format(),
+                decoder.width(),
+                decoder.height(),
+                format,
+                decoder.width(),
+                decoder.height(),
+                ffmpeg::software::scaling::flag::Flags::BICUBIC,
+            )?)
+        };
+        Ok(Self { scaler, converted: ffmpeg::util::frame::video::Video::empty() })
+    }
+    
+    /// `frame` in the encoder's format, with its timestamp
+    fn convert<'a>(&'a mut self, frame: &'a mut ffmpeg::util::frame::video::Video) -> Result<&'a mut ffmpeg::util::frame::video::Video> {
+        let Some(scaler) = &mut self.scaler else {
+            return Ok(frame);
+        };
+        scaler.run(frame, &mut self.converted)?;
+        self.converted.set_pts(frame.pts());
+        Ok(&mut self.converted)
+    }
+}
+
+/// Index of the video stream the transcode tasks encode: the `video_stream`-th video stream
+/// of the input (counting from 0, cover art left out), or the best one
+pub(crate) fn select_video_stream(ictx: &ffmpeg::format::context::Input, video_stream: Option<usize>) -> Result<usize> {
+    let Some(position) = video_stream else {
+        return Ok(ictx
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .context("No video stream found")?
+            .index());
+    };
+    let videos: Vec<usize> = ictx
+        .streams()
+        .filter(|stream| {
+            stream.parameters().medium() == ffmpeg::media::Type::Video
+                && !stream.disposition().contains(ffmpeg::format::stream::Disposition::ATTACHED_PIC)
+        })
+        .map(|stream| stream.index())
+        .collect();
+    videos
+        .get(position)
+        .copied()
+        .context(format!("video_stream {} not found; the input has {} video streams", position, videos.len()))
+}
+
+/// The `video_stream` param of the transcode tasks
+pub(crate) fn video_stream_param(params: &serde_json::Value) -> Option<usize> {
+    params.get("video_stream").and_then(|v| v.as_u64()).map(|v| v as usize)
+}
+
+/// Which of the input's streams other than the encoded video the transcode tasks keep, and how
+///
+/// Set by `audio` (`copy`, `none` or an encoder name such as `aac`, default: copy),
+/// `audio_bitrate` (default: 128k), `subtitles` and `data` (`copy` or `none`, default: copy).
+/// `audio_streams` and `subtitle_streams` pick streams by their position among the input's
+/// streams of that type (counting from 0, in the order given), and `audio_languages` and
+/// `subtitle_languages` keep those whose language tag is listed; by default all are kept.
+/// Copied streams the output container cannot hold are left out with a warning.
+pub(crate) struct StreamPassthrough {
+    audio: AudioMode,
+    audio_bit_rate: usize,
+    audio_selection: StreamSelection,
+    subtitles: bool,
+    subtitle_selection: StreamSelection,
+    data: bool,
+    /// Playback speed of the output relative to the input, and whether audio keeps its pitch
+    speed: Option<(f64, bool)>,
+}
+
+enum AudioMode {
+    Copy,
+    Drop,
+    Encode(String),
+}
+
+/// Streams of one type picked by position and language
+#[derive(Default)]
+struct StreamSelection {
+    positions: Option<Vec<usize>>,
+    languages: Option<Vec<String>>,
+}
+
+impl StreamSelection {
+    fn from_params(params: &serde_json::Value, streams_key: &str, languages_key: &str) -> Result<Self> {
+        let positions = match params.get(streams_key) {
+            None => None,
+            Some(value) if value.as_str() == Some("all") => None,
+            Some(value) => Some(
+                value
+                    .as_array()
+                    .and_then(|values| values.iter().map(|v| v.as_u64().map(|v| v as usize)).collect::<Option<Vec<_>>>())
+                    .context(format!("{} must be an array of stream positions or \"all\"", streams_key))?,
+            ),
+        };
+        
+        let languages = match params.get(languages_key) {
+            None => None,
+            Some(serde_json::Value::String(language)) => Some(vec![language.to_lowercase()]),
+            Some(value) => Some(
+                value
+                    .as_array()
+                    .and_then(|values| values.iter().map(|v| v.as_str().map(str::to_lowercase)).collect::<Option<Vec<_>>>())
+                    .context(format!("{} must be an array of language codes", languages_key))?,
+            ),
+        };
+        
+        Ok(Self { positions, languages })
+    }
+    
+    /// Indexes of the selected `medium` streams of `ictx`, in output order
+    fn select(&self, ictx: &ffmpeg::format::context::Input, medium: ffmpeg::media::Type) -> Result<Vec<usize>> {
+        let streams: Vec<(usize, Option<String>)> = ictx
+            .streams()
+            .filter(|stream| stream.parameters().medium() == medium)
+            .map(|stream| {
+                let language = stream.metadata().get("language").map(str::to_lowercase);
+                (stream.index(), language)
+            })
+            .collect();
+        
+        let picked: Vec<&(usize, Option<String>)> = match &self.positions {
+            Some(positions) => positions
+                .iter()
+                .map(|&position| {
+                    streams.get(position).context(format!(
+                        "{:?} stream {} not found; the input has {}",
+                        medium,
+                        position,
+                        streams.len()
+                    ))
+                })
+                .collect::<Result<_>>()?,
+            None => streams.iter().collect(),
+        };
+        
+        let Some(languages) = &self.languages else {
+            return Ok(picked.into_iter().map(|(index, _)| *index).collect());
+        };
+        let selected: Vec<usize> = picked
+            .iter()
+            .filter(|(_, language)| language.as_ref().map_or(false, |language| languages.contains(language)))
+            .map(|(index, _)| *index)
+            .collect();
+        if selected.is_empty() && !picked.is_empty() {
+            let available: Vec<&str> = picked
+                .iter()
+                .map(|(_, language)| language.as_deref().unwrap_or("und"))
+                .collect();
+            anyhow::bail!(
+                "No {:?} stream in languages {} (available: {})",
+                medium,
+                languages.join(", "),
+                available.join(", ")
+            );
+        }
+        Ok(selected)
+    }
+}
+
+impl StreamPassthrough {
+    pub(crate) fn from_params(params: &serde_json::Value) -> Result<Self> {
+        let audio = match params.get("audio").and_then(|v| v.as_str()).unwrap_or("copy") {
+            "copy" => AudioMode::Copy,
+            "none" => AudioMode::Drop,
+            codec => {
+                ffmpeg::encoder::find_by_name(codec)
+                    .filter(|encoder| encoder.medium() == ffmpeg::media::Type::Audio)
+                    .context(format!("Unknown audio encoder: {}", codec))?;
+                AudioMode::Encode(codec.to_string())
+            }
+        };
+        
+        let audio_bit_rate = parse_bitrate(
+            params.get("audio_bitrate")
+                .and_then(|v| v.as_str())
+                .unwrap_or("128k"),
+        )?;
+        
+        let copy_or_none = |key: &str| match params.get(key).and_then(|v| v.as_str()).unwrap_or("copy") {
+            "copy" => Ok(true),
+            "none" => Ok(false),
+            other => anyhow::bail!("{} must be copy or none, got {}", key, other),
+        };
+        
+        Ok(Self {
+            audio,
+            audio_bit_rate,
+            audio_selection: StreamSelection::from_params(params, "audio_streams", "audio_languages")?,
+            subtitles: copy_or_none("subtitles")?,
+            subtitle_selection: StreamSelection::from_params(params, "subtitle_streams", "subtitle_languages")?,
+            data: copy_or_none("data")?,
+            speed: None,
+        })
+    }
+    
+    /// For outputs that play `factor` times as fast as the input: audio is re-encoded at that
+    /// speed, its pitch shifted along unless `keep_pitch`, and subtitle and data streams, whose
+    /// timestamps would no longer line up, are left out
+    pub(crate) fn change_speed(mut self, factor: f64, keep_pitch: bool) -> Self {
+        self.speed = Some((factor, keep_pitch));
+        self
+    }
+    
+    /// Selected audio streams of the input for `concat_segments` to copy, for encodes that
+    /// are joined from segments; those carry no subtitle or data streams and cannot
+    /// re-encode audio
+    pub(crate) fn segment_audio(&self, input_path: &str) -> Result<Vec<usize>> {
+        match &self.audio {
+            AudioMode::Copy => {
+                let ictx = ffmpeg::format::input(&input_path)
+                    .context("Failed to open input file")?;
+                self.audio_selection.select(&ictx, ffmpeg::media::Type::Audio)
+            }
+            AudioMode::Drop => Ok(Vec::new()),
+            AudioMode::Encode(_) => anyhow::bail!("Segmented encodes can only copy audio; use audio: copy or none"),
+        }
+    }
+    
+    /// Add outputs to `octx` for the selected streams of `ictx`, keyed by input stream index
+    fn open(
+        &self,
+        ictx: &ffmpeg::format::context::Input,
+        octx: &mut ffmpeg::format::context::Output,
+        output_path: &str,
+    ) -> Result<std::collections::HashMap<usize, StreamOutput>> {
+        let mut selected = Vec::new();
+        if !matches!(self.audio, AudioMode::Drop) {
+            selected.extend(self.audio_selection.select(ictx, ffmpeg::media::Type::Audio)?);
+        }
+        if self.subtitles && self.speed.is_none() {
+            selected.extend(self.subtitle_selection.select(ictx, ffmpeg::media::Type::Subtitle)?);
+        }
+        if self.data && self.speed.is_none() {
+            selected.extend(
+                ictx.streams()
+                    .filter(|stream| {
+                        matches!(
+                            stream.parameters().medium(),
+                            ffmpeg::media::Type::Data | ffmpeg::media::Type::Attachment
+                        )
+                    })
+                    .map(|stream| stream.index()),
+            );
+        }
+        
+        let mut outputs = std::collections::HashMap::new();
+        for index in selected {
+            let stream = ictx.stream(index).context("Input stream missing")?;
+            let medium = stream.parameters().medium();
+            if let (ffmpeg::media::Type::Audio, Some((factor, keep_pitch))) = (medium, self.speed) {
+                let sample_rate = unsafe { (*stream.parameters().as_ptr()).sample_rate };
+                let mut filters = vec!["aresample=async=1".to_string()];
+                if keep_pitch {
+                    filters.extend(crate::audio::atempo_chain(factor));
+                } else {
+                    filters.push(format!("asetrate={}", (f64::from(sample_rate) * factor).round()));
+                    filters.push(format!("aresample={}", sample_rate));
+                }
+                let codec = match &self.audio {
+                    AudioMode::Encode(codec) => Some(codec.as_str()),
+                    _ => None,
+                };
+                let transcoder = AudioTranscoder::open_filtered(
+                    &stream,
+                    octx,
+                    output_path,
+                    codec,
+                    Some(self.audio_bit_rate),
+                    &filters.join(","),
+                )?;
+                outputs.insert(index, StreamOutput::Audio(transcoder));
+                continue;
+            }
+            if let (ffmpeg::media::Type::Audio, AudioMode::Encode(codec)) = (medium, &self.audio) {
+                let transcoder = AudioTranscoder::open(&stream, octx, output_path, Some(codec), Some(self.audio_bit_rate))?;
+                outputs.insert(index, StreamOutput::Audio(transcoder));
+                continue;
+            }
+            
+            let codec_id = stream.parameters().id();
+            // 1 when the muxer takes the codec, 0 when it does not, negative when unsure
+            let supported = unsafe {
+                ffmpeg::ffi::avformat_query_codec(octx.format().as_ptr(), codec_id.into(), 0)
+            };
+            if supported == 0 {
+                warn!(stream = index, codec = ?codec_id, ?medium, "Output container cannot hold stream; leaving it out");
+                continue;
+            }
+            outputs.insert(index, StreamOutput::copy(&stream, octx)?);
+        }
+        Ok(outputs)
+    }
+}
+
+/// Run one encode of `transcode_video_native`, returning the number of frames
+///
+/// For two-pass encodes `pass` holds the pass number and the stats file; the first pass
+/// only gathers statistics and writes no output. The input's other streams are carried over
+/// as `passthrough` says.
+#[allow(clippy::too_many_arguments)]
+fn encode_video_pass(
+    job: &JobPayload,
+    codec_name: &str,
+    bit_rate: Option<usize>,
+    options: &ffmpeg::Dictionary,
+    keyframes: &ForcedKeyframes,
+    passthrough: &StreamPassthrough,
+    pass: Option<(u32, &str)>,
+    progress: &mut Option<ProgressReporter>,
+) -> Result<usize> {
+    let _span = tracing::info_span!("encode", pass = pass.map(|(number, _)| number)).entered();
+    let mut options = options.clone();
+    let mut keyframes = keyframes.clone();
+    let mut stats = String::new();
+    
     // Open input
     let mut ictx = ffmpeg::format::input(&job.input_path)
         .context("Failed to open input file")?;
     
     // Find video stream
-    let input_stream = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .context("No video stream found")?;
+    let video_stream_index = select_video_stream(&ictx, video_stream_param(&job.params))?;
+    let input_stream = ictx.stream(video_stream_index).context("No video stream found")?;
     
-    let video_stream_index = input_stream.index();
+    if let Some(progress) = progress.as_mut() {
+        let total_frames = expected_frame_count(&input_stream, ictx.duration());
+        match pass {
+            Some((number, _)) => progress.start_stage(&format!("pass {}", number), total_frames),
+            None => progress.set_total_frames(total_frames),
+        }
+    }
     
     // Get decoder
     let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
     let mut decoder = context_decoder.decoder().video()?;
+    let picture = PictureFormat::from_params(&job.params)?.with_source(&input_stream);
     
-    // Create output
-    let mut octx = ffmpeg::format::output(&job.output_path)
-        .context("Failed to create output file")?;
+    // Create output; the first pass goes to the null muxer
+    let mut octx = match pass {
+        Some((1, stats_path)) => ffmpeg::format::output_as(&format!("{}.null", stats_path), "null")
+            .context("Failed to create first-pass output")?,
+        _ => ffmpeg::format::output(&job.output_path)
+            .context("Failed to create output file")?,
+    };
     
     // Find encoder
     let codec = ffmpeg::encoder::find_by_name(codec_name)
@@ -55,24 +943,67 @@ pub async fn transcode_video_native(job: &JobPayload, _config: &Config) -> Resul
     // Configure encoder
     encoder.set_width(decoder.width());
     encoder.set_height(decoder.height());
-    encoder.set_format(decoder.format());
+    picture.configure(&decoder, codec_name, &mut encoder, &mut options)?;
     encoder.set_time_base(input_stream.time_base());
-    encoder.set_bit_rate(bitrate_value);
+    if let Some(bit_rate) = bit_rate {
+        encoder.set_bit_rate(bit_rate);
+    }
     
     if let Some(frame_rate) = input_stream.avg_frame_rate() {
         encoder.set_frame_rate(Some(frame_rate));
     }
     
-    let encoder = encoder.open_as(codec)?;
+    if let Some((number, stats_path)) = pass {
+        encoder.set_flags(if number == 1 {
+            ffmpeg::codec::Flags::PASS1
+        } else {
+            ffmpeg::codec::Flags::PASS2
+        });
+        match codec_name {
+            // x264 and x265 read and write their stats files themselves
+            "libx264" => options.set("stats", stats_path),
+            "libx265" => {
+                let pass_params = format!("pass={}:stats={}", number, stats_path);
+                let x265_params = match options.get("x265-params") {
+                    Some(params) => format!("{}:{}", params, pass_params),
+                    None => pass_params,
+                };
+                options.set("x265-params", &x265_params);
+            }
+            _ if number == 2 => {
+                let stats_in = std::fs::read_to_string(stats_path)
+                    .context("Failed to read first-pass stats")?;
+                let stats_in = std::ffi::CString::new(stats_in)?;
+                unsafe {
+                    (*encoder.as_mut_ptr()).stats_in = ffmpeg::ffi::av_strdup(stats_in.as_ptr());
+                }
+            }
+            _ => {}
+        }
+    }
+    
+    let encoder = encoder.open_as_with(codec, options)?;
     ost.set_parameters(&encoder);
+    // Frames are encoded as stored, so players still need the source's rotation
+    copy_display_matrix(&input_stream, &mut octx, 0)?;
+    picture.add_side_data(&mut octx, 0)?;
+    let mut converter = PixelConverter::new(&decoder, encoder.format())?;
+    
+    let mut passthrough_outputs = match pass {
+        Some((1, _)) => std::collections::HashMap::new(),
+        _ => passthrough.open(&ictx, &mut octx, &job.output_path)?,
+    };
     
     // Write header
-    octx.write_header()?;
+    let muxer_options = mp4_muxer_options(&job.params, &octx.format())?;
+    octx.write_header_with(muxer_options)?;
+    let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
     
     // Process frames
     let mut frame_index = 0;
     
     for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
         if stream.index() == video_stream_index {
             decoder.send_packet(&packet)?;
             
@@ -81,14 +1012,17 @@ pub async fn transcode_video_native(job: &JobPayload, _config: &Config) -> Resul
                 let mut encoded_packet = ffmpeg::Packet::empty();
                 
                 // Send frame to encoder
-                encoder.send_frame(&decoded)?;
+                let frame = converter.convert(&mut decoded)?;
+                keyframes.apply(frame, input_stream.time_base());
+                encoder.send_frame(frame)?;
                 
                 // Receive encoded packets
                 while encoder.receive_packet(&mut encoded_packet).is_ok() {
+                    collect_pass_stats(&encoder, &mut stats);
                     encoded_packet.set_stream(0);
                     encoded_packet.rescale_ts(
                         input_stream.time_base(),
-                        ost.time_base(),
+                        output_time_base,
                     );
                     encoded_packet.write_interleaved(&mut octx)?;
                 }
@@ -97,7 +1031,12 @@ pub async fn transcode_video_native(job: &JobPayload, _config: &Config) -> Resul
                 if frame_index % 100 == 0 {
                     info!("Processed {} frames", frame_index);
                 }
+                if let Some(progress) = progress.as_mut() {
+                    progress.update(frame_index as u64);
+                }
             }
+        } else if let Some(output) = passthrough_outputs.get_mut(&stream.index()) {
+            output.write(packet, &mut octx)?;
         }
     }
     
@@ -105,625 +1044,7831 @@ pub async fn transcode_video_native(job: &JobPayload, _config: &Config) -> Resul
     encoder.send_eof()?;
     let mut encoded_packet = ffmpeg::Packet::empty();
     while encoder.receive_packet(&mut encoded_packet).is_ok() {
+        collect_pass_stats(&encoder, &mut stats);
         encoded_packet.set_stream(0);
+        encoded_packet.rescale_ts(input_stream.time_base(), output_time_base);
         encoded_packet.write_interleaved(&mut octx)?;
     }
+    collect_pass_stats(&encoder, &mut stats);
+    for output in passthrough_outputs.values_mut() {
+        output.finish(&mut octx)?;
+    }
+    
+    if let Some(progress) = progress.as_mut() {
+        progress.finish(frame_index as u64);
+    }
+    // A first pass decodes the same frames again in the second
+    let final_pass = pass.map_or(true, |(number, _)| number == 2);
+    if final_pass {
+        metrics::add_frames(frame_index as u64);
+    }
+    
+    if let Some((1, stats_path)) = pass {
+        if !stats.is_empty() {
+            std::fs::write(stats_path, &stats).context("Failed to write first-pass stats")?;
+        }
+    }
     
     // Write trailer
     octx.write_trailer()?;
+    if final_pass {
+        finish_mp4_output(&job.params, &job.output_path)?;
+    }
     
-    info!("Transcoding complete: {} frames processed", frame_index);
-    Ok(job.output_path.clone())
+    Ok(frame_index)
 }
 
-/// Extract video frames as images
-pub async fn extract_frames_native(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Extracting frames using ffmpeg-next");
-    
-    let count = job.params.get("count")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(10) as usize;
+/// Append the encoder's latest first-pass statistics, for encoders that report them in memory
+fn collect_pass_stats(encoder: &ffmpeg::encoder::Video, stats: &mut String) {
+    unsafe {
+        let stats_out = (*encoder.as_ptr()).stats_out;
+        if !stats_out.is_null() {
+            stats.push_str(&std::ffi::CStr::from_ptr(stats_out).to_string_lossy());
+        }
+    }
+}
+
+/// Progress of a resumable `transcode_video_native`, stored as `<output>.checkpoint.json`
+#[derive(Debug, Serialize, Deserialize)]
+struct TranscodeCheckpoint {
+    input_path: String,
+    input_size: u64,
+    /// The job's params; a checkpoint from an encode with other settings is not reused
+    params: serde_json::Value,
+    segments: Vec<EncodedSegment>,
+}
+
+/// A finished segment of a resumable or parallel encode
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EncodedSegment {
+    pub path: String,
+    /// First input timestamp after the segment, in the input stream's time base
+    pub end_pts: i64,
+    pub frames: usize,
+}
+
+impl TranscodeCheckpoint {
+    fn save(&self, path: &str) -> Result<()> {
+        let temporary = format!("{}.tmp", path);
+        std::fs::write(&temporary, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&temporary, path).context("Failed to write transcode checkpoint")?;
+        Ok(())
+    }
+}
+
+/// Single-pass encode of `transcode_video_native` that can resume after a crash
+///
+/// The video is encoded into segments of `segment_seconds` under `<output>.segments`, each
+/// starting with a keyframe, and every finished segment is recorded in the checkpoint. A
+/// rerun with the same input and params seeks past the recorded segments and carries on; the
+/// segments are then joined into the output without re-encoding, with the input's
+/// `audio_streams`, and removed.
+#[allow(clippy::too_many_arguments)]
+fn encode_video_resumable(
+    job: &JobPayload,
+    codec_name: &str,
+    bit_rate: Option<usize>,
+    options: &ffmpeg::Dictionary,
+    keyframes: &ForcedKeyframes,
+    audio_streams: &[usize],
+    segment_seconds: f64,
+    progress: &mut Option<ProgressReporter>,
+) -> Result<usize> {
+    use ffmpeg::Rescale;
     
-    // Open input
-    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    let _span = tracing::info_span!("encode").entered();
+    let checkpoint_path = format!("{}.checkpoint.json", job.output_path);
+    let segments_dir = format!("{}.segments", job.output_path);
+    let input_size = std::fs::metadata(&job.input_path)
+        .context("Failed to read input file")?
+        .len();
     
-    // Find video stream
-    let input_stream = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .context("No video stream found")?;
+    let saved: Option<TranscodeCheckpoint> = std::fs::read_to_string(&checkpoint_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok());
+    let mut checkpoint = match saved {
+        Some(saved)
+            if saved.input_path == job.input_path
+                && saved.input_size == input_size
+                && saved.params == job.params =>
+        {
+            info!(segments = saved.segments.len(), "Resuming transcode from checkpoint");
+            saved
+        }
+        saved => {
+            if saved.is_some() {
+                warn!("Transcode checkpoint is for a different input or params; starting over");
+            }
+            let _ = std::fs::remove_dir_all(&segments_dir);
+            TranscodeCheckpoint {
+                input_path: job.input_path.clone(),
+                input_size,
+                params: job.params.clone(),
+                segments: Vec::new(),
+            }
+        }
+    };
+    std::fs::create_dir_all(&segments_dir)?;
     
-    let video_stream_index = input_stream.index();
-    
-    // Get decoder
-    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
-    let mut decoder = context_decoder.decoder().video()?;
-    
-    // Calculate frame interval
-    let total_frames = input_stream.frames() as usize;
-    let interval = if total_frames > count {
-        total_frames / count
-    } else {
-        1
-    };
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    let video_stream_index = select_video_stream(&ictx, video_stream_param(&job.params))?;
+    let input_stream = ictx.stream(video_stream_index).context("No video stream found")?;
+    let time_base = input_stream.time_base();
+    let frame_rate = input_stream.avg_frame_rate();
+    let total_frames = expected_frame_count(&input_stream, ictx.duration());
+    let picture = PictureFormat::from_params(&job.params)?.with_source(&input_stream);
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?
+        .decoder()
+        .video()?;
     
-    let mut frame_index = 0;
-    let mut saved_count = 0;
+    let resume_pts = checkpoint.segments.last().map(|segment| segment.end_pts);
+    let mut frame_index: usize = checkpoint.segments.iter().map(|segment| segment.frames).sum();
+    if let Some(pts) = resume_pts {
+        // Lands on the keyframe before the resume point; earlier frames are decoded and dropped
+        let position = pts.rescale(time_base, ffmpeg::rescale::TIME_BASE);
+        ictx.seek(position, ..position)?;
+    }
+    if let Some(progress) = progress.as_mut() {
+        progress.set_total_frames(total_frames);
+    }
     
-    // Create scaler for RGB conversion
-    let mut scaler = ffmpeg::software::scaling::context::Context::get(
-        decoder.format(),
-        decoder.width(),
-        decoder.height(),
-        ffmpeg::format::Pixel::RGB24,
-        decoder.width(),
-        decoder.height(),
-        ffmpeg::software::scaling::flag::Flags::BILINEAR,
-    )?;
+    let segment_ticks = ((segment_seconds / f64::from(time_base)) as i64).max(1);
+    let mut segment: Option<SegmentEncoder> = None;
+    let mut last_pts: Option<i64> = None;
+    let mut decoded = ffmpeg::util::frame::video::Video::empty();
+    let mut packets = ictx.packets();
     
-    for (stream, packet) in ictx.packets() {
-        if stream.index() == video_stream_index {
-            decoder.send_packet(&packet)?;
+    loop {
+        shutdown::check()?;
+        let flushing = match packets.next() {
+            Some((stream, packet)) if stream.index() == video_stream_index => {
+                decoder.send_packet(&packet)?;
+                false
+            }
+            Some(_) => continue,
+            None => {
+                decoder.send_eof()?;
+                true
+            }
+        };
+        
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let pts = decoded.timestamp().unwrap_or_else(|| last_pts.map_or(0, |last| last + 1));
+            if resume_pts.map_or(false, |resume| pts < resume) {
+                continue;
+            }
+            last_pts = Some(pts);
             
-            let mut decoded = ffmpeg::util::frame::video::Video::empty();
-            while decoder.receive_frame(&mut decoded).is_ok() {
-                if frame_index % interval == 0 && saved_count < count {
-                    // Convert to RGB
-                    let mut rgb_frame = ffmpeg::util::frame::video::Video::empty();
-                    scaler.run(&decoded, &mut rgb_frame)?;
-                    
-                    // Save frame as image
-                    let output_path = format!("{}_{:04}.jpg", job.output_path, saved_count);
-                    save_frame_as_jpeg(&rgb_frame, &output_path)?;
-                    
-                    saved_count += 1;
-                    info!("Saved frame {}/{}", saved_count, count);
-                }
-                frame_index += 1;
+            if let Some(finished) = segment.take_if(|current| pts >= current.start_pts + segment_ticks) {
+                checkpoint.segments.push(finished.finish(pts)?);
+                checkpoint.save(&checkpoint_path)?;
+            }
+            if segment.is_none() {
+                let path = Path::new(&segments_dir)
+                    .join(format!("segment_{:05}.mkv", checkpoint.segments.len()))
+                    .to_string_lossy()
+                    .to_string();
+                segment = Some(SegmentEncoder::open(
+                    &path, &decoder, time_base, frame_rate, codec_name, bit_rate, options, keyframes, &picture, pts,
+                )?);
+            }
+            
+            decoded.set_pts(Some(pts));
+            if let Some(current) = &mut segment {
+                current.encode(&mut decoded)?;
+            }
+            frame_index += 1;
+            if let Some(progress) = progress.as_mut() {
+                progress.update(frame_index as u64);
             }
         }
+        
+        if flushing {
+            break;
+        }
     }
     
-    info!("Extracted {} frames", saved_count);
-    Ok(job.output_path.clone())
+    if let Some(finished) = segment.take() {
+        checkpoint.segments.push(finished.finish(last_pts.map_or(0, |last| last + 1))?);
+        checkpoint.save(&checkpoint_path)?;
+    }
+    if let Some(progress) = progress.as_mut() {
+        progress.finish(frame_index as u64);
+    }
+    
+    concat_segments(
+        &checkpoint.segments,
+        &job.output_path,
+        Some((&job.input_path, audio_streams)),
+        &job.params,
+    )?;
+    let _ = std::fs::remove_dir_all(&segments_dir);
+    let _ = std::fs::remove_file(&checkpoint_path);
+    
+    Ok(frame_index)
 }
 
-/// Get video information
-pub async fn get_video_info_native(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Getting video info using ffmpeg-next");
+/// One segment of a resumable or parallel encode, with its own encoder so that it starts on
+/// a keyframe
+struct SegmentEncoder {
+    octx: ffmpeg::format::context::Output,
+    encoder: ffmpeg::encoder::Video,
+    path: String,
+    input_time_base: ffmpeg::Rational,
+    keyframes: ForcedKeyframes,
+    converter: PixelConverter,
+    start_pts: i64,
+    frames: usize,
+}
+
+impl SegmentEncoder {
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        path: &str,
+        decoder: &ffmpeg::decoder::Video,
+        time_base: ffmpeg::Rational,
+        frame_rate: Option<ffmpeg::Rational>,
+        codec_name: &str,
+        bit_rate: Option<usize>,
+        options: &ffmpeg::Dictionary,
+        keyframes: &ForcedKeyframes,
+        picture: &PictureFormat,
+        start_pts: i64,
+    ) -> Result<Self> {
+        let mut octx = ffmpeg::format::output(&path)
+            .context(format!("Failed to create segment {}", path))?;
+        let codec = ffmpeg::encoder::find_by_name(codec_name)
+            .context(format!("Codec {} not found", codec_name))?;
+        let global_header = octx
+            .format()
+            .flags()
+            .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+        
+        let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        let mut options = options.clone();
+        encoder.set_width(decoder.width());
+        encoder.set_height(decoder.height());
+        picture.configure(decoder, codec_name, &mut encoder, &mut options)?;
+        encoder.set_time_base(time_base);
+        encoder.set_frame_rate(frame_rate);
+        if let Some(bit_rate) = bit_rate {
+            encoder.set_bit_rate(bit_rate);
+        }
+        if global_header {
+            encoder.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+        }
+        let encoder = encoder.open_as_with(codec, options)?;
+        
+        let mut ost = octx.add_stream(codec)?;
+        ost.set_parameters(&encoder);
+        let output_index = ost.index();
+        picture.add_side_data(&mut octx, output_index)?;
+        octx.write_header()?;
+        let converter = PixelConverter::new(decoder, encoder.format())?;
+        
+        Ok(Self {
+            octx,
+            encoder,
+            path: path.to_string(),
+            input_time_base: time_base,
+            keyframes: keyframes.clone(),
+            converter,
+            start_pts,
+            frames: 0,
+        })
+    }
     
-    let ictx = ffmpeg::format::input(&job.input_path)?;
+    fn encode(&mut self, frame: &mut ffmpeg::util::frame::video::Video) -> Result<()> {
+        let frame = self.converter.convert(frame)?;
+        self.keyframes.apply(frame, self.input_time_base);
+        self.encoder.send_frame(frame)?;
+        self.frames += 1;
+        self.write_encoded()
+    }
     
-    let mut info = serde_json::json!({
-        "format": ictx.format().name(),
-        "duration": ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE),
-        "bit_rate": ictx.bit_rate(),
-        "streams": []
-    });
+    /// Flush and close the segment, which ends just before `end_pts`
+    fn finish(mut self, end_pts: i64) -> Result<EncodedSegment> {
+        self.encoder.send_eof()?;
+        self.write_encoded()?;
+        self.octx.write_trailer()?;
+        metrics::add_frames(self.frames as u64);
+        
+        Ok(EncodedSegment {
+            path: self.path,
+            end_pts,
+            frames: self.frames,
+        })
+    }
     
-    let streams = info["streams"].as_array_mut().unwrap();
+    fn write_encoded(&mut self) -> Result<()> {
+        let output_time_base = self.octx.stream(0).context("Output stream missing")?.time_base();
+        let mut encoded = ffmpeg::Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(0);
+            encoded.rescale_ts(self.input_time_base, output_time_base);
+            encoded.write_interleaved(&mut self.octx)?;
+        }
+        Ok(())
+    }
+}
+
+/// End times, in seconds, of the streams written by `concat_segments`
+pub(crate) struct ConcatTimes {
+    pub video_end: f64,
+    pub audio_end: Option<f64>,
+}
+
+/// Join encoded segments into `output_path` without re-encoding, copying the listed audio
+/// streams of `audio_source` alongside them; `params` holds the output's muxer params
+/// (see `mp4_muxer_options`)
+pub(crate) fn concat_segments(
+    segments: &[EncodedSegment],
+    output_path: &str,
+    audio_source: Option<(&str, &[usize])>,
+    params: &serde_json::Value,
+) -> Result<ConcatTimes> {
+    let first = segments.first().context("No segments were encoded")?;
     
-    for stream in ictx.streams() {
-        let codec = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
-        
-        let stream_info = match codec.medium() {
-            ffmpeg::media::Type::Video => {
-                let video = codec.decoder().video()?;
-                serde_json::json!({
-                    "type": "video",
-                    "codec": video.codec().map(|c| c.name()).unwrap_or("unknown"),
-                    "width": video.width(),
-                    "height": video.height(),
-                    "frame_rate": stream.avg_frame_rate().numerator() as f64 / stream.avg_frame_rate().denominator() as f64,
-                    "pixel_format": format!("{:?}", video.format()),
-                    "bit_rate": video.bit_rate(),
-                })
-            }
-            ffmpeg::media::Type::Audio => {
-                let audio = codec.decoder().audio()?;
-                serde_json::json!({
-                    "type": "audio",
-                    "codec": audio.codec().map(|c| c.name()).unwrap_or("unknown"),
-                    "sample_rate": audio.rate(),
-                    "channels": audio.channels(),
-                    "channel_layout": format!("{:?}", audio.channel_layout()),
-                    "bit_rate": audio.bit_rate(),
-                })
+    let mut octx = ffmpeg::format::output(&output_path)
+        .context("Failed to create output file")?;
+    {
+        let ictx = ffmpeg::format::input(&first.path)
+            .context(format!("Failed to open segment {}", first.path))?;
+        let stream = ictx.stream(0).context("Segment has no video stream")?;
+        let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        ost.set_parameters(stream.parameters());
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+    }
+    
+    let mut audio = match audio_source {
+        Some((path, streams)) if !streams.is_empty() => {
+            let actx = ffmpeg::format::input(&path)
+                .context(format!("Failed to open audio source {}", path))?;
+            let mut mapping = std::collections::HashMap::new();
+            for &index in streams {
+                let stream = actx.stream(index).context(format!("Audio source has no stream {}", index))?;
+                let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+                ost.set_parameters(stream.parameters());
+                unsafe {
+                    (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+                }
+                mapping.insert(stream.index(), ost.index());
             }
-            _ => {
-                serde_json::json!({
-                    "type": format!("{:?}", codec.medium()),
-                })
+            Some((actx, mapping))
+        }
+        _ => None,
+    };
+    
+    let muxer_options = mp4_muxer_options(params, &octx.format())?;
+    octx.write_header_with(muxer_options)?;
+    let video_time_base = octx.stream(0).context("Output stream missing")?.time_base();
+    
+    let mut video = SegmentPackets { segments, index: 0, input: None };
+    let mut next_video = video.next_packet()?;
+    let mut next_audio = next_audio_packet(&mut audio);
+    let mut last_dts: Option<i64> = None;
+    let mut times = ConcatTimes { video_end: 0.0, audio_end: None };
+    
+    loop {
+        shutdown::check()?;
+        // Write whichever stream is behind, so the muxer never has much to interleave
+        let take_video = match (&next_video, &next_audio) {
+            (None, None) => break,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some((video, video_base)), Some((audio, audio_base, _))) => {
+                packet_seconds(video, *video_base) <= packet_seconds(audio, *audio_base)
             }
         };
         
-        streams.push(stream_info);
+        if take_video {
+            let (mut packet, time_base) = next_video.take().unwrap();
+            packet.rescale_ts(time_base, video_time_base);
+            // Each segment's encoder starts its decode timestamps a few frames early for
+            // B-frame reordering; nudge them past the previous segment's last packet
+            if let (Some(dts), Some(last)) = (packet.dts(), last_dts) {
+                if dts <= last {
+                    packet.set_dts(Some(last + 1));
+                    if packet.pts().map_or(false, |pts| pts <= last) {
+                        packet.set_pts(Some(last + 1));
+                    }
+                }
+            }
+            last_dts = packet.dts().or(last_dts);
+            times.video_end = times.video_end.max(packet_end_seconds(&packet, video_time_base));
+            packet.set_position(-1);
+            packet.set_stream(0);
+            packet.write_interleaved(&mut octx)?;
+            next_video = video.next_packet()?;
+        } else {
+            let (mut packet, time_base, output_index) = next_audio.take().unwrap();
+            let output_time_base = octx.stream(output_index).context("Output stream missing")?.time_base();
+            packet.rescale_ts(time_base, output_time_base);
+            let end = packet_end_seconds(&packet, output_time_base);
+            times.audio_end = Some(times.audio_end.map_or(end, |audio_end: f64| audio_end.max(end)));
+            packet.set_position(-1);
+            packet.set_stream(output_index);
+            packet.write_interleaved(&mut octx)?;
+            next_audio = next_audio_packet(&mut audio);
+        }
     }
     
-    std::fs::write(&job.output_path, serde_json::to_string_pretty(&info)?)?;
-    
-    Ok(job.output_path.clone())
+    octx.write_trailer()?;
+    finish_mp4_output(params, output_path)?;
+    Ok(times)
 }
 
-/// Resize video using ffmpeg-next
-pub async fn resize_video_native(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Resizing video using ffmpeg-next");
-    
-    let target_height = job.params.get("height")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(720) as u32;
-    
-    // Open input
-    let mut ictx = ffmpeg::format::input(&job.input_path)?;
-    
-    let input_stream = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .context("No video stream found")?;
-    
-    let video_stream_index = input_stream.index();
+/// Video packets of encoded segments in order, opening each segment as it is reached
+struct SegmentPackets<'a> {
+    segments: &'a [EncodedSegment],
+    index: usize,
+    input: Option<ffmpeg::format::context::Input>,
+}
+
+impl SegmentPackets<'_> {
+    fn next_packet(&mut self) -> Result<Option<(ffmpeg::Packet, ffmpeg::Rational)>> {
+        loop {
+            if self.input.is_none() {
+                let Some(segment) = self.segments.get(self.index) else {
+                    return Ok(None);
+                };
+                self.index += 1;
+                self.input = Some(
+                    ffmpeg::format::input(&segment.path)
+                        .context(format!("Failed to open segment {}", segment.path))?,
+                );
+            }
+            
+            let input = self.input.as_mut().unwrap();
+            let time_base = input.stream(0).context("Segment has no video stream")?.time_base();
+            let packet = input.packets().next().map(|(_, packet)| packet);
+            match packet {
+                Some(packet) => return Ok(Some((packet, time_base))),
+                None => self.input = None,
+            }
+        }
+    }
+}
+
+/// Next packet of a copied audio stream, with its time base and output stream index
+fn next_audio_packet(
+    audio: &mut Option<(ffmpeg::format::context::Input, std::collections::HashMap<usize, usize>)>,
+) -> Option<(ffmpeg::Packet, ffmpeg::Rational, usize)> {
+    let (input, mapping) = audio.as_mut()?;
+    for (stream, packet) in input.packets() {
+        if let Some(&output_index) = mapping.get(&stream.index()) {
+            return Some((packet, stream.time_base(), output_index));
+        }
+    }
+    None
+}
+
+fn packet_seconds(packet: &ffmpeg::Packet, time_base: ffmpeg::Rational) -> f64 {
+    packet.dts().or(packet.pts()).unwrap_or(0) as f64 * f64::from(time_base)
+}
+
+fn packet_end_seconds(packet: &ffmpeg::Packet, time_base: ffmpeg::Rational) -> f64 {
+    (packet.pts().or(packet.dts()).unwrap_or(0) + packet.duration()) as f64 * f64::from(time_base)
+}
+
+/// Transcode the video stream in parallel segments, using the params of `transcode_video_native`
+///
+/// The input is split at keyframes into `segments` (default: `processing.max_workers`) parts
+/// of roughly equal length. Up to `processing.max_workers` are encoded at once, each with its
+/// own decoder and encoder, and the results are joined without re-encoding. Every segment
+/// starts with a keyframe, so rate control restarts at each boundary; two-pass encoding is
+/// not supported.
+pub async fn transcode_parallel(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Transcoding video in parallel segments");
     
-    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
-    let mut decoder = context_decoder.decoder().video()?;
+    let bitrate = job.params.get("bitrate")
+        .and_then(|v| v.as_str())
+        .unwrap_or("1M");
     
-    // Calculate target width maintaining aspect ratio
-    let aspect_ratio = decoder.width() as f64 / decoder.height() as f64;
-    let target_width = (target_height as f64 * aspect_ratio) as u32;
+    let codec_name = job.params.get("codec")
+        .and_then(|v| v.as_str())
+        .unwrap_or("libx265");
     
-    // Make dimensions even (required by many codecs)
-    let target_width = target_width - (target_width % 2);
-    let target_height = target_height - (target_height % 2);
+    let quality_mode = job.params.get("quality_mode")
+        .and_then(|v| v.as_str())
+        .unwrap_or("bitrate");
     
-    info!("Resizing from {}x{} to {}x{}", decoder.width(), decoder.height(), target_width, target_height);
+    let segment_count = job.params.get("segments")
+        .and_then(|v| v.as_u64())
+        .map_or(config.processing.max_workers, |v| v as usize)
+        .max(1);
     
-    // Create scaler
-    let mut scaler = ffmpeg::software::scaling::context::Context::get(
-        decoder.format(),
-        decoder.width(),
-        decoder.height(),
-        decoder.format(),
-        target_width,
-        target_height,
-        ffmpeg::software::scaling::flag::Flags::BILINEAR,
-    )?;
+    let bit_rate = (quality_mode == "bitrate").then(|| parse_bitrate(bitrate)).transpose()?;
+    let options = transcode_options(job, codec_name, quality_mode)?;
+    let forced_keyframes = ForcedKeyframes::from_params(&job.params)?;
+    let picture = PictureFormat::from_params(&job.params)?;
+    let audio_streams = StreamPassthrough::from_params(&job.params)?.segment_audio(&job.input_path)?;
+    let video_stream = video_stream_param(&job.params);
     
-    // Create output
-    let mut octx = ffmpeg::format::output(&job.output_path)?;
+    let (keyframes, end_pts) = keyframe_timestamps(&job.input_path, video_stream)?;
+    let boundaries = split_points(&keyframes, end_pts, segment_count);
+    info!(segments = boundaries.len(), keyframes = keyframes.len(), "Split input at keyframes");
     
-    let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::H264)
-        .context("H264 encoder not found")?;
+    let scratch = ScratchDir::new(&config.scratch, "parallel")?;
+    let progress = std::sync::Mutex::new(ProgressReporter::from_params(&job.task, &job.params)?);
+    let total_frames = {
+        let ictx = ffmpeg::format::input(&job.input_path)?;
+        let stream = ictx.stream(select_video_stream(&ictx, video_stream)?).context("No video stream found")?;
+        expected_frame_count(&stream, ictx.duration())
+    };
+    if let Some(progress) = progress.lock().unwrap().as_mut() {
+        progress.set_total_frames(total_frames);
+    }
     
-    let mut ost = octx.add_stream(codec)?;
-    let mut encoder = ost.codec().encoder().video()?;
+    let frames_done = std::sync::atomic::AtomicUsize::new(0);
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let results: Vec<std::sync::Mutex<Option<Result<EncodedSegment>>>> =
+        boundaries.iter().map(|_| std::sync::Mutex::new(None)).collect();
+    let workers = config.processing.max_workers.clamp(1, boundaries.len());
     
-    encoder.set_width(target_width);
-    encoder.set_height(target_height);
-    encoder.set_format(decoder.format());
-    encoder.set_time_base(input_stream.time_base());
-    encoder.set_bit_rate(decoder.bit_rate());
+    std::thread::scope(|threads| {
+        for _ in 0..workers {
+            threads.spawn(|| loop {
+                let index = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let Some(&(start, end)) = boundaries.get(index) else {
+                    break;
+                };
+                let path = scratch.file(&format!("segment_{:05}.mkv", index));
+                let on_frame = || {
+                    let done = frames_done.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+                    if let Some(progress) = progress.lock().unwrap().as_mut() {
+                        progress.update(done as u64);
+                    }
+                };
+                let result = scratch.check().and_then(|()| {
+                    encode_segment_range(
+                        &job.input_path, video_stream, &path, start, end, codec_name, bit_rate, &options,
+                        &forced_keyframes, &picture, on_frame,
+                    )
+                });
+                let failed = result.is_err();
+                *results[index].lock().unwrap() = Some(result);
+                // Stop handing out segments once one has failed
+                if failed {
+                    next.store(boundaries.len(), std::sync::atomic::Ordering::Relaxed);
+                }
+            });
+        }
+    });
     
-    if let Some(frame_rate) = input_stream.avg_frame_rate() {
-        encoder.set_frame_rate(Some(frame_rate));
+    let mut segments = Vec::with_capacity(boundaries.len());
+    for (index, result) in results.into_iter().enumerate() {
+        match result.into_inner().unwrap() {
+            Some(Ok(segment)) => segments.push(segment),
+            Some(Err(e)) => return Err(e.context(format!("Segment {} failed", index))),
+            None => anyhow::bail!("Segment {} was not encoded", index),
+        }
     }
     
-    let encoder = encoder.open_as(codec)?;
-    ost.set_parameters(&encoder);
+    let frame_count = frames_done.into_inner();
+    if let Some(progress) = progress.lock().unwrap().as_mut() {
+        progress.finish(frame_count as u64);
+    }
     
-    octx.write_header()?;
+    concat_segments(&segments, &job.output_path, Some((&job.input_path, &audio_streams)), &job.params)?;
     
-    // Process frames
-    let mut frame_count = 0;
+    info!(segments = segments.len(), frames = frame_count, "Parallel transcode complete");
+    Ok(job.output_path.clone())
+}
+
+/// Keyframe timestamps of the input's video stream (see `select_video_stream`), read from
+/// packet flags without decoding, and the timestamp just past its last packet
+pub(crate) fn keyframe_timestamps(input_path: &str, video_stream: Option<usize>) -> Result<(Vec<i64>, i64)> {
+    let mut ictx = ffmpeg::format::input(&input_path)
+        .context("Failed to open input file")?;
+    let video_stream_index = select_video_stream(&ictx, video_stream)?;
     
+    let mut keyframes = Vec::new();
+    let mut end_pts = 0;
     for (stream, packet) in ictx.packets() {
-        if stream.index() == video_stream_index {
-            decoder.send_packet(&packet)?;
-            
-            let mut decoded = ffmpeg::util::frame::video::Video::empty();
-            while decoder.receive_frame(&mut decoded).is_ok() {
-                let mut scaled = ffmpeg::util::frame::video::Video::empty();
-                scaler.run(&decoded, &mut scaled)?;
-                
-                encoder.send_frame(&scaled)?;
-                
-                let mut encoded = ffmpeg::Packet::empty();
-                while encoder.receive_packet(&mut encoded).is_ok() {
-                    encoded.set_stream(0);
-                    encoded.rescale_ts(input_stream.time_base(), ost.time_base());
-                    encoded.write_interleaved(&mut octx)?;
-                }
-                
-                frame_count += 1;
-                if frame_count % 100 == 0 {
-                    info!("Processed {} frames", frame_count);
-                }
-            }
+        shutdown::check()?;
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        let Some(pts) = packet.pts().or(packet.dts()) else {
+            continue;
+        };
+        if packet.is_key() {
+            keyframes.push(pts);
         }
+        end_pts = end_pts.max(pts + packet.duration().max(1));
     }
+    keyframes.sort_unstable();
+    keyframes.dedup();
     
-    // Flush
-    encoder.send_eof()?;
-    let mut encoded = ffmpeg::Packet::empty();
-    while encoder.receive_packet(&mut encoded).is_ok() {
-        encoded.set_stream(0);
-        encoded.write_interleaved(&mut octx)?;
+    if keyframes.is_empty() {
+        anyhow::bail!("No keyframes found in the video stream");
     }
+    Ok((keyframes, end_pts))
+}
+
+/// `[start, end)` timestamp ranges for up to `count` segments, each starting on the keyframe
+/// nearest an even split
+pub(crate) fn split_points(keyframes: &[i64], end_pts: i64, count: usize) -> Vec<(i64, Option<i64>)> {
+    let first = keyframes[0];
+    let length = (end_pts - first).max(1) as f64;
     
-    octx.write_trailer()?;
+    let mut starts = vec![first];
+    for i in 1..count {
+        let target = first + (length * i as f64 / count as f64) as i64;
+        let nearest = keyframes
+            .iter()
+            .copied()
+            .min_by_key(|&keyframe| (keyframe - target).abs())
+            .unwrap_or(first);
+        if nearest > *starts.last().unwrap() {
+            starts.push(nearest);
+        }
+    }
     
-    info!("Resize complete: {} frames", frame_count);
-    Ok(job.output_path.clone())
+    // The last segment runs to the end of the input
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| (start, starts.get(i + 1).copied()))
+        .collect()
 }
 
-// Helper functions
-
-fn parse_bitrate(bitrate: &str) -> Result<usize> {
-    let bitrate = bitrate.to_uppercase();
+/// Encode the frames of the input's video stream (see `select_video_stream`) with timestamps
+/// in `[start, end)` into a segment at `path`
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn encode_segment_range(
+    input_path: &str,
+    video_stream: Option<usize>,
+    path: &str,
+    start: i64,
+    end: Option<i64>,
+    codec_name: &str,
+    bit_rate: Option<usize>,
+    options: &[(String, String)],
+    keyframes: &ForcedKeyframes,
+    picture: &PictureFormat,
+    on_frame: impl Fn(),
+) -> Result<EncodedSegment> {
+    use ffmpeg::Rescale;
     
-    if bitrate.ends_with('K') {
-        let num: usize = bitrate.trim_end_matches('K').parse()?;
-        Ok(num * 1000)
-    } else if bitrate.ends_with('M') {
-        let num: usize = bitrate.trim_end_matches('M').parse()?;
-        Ok(num * 1_000_000)
-    } else {
-        Ok(bitrate.parse()?)
+    let mut ictx = ffmpeg::format::input(&input_path)
+        .context("Failed to open input file")?;
+    let video_stream_index = select_video_stream(&ictx, video_stream)?;
+    let input_stream = ictx.stream(video_stream_index).context("No video stream found")?;
+    let time_base = input_stream.time_base();
+    let frame_rate = input_stream.avg_frame_rate();
+    let picture = picture.clone().with_source(&input_stream);
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?
+        .decoder()
+        .video()?;
+    
+    let position = start.rescale(time_base, ffmpeg::rescale::TIME_BASE);
+    ictx.seek(position, ..position)?;
+    
+    let mut dictionary = ffmpeg::Dictionary::new();
+    for (key, value) in options {
+        dictionary.set(key, value);
+    }
+    
+    let mut segment: Option<SegmentEncoder> = None;
+    let mut last_pts: Option<i64> = None;
+    let mut decoded = ffmpeg::util::frame::video::Video::empty();
+    let mut packets = ictx.packets();
+    
+    'decode: loop {
+        shutdown::check()?;
+        let flushing = match packets.next() {
+            Some((stream, packet)) if stream.index() == video_stream_index => {
+                decoder.send_packet(&packet)?;
+                false
+            }
+            Some(_) => continue,
+            None => {
+                decoder.send_eof()?;
+                true
+            }
+        };
+        
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let pts = decoded.timestamp().unwrap_or_else(|| last_pts.map_or(start, |last| last + 1));
+            // Frames come out in presentation order, so the first one past the end closes
+            // the segment; leading frames of an open GOP belong to the previous segment
+            if end.map_or(false, |end| pts >= end) {
+                break 'decode;
+            }
+            if pts < start {
+                continue;
+            }
+            last_pts = Some(pts);
+            
+            if segment.is_none() {
+                segment = Some(SegmentEncoder::open(
+                    path, &decoder, time_base, frame_rate, codec_name, bit_rate, &dictionary, keyframes, &picture, pts,
+                )?);
+            }
+            decoded.set_pts(Some(pts));
+            if let Some(current) = &mut segment {
+                current.encode(&mut decoded)?;
+            }
+            on_frame();
+        }
+        
+        if flushing {
+            break;
+        }
     }
+    
+    let segment = segment.context("Segment contains no frames")?;
+    segment.finish(end.unwrap_or_else(|| last_pts.map_or(start, |last| last + 1)))
 }
 
-fn save_frame_as_jpeg(frame: &ffmpeg::util::frame::video::Video, path: &str) -> Result<()> {
-    // For simplicity, use image crate to save
-    // In production, you might want to use ffmpeg's image encoder
-    let width = frame.width();
-    let height = frame.height();
-    let data = frame.data(0);
+/// Rewrite the input in the output's container (mkv to mp4, ts to mp4, ...) without
+/// re-encoding
+///
+/// Keeps every video stream, or only `video_stream`, and the audio, subtitle and data streams
+/// picked by the stream selection params of `transcode_video_native` (`audio` is copy or
+/// none here). Container and stream tags and chapters are carried over. Streams the output
+/// container cannot hold are left out with a warning, except video, which fails the job.
+/// MP4 outputs are written with `faststart` unless it is false.
+pub async fn remux_container(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Remuxing into a new container");
     
-    // Create RGB image buffer
-    let img = image::RgbImage::from_raw(width, height, data.to_vec())
-        .context("Failed to create image from frame data")?;
+    let passthrough = StreamPassthrough::from_params(&job.params)?;
+    if matches!(passthrough.audio, AudioMode::Encode(_)) {
+        anyhow::bail!("remux_container does not re-encode; use audio: copy or none");
+    }
     
-    img.save(path).context("Failed to save image")?;
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    let mut octx = ffmpeg::format::output(&job.output_path)
+        .context("Failed to create output file")?;
     
-    Ok(())
+    let video_streams: Vec<usize> = match video_stream_param(&job.params) {
+        Some(position) => vec![select_video_stream(&ictx, Some(position))?],
+        None => ictx
+            .streams()
+            .filter(|stream| stream.parameters().medium() == ffmpeg::media::Type::Video)
+            .map(|stream| stream.index())
+            .collect(),
+    };
+    
+    let mut outputs = std::collections::HashMap::new();
+    for &index in &video_streams {
+        let stream = ictx.stream(index).context("Input stream missing")?;
+        let codec_id = stream.parameters().id();
+        let supported = unsafe {
+            ffmpeg::ffi::avformat_query_codec(octx.format().as_ptr(), codec_id.into(), 0)
+        };
+        if supported == 0 {
+            anyhow::bail!(
+                "{} cannot hold {:?} video without re-encoding; use transcode_h264_to_h265 instead",
+                octx.format().name(),
+                codec_id
+            );
+        }
+        outputs.insert(index, StreamOutput::copy(&stream, &mut octx)?);
+    }
+    outputs.extend(passthrough.open(&ictx, &mut octx, &job.output_path)?);
+    if outputs.is_empty() {
+        anyhow::bail!("No streams selected to remux");
+    }
+    
+    octx.set_metadata(ictx.metadata().to_owned());
+    for chapter in ictx.chapters() {
+        octx.add_chapter(
+            chapter.id(),
+            chapter.time_base(),
+            chapter.start(),
+            chapter.end(),
+            chapter.metadata().get("title").unwrap_or_default(),
+        )?;
+    }
+    
+    let muxer_options = mp4_muxer_options(&job.params, &octx.format())?;
+    octx.write_header_with(muxer_options)?;
+    
+    let mut packet_count: u64 = 0;
+    for (stream, packet) in ictx.packets() {
+        if let Some(output) = outputs.get_mut(&stream.index()) {
+            output.write(packet, &mut octx)?;
+            packet_count += 1;
+        }
+    }
+    
+    octx.write_trailer()?;
+    finish_mp4_output(&job.params, &job.output_path)?;
+    
+    info!(streams = outputs.len(), packets = packet_count, "Remux complete");
+    Ok(job.output_path.clone())
 }
 
-/// Extract thumbnails (alias for extract_frames)
-pub async fn extract_thumbnails(job: &JobPayload, config: &Config) -> Result<String> {
-    extract_frames_native(job, config).await
+/// Move the index (moov atom) of an MP4 or MOV file to the front, so that players can start
+/// before the whole file has downloaded
+///
+/// Files whose index already comes first are copied as they are; others are remuxed as by
+/// `remux_container`, which takes the same stream selection params.
+pub async fn faststart_mp4(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Moving MP4 index to the front");
+    
+    if moov_before_mdat(&job.input_path)? {
+        info!("Index already precedes the media data");
+        if job.input_path != job.output_path {
+            std::fs::copy(&job.input_path, &job.output_path)
+                .context("Failed to copy input file")?;
+        }
+        return Ok(job.output_path.clone());
+    }
+    
+    let extension = Path::new(&job.output_path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    if !matches!(extension.as_str(), "mp4" | "m4v" | "m4a" | "mov" | "3gp") {
+        anyhow::bail!("faststart_mp4 writes MP4 or MOV files; got output {}", job.output_path);
+    }
+    
+    let mut params = job.params.clone();
+    if let Some(params) = params.as_object_mut() {
+        params.insert("faststart".to_string(), serde_json::json!(true));
+    }
+    let remux = JobPayload {
+        job_id: job.job_id.clone(),
+        traceparent: job.traceparent.clone(),
+        task: job.task.clone(),
+        input_path: job.input_path.clone(),
+        output_path: job.output_path.clone(),
+        params,
+    };
+    remux_container(&remux, config).await
 }
 
-/// Create animated GIF from video
-pub async fn create_animated_gif(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Creating animated GIF using ffmpeg-next");
+/// Muxer options for MP4 and MOV outputs of the tasks that take the MP4 output params
+///
+/// With `faststart` (default: true) the muxer writes the index (moov atom) at the front of
+/// the file once the media data is written, which costs a second pass over the file but lets
+/// browsers play it progressively. `fragmented` writes fragmented MP4 instead: an index
+/// without samples up front, then `moof`/`mdat` fragments starting at keyframes, or every
+/// `fragment_duration` seconds, with `default-base-moof` offsets as MSE and CMAF expect.
+/// `cmaf` (implies `fragmented`) adds the CMAF brand and segment type boxes; `split_init`
+/// (see `finish_mp4_output`) also implies `fragmented`.
+fn mp4_muxer_options(params: &serde_json::Value, format: &ffmpeg::format::format::Output) -> Result<ffmpeg::Dictionary<'static>> {
+    let mut options = ffmpeg::Dictionary::new();
+    if !is_mp4_muxer(format.name()) {
+        return Ok(options);
+    }
     
-    let duration = job.params.get("duration")
-        .and_then(|v| v.as_f64())
-        .unwrap_or(5.0);
+    let cmaf = params.get("cmaf").and_then(|v| v.as_bool()).unwrap_or(false);
+    let split_init = params.get("split_init").and_then(|v| v.as_bool()).unwrap_or(false);
+    let fragment_duration = params.get("fragment_duration").and_then(|v| v.as_f64());
+    let fragmented = params.get("fragmented")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(cmaf || split_init || fragment_duration.is_some());
     
-    let fps = job.params.get("fps")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(10) as u32;
+    if !fragmented {
+        if cmaf || split_init {
+            anyhow::bail!("cmaf and split_init need a fragmented output");
+        }
+        if params.get("faststart").and_then(|v| v.as_bool()).unwrap_or(true) {
+            options.set("movflags", "+faststart");
+        }
+        return Ok(options);
+    }
     
-    // Open input
-    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    // The empty index already sits at the front, so faststart has nothing to do
+    let mut flags = vec!["+empty_moov", "+default_base_moof"];
+    match fragment_duration {
+        Some(seconds) if seconds <= 0.0 => anyhow::bail!("fragment_duration must be positive"),
+        Some(seconds) => options.set("frag_duration", &((seconds * 1_000_000.0).round() as i64).to_string()),
+        None => flags.push("+frag_keyframe"),
+    }
+    if cmaf {
+        flags.push("+cmaf");
+    }
+    options.set("movflags", &flags.concat());
+    Ok(options)
+}
+
+/// Post-process an MP4 output written with `mp4_muxer_options`: with `split_init` the file
+/// is cut into an init segment, left at `path`, and `<stem>_NNNNN.m4s` media segments, one
+/// per fragment, next to it; returns the number of media segments
+fn finish_mp4_output(params: &serde_json::Value, path: &str) -> Result<Option<usize>> {
+    if !params.get("split_init").and_then(|v| v.as_bool()).unwrap_or(false) {
+        return Ok(None);
+    }
+    split_fragmented_mp4(path).map(Some)
+}
+
+fn is_mp4_muxer(name: &str) -> bool {
+    matches!(name, "mp4" | "mov" | "ipod" | "ismv" | "3gp" | "3g2" | "f4v" | "psp")
+}
+
+/// Type and size of the top-level MP4 box at `offset`, read without loading the box
+fn read_box_header(file: &mut std::fs::File, offset: u64, length: u64) -> Result<([u8; 4], u64)> {
+    use std::io::{Read, Seek, SeekFrom};
     
-    let input_stream = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .context("No video stream found")?;
+    let mut header = [0u8; 16];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut header[..8])?;
+    let kind: [u8; 4] = header[4..8].try_into().unwrap();
+    let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+        // The box runs to the end of the file
+        0 => length - offset,
+        // The real size follows the type as a 64-bit field
+        1 => {
+            file.read_exact(&mut header[8..16])?;
+            u64::from_be_bytes(header[8..16].try_into().unwrap())
+        }
+        size => size as u64,
+    };
+    if size < 8 || offset + size > length {
+        anyhow::bail!("Invalid MP4 box at offset {}", offset);
+    }
+    Ok((kind, size))
+}
+
+/// Whether the `moov` box of an MP4 file comes before its `mdat` box, read from the top-level
+/// box headers without loading the file
+fn moov_before_mdat(path: &str) -> Result<bool> {
+    let mut file = std::fs::File::open(path).context("Failed to open input file")?;
+    let length = file.metadata()?.len();
+    let mut offset = 0u64;
+    while offset + 8 <= length {
+        let (kind, size) = read_box_header(&mut file, offset, length)?;
+        match &kind {
+            b"moov" => return Ok(true),
+            b"mdat" => return Ok(false),
+            _ => {}
+        }
+        offset += size;
+    }
+    anyhow::bail!("{} has no moov or mdat box; is it an MP4 file?", path)
+}
+
+/// Cut a fragmented MP4 file into its init segment (everything before the first fragment),
+/// which replaces the file, and one `<stem>_NNNNN.m4s` file per fragment
+///
+/// A fragment is a `moof` box with the `mdat` after it, together with any `styp`, `sidx` or
+/// `prft` boxes just before it. The trailing `mfra` index only describes the joined file and
+/// is dropped.
+fn split_fragmented_mp4(path: &str) -> Result<usize> {
+    use std::io::{Read, Seek, SeekFrom, Write};
     
-    let video_stream_index = input_stream.index();
-    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
-    let mut decoder = context_decoder.decoder().video()?;
+    let mut file = std::fs::File::open(path).context(format!("Failed to open {}", path))?;
+    let length = file.metadata()?.len();
     
-    // Create output for GIF
-    let mut octx = ffmpeg::format::output(&job.output_path)?;
+    let mut boxes = Vec::new();
+    let mut offset = 0u64;
+    while offset + 8 <= length {
+        let (kind, size) = read_box_header(&mut file, offset, length)?;
+        boxes.push((kind, offset, size));
+        offset += size;
+    }
     
-    let codec = ffmpeg::encoder::find_by_name("gif")
-        .context("GIF encoder not found")?;
+    let first_fragment = boxes
+        .iter()
+        .position(|(kind, _, _)| kind == b"moof")
+        .context(format!("{} is not a fragmented MP4 file", path))?;
+    // Segment-level boxes before the first moof belong to the first segment
+    let init_end = boxes[..first_fragment]
+        .iter()
+        .rposition(|(kind, _, _)| !matches!(kind, b"styp" | b"sidx" | b"prft"))
+        .map_or(0, |last| last + 1);
     
-    let mut ost = octx.add_stream(codec)?;
-    let mut encoder = ost.codec().encoder().video()?;
+    let output = Path::new(path);
+    let stem = output.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let mut copy_range = |target: &Path, start: u64, end: u64| -> Result<()> {
+        file.seek(SeekFrom::Start(start))?;
+        let mut writer = std::io::BufWriter::new(
+            std::fs::File::create(target).context(format!("Failed to create {}", target.display()))?,
+        );
+        std::io::copy(&mut (&mut file).take(end - start), &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    };
     
-    encoder.set_width(decoder.width());
-    encoder.set_height(decoder.height());
-    encoder.set_format(ffmpeg::format::Pixel::RGB8);
-    encoder.set_time_base((1, fps as i32));
-    encoder.set_frame_rate(Some((fps as i32, 1).into()));
+    let init_length = boxes[init_end].1;
+    let mut segments = 0;
+    let mut start = init_length;
+    let mut in_fragment = false;
+    for &(kind, offset, size) in &boxes[init_end..] {
+        match &kind {
+            b"moof" => in_fragment = true,
+            // A segment ends with the mdat that follows its moof
+            b"mdat" if in_fragment => {
+                segments += 1;
+                copy_range(&output.with_file_name(format!("{}_{:05}.m4s", stem, segments)), start, offset + size)?;
+                start = offset + size;
+                in_fragment = false;
+            }
+            b"mfra" => start = offset + size,
+            _ => {}
+        }
+    }
     
-    let encoder = encoder.open_as(codec)?;
-    ost.set_parameters(&encoder);
+    let init_path = output.with_extension("init.tmp");
+    copy_range(&init_path, 0, init_length)?;
+    drop(file);
+    std::fs::rename(&init_path, output)?;
     
-    octx.write_header()?;
+    info!(segments, init_bytes = init_length, "Split fragmented MP4 into init and media segments");
+    Ok(segments)
+}
+
+/// Cut the span from `start` to `end` (or `start` + `duration`; default: the end of the input)
+/// out of a video
+///
+/// By default the clip is stream-copied from the keyframe at or before `start`, so it may
+/// begin up to a GOP early. With `accurate` it starts exactly on `start`: only the frames
+/// before the first keyframe inside the clip and after the last one are re-encoded, with the
+/// source's codec (or `codec`) at its bit rate (or `bitrate`), and the GOPs in between are
+/// copied. Players that cannot cope with the re-encoded frames' parameter sets changing
+/// mid-stream can be given a fully re-encoded clip with `smart_cut: false`. Audio, subtitle
+/// and data streams are copied as selected by the stream selection params of
+/// `transcode_video_native`, trimmed to the nearest packet; MP4 outputs take the MP4 output
+/// params.
+pub async fn extract_clip(job: &JobPayload, _config: &Config) -> Result<String> {
+    use ffmpeg::Rescale;
     
-    // Create scaler for RGB8 conversion
-    let mut scaler = ffmpeg::software::scaling::context::Context::get(
-        decoder.format(),
-        decoder.width(),
-        decoder.height(),
-        ffmpeg::format::Pixel::RGB8,
-        decoder.width(),
-        decoder.height(),
-        ffmpeg::software::scaling::flag::Flags::BILINEAR,
-    )?;
+    info!("Extracting clip");
     
-    let max_frames = (duration * fps as f64) as usize;
-    let mut frame_count = 0;
+    let start = crate::audio::param_seconds(&job.params, "start")?
+        .context("start parameter required")?;
+    let end = match (
+        crate::audio::param_seconds(&job.params, "end")?,
+        crate::audio::param_seconds(&job.params, "duration")?,
+    ) {
+        (Some(_), Some(_)) => anyhow::bail!("Give end or duration, not both"),
+        (Some(end), None) => Some(end),
+        (None, Some(duration)) => Some(start + duration),
+        (None, None) => None,
+    };
+    if start < 0.0 || end.map_or(false, |end| end <= start) {
+        anyhow::bail!("The clip must start at or after 0 and end after it starts");
+    }
     
-    for (stream, packet) in ictx.packets() {
-        if stream.index() == video_stream_index && frame_count < max_frames {
-            decoder.send_packet(&packet)?;
+    let accurate = job.params.get("accurate")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let smart_cut = job.params.get("smart_cut")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let passthrough = StreamPassthrough::from_params(&job.params)?;
+    if matches!(passthrough.audio, AudioMode::Encode(_)) {
+        anyhow::bail!("extract_clip copies audio; use audio: copy or none");
+    }
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    let video_stream_index = select_video_stream(&ictx, video_stream_param(&job.params))?;
+    let (time_base, frame_rate, source_bit_rate, parameters) = {
+        let stream = ictx.stream(video_stream_index).context("No video stream found")?;
+        let parameters = stream.parameters();
+        let bit_rate = unsafe { (*parameters.as_ptr()).bit_rate };
+        (stream.time_base(), stream.avg_frame_rate(), bit_rate.max(0) as usize, parameters)
+    };
+    let to_pts = |seconds: f64| (seconds / f64::from(time_base)).round() as i64;
+    let start_pts = to_pts(start);
+    let end_pts = end.map(to_pts);
+    
+    let keyframes = clip_keyframes(&mut ictx, video_stream_index, start_pts, end_pts)?;
+    // Copy [copy_start, copy_end) and encode what lies before and after it
+    let (offset_pts, copy_start, copy_end) = if !accurate {
+        let keyframe = keyframes.iter().copied().filter(|&pts| pts <= start_pts).last()
+            .or(keyframes.first().copied())
+            .context("No keyframe found at or after start")?;
+        (keyframe, Some(keyframe), None)
+    } else if !smart_cut {
+        (start_pts, None, None)
+    } else {
+        let first = keyframes
+            .iter()
+            .copied()
+            .find(|&pts| pts >= start_pts && end_pts.map_or(true, |end| pts < end));
+        let last = keyframes
+            .iter()
+            .copied()
+            .filter(|&pts| end_pts.map_or(false, |end| pts <= end) && first.map_or(false, |first| pts >= first))
+            .last();
+        // A clip that ends on a keyframe needs no re-encoded tail
+        (start_pts, first, last.filter(|&last| Some(last) != end_pts))
+    };
+    info!(offset_pts, ?copy_start, ?copy_end, keyframes = keyframes.len(), "Planned clip");
+    
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(parameters.clone())?
+        .decoder()
+        .video()?;
+    let codec_name = job.params.get("codec")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .or_else(|| ffmpeg::encoder::find(parameters.id()).map(|codec| codec.name().to_string()))
+        .context(format!("No encoder for {:?}; set codec", parameters.id()))?;
+    let bit_rate = match job.params.get("bitrate").and_then(|v| v.as_str()) {
+        Some(bitrate) => Some(parse_bitrate(bitrate)?),
+        None => (source_bit_rate > 0).then_some(source_bit_rate),
+    };
+    let quality_mode = job.params.get("quality_mode")
+        .and_then(|v| v.as_str())
+        .unwrap_or("bitrate");
+    let mut options = ffmpeg::Dictionary::new();
+    for (key, value) in transcode_options(job, &codec_name, quality_mode)? {
+        options.set(&key, &value);
+    }
+    
+    let mut octx = ffmpeg::format::output(&job.output_path)
+        .context("Failed to create output file")?;
+    let global_header = octx.format().flags().contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+    let open_encoder = |decoder: &ffmpeg::decoder::Video| -> Result<ffmpeg::encoder::Video> {
+        let codec = ffmpeg::encoder::find_by_name(&codec_name)
+            .context(format!("Codec {} not found", codec_name))?;
+        let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        encoder.set_width(decoder.width());
+        encoder.set_height(decoder.height());
+        encoder.set_format(decoder.format());
+        encoder.set_time_base(time_base);
+        encoder.set_frame_rate(frame_rate);
+        if let Some(bit_rate) = bit_rate.filter(|_| quality_mode == "bitrate") {
+            encoder.set_bit_rate(bit_rate);
+        }
+        if global_header {
+            encoder.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+        }
+        Ok(encoder.open_as_with(codec, options.clone())?)
+    };
+    
+    // Frames before the first copied keyframe (or all of them) go through this encoder
+    let head_needed = accurate && copy_start.map_or(true, |copy_start| copy_start > start_pts);
+    let mut encoder = if head_needed { Some(open_encoder(&decoder)?) } else { None };
+    {
+        let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        match (&encoder, copy_start) {
+            // Nothing is copied, so the stream is described by the encoder
+            (Some(encoder), None) => ost.set_parameters(encoder),
+            _ => ost.set_parameters(parameters.clone()),
+        }
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        if let Some(stream) = ictx.stream(video_stream_index) {
+            ost.set_metadata(stream.metadata().to_owned());
+        }
+    }
+    let mut outputs = passthrough.open(&ictx, &mut octx, &job.output_path)?;
+    // Sparse streams such as subtitles may have no packet after the clip to end them
+    let dense_streams: Vec<usize> = outputs
+        .keys()
+        .copied()
+        .filter(|&index| {
+            ictx.stream(index).map_or(false, |stream| stream.parameters().medium() == ffmpeg::media::Type::Audio)
+        })
+        .collect();
+    octx.set_metadata(ictx.metadata().to_owned());
+    
+    let muxer_options = mp4_muxer_options(&job.params, &octx.format())?;
+    octx.write_header_with(muxer_options)?;
+    let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
+    
+    let offset_seconds = offset_pts as f64 * f64::from(time_base);
+    let mut clip = ClipWriter { offset_pts, time_base, output_time_base, last_dts: None, frames: 0 };
+    let mut copying = !head_needed;
+    let mut tail_started = false;
+    let mut video_done = false;
+    let mut done_streams = std::collections::HashSet::new();
+    let mut decoded = ffmpeg::util::frame::video::Video::empty();
+    
+    let position = offset_pts.rescale(time_base, ffmpeg::rescale::TIME_BASE);
+    ictx.seek(position, ..position)?;
+    for (stream, mut packet) in ictx.packets() {
+        shutdown::check()?;
+        let index = stream.index();
+        
+        if index != video_stream_index {
+            let Some(output) = outputs.get_mut(&index) else {
+                continue;
+            };
+            let stream_base = stream.time_base();
+            let seconds = packet.pts().or(packet.dts()).map_or(0.0, |pts| pts as f64 * f64::from(stream_base));
+            if end.map_or(false, |end| seconds >= end) {
+                done_streams.insert(index);
+            } else if seconds >= offset_seconds {
+                let offset = (offset_seconds / f64::from(stream_base)).round() as i64;
+                packet.set_pts(packet.pts().map(|pts| pts - offset));
+                packet.set_dts(packet.dts().map(|dts| dts - offset));
+                output.write(packet, &mut octx)?;
+            }
+        } else if !video_done {
+            let pts = packet.pts().or(packet.dts()).unwrap_or(0);
+            let dts = packet.dts().unwrap_or(pts);
             
-            let mut decoded = ffmpeg::util::frame::video::Video::empty();
-            while decoder.receive_frame(&mut decoded).is_ok() && frame_count < max_frames {
-                let mut scaled = ffmpeg::util::frame::video::Video::empty();
-                scaler.run(&decoded, &mut scaled)?;
-                
-                encoder.send_frame(&scaled)?;
-                
-                let mut encoded = ffmpeg::Packet::empty();
-                while encoder.receive_packet(&mut encoded).is_ok() {
-                    encoded.set_stream(0);
-                    encoded.write_interleaved(&mut octx)?;
+            if !copying && !tail_started && packet.is_key() && Some(pts) == copy_start {
+                // The head ends where copying starts
+                if let Some(mut head) = encoder.take() {
+                    decoder.send_eof()?;
+                    clip.encode_decoded(&mut decoder, &mut head, &mut decoded, start_pts, copy_start, &mut octx)?;
+                    clip.finish_encoder(&mut head, &mut octx)?;
+                    decoder.flush();
                 }
-                
-                frame_count += 1;
+                copying = true;
             }
+            if copying && packet.is_key() && Some(pts) == copy_end {
+                copying = false;
+                tail_started = true;
+                encoder = Some(open_encoder(&decoder)?);
+            }
+            
+            if copying {
+                if end_pts.map_or(false, |end| dts >= end) {
+                    video_done = true;
+                } else if pts >= copy_start.unwrap_or(offset_pts) && (!accurate || end_pts.map_or(true, |end| pts < end)) {
+                    // Fast cuts end on decode order, so every copied frame keeps its references
+                    clip.write_packet(packet, &mut octx)?;
+                }
+            } else if let Some(current) = &mut encoder {
+                if end_pts.map_or(false, |end| dts >= end) {
+                    video_done = true;
+                } else {
+                    decoder.send_packet(&packet)?;
+                    let range_start = if tail_started { copy_end.unwrap_or(start_pts) } else { start_pts };
+                    clip.encode_decoded(&mut decoder, current, &mut decoded, range_start, end_pts, &mut octx)?;
+                }
+            }
+        }
+        
+        if video_done && dense_streams.iter().all(|index| done_streams.contains(index)) {
+            break;
         }
     }
     
-    encoder.send_eof()?;
-    let mut encoded = ffmpeg::Packet::empty();
-    while encoder.receive_packet(&mut encoded).is_ok() {
-        encoded.set_stream(0);
-        encoded.write_interleaved(&mut octx)?;
+    if let Some(mut current) = encoder.take() {
+        decoder.send_eof()?;
+        let range_start = if tail_started { copy_end.unwrap_or(start_pts) } else { start_pts };
+        clip.encode_decoded(&mut decoder, &mut current, &mut decoded, range_start, end_pts, &mut octx)?;
+        clip.finish_encoder(&mut current, &mut octx)?;
+    }
+    for output in outputs.values_mut() {
+        output.finish(&mut octx)?;
     }
-    
     octx.write_trailer()?;
+    finish_mp4_output(&job.params, &job.output_path)?;
+    metrics::add_frames(clip.frames as u64);
     
-    info!("Created GIF with {} frames", frame_count);
+    info!(start = offset_seconds, encoded_frames = clip.frames, "Clip extracted");
     Ok(job.output_path.clone())
 }
 
-/// Detect scene cuts in video
-pub async fn detect_scene_cuts(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Detecting scene cuts using ffmpeg-next");
+/// Keyframe timestamps of the video stream from the keyframe at or before `start_pts` up to
+/// `end_pts`, or up to the first keyframe at or after `start_pts` when the clip runs to the end
+fn clip_keyframes(
+    ictx: &mut ffmpeg::format::context::Input,
+    video_stream_index: usize,
+    start_pts: i64,
+    end_pts: Option<i64>,
+) -> Result<Vec<i64>> {
+    use ffmpeg::Rescale;
     
-    let threshold = job.params.get("threshold")
-        .and_then(|v| v.as_f64())
-        .unwrap_or(0.3);
+    let time_base = ictx.stream(video_stream_index).context("No video stream found")?.time_base();
+    let position = start_pts.rescale(time_base, ffmpeg::rescale::TIME_BASE);
+    ictx.seek(position, ..position)?;
+    
+    let mut keyframes = Vec::new();
+    for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        let Some(pts) = packet.pts().or(packet.dts()) else {
+            continue;
+        };
+        if end_pts.map_or(false, |end| packet.dts().unwrap_or(pts) > end) {
+            break;
+        }
+        if packet.is_key() {
+            keyframes.push(pts);
+            if end_pts.is_none() && pts >= start_pts {
+                break;
+            }
+        }
+    }
+    keyframes.sort_unstable();
+    Ok(keyframes)
+}
+
+/// Writes the video of `extract_clip`, shifted so that the clip starts at zero
+struct ClipWriter {
+    offset_pts: i64,
+    time_base: ffmpeg::Rational,
+    output_time_base: ffmpeg::Rational,
+    last_dts: Option<i64>,
+    /// Frames re-encoded
+    frames: usize,
+}
+
+impl ClipWriter {
+    /// Write a packet in the input's time base as output stream 0
+    fn write_packet(&mut self, mut packet: ffmpeg::Packet, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        packet.set_pts(packet.pts().map(|pts| pts - self.offset_pts));
+        packet.set_dts(packet.dts().map(|dts| dts - self.offset_pts));
+        packet.rescale_ts(self.time_base, self.output_time_base);
+        // Re-encoded and copied frames each start their decode timestamps early for
+        // reordering; keep them increasing across the joins
+        if let (Some(dts), Some(last)) = (packet.dts(), self.last_dts) {
+            if dts <= last {
+                packet.set_dts(Some(last + 1));
+                if packet.pts().map_or(false, |pts| pts <= last) {
+                    packet.set_pts(Some(last + 1));
+                }
+            }
+        }
+        self.last_dts = packet.dts().or(self.last_dts);
+        packet.set_position(-1);
+        packet.set_stream(0);
+        packet.write_interleaved(octx)?;
+        Ok(())
+    }
+    
+    /// Encode the decoder's pending frames with timestamps in `[start, end)`
+    fn encode_decoded(
+        &mut self,
+        decoder: &mut ffmpeg::decoder::Video,
+        encoder: &mut ffmpeg::encoder::Video,
+        decoded: &mut ffmpeg::util::frame::video::Video,
+        start: i64,
+        end: Option<i64>,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        while decoder.receive_frame(decoded).is_ok() {
+            let Some(pts) = decoded.timestamp() else {
+                continue;
+            };
+            if pts < start || end.map_or(false, |end| pts >= end) {
+                continue;
+            }
+            decoded.set_pts(Some(pts));
+            decoded.set_kind(ffmpeg::picture::Type::None);
+            encoder.send_frame(decoded)?;
+            self.frames += 1;
+            self.write_encoded(encoder, octx)?;
+        }
+        Ok(())
+    }
+    
+    fn finish_encoder(&mut self, encoder: &mut ffmpeg::encoder::Video, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        encoder.send_eof()?;
+        self.write_encoded(encoder, octx)
+    }
+    
+    fn write_encoded(&mut self, encoder: &mut ffmpeg::encoder::Video, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let mut encoded = ffmpeg::Packet::empty();
+        while encoder.receive_packet(&mut encoded).is_ok() {
+            self.write_packet(encoded.clone(), octx)?;
+        }
+        Ok(())
+    }
+}
+
+/// Extract video frames as images
+pub async fn extract_frames_native(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Extracting frames using ffmpeg-next");
+    
+    let count = job.params.get("count")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(10) as usize;
     
+    // Open input
     let mut ictx = ffmpeg::format::input(&job.input_path)?;
     
+    // Find video stream
     let input_stream = ictx
         .streams()
         .best(ffmpeg::media::Type::Video)
         .context("No video stream found")?;
     
     let video_stream_index = input_stream.index();
+    
+    // Get decoder
     let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
     let mut decoder = context_decoder.decoder().video()?;
     
-    let mut scene_cuts = Vec::new();
-    let mut prev_frame: Option<ffmpeg::util::frame::video::Video> = None;
-    let mut frame_index = 0;
+    let mut progress = ProgressReporter::from_params(&job.task, &job.params)?;
+    if let Some(progress) = &mut progress {
+        progress.set_total_frames(expected_frame_count(&input_stream, ictx.duration()));
+    }
     
-    let time_base = input_stream.time_base();
+    // Calculate frame interval
+    let total_frames = input_stream.frames() as usize;
+    let interval = if total_frames > count {
+        total_frames / count
+    } else {
+        1
+    };
     
-    for (stream, packet) in ictx.packets() {
-        if stream.index() == video_stream_index {
-            decoder.send_packet(&packet)?;
-            
-            let mut decoded = ffmpeg::util::frame::video::Video::empty();
-            while decoder.receive_frame(&mut decoded).is_ok() {
-                if let Some(prev) = &prev_frame {
-                    // Simple scene detection: compare frame differences
-                    let diff = calculate_frame_difference(prev, &decoded);
+    let _span = tracing::info_span!("decode").entered();
+    let mut frame_index = 0;
+    let mut saved_count = 0;
+    
+    // Create scaler for RGB conversion
+    let mut scaler = ffmpeg::software::scaling::context::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::format::Pixel::RGB24,
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::software::scaling::flag::Flags::BILINEAR,
+    )?;
+    
+    for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
+        if stream.index() == video_stream_index {
+            decoder.send_packet(&packet)?;
+            
+            let mut decoded = ffmpeg::util::frame::video::Video::empty();
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                if frame_index % interval == 0 && saved_count < count {
+                    // Convert to RGB
+                    let mut rgb_frame = ffmpeg::util::frame::video::Video::empty();
+                    scaler.run(&decoded, &mut rgb_frame)?;
                     
-                    if diff > threshold {
-                        let timestamp = frame_index as f64 * time_base.numerator() as f64 / time_base.denominator() as f64;
-                        scene_cuts.push(serde_json::json!({
-                            "frame": frame_index,
-                            "timestamp": timestamp,
-                            "difference": diff
-                        }));
-                    }
+                    // Save frame as image
+                    let output_path = format!("{}_{:04}.jpg", job.output_path, saved_count);
+                    save_frame_as_jpeg(&rgb_frame, &output_path)?;
+                    
+                    saved_count += 1;
+                    info!("Saved frame {}/{}", saved_count, count);
                 }
-                
-                prev_frame = Some(decoded.clone());
                 frame_index += 1;
+                if let Some(progress) = &mut progress {
+                    progress.update(frame_index as u64);
+                }
             }
         }
     }
     
-    let result = serde_json::json!({
-        "scene_cuts": scene_cuts,
-        "total_frames": frame_index,
-        "threshold": threshold
+    if let Some(progress) = &mut progress {
+        progress.finish(frame_index as u64);
+    }
+    
+    info!("Extracted {} frames", saved_count);
+    Ok(job.output_path.clone())
+}
+
+/// Get video information
+pub async fn get_video_info_native(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Getting video info using ffmpeg-next");
+    
+    let ictx = ffmpeg::format::input(&job.input_path)?;
+    
+    let mut info = serde_json::json!({
+        "format": ictx.format().name(),
+        "duration": ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE),
+        "bit_rate": ictx.bit_rate(),
+        "streams": []
     });
     
-    std::fs::write(&job.output_path, serde_json::to_string_pretty(&result)?)?;
+    let streams = info["streams"].as_array_mut().unwrap();
+    
+    for stream in ictx.streams() {
+        let codec = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
+        
+        let stream_info = match codec.medium() {
+            ffmpeg::media::Type::Video => {
+                let video = codec.decoder().video()?;
+                serde_json::json!({
+                    "type": "video",
+                    "codec": video.codec().map(|c| c.name()).unwrap_or("unknown"),
+                    "width": video.width(),
+                    "height": video.height(),
+                    "frame_rate": stream.avg_frame_rate().numerator() as f64 / stream.avg_frame_rate().denominator() as f64,
+                    "pixel_format": format!("{:?}", video.format()),
+                    "bit_rate": video.bit_rate(),
+                })
+            }
+            ffmpeg::media::Type::Audio => {
+                let audio = codec.decoder().audio()?;
+                serde_json::json!({
+                    "type": "audio",
+                    "codec": audio.codec().map(|c| c.name()).unwrap_or("unknown"),
+                    "sample_rate": audio.rate(),
+                    "channels": audio.channels(),
+                    "channel_layout": format!("{:?}", audio.channel_layout()),
+                    "bit_rate": audio.bit_rate(),
+                })
+            }
+            _ => {
+                serde_json::json!({
+                    "type": format!("{:?}", codec.medium()),
+                })
+            }
+        };
+        
+        streams.push(stream_info);
+    }
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&info)?)?;
     
-    info!("Detected {} scene cuts", scene_cuts.len());
     Ok(job.output_path.clone())
 }
 
-/// Apply watermark to video
-pub async fn apply_watermark(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Applying watermark using ffmpeg-next");
+/// Resize video using ffmpeg-next
+///
+/// Scales to `height` (default: 720) keeping the aspect ratio; phone footage stored sideways
+/// is turned upright first (see `auto_rotate`). Video takes the usual `codec`/`bitrate`/`crf`/
+/// `preset`/`pixel_format` params; the other streams are carried over as by
+/// `transcode_video_native`, and MP4 outputs take its MP4 output params.
+pub async fn resize_video_native(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Resizing video using ffmpeg-next");
     
-    let watermark_path = job.params.get("watermark_path")
+    let target_height = job.params.get("height")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(720) as u32;
+    
+    // Make dimensions even (required by many codecs)
+    let target_height = target_height.max(2) & !1;
+    
+    let settings = VideoOutputSettings::from_params(&job.params)?;
+    let passthrough = StreamPassthrough::from_params(&job.params)?;
+    let frame_count = filter_video(job, &settings, None, &passthrough, |(width, height)| {
+        info!("Resizing from {:.0}x{:.0} to height {}", width, height, target_height);
+        Ok(format!("scale=-2:{}", target_height))
+    })?;
+    
+    info!("Resize complete: {} frames", frame_count);
+    Ok(job.output_path.clone())
+}
+
+/// Fit a video into a target frame, letterboxing or pillarboxing it
+///
+/// The frame is `width`x`height`, or, without them, the source padded out to `aspect`
+/// (default: `16:9`) at its own resolution. The picture is scaled to fit inside the frame
+/// keeping its display aspect ratio (never enlarged when `upscale` is false) and centred on
+/// the `fill`: `color` (default), a solid `color` (default: black; a name or `#RRGGBB`), or
+/// `blur`, a copy of the picture scaled to cover the frame and blurred by `blur_radius`
+/// (default: 40). Video takes the usual `codec`/`bitrate`/`crf`/`preset`/`pixel_format`
+/// params; the other streams are carried over as by `transcode_video_native`, and MP4
+/// outputs take its MP4 output params.
+pub async fn pad_video(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Padding video using ffmpeg-next");
+    
+    let target = match (
+        job.params.get("width").and_then(|v| v.as_u64()),
+        job.params.get("height").and_then(|v| v.as_u64()),
+    ) {
+        (Some(width), Some(height)) => Some((width as u32, height as u32)),
+        (None, None) => None,
+        _ => anyhow::bail!("Give both width and height, or neither"),
+    };
+    
+    let aspect = job.params.get("aspect")
         .and_then(|v| v.as_str())
-        .context("watermark_path parameter required")?;
+        .unwrap_or("16:9");
+    let aspect = match aspect.split_once(':').or_else(|| aspect.split_once('/')) {
+        Some((width, height)) => width.trim().parse::<f64>()? / height.trim().parse::<f64>()?,
+        None => aspect.parse::<f64>()?,
+    };
+    if !aspect.is_finite() || aspect <= 0.0 {
+        anyhow::bail!("aspect must be a positive ratio such as 16:9");
+    }
+    
+    let fill = job.params.get("fill")
+        .and_then(|v| v.as_str())
+        .unwrap_or("color");
+    
+    let color = job.params.get("color")
+        .and_then(|v| v.as_str())
+        .unwrap_or("black");
+    if !color.chars().all(|c| c.is_ascii_alphanumeric() || c == '#' || c == '@' || c == '.') {
+        anyhow::bail!("Invalid color: {}", color);
+    }
+    
+    let blur_radius = job.params.get("blur_radius")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(40);
+    
+    let upscale = job.params.get("upscale")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let settings = VideoOutputSettings::from_params(&job.params)?;
+    let passthrough = StreamPassthrough::from_params(&job.params)?;
+    
+    let mut frame_size = (0, 0);
+    let frames = filter_video(job, &settings, None, &passthrough, |(display_width, height)| {
+        let (width, height) = target.unwrap_or_else(|| {
+            if display_width / height < aspect {
+                ((height * aspect).round() as u32, height as u32)
+            } else {
+                (display_width as u32, (display_width / aspect).round() as u32)
+            }
+        });
+        let (width, height) = (width.max(2) & !1, height.max(2) & !1);
+        frame_size = (width, height);
+        
+        let limit = |size: u32, dimension: &str| {
+            if upscale {
+                size.to_string()
+            } else {
+                format!("'min({},{})'", size, dimension)
+            }
+        };
+        let fitted = format!(
+            "scale={}:{}:force_original_aspect_ratio=decrease:force_divisible_by=2",
+            limit(width, "iw"),
+            limit(height, "ih"),
+        );
+        
+        // Pixels are made square first so the fit follows the display aspect ratio
+        let spec = match fill {
+            "color" => format!(
+                "scale=iw*sar:ih,setsar=1,{},pad={w}:{h}:(ow-iw)/2:(oh-ih)/2:color={}",
+                fitted,
+                color,
+                w = width,
+                h = height,
+            ),
+            "blur" => format!(
+                "scale=iw*sar:ih,setsar=1,split[background][foreground];\
+                 [background]scale={w}:{h}:force_original_aspect_ratio=increase,crop={w}:{h},\
+                 boxblur=luma_radius={}:luma_power=2[blurred];\
+                 [foreground]{}[fitted];\
+                 [blurred][fitted]overlay=(W-w)/2:(H-h)/2",
+                // boxblur rejects radii larger than the (subsampled) chroma planes allow
+                blur_radius.min(u64::from(width.min(height) / 4)).max(1),
+                fitted,
+                w = width,
+                h = height,
+            ),
+            other => anyhow::bail!("Unknown fill: {}", other),
+        };
+        Ok(format!("{},setsar=1", spec))
+    })?;
+    
+    info!("Padded {} frames to {}x{}", frames, frame_size.0, frame_size.1);
+    Ok(job.output_path.clone())
+}
+
+/// Convert a video to another frame rate
+///
+/// `fps` is the target rate, as a number (23.976, 29.97 and 59.94 are taken as the exact
+/// NTSC rates) or a fraction such as `"24000/1001"`. `mode` picks the conversion: `drop`
+/// (default) drops or repeats frames; `blend` blends neighbouring frames; `interpolate`
+/// synthesises new frames with motion-compensated interpolation (minterpolate; slow, best
+/// for smooth motion); `retime` keeps every frame and changes the playback speed instead, as
+/// for 25 to 23.976 fps conversions, with the audio resampled to match (its pitch shifts with
+/// the speed unless `keep_pitch` is true). Retimed outputs leave subtitle and data streams
+/// out. Video takes the usual `codec`/`bitrate`/`crf`/`preset`/`pixel_format` params; the
+/// other streams are carried over as by `transcode_video_native`, and MP4 outputs take its
+/// MP4 output params.
+pub async fn convert_frame_rate(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Converting frame rate using ffmpeg-next");
+    
+    let frame_rate = match job.params.get("fps") {
+        Some(serde_json::Value::String(fps)) => {
+            let (numerator, denominator) = fps
+                .split_once('/')
+                .context(format!("fps must be a number or a fraction, got {}", fps))?;
+            ffmpeg::Rational::new(numerator.trim().parse()?, denominator.trim().parse()?)
+        }
+        Some(fps) => {
+            let fps = fps.as_f64().context("fps must be a number or a fraction")?;
+            match [24, 30, 60].into_iter().find(|rate| (fps - f64::from(*rate) * 1000.0 / 1001.0).abs() < 0.01) {
+                Some(rate) => ffmpeg::Rational::new(rate * 1000, 1001),
+                None => ffmpeg::Rational::new((fps * 1000.0).round() as i32, 1000).reduce(),
+            }
+        }
+        None => anyhow::bail!("fps parameter required"),
+    };
+    if frame_rate.numerator() <= 0 || frame_rate.denominator() <= 0 {
+        anyhow::bail!("fps must be positive");
+    }
+    
+    let mode = job.params.get("mode")
+        .and_then(|v| v.as_str())
+        .unwrap_or("drop");
+    
+    let keep_pitch = job.params.get("keep_pitch")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let source_rate = {
+        let ictx = ffmpeg::format::input(&job.input_path)
+            .context("Failed to open input file")?;
+        let video_stream_index = select_video_stream(&ictx, video_stream_param(&job.params))?;
+        stream_frame_rate(&ictx.stream(video_stream_index).context("No video stream found")?)
+    };
+    let speed = f64::from(frame_rate) / f64::from(source_rate);
+    
+    let settings = VideoOutputSettings::from_params(&job.params)?;
+    let mut passthrough = StreamPassthrough::from_params(&job.params)?;
+    let filter_spec = match mode {
+        "drop" => "null".to_string(),
+        "blend" => format!("framerate=fps={}/{}", frame_rate.numerator(), frame_rate.denominator()),
+        "interpolate" => format!(
+            "minterpolate=fps={}/{}:mi_mode=mci:mc_mode=aobmc:me_mode=bidir:vsbmc=1",
+            frame_rate.numerator(),
+            frame_rate.denominator()
+        ),
+        "retime" => {
+            passthrough = passthrough.change_speed(speed, keep_pitch);
+            format!("setpts=PTS/{:.9}", speed)
+        }
+        other => anyhow::bail!("Unknown mode: {}", other),
+    };
+    
+    info!(mode, from = %source_rate, to = %frame_rate, "Converting frame rate");
+    let frames = filter_video(job, &settings, Some(frame_rate), &passthrough, |_| Ok(filter_spec))?;
+    
+    info!("Converted to {} fps: {} frames", f64::from(frame_rate), frames);
+    Ok(job.output_path.clone())
+}
+
+/// Tonemap an HDR10 (PQ) or HLG video down to SDR BT.709
+///
+/// The picture is linearised, mapped from BT.2020 to BT.709 primaries and compressed into
+/// SDR range by `algorithm`: `hable` (default, keeps highlight detail), `mobius` (keeps
+/// in-range colours exact), `reinhard`, `clip`, `linear`, `gamma`, or `bt2390` (ITU-R BT.2390
+/// EETF; needs an FFmpeg with libplacebo). `npl` is the nominal peak luminance in nits the
+/// linearisation assumes (default: 100), `desat` how strongly overbright highlights are
+/// desaturated (default: 0, off) and `tonemap_param` tunes the algorithm (see FFmpeg's
+/// tonemap filter). Sources without colour tags are taken as `input_transfer` (`pq` or `hlg`);
+/// SDR sources are passed through with a warning. The output is tagged BT.709 and carries no
+/// HDR metadata. Video takes the usual `codec`/`bitrate`/`crf`/`preset`/`pixel_format`
+/// params; the other streams are carried over as by `transcode_video_native`, and MP4
+/// outputs take its MP4 output params.
+pub async fn tonemap_hdr_to_sdr(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Tonemapping HDR to SDR using ffmpeg-next");
+    
+    let algorithm = job.params.get("algorithm")
+        .and_then(|v| v.as_str())
+        .unwrap_or("hable");
+    if !["hable", "mobius", "reinhard", "clip", "linear", "gamma", "bt2390"].contains(&algorithm) {
+        anyhow::bail!("Unknown tonemapping algorithm: {}", algorithm);
+    }
+    
+    let npl = job.params.get("npl")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(100.0);
+    
+    let desat = job.params.get("desat")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.0);
+    
+    let tonemap_param = job.params.get("tonemap_param")
+        .and_then(|v| v.as_f64());
+    
+    let source = {
+        let ictx = ffmpeg::format::input(&job.input_path)
+            .context("Failed to open input file")?;
+        let video_stream_index = select_video_stream(&ictx, video_stream_param(&job.params))?;
+        ColorTags::of_stream(&ictx.stream(video_stream_index).context("No video stream found")?)
+    };
+    let transfer = match job.params.get("input_transfer").and_then(|v| v.as_str()) {
+        _ if source.is_hdr() => source.transfer,
+        Some("pq") => ffmpeg::ffi::AVColorTransferCharacteristic::AVCOL_TRC_SMPTE2084,
+        Some("hlg") => ffmpeg::ffi::AVColorTransferCharacteristic::AVCOL_TRC_ARIB_STD_B67,
+        Some(other) => anyhow::bail!("input_transfer must be pq or hlg, got {}", other),
+        None => {
+            warn!(transfer = ?source.transfer, "Input is not tagged as HDR; converting to BT.709 without tonemapping");
+            source.transfer
+        }
+    };
+    let hdr = ColorTags { transfer, ..source }.is_hdr();
+    let zscale_transfer = if transfer == ffmpeg::ffi::AVColorTransferCharacteristic::AVCOL_TRC_SMPTE2084 {
+        "smpte2084"
+    } else {
+        "arib-std-b67"
+    };
+    
+    let filter_spec = if !hdr {
+        "zscale=p=bt709:t=bt709:m=bt709:r=tv".to_string()
+    } else if algorithm == "bt2390" {
+        if ffmpeg::filter::find("libplacebo").is_none() {
+            anyhow::bail!("bt2390 tonemapping needs FFmpeg built with libplacebo");
+        }
+        format!(
+            "setparams=color_primaries=bt2020:color_trc={}:colorspace=bt2020nc,\
+             libplacebo=tonemapping=bt.2390:colorspace=bt709:color_primaries=bt709:color_trc=bt709:range=tv",
+            zscale_transfer
+        )
+    } else {
+        let param = tonemap_param.map_or(String::new(), |param| format!(":param={}", param));
+        format!(
+            "zscale=pin=bt2020:tin={}:min=bt2020nc:t=linear:npl={},format=gbrpf32le,zscale=p=bt709,\
+             tonemap=tonemap={}:desat={}{},zscale=t=bt709:m=bt709:r=tv",
+            zscale_transfer, npl, algorithm, desat, param
+        )
+    };
+    info!(filter = %filter_spec, "Tonemapping with {}", if hdr { algorithm } else { "none" });
+    
+    let passthrough = StreamPassthrough::from_params(&job.params)?;
+    let mut settings = VideoOutputSettings::from_params(&job.params)?;
+    settings.color = Some(ColorTags::BT709);
+    let frames = filter_video(job, &settings, None, &passthrough, |_| Ok(filter_spec))?;
+    
+    info!("Tonemapped {} frames", frames);
+    Ok(job.output_path.clone())
+}
+
+/// Compare an encode (the input) with its `reference` and write VMAF, PSNR and SSIM scores
+/// as JSON
+///
+/// `metrics` picks the scores (default: all three; VMAF is left out with a warning when
+/// FFmpeg was built without libvmaf, unless asked for by name). The encode is scaled to the
+/// reference's size and both start from zero, so encodes at another resolution or with a
+/// start offset compare frame for frame; comparison stops at the end of the shorter one.
+/// `vmaf_model` picks the VMAF model (default: libvmaf's, vmaf_v0.6.1; vmaf_4k_v0.6.1 suits
+/// 4K). The report has the mean, minimum, maximum and harmonic mean of each score, plus every
+/// frame's scores unless `per_frame` is false. PSNR of identical frames is reported as 100 dB.
+/// With `min_vmaf`, `min_psnr` or `min_ssim` the job fails, after writing the report, when the
+/// pooled mean falls below it.
+pub async fn measure_quality(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Measuring video quality using ffmpeg-next");
+    
+    let reference_path = job.params.get("reference")
+        .and_then(|v| v.as_str())
+        .context("reference is required")?;
+    
+    let per_frame = job.params.get("per_frame")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let vmaf_model = job.params.get("vmaf_model")
+        .and_then(|v| v.as_str());
+    
+    let vmaf_available = ffmpeg::filter::find("libvmaf").is_some();
+    let metrics: Vec<&str> = match job.params.get("metrics") {
+        Some(value) => {
+            let metrics: Vec<&str> = value
+                .as_array()
+                .context("metrics must be an array")?
+                .iter()
+                .map(|metric| metric.as_str().context("metrics must be names"))
+                .collect::<Result<_>>()?;
+            if let Some(metric) = metrics.iter().find(|metric| !QUALITY_METRICS.contains(metric)) {
+                anyhow::bail!("Unknown quality metric: {} (expected vmaf, psnr or ssim)", metric);
+            }
+            if metrics.contains(&"vmaf") && !vmaf_available {
+                anyhow::bail!("VMAF needs an FFmpeg built with libvmaf");
+            }
+            metrics
+        }
+        None if vmaf_available => QUALITY_METRICS.to_vec(),
+        None => {
+            warn!("FFmpeg was built without libvmaf; measuring PSNR and SSIM only");
+            vec!["psnr", "ssim"]
+        }
+    };
+    if metrics.is_empty() {
+        anyhow::bail!("metrics must name at least one of vmaf, psnr or ssim");
+    }
+    
+    let mut distorted = QualityInput::open(&job.input_path, video_stream_param(&job.params), "in0")?;
+    let mut reference = QualityInput::open(reference_path, None, "in1")?;
+    let (width, height) = (reference.decoder.width(), reference.decoder.height());
+    let pixel_format = reference.decoder.format();
+    let pixel_format_name = pixel_format.descriptor().map_or("yuv420p", |descriptor| descriptor.name());
+    
+    let scratch = ScratchDir::new(&config.scratch, "vmaf")?;
+    let vmaf_log = scratch.file("vmaf.json");
+    
+    // The metric filters pass the encode's frames through with their scores attached, so
+    // they chain one after another
+    let mut filter_spec = format!(
+        "[in0]settb=AVTB,setpts=PTS-STARTPTS,scale={}:{}:flags=bicubic,format={}[d0];\
+         [in1]settb=AVTB,setpts=PTS-STARTPTS,format={},split={}",
+        width, height, pixel_format_name, pixel_format_name, metrics.len()
+    );
+    for index in 0..metrics.len() {
+        filter_spec.push_str(&format!("[r{}]", index));
+    }
+    for (index, metric) in metrics.iter().enumerate() {
+        let filter = match *metric {
+            "vmaf" => {
+                let model = vmaf_model.map_or(String::new(), |model| format!(":model=version={}", model));
+                let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+                format!("libvmaf=shortest=1:log_path='{}':log_fmt=json:n_threads={}{}", vmaf_log, threads, model)
+            }
+            metric => format!("{}=shortest=1", metric),
+        };
+        let output = if index + 1 == metrics.len() { "out".to_string() } else { format!("d{}", index + 1) };
+        filter_spec.push_str(&format!(";[d{}][r{}]{}[{}]", index, index, filter, output));
+    }
+    
+    let mut graph = build_multi_video_filter_graph(
+        &[(&distorted.decoder, distorted.time_base), (&reference.decoder, reference.time_base)],
+        &filter_spec,
+        pixel_format,
+    )?;
+    
+    // Read whichever input is behind, so the filters never queue much of either
+    let mut frames = Vec::new();
+    loop {
+        shutdown::check()?;
+        let next = [&mut distorted, &mut reference]
+            .into_iter()
+            .filter(|input| !input.done)
+            .min_by(|a, b| a.position.total_cmp(&b.position));
+        let Some(input) = next else {
+            break;
+        };
+        input.advance(&mut graph)?;
+        
+        let mut filtered = ffmpeg::util::frame::video::Video::empty();
+        while graph.get("out").context("Filter sink missing")?.sink().frame(&mut filtered).is_ok() {
+            let metadata = filtered.metadata();
+            let score = |key: &str| metadata.get(key).and_then(|value| value.parse::<f64>().ok());
+            let mut scores = serde_json::Map::new();
+            scores.insert("frame".to_string(), frames.len().into());
+            scores.insert(
+                "time".to_string(),
+                filtered.timestamp().map_or(0.0, |pts| pts as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)).into(),
+            );
+            if let Some(psnr) = score("lavfi.psnr.psnr_avg") {
+                scores.insert("psnr".to_string(), psnr.min(MAX_PSNR).into());
+            }
+            if let Some(ssim) = score("lavfi.ssim.All") {
+                scores.insert("ssim".to_string(), ssim.into());
+            }
+            frames.push(scores);
+        }
+    }
+    // libvmaf writes its log when the graph is freed
+    drop(graph);
+    
+    let mut pooled = serde_json::Map::new();
+    if metrics.contains(&"vmaf") {
+        let log: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(&vmaf_log).context("libvmaf wrote no log")?,
+        )
+        .context("Failed to parse the libvmaf log")?;
+        for frame in log["frames"].as_array().into_iter().flatten() {
+            let scores = frame["frameNum"].as_u64().and_then(|index| frames.get_mut(index as usize));
+            if let (Some(scores), Some(vmaf)) = (scores, frame["metrics"]["vmaf"].as_f64()) {
+                scores.insert("vmaf".to_string(), vmaf.into());
+            }
+        }
+    }
+    for metric in &metrics {
+        let values: Vec<f64> = frames.iter().filter_map(|scores| scores.get(*metric)?.as_f64()).collect();
+        if values.is_empty() {
+            anyhow::bail!("No {} scores; do the input and reference overlap?", metric);
+        }
+        let count = values.len() as f64;
+        pooled.insert(
+            metric.to_string(),
+            serde_json::json!({
+                "mean": values.iter().sum::<f64>() / count,
+                "min": values.iter().copied().fold(f64::INFINITY, f64::min),
+                "max": values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                // Weighs the worst frames more heavily than the mean
+                "harmonic_mean": count / values.iter().map(|value| 1.0 / (value + 1.0)).sum::<f64>() - 1.0,
+            }),
+        );
+    }
+    
+    let mut failures = Vec::new();
+    for metric in &metrics {
+        let Some(minimum) = job.params.get(format!("min_{}", metric)).and_then(|v| v.as_f64()) else {
+            continue;
+        };
+        let mean = pooled[*metric]["mean"].as_f64().unwrap_or_default();
+        if mean < minimum {
+            failures.push(format!("{} {:.3} is below {}", metric, mean, minimum));
+        }
+    }
+    
+    let frame_count = frames.len();
+    let report = serde_json::json!({
+        "input": job.input_path,
+        "reference": reference_path,
+        "width": width,
+        "height": height,
+        "frame_count": frame_count,
+        "pooled": pooled,
+        "passed": failures.is_empty(),
+        "frames": if per_frame { serde_json::Value::from(frames) } else { serde_json::Value::Null },
+    });
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    info!(frames = frame_count, pooled = %report["pooled"], "Measured quality");
+    if !failures.is_empty() {
+        anyhow::bail!("Quality check failed: {}", failures.join(", "));
+    }
+    Ok(job.output_path.clone())
+}
+
+/// Scores `measure_quality` can compute
+const QUALITY_METRICS: [&str; 3] = ["vmaf", "psnr", "ssim"];
+
+/// PSNR reported for identical frames, whose PSNR is infinite
+const MAX_PSNR: f64 = 100.0;
+
+/// One side of a `measure_quality` comparison, read a packet at a time
+struct QualityInput {
+    ictx: ffmpeg::format::context::Input,
+    index: usize,
+    decoder: ffmpeg::decoder::Video,
+    time_base: ffmpeg::Rational,
+    /// Filter source the decoded frames go to
+    source: &'static str,
+    /// Seconds read so far, from the first packet
+    position: f64,
+    start: Option<f64>,
+    done: bool,
+}
+
+impl QualityInput {
+    fn open(path: &str, video_stream: Option<usize>, source: &'static str) -> Result<Self> {
+        let ictx = ffmpeg::format::input(&path).context(format!("Failed to open {}", path))?;
+        let index = select_video_stream(&ictx, video_stream)?;
+        let stream = ictx.stream(index).context("No video stream found")?;
+        let time_base = stream.time_base();
+        let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        Ok(Self { ictx, index, decoder, time_base, source, position: 0.0, start: None, done: false })
+    }
+    
+    /// Decode the next packet into the graph, flushing the source at the end of the file
+    fn advance(&mut self, graph: &mut ffmpeg::filter::Graph) -> Result<()> {
+        match self.ictx.packets().next() {
+            Some((stream, packet)) => {
+                if stream.index() != self.index {
+                    return Ok(());
+                }
+                if let Some(dts) = packet.dts() {
+                    let seconds = dts as f64 * f64::from(self.time_base);
+                    self.position = seconds - *self.start.get_or_insert(seconds);
+                }
+                self.decoder.send_packet(&packet)?;
+                send_decoded_video(&mut self.decoder, graph, self.source)
+            }
+            None => {
+                self.decoder.send_eof()?;
+                send_decoded_video(&mut self.decoder, graph, self.source)?;
+                graph.get(self.source).context("Filter source missing")?.source().flush()?;
+                self.done = true;
+                Ok(())
+            }
+        }
+    }
+}
+
+// Helper functions
+
+pub(crate) fn parse_bitrate(bitrate: &str) -> Result<usize> {
+    let bitrate = bitrate.to_uppercase();
+    
+    if bitrate.ends_with('K') {
+        let num: usize = bitrate.trim_end_matches('K').parse()?;
+        Ok(num * 1000)
+    } else if bitrate.ends_with('M') {
+        let num: usize = bitrate.trim_end_matches('M').parse()?;
+        Ok(num * 1_000_000)
+    } else {
+        Ok(bitrate.parse()?)
+    }
+}
+
+fn save_frame_as_jpeg(frame: &ffmpeg::util::frame::video::Video, path: &str) -> Result<()> {
+    // For simplicity, use image crate to save
+    // In production, you might want to use ffmpeg's image encoder
+    let width = frame.width();
+    let height = frame.height();
+    let data = frame.data(0);
+    
+    // Create RGB image buffer
+    let img = image::RgbImage::from_raw(width, height, data.to_vec())
+        .context("Failed to create image from frame data")?;
+    
+    img.save(path).context("Failed to save image")?;
+    
+    Ok(())
+}
+
+/// Extract thumbnails (alias for extract_frames)
+pub async fn extract_thumbnails(job: &JobPayload, config: &Config) -> Result<String> {
+    extract_frames_native(job, config).await
+}
+
+/// Create an animated GIF, WebP or AVIF from a stretch of video
+///
+/// `format` is `gif`, `webp` or `avif` (default: from the output extension, else gif). Frames
+/// from `start` (default: 0) for `duration` seconds (default: 5) are resampled to `fps`
+/// (default: 10) and scaled to `width` (keeping the aspect ratio; default: the source size).
+/// GIFs get an optimised palette built from the whole clip (palettegen/paletteuse), with
+/// `max_colors` (default: 256) and `dither` (default: sierra2_4a; also bayer,
+/// floyd_steinberg, none). WebP takes `quality` (0-100, default: 75) and `lossless`; AVIF
+/// takes `crf` (default: 35). `loop` is the number of repeats, 0 (default) looping forever
+/// and -1 playing once.
+pub async fn create_animated_gif(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Creating animated image using ffmpeg-next");
+    
+    let format = job.params.get("format")
+        .and_then(|v| v.as_str())
+        .map(|format| format.to_ascii_lowercase())
+        .unwrap_or_else(|| {
+            match Path::new(&job.output_path).extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()) {
+                Some(extension) if extension == "webp" || extension == "avif" => extension,
+                _ => "gif".to_string(),
+            }
+        });
+    
+    let start = crate::audio::param_seconds(&job.params, "start")?.unwrap_or(0.0);
+    
+    let duration = job.params.get("duration")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(5.0);
+    
+    let fps = job.params.get("fps")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(10)
+        .max(1) as i32;
+    
+    let loop_count = job.params.get("loop")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    
+    let max_colors = job.params.get("max_colors")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(256)
+        .clamp(4, 256);
+    
+    let dither = job.params.get("dither")
+        .and_then(|v| v.as_str())
+        .unwrap_or("sierra2_4a");
+    
+    // Open input
+    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    
+    let input_stream = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .context("No video stream found")?;
+    
+    let video_stream_index = input_stream.index();
+    let time_base = input_stream.time_base();
+    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
+    let mut decoder = context_decoder.decoder().video()?;
+    
+    let width = job.params.get("width").and_then(|v| v.as_u64()).map(|v| v as u32);
+    let (width, height) = match width {
+        Some(width) => {
+            let height = (width as f64 * decoder.height() as f64 / decoder.width() as f64).round() as u32;
+            (width.max(2), height.max(2))
+        }
+        None => (decoder.width(), decoder.height()),
+    };
+    
+    let (codec, pixel_format, filters, options) = match format.as_str() {
+        "gif" => (
+            ffmpeg::encoder::find_by_name("gif").context("GIF encoder not found")?,
+            ffmpeg::format::Pixel::PAL8,
+            format!(
+                "split[frames][stats];[stats]palettegen=max_colors={}:stats_mode=full[palette];\
+                 [frames][palette]paletteuse=dither={}:diff_mode=rectangle",
+                max_colors, dither
+            ),
+            ffmpeg::Dictionary::new(),
+        ),
+        "webp" => {
+            let mut options = ffmpeg::Dictionary::new();
+            let quality = job.params.get("quality")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(75)
+                .min(100);
+            options.set("quality", &quality.to_string());
+            if job.params.get("lossless").and_then(|v| v.as_bool()).unwrap_or(false) {
+                options.set("lossless", "1");
+            }
+            (
+                ffmpeg::encoder::find_by_name("libwebp_anim").context("libwebp_anim encoder not found")?,
+                ffmpeg::format::Pixel::YUVA420P,
+                "null".to_string(),
+                options,
+            )
+        }
+        "avif" => {
+            let codec = ["libaom-av1", "libsvtav1"]
+                .iter()
+                .find_map(|name| ffmpeg::encoder::find_by_name(name))
+                .context("No AV1 encoder found for AVIF")?;
+            let mut options = ffmpeg::Dictionary::new();
+            let crf = job.params.get("crf")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(35);
+            options.set("crf", &crf.to_string());
+            (codec, ffmpeg::format::Pixel::YUV420P, "null".to_string(), options)
+        }
+        other => anyhow::bail!("Unsupported animation format: {}", other),
+    };
+    
+    // 4:2:0 encoders need even dimensions
+    let (width, height) = if pixel_format == ffmpeg::format::Pixel::PAL8 {
+        (width, height)
+    } else {
+        (width.max(2) & !1, height.max(2) & !1)
+    };
+    
+    let mut graph = build_video_filter_graph(
+        &decoder,
+        time_base,
+        &format!("fps={},scale={}:{}:flags=lanczos,{}", fps, width, height, filters),
+        pixel_format,
+    )?;
+    
+    // Create output
+    let mut octx = ffmpeg::format::output_as(&job.output_path, &format)
+        .context(format!("Failed to create {} output", format))?;
+    
+    let mut ost = octx.add_stream(codec)?;
+    let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(pixel_format);
+    encoder.set_time_base((1, fps));
+    encoder.set_frame_rate(Some((fps, 1).into()));
+    
+    let mut encoder = encoder.open_as_with(codec, options)?;
+    ost.set_parameters(&encoder);
+    ost.set_time_base((1, fps));
+    
+    let mut muxer_options = ffmpeg::Dictionary::new();
+    muxer_options.set("loop", &loop_count.to_string());
+    octx.write_header_with(muxer_options)?;
+    let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
+    
+    if start > 0.0 {
+        let position = (start * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+        ictx.seek(position, ..position)?;
+    }
+    
+    let end = start + duration;
+    let mut frame_count = 0;
+    
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        let finished = send_clip_frames(&mut decoder, &mut graph, time_base, start, end)?;
+        // GIF frames only come out at the end, once the palette is known
+        frame_count += encode_filtered_video(&mut graph, &mut encoder, &mut octx, output_time_base)?;
+        
+        if finished {
+            break;
+        }
+    }
+    decoder.send_eof()?;
+    send_clip_frames(&mut decoder, &mut graph, time_base, start, end)?;
+    
+    graph.get("in").context("Filter source missing")?.source().flush()?;
+    frame_count += encode_filtered_video(&mut graph, &mut encoder, &mut octx, output_time_base)?;
+    
+    encoder.send_eof()?;
+    write_encoded_packets(&mut encoder, &mut octx, output_time_base)?;
+    
+    octx.write_trailer()?;
+    
+    info!("Created {} with {} frames", format, frame_count);
+    Ok(job.output_path.clone())
+}
+
+/// Detect stretches of frozen picture and write them as a JSON report
+///
+/// A freeze is a run of frames that differ from each other by less than `noise` (dB, default:
+/// -60) for at least `min_duration` seconds (default: 2), the signature of an encoder that
+/// stalled or a capture that lost its signal. Raise `noise` towards -40 for noisy sources
+/// whose frozen frames still differ by grain.
+pub async fn detect_freeze_frames(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Detecting freeze frames using ffmpeg-next");
+    
+    let noise_db = job.params.get("noise")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(-60.0);
+    
+    let min_duration = job.params.get("min_duration")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(2.0);
+    if min_duration <= 0.0 {
+        anyhow::bail!("min_duration must be positive");
+    }
+    
+    let mut freezes = DetectedIntervals::freezes(noise_db, min_duration);
+    let filter = freezes.filter.clone();
+    let duration = analyze_video(
+        &job.input_path,
+        video_stream_param(&job.params),
+        &filter,
+        None,
+        |frame| {
+            freezes.frame(frame);
+            Ok(())
+        },
+    )?;
+    let freezes = freezes.finish(duration);
+    let total_frozen: f64 = freezes.iter().map(|(start, end)| end - start).sum();
+    
+    info!(
+        "Found {} freezes ({:.2}s of {:.2}s)",
+        freezes.len(), total_frozen, duration
+    );
+    
+    let report = serde_json::json!({
+        "noise_db": noise_db,
+        "min_duration": min_duration,
+        "duration": duration,
+        "total_frozen": total_frozen,
+        "frozen_ratio": if duration > 0.0 { total_frozen / duration } else { 0.0 },
+        "fully_frozen": duration > 0.0 && (total_frozen - duration).abs() < 1e-3,
+        "freezes": freezes.iter().map(|(start, end)| serde_json::json!({
+            "start": start,
+            "end": end,
+            "duration": end - start,
+        })).collect::<Vec<_>>(),
+    });
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Work out whether video is progressive, interlaced or telecined and write a JSON report
+///
+/// Every frame's fields are compared (FFmpeg's `idet`): frames whose fields are from different
+/// moments count as interlaced, top or bottom field first, and fields repeated from the
+/// previous frame mark 3:2 pulldown. The video is `interlaced` when more than `threshold`
+/// (default: 0.25) of the frames that could be decided are interlaced, and `telecined` when
+/// repeated fields follow the pulldown pattern of two frames in five. The report gives the
+/// `scan_type`, `field_order`, the counts behind them, the field order the stream declares,
+/// and `needs_deinterlace` with the filter that would undo it (`bwdif` for interlaced video,
+/// `fieldmatch,decimate` to recover the film frames of telecined video).
+pub async fn detect_interlacing(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Detecting interlacing using ffmpeg-next");
+    
+    let threshold = job.params.get("threshold")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.25);
+    
+    let declared_field_order = {
+        let ictx = ffmpeg::format::input(&job.input_path)
+            .context("Failed to open input file")?;
+        let video_stream_index = select_video_stream(&ictx, video_stream_param(&job.params))?;
+        let stream = ictx.stream(video_stream_index).context("No video stream found")?;
+        field_order_name(unsafe { (*stream.parameters().as_ptr()).field_order })
+    };
+    
+    let mut fields = FieldCounts::default();
+    let duration = analyze_video(&job.input_path, video_stream_param(&job.params), FieldCounts::FILTER, None, |frame| {
+        fields.frame(frame);
+        Ok(())
+    })?;
+    let (scan_type, field_order) = fields.classify(threshold);
+    
+    info!(scan_type, field_order, declared_field_order, "Analysed {:.2}s of video", duration);
+    
+    let report = serde_json::json!({
+        "threshold": threshold,
+        "duration": duration,
+        "scan_type": scan_type,
+        "field_order": field_order,
+        "declared_field_order": declared_field_order,
+        "needs_deinterlace": scan_type != "progressive",
+        "recommended_filter": match scan_type {
+            "telecined" => Some("fieldmatch,decimate".to_string()),
+            "interlaced" => Some(format!("bwdif=mode=send_frame:parity={}", field_order)),
+            _ => None,
+        },
+        "frames": {
+            "tff": fields.tff,
+            "bff": fields.bff,
+            "progressive": fields.progressive,
+            "undetermined": fields.undetermined,
+        },
+        "repeated_fields": {
+            "neither": fields.repeated_neither,
+            "top": fields.repeated_top,
+            "bottom": fields.repeated_bottom,
+        },
+    });
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Measure how far audio runs ahead of or behind the picture across the file, as JSON
+///
+/// Cuts and flashes in the picture are lined up with onsets in the sound (a slammed door, a
+/// clap, a music hit): in each `window` seconds (default: 60) the picture change and audio
+/// onset envelopes are cross-correlated at offsets up to `max_offset` seconds (default: 1),
+/// and the best offset is that window's sync error as played (the streams placed by their
+/// timestamps), positive when the audio is late. Windows whose correlation is under
+/// `min_confidence` (default: 0.2) have too few matching events to trust and are left out of
+/// the drift, a straight line fitted through the others: `offset` at the start plus
+/// `drift_per_hour`. The report also has the offset between the streams'
+/// start timestamps and the difference between their end times, and is `in_sync` when every
+/// trusted window is within `tolerance` seconds (default: 0.045).
+pub async fn measure_av_sync(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Measuring audio/video sync using ffmpeg-next");
+    
+    let window = job.params.get("window")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(60.0);
+    
+    let max_offset = job.params.get("max_offset")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(1.0);
+    
+    let min_confidence = job.params.get("min_confidence")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.2);
+    
+    let tolerance = job.params.get("tolerance")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.045);
+    
+    if window <= 2.0 * max_offset || max_offset <= 0.0 {
+        anyhow::bail!("window must be more than twice max_offset, and max_offset positive");
+    }
+    
+    // Where each stream's first frame falls, which the envelopes below both start from
+    let stream_offset = {
+        let ictx = ffmpeg::format::input(&job.input_path)
+            .context("Failed to open input file")?;
+        let video_stream_index = select_video_stream(&ictx, video_stream_param(&job.params))?;
+        let start = |stream: &ffmpeg::format::stream::Stream| match stream.start_time() {
+            ffmpeg::ffi::AV_NOPTS_VALUE => 0.0,
+            start => start as f64 * f64::from(stream.time_base()),
+        };
+        let audio = ictx.streams().best(ffmpeg::media::Type::Audio).context("No audio stream found")?;
+        start(&audio) - start(&ictx.stream(video_stream_index).context("No video stream found")?)
+    };
+    
+    let audio = crate::audio::onset_envelope(&job.input_path, SYNC_HOP)?;
+    
+    // Mean change between consecutive frames of a small greyscale copy of the picture
+    let mut video = Vec::new();
+    let mut previous: Option<Vec<u8>> = None;
+    let video_duration = analyze_video(
+        &job.input_path,
+        video_stream_param(&job.params),
+        "scale=64:36,format=gray",
+        Some(ffmpeg::format::Pixel::GRAY8),
+        |frame| {
+            let (width, height, stride) = (frame.width() as usize, frame.height() as usize, frame.stride(0));
+            let pixels: Vec<u8> = (0..height)
+                .flat_map(|row| frame.data(0)[row * stride..row * stride + width].iter().copied())
+                .collect();
+            if let Some(previous) = &previous {
+                let change = pixels.iter().zip(previous).map(|(a, b)| a.abs_diff(*b) as f64).sum::<f64>()
+                    / pixels.len().max(1) as f64;
+                let seconds = frame.timestamp().map_or(0.0, |pts| pts as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE));
+                let index = (seconds / SYNC_HOP).round() as usize;
+                if video.len() <= index {
+                    video.resize(index + 1, 0.0);
+                }
+                video[index] = f64::max(video[index], change);
+            }
+            previous = Some(pixels);
+            Ok(())
+        },
+    )?;
+    let audio_duration = audio.len() as f64 * SYNC_HOP;
+    
+    // Audio envelope on the picture's clock
+    let shift = (stream_offset / SYNC_HOP).round() as i64;
+    let audio_at = |index: i64| -> f64 {
+        usize::try_from(index - shift).ok().and_then(|index| audio.get(index)).copied().unwrap_or(0.0)
+    };
+    
+    let window_hops = (window / SYNC_HOP).round() as usize;
+    let max_lag = (max_offset / SYNC_HOP).round() as i64;
+    let mut windows = Vec::new();
+    for start in (0..video.len()).step_by(window_hops) {
+        shutdown::check()?;
+        let end = (start + window_hops).min(video.len());
+        if end - start <= 2 * max_lag as usize {
+            break;
+        }
+        let picture = &video[start..end];
+        let best = (-max_lag..=max_lag)
+            .map(|lag| {
+                let sound: Vec<f64> = (start..end).map(|index| audio_at(index as i64 + lag)).collect();
+                (lag, correlation(picture, &sound))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((lag, confidence)) = best {
+            windows.push((
+                (start + end) as f64 / 2.0 * SYNC_HOP,
+                lag as f64 * SYNC_HOP,
+                confidence,
+            ));
+        }
+    }
+    
+    // Least-squares line through the trusted windows, weighted by how well each matched
+    let trusted: Vec<&(f64, f64, f64)> = windows.iter().filter(|(_, _, confidence)| *confidence >= min_confidence).collect();
+    let weight: f64 = trusted.iter().map(|(_, _, confidence)| confidence).sum();
+    let fit = (weight > 0.0).then(|| {
+        let mean_time = trusted.iter().map(|(time, _, confidence)| time * confidence).sum::<f64>() / weight;
+        let mean_offset = trusted.iter().map(|(_, offset, confidence)| offset * confidence).sum::<f64>() / weight;
+        let spread: f64 = trusted.iter().map(|(time, _, confidence)| confidence * (time - mean_time).powi(2)).sum();
+        let slope = if spread > 0.0 {
+            trusted.iter()
+                .map(|(time, offset, confidence)| confidence * (time - mean_time) * (offset - mean_offset))
+                .sum::<f64>() / spread
+        } else {
+            0.0
+        };
+        (mean_offset - slope * mean_time, slope)
+    });
+    let in_sync = !trusted.is_empty() && trusted.iter().all(|(_, offset, _)| offset.abs() <= tolerance);
+    
+    match fit {
+        Some((offset, slope)) => info!(
+            "Audio is {:.0}ms {} at the start, drifting {:.0}ms per hour ({} of {} windows trusted)",
+            offset.abs() * 1000.0, if offset >= 0.0 { "late" } else { "early" },
+            slope * 3600.0 * 1000.0, trusted.len(), windows.len()
+        ),
+        None => warn!("No window had enough matching picture and sound events to measure sync"),
+    }
+    
+    let report = serde_json::json!({
+        "window": window,
+        "max_offset": max_offset,
+        "min_confidence": min_confidence,
+        "tolerance": tolerance,
+        "video_duration": video_duration,
+        "audio_duration": audio_duration,
+        "duration_difference": audio_duration + stream_offset - video_duration,
+        "stream_offset": stream_offset,
+        "offset": fit.map(|(offset, _)| offset),
+        "drift_per_hour": fit.map(|(_, slope)| slope * 3600.0),
+        "in_sync": in_sync,
+        "windows": windows.iter().map(|(time, offset, confidence)| serde_json::json!({
+            "time": time,
+            "offset": offset,
+            "confidence": confidence,
+            "trusted": *confidence >= min_confidence,
+        })).collect::<Vec<_>>(),
+    });
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Check a file against a QC profile and write a report with a verdict for each rule
+///
+/// `profile` names a built-in profile (`web` or `broadcast`) or is an object of rules; an
+/// object with `base` starts from that built-in profile and overrides its rules. Rules left
+/// out are not checked. The file is opened and decoded (`readable`, `decodes`); stream rules
+/// cover presence (`require_video`, `require_audio`), codecs, resolution, frame rate, pixel
+/// format, scan type (`allow_interlaced`), duration, sample rate and channels; content rules
+/// cover the longest run of black picture (`max_black`), frozen picture (`max_freeze`) and
+/// silence (`max_silence`, below `silence_threshold` dB), integrated loudness (`loudness`
+/// within `loudness_tolerance` LU) and sample peak (`max_peak` dBFS). Black, freeze and
+/// interlace analysis share one decode of the video. The report's `passed` is true when
+/// every rule passed; with `strict` the job fails when one did not, after writing the report.
+pub async fn run_qc_report(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Running QC report using ffmpeg-next");
+    
+    let strict = job.params.get("strict")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let (profile_name, profile) = qc_profile(job.params.get("profile"))?;
+    
+    let mut rules = Vec::new();
+    let mut check = |rule: &str, passed: bool, value: serde_json::Value, expected: serde_json::Value| {
+        if !passed {
+            warn!(rule, %value, %expected, "QC rule failed");
+        }
+        rules.push(serde_json::json!({
+            "rule": rule,
+            "passed": passed,
+            "value": value,
+            "expected": expected,
+        }));
+    };
+    
+    let mut analysis = serde_json::Map::new();
+    match ffmpeg::format::input(&job.input_path) {
+        Err(e) => check("readable", false, e.to_string().into(), "a media file FFmpeg can open".into()),
+        Ok(ictx) => {
+            check("readable", true, ictx.format().name().into(), "a media file FFmpeg can open".into());
+            let video = select_video_stream(&ictx, video_stream_param(&job.params))
+                .ok()
+                .and_then(|index| ictx.stream(index));
+            let audio = ictx.streams().best(ffmpeg::media::Type::Audio);
+            let codec_name = |stream: &ffmpeg::format::stream::Stream| {
+                ffmpeg::decoder::find(stream.parameters().id()).map_or("unknown".to_string(), |codec| codec.name().to_string())
+            };
+            
+            if let Some(required) = profile.require_video {
+                check("require_video", video.is_some() || !required, video.is_some().into(), required.into());
+            }
+            if let Some(required) = profile.require_audio {
+                check("require_audio", audio.is_some() || !required, audio.is_some().into(), required.into());
+            }
+            
+            let mut decode_errors = Vec::new();
+            let mut duration = ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE);
+            
+            if let Some(stream) = &video {
+                let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+                    .decoder()
+                    .video()?;
+                let codec = codec_name(stream);
+                let (width, height) = (decoder.width(), decoder.height());
+                let frame_rate = f64::from(stream_frame_rate(stream));
+                let pixel_format = decoder.format().descriptor().map_or("unknown", |descriptor| descriptor.name());
+                analysis.insert("video".to_string(), serde_json::json!({
+                    "codec": codec,
+                    "width": width,
+                    "height": height,
+                    "frame_rate": frame_rate,
+                    "pixel_format": pixel_format,
+                }));
+                
+                if let Some(codecs) = &profile.video_codecs {
+                    check("video_codecs", codecs.contains(&codec), codec.clone().into(), codecs.clone().into());
+                }
+                if profile.min_width.is_some() || profile.min_height.is_some() {
+                    let (min_width, min_height) = (profile.min_width.unwrap_or(0), profile.min_height.unwrap_or(0));
+                    check(
+                        "min_resolution",
+                        width >= min_width && height >= min_height,
+                        format!("{}x{}", width, height).into(),
+                        format!("at least {}x{}", min_width, min_height).into(),
+                    );
+                }
+                if profile.max_width.is_some() || profile.max_height.is_some() {
+                    let (max_width, max_height) = (profile.max_width.unwrap_or(u32::MAX), profile.max_height.unwrap_or(u32::MAX));
+                    check(
+                        "max_resolution",
+                        width <= max_width && height <= max_height,
+                        format!("{}x{}", width, height).into(),
+                        format!("at most {}x{}", max_width, max_height).into(),
+                    );
+                }
+                if let Some(rates) = &profile.frame_rates {
+                    let passed = rates.iter().any(|rate| (rate - frame_rate).abs() < 0.01);
+                    check("frame_rates", passed, frame_rate.into(), rates.clone().into());
+                }
+                if let Some(formats) = &profile.pixel_formats {
+                    let passed = formats.iter().any(|format| format == pixel_format);
+                    check("pixel_formats", passed, pixel_format.into(), formats.clone().into());
+                }
+                
+                // Black, freeze and interlace analysis in one pass over the picture
+                let mut black = profile.max_black.map(|max| DetectedIntervals::black(0.1, max.max(0.0)));
+                let mut freezes = profile.max_freeze.map(|max| DetectedIntervals::freezes(-60.0, max.max(0.0)));
+                let mut fields = FieldCounts::default();
+                let filter_spec = [black.as_ref().map(|black| black.filter.as_str()), freezes.as_ref().map(|freezes| freezes.filter.as_str())]
+                    .into_iter()
+                    .flatten()
+                    .chain([FieldCounts::FILTER])
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let mut frames = 0u64;
+                let analysed = analyze_video(&job.input_path, video_stream_param(&job.params), &filter_spec, None, |frame| {
+                    if let Some(black) = &mut black {
+                        black.frame(frame);
+                    }
+                    if let Some(freezes) = &mut freezes {
+                        freezes.frame(frame);
+                    }
+                    fields.frame(frame);
+                    frames += 1;
+                    Ok(())
+                });
+                match analysed {
+                    Ok(video_duration) => {
+                        duration = video_duration;
+                        let expected = expected_frame_count(stream, ictx.duration());
+                        // Allow for a few frames lost to edit lists and decoder delay
+                        if let Some(expected) = expected.filter(|&expected| frames + 2 + expected / 100 < expected) {
+                            decode_errors.push(format!("decoded {} of {} video frames", frames, expected));
+                        }
+                    }
+                    Err(e) => {
+                        shutdown::check()?;
+                        decode_errors.push(format!("video: {:#}", e));
+                    }
+                }
+                
+                let (scan_type, field_order) = fields.classify(0.25);
+                analysis.insert("scan_type".to_string(), scan_type.into());
+                analysis.insert("field_order".to_string(), field_order.into());
+                if profile.allow_interlaced == Some(false) {
+                    check("allow_interlaced", scan_type == "progressive", scan_type.into(), "progressive".into());
+                }
+                
+                let intervals = |intervals: Vec<(f64, f64)>| -> serde_json::Value {
+                    intervals.iter().map(|(start, end)| serde_json::json!({ "start": start, "end": end })).collect()
+                };
+                if let (Some(black), Some(max)) = (black, profile.max_black) {
+                    let black = black.finish(duration);
+                    let longest = black.iter().map(|(start, end)| end - start).fold(0.0, f64::max);
+                    check("max_black", black.is_empty() || longest <= max, longest.into(), max.into());
+                    analysis.insert("black".to_string(), intervals(black));
+                }
+                if let (Some(freezes), Some(max)) = (freezes, profile.max_freeze) {
+                    let freezes = freezes.finish(duration);
+                    let longest = freezes.iter().map(|(start, end)| end - start).fold(0.0, f64::max);
+                    check("max_freeze", freezes.is_empty() || longest <= max, longest.into(), max.into());
+                    analysis.insert("freezes".to_string(), intervals(freezes));
+                }
+            }
+            
+            if let Some(stream) = &audio {
+                let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+                    .decoder()
+                    .audio()?;
+                let codec = codec_name(stream);
+                let (sample_rate, channels) = (decoder.rate(), decoder.channels());
+                analysis.insert("audio".to_string(), serde_json::json!({
+                    "codec": codec,
+                    "sample_rate": sample_rate,
+                    "channels": channels,
+                }));
+                
+                if let Some(codecs) = &profile.audio_codecs {
+                    check("audio_codecs", codecs.contains(&codec), codec.clone().into(), codecs.clone().into());
+                }
+                if let Some(rates) = &profile.sample_rates {
+                    check("sample_rates", rates.contains(&sample_rate), sample_rate.into(), rates.clone().into());
+                }
+                if let Some(counts) = &profile.channels {
+                    check("channels", counts.contains(&channels), channels.into(), counts.clone().into());
+                }
+                
+                if profile.loudness.is_some() || profile.max_peak.is_some() {
+                    match crate::audio::measure_loudness(&job.input_path) {
+                        Ok(measurement) => {
+                            let peak_db = 20.0 * measurement.sample_peak.max(1e-10).log10();
+                            analysis.insert("integrated_lufs".to_string(), measurement.integrated_lufs.into());
+                            analysis.insert("sample_peak_db".to_string(), peak_db.into());
+                            if let Some(target) = profile.loudness {
+                                let tolerance = profile.loudness_tolerance.unwrap_or(1.0);
+                                let passed = measurement.integrated_lufs.map_or(false, |lufs| (lufs - target).abs() <= tolerance);
+                                check(
+                                    "loudness",
+                                    passed,
+                                    measurement.integrated_lufs.into(),
+                                    format!("{} LUFS ± {} LU", target, tolerance).into(),
+                                );
+                            }
+                            if let Some(max_peak) = profile.max_peak {
+                                check("max_peak", peak_db <= max_peak, peak_db.into(), max_peak.into());
+                            }
+                        }
+                        Err(e) => {
+                            shutdown::check()?;
+                            decode_errors.push(format!("audio: {:#}", e));
+                        }
+                    }
+                }
+                
+                if let Some(max) = profile.max_silence {
+                    let threshold_db = profile.silence_threshold.unwrap_or(-50.0);
+                    match crate::audio::find_silences(&job.input_path, threshold_db, max.max(0.0), 10) {
+                        Ok((silences, _)) => {
+                            let longest = silences.iter().map(|(start, end)| end - start).fold(0.0, f64::max);
+                            check("max_silence", silences.is_empty() || longest <= max, longest.into(), max.into());
+                            analysis.insert(
+                                "silences".to_string(),
+                                silences.iter().map(|(start, end)| serde_json::json!({ "start": start, "end": end })).collect(),
+                            );
+                        }
+                        Err(e) => {
+                            shutdown::check()?;
+                            decode_errors.push(format!("audio: {:#}", e));
+                        }
+                    }
+                }
+            }
+            
+            analysis.insert("duration".to_string(), duration.into());
+            if let Some(min) = profile.min_duration {
+                check("min_duration", duration >= min, duration.into(), min.into());
+            }
+            if let Some(max) = profile.max_duration {
+                check("max_duration", duration <= max, duration.into(), max.into());
+            }
+            check("decodes", decode_errors.is_empty(), decode_errors.clone().into(), serde_json::json!([]));
+        }
+    }
+    
+    let failed: Vec<String> = rules
+        .iter()
+        .filter(|rule| rule["passed"] == false)
+        .filter_map(|rule| rule["rule"].as_str().map(String::from))
+        .collect();
+    info!(profile = %profile_name, rules = rules.len(), failed = failed.len(), "QC finished");
+    
+    let report = serde_json::json!({
+        "input": job.input_path,
+        "profile": profile_name,
+        "passed": failed.is_empty(),
+        "failed_rules": failed,
+        "rules": rules,
+        "analysis": analysis,
+    });
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    if strict && !failed.is_empty() {
+        anyhow::bail!("QC failed: {}", failed.join(", "));
+    }
+    Ok(job.output_path.clone())
+}
+
+/// Detect scene cuts in video
+pub async fn detect_scene_cuts(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Detecting scene cuts using ffmpeg-next");
+    
+    let threshold = job.params.get("threshold")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.3);
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    
+    let input_stream = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .context("No video stream found")?;
+    
+    let video_stream_index = input_stream.index();
+    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
+    let mut decoder = context_decoder.decoder().video()?;
+    
+    let mut scene_cuts = Vec::new();
+    let mut prev_frame: Option<ffmpeg::util::frame::video::Video> = None;
+    let mut frame_index = 0;
+    
+    let time_base = input_stream.time_base();
+    
+    for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
+        if stream.index() == video_stream_index {
+            decoder.send_packet(&packet)?;
+            
+            let mut decoded = ffmpeg::util::frame::video::Video::empty();
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                if let Some(prev) = &prev_frame {
+                    // Simple scene detection: compare frame differences
+                    let diff = calculate_frame_difference(prev, &decoded);
+                    
+                    if diff > threshold {
+                        let timestamp = frame_index as f64 * time_base.numerator() as f64 / time_base.denominator() as f64;
+                        scene_cuts.push(serde_json::json!({
+                            "frame": frame_index,
+                            "timestamp": timestamp,
+                            "difference": diff
+                        }));
+                    }
+                }
+                
+                prev_frame = Some(decoded.clone());
+                frame_index += 1;
+            }
+        }
+    }
+    
+    let result = serde_json::json!({
+        "scene_cuts": scene_cuts,
+        "total_frames": frame_index,
+        "threshold": threshold
+    });
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&result)?)?;
+    
+    info!("Detected {} scene cuts", scene_cuts.len());
+    Ok(job.output_path.clone())
+}
+
+/// Apply watermark to video
+pub async fn apply_watermark(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Applying watermark using ffmpeg-next");
+    
+    let watermark_path = job.params.get("watermark_path")
+        .and_then(|v| v.as_str())
+        .context("watermark_path parameter required")?;
+    
+    // For watermarking, we'll use a simple approach
+    // In production, you'd want more sophisticated overlay logic
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    let input_stream = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .context("No video stream found")?;
+    
+    let video_stream_index = input_stream.index();
+    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
+    let mut decoder = context_decoder.decoder().video()?;
+    
+    // Load watermark image
+    let watermark_img = image::open(watermark_path)
+        .context("Failed to open watermark image")?;
+    
+    let mut octx = ffmpeg::format::output(&job.output_path)?;
+    let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::H264)?;
+    
+    let mut ost = octx.add_stream(codec)?;
+    let mut encoder = ost.codec().encoder().video()?;
+    
+    encoder.set_width(decoder.width());
+    encoder.set_height(decoder.height());
+    encoder.set_format(decoder.format());
+    encoder.set_time_base(input_stream.time_base());
+    encoder.set_bit_rate(decoder.bit_rate());
+    
+    if let Some(frame_rate) = input_stream.avg_frame_rate() {
+        encoder.set_frame_rate(Some(frame_rate));
+    }
+    
+    let encoder = encoder.open_as(codec)?;
+    ost.set_parameters(&encoder);
+    
+    octx.write_header()?;
+    
+    let mut frame_count = 0;
+    
+    for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
+        if stream.index() == video_stream_index {
+            decoder.send_packet(&packet)?;
+            
+            let mut decoded = ffmpeg::util::frame::video::Video::empty();
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                // Note: Actual watermark overlay would require pixel manipulation
+                // This is a simplified version
+                
+                encoder.send_frame(&decoded)?;
+                
+                let mut encoded = ffmpeg::Packet::empty();
+                while encoder.receive_packet(&mut encoded).is_ok() {
+                    encoded.set_stream(0);
+                    encoded.rescale_ts(input_stream.time_base(), ost.time_base());
+                    encoded.write_interleaved(&mut octx)?;
+                }
+                
+                frame_count += 1;
+            }
+        }
+    }
+    
+    encoder.send_eof()?;
+    let mut encoded = ffmpeg::Packet::empty();
+    while encoder.receive_packet(&mut encoded).is_ok() {
+        encoded.set_stream(0);
+        encoded.write_interleaved(&mut octx)?;
+    }
+    
+    octx.write_trailer()?;
+    
+    info!("Applied watermark to {} frames", frame_count);
+    Ok(job.output_path.clone())
+}
+
+/// Extract a single key frame
+pub async fn extract_key_frame(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Extracting key frame");
+    
+    let timestamp = job.params.get("timestamp")
+        .and_then(|v| v.as_str())
+        .unwrap_or("00:00:01");
+    
+    // Parse timestamp to seconds
+    let seconds = parse_timestamp(timestamp)?;
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    
+    // Seek to timestamp
+    ictx.seek(seconds as i64 * 1000, ..)?;
+    
+    let input_stream = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .context("No video stream found")?;
+    
+    let video_stream_index = input_stream.index();
+    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
+    let mut decoder = context_decoder.decoder().video()?;
+    
+    let mut scaler = ffmpeg::software::scaling::context::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::format::Pixel::RGB24,
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::software::scaling::flag::Flags::BILINEAR,
+    )?;
+    
+    for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
+        if stream.index() == video_stream_index {
+            decoder.send_packet(&packet)?;
+            
+            let mut decoded = ffmpeg::util::frame::video::Video::empty();
+            if decoder.receive_frame(&mut decoded).is_ok() {
+                let mut rgb_frame = ffmpeg::util::frame::video::Video::empty();
+                scaler.run(&decoded, &mut rgb_frame)?;
+                
+                save_frame_as_jpeg(&rgb_frame, &job.output_path)?;
+                break;
+            }
+        }
+    }
+    
+    Ok(job.output_path.clone())
+}
+
+/// Assemble numbered images into a video
+///
+/// Frames come from the `images` param (a list of paths) or from `input_path`, which may be a
+/// directory or a glob such as `frames/shot_*.png`; they are ordered by natural sort so
+/// `frame_9` comes before `frame_10`. Every image is scaled to `width`x`height` (default: the
+/// first image's size, rounded down to even). With `audio`, that file's best audio stream is
+/// copied in and, unless `shortest` is false, cut at the end of the video.
+pub async fn images_to_video(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Assembling image sequence using ffmpeg-next");
+    
+    let fps = job.params.get("fps")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(25.0);
+    
+    if fps <= 0.0 {
+        anyhow::bail!("fps must be positive");
+    }
+    
+    let audio_path = job.params.get("audio")
+        .and_then(|v| v.as_str());
+    
+    let shortest = job.params.get("shortest")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let images = match job.params.get("images").and_then(|v| v.as_array()) {
+        Some(images) => images
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(std::path::PathBuf::from)
+            .collect(),
+        None => list_image_sequence(&job.input_path)?,
+    };
+    
+    let first = images.first().context("No images found for the sequence")?;
+    let (first_width, first_height) = image::image_dimensions(first)
+        .context(format!("Failed to read image {}", first.display()))?;
+    
+    let width = job.params.get("width").and_then(|v| v.as_u64()).map(|v| v as u32).unwrap_or(first_width) & !1;
+    let height = job.params.get("height").and_then(|v| v.as_u64()).map(|v| v as u32).unwrap_or(first_height) & !1;
+    if width == 0 || height == 0 {
+        anyhow::bail!("Output size {}x{} is too small", width, height);
+    }
+    
+    let frame_rate = ffmpeg::Rational::from(fps);
+    let settings = VideoOutputSettings::from_params(&job.params)?;
+    
+    let mut octx = ffmpeg::format::output(&job.output_path)
+        .context("Failed to create output file")?;
+    let (mut encoder, video_index) =
+        open_video_encoder(&mut octx, &job.output_path, &settings, width, height, frame_rate)?;
+    
+    let mut audio = match audio_path {
+        Some(path) => Some(AudioPassthrough::open(path, &mut octx)?),
+        None => None,
+    };
+    
+    octx.write_header()?;
+    
+    let encoder_time_base = encoder.time_base();
+    let video_time_base = octx.stream(video_index).context("Output stream missing")?.time_base();
+    let video_duration = images.len() as f64 / fps;
+    
+    let mut scalers: std::collections::HashMap<(u32, u32), ffmpeg::software::scaling::Context> =
+        std::collections::HashMap::new();
+    let mut encoded = ffmpeg::Packet::empty();
+    
+    for (index, path) in images.iter().enumerate() {
+        let rgb = image::open(path)
+            .context(format!("Failed to open image {}", path.display()))?
+            .to_rgb8();
+        let (image_width, image_height) = rgb.dimensions();
+        
+        let mut source = ffmpeg::util::frame::video::Video::new(ffmpeg::format::Pixel::RGB24, image_width, image_height);
+        let stride = source.stride(0);
+        let row_bytes = image_width as usize * 3;
+        for (row, line) in rgb.as_raw().chunks_exact(row_bytes).enumerate() {
+            source.data_mut(0)[row * stride..row * stride + row_bytes].copy_from_slice(line);
+        }
+        
+        let scaler = match scalers.entry((image_width, image_height)) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => entry.insert(
+                ffmpeg::software::scaling::context::Context::get(
+                    ffmpeg::format::Pixel::RGB24,
+                    image_width,
+                    image_height,
+                    encoder.format(),
+                    width,
+                    height,
+                    ffmpeg::software::scaling::flag::Flags::BICUBIC,
+                )?,
+            ),
+        };
+        
+        let mut frame = ffmpeg::util::frame::video::Video::empty();
+        scaler.run(&source, &mut frame)?;
+        frame.set_pts(Some(index as i64));
+        
+        encoder.send_frame(&frame)?;
+        while encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(video_index);
+            encoded.rescale_ts(encoder_time_base, video_time_base);
+            encoded.write_interleaved(&mut octx)?;
+        }
+        
+        // Keep the soundtrack interleaved with the pictures rather than buffered until the end
+        if let Some(audio) = audio.as_mut() {
+            audio.write_until(&mut octx, (index + 1) as f64 / fps)?;
+        }
+        
+        if (index + 1) % 100 == 0 {
+            info!("Encoded {}/{} images", index + 1, images.len());
+        }
+    }
+    
+    encoder.send_eof()?;
+    while encoder.receive_packet(&mut encoded).is_ok() {
+        encoded.set_stream(video_index);
+        encoded.rescale_ts(encoder_time_base, video_time_base);
+        encoded.write_interleaved(&mut octx)?;
+    }
+    
+    if let Some(audio) = audio.as_mut() {
+        let end = if shortest { video_duration } else { f64::INFINITY };
+        audio.write_until(&mut octx, end)?;
+    }
+    
+    octx.write_trailer()?;
+    
+    info!("Assembled {} images at {} fps ({}x{})", images.len(), fps, width, height);
+    Ok(job.output_path.clone())
+}
+
+/// Export frames as numbered PNG, JPEG or WebP images with an index of their timestamps
+///
+/// Frames are selected with `every_n` (every Nth decoded frame) or `fps` (a fixed sampling
+/// rate), optionally within `start`/`end` and up to `max_frames`. File names come from
+/// `name_template` (default: `frame_{index:06}`), where `{index}` counts exported images,
+/// `{frame}` is the source frame number and `{ms}` the timestamp in milliseconds; `:0N`
+/// zero-pads to N digits. Images go to `output_dir` (default: `<output_path stem>_frames`);
+/// the JSON index mapping each file to its frame number and timestamp goes to `output_path`.
+pub async fn export_image_sequence(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Exporting image sequence using ffmpeg-next");
+    
+    let format = job.params.get("format")
+        .and_then(|v| v.as_str())
+        .unwrap_or("png")
+        .to_ascii_lowercase();
+    
+    let quality = job.params.get("quality")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(90)
+        .clamp(1, 100) as u32;
+    
+    let every_n = job.params.get("every_n")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(1)
+        .max(1);
+    
+    let sample_fps = job.params.get("fps")
+        .and_then(|v| v.as_f64())
+        .filter(|fps| *fps > 0.0);
+    
+    let max_frames = job.params.get("max_frames")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(u64::MAX);
+    
+    let name_template = job.params.get("name_template")
+        .and_then(|v| v.as_str())
+        .unwrap_or("frame_{index:06}");
+    
+    let start = crate::audio::param_seconds(&job.params, "start")?.unwrap_or(0.0);
+    let end = crate::audio::param_seconds(&job.params, "end")?;
+    
+    let output_dir = job.params.get("output_dir")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(|| {
+            let output = Path::new(&job.output_path);
+            let stem = output.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            output.with_file_name(format!("{}_frames", stem)).to_string_lossy().to_string()
+        });
+    std::fs::create_dir_all(&output_dir)?;
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    
+    let (video_stream_index, time_base, mut decoder) = {
+        let input_stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .context("No video stream found")?;
+        let decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?
+            .decoder()
+            .video()?;
+        (input_stream.index(), input_stream.time_base(), decoder)
+    };
+    
+    let width = job.params.get("width").and_then(|v| v.as_u64()).map(|v| v as u32);
+    let (width, height) = match width {
+        Some(width) => {
+            let height = (width as f64 * decoder.height() as f64 / decoder.width() as f64).round() as u32;
+            (width.max(2) & !1, height.max(2) & !1)
+        }
+        None => (decoder.width(), decoder.height()),
+    };
+    
+    let frame_rate = ictx
+        .stream(video_stream_index)
+        .map(|stream| f64::from(stream.avg_frame_rate()))
+        .filter(|fps| *fps > 0.0)
+        .unwrap_or(25.0);
+    
+    let (encoder, extension) = open_image_encoder(&format, quality, width, height)?;
+    let scaler = ffmpeg::software::scaling::context::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        encoder.format(),
+        width,
+        height,
+        ffmpeg::software::scaling::flag::Flags::BICUBIC,
+    )?;
+    
+    if start > 0.0 {
+        let position = (start * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+        ictx.seek(position, ..position)?;
+    }
+    
+    let mut exporter = ImageSequenceExporter {
+        encoder,
+        scaler,
+        output_dir: output_dir.clone(),
+        name_template: name_template.to_string(),
+        extension,
+        time_base,
+        frame_rate,
+        start,
+        end,
+        every_n,
+        sample_fps,
+        max_frames,
+        frame_number: 0,
+        next_sample: start,
+        entries: Vec::new(),
+    };
+    
+    let mut decoded = ffmpeg::util::frame::video::Video::empty();
+    let mut finished = false;
+    
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        while !finished && decoder.receive_frame(&mut decoded).is_ok() {
+            finished = exporter.push(&decoded)?;
+        }
+        if finished {
+            break;
+        }
+    }
+    
+    if !finished {
+        decoder.send_eof()?;
+        while !finished && decoder.receive_frame(&mut decoded).is_ok() {
+            finished = exporter.push(&decoded)?;
+        }
+    }
+    
+    let entries = exporter.entries;
+    let index = serde_json::json!({
+        "source": job.input_path,
+        "output_dir": output_dir,
+        "format": exporter.extension,
+        "width": width,
+        "height": height,
+        "count": entries.len(),
+        "frames": entries,
+    });
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&index)?)?;
+    
+    info!("Exported {} images to {}", index["count"], output_dir);
+    Ok(job.output_path.clone())
+}
+
+/// Transcode a live input (RTMP, SRT, RTSP, ...) into a rolling HLS playlist
+///
+/// `output_path` is the media playlist; `segment_seconds` segments are written next to it as
+/// `<stem>_00001.ts` (`.m4s` with `segment_type: fmp4`). The playlist lists the last `window`
+/// segments, or `dvr_seconds` worth when set so players can seek back, and segments that drop
+/// out of it are deleted unless `keep_segments` is true. Video is encoded with a keyframe at
+/// every segment boundary (`codec`, `bitrate`, `crf`, `preset`, `width`, `height`) or
+/// repackaged as is with `codec: "copy"`; audio is copied when HLS can carry it and encoded to
+/// AAC otherwise. Runs until the source ends or for `max_duration` seconds. With `listen` it
+/// waits for a publisher on `url`; with `follow` it reads a local file that is still growing.
+///
+/// `low_latency: true` produces LL-HLS instead: fMP4 segments made of `part_seconds` parts
+/// (default: 0.5) advertised with `EXT-X-PART` and a preload hint for the next one, plus
+/// `CAN-BLOCK-RELOAD` unless `blocking_reload` is false (the origin serving the playlist must
+/// then answer `_HLS_msn`/`_HLS_part` requests by holding them until that part exists).
+///
+/// `encrypt: true` (or an `encryption_key` in hex) encrypts segments with AES-128. Keys are
+/// written to `key_dir` (default: next to the playlist) and referenced as `key_url_prefix` +
+/// file name, each with a random IV listed in `EXT-X-KEY`; `key_rotation` switches to a fresh
+/// key every that many segments.
+pub async fn stream_to_hls(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Streaming to HLS using ffmpeg-next");
+    
+    let listen = job.params.get("listen")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let timeout = job.params.get("timeout")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(30);
+    
+    let segment_seconds = job.params.get("segment_seconds")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(4.0);
+    
+    if segment_seconds <= 0.0 {
+        anyhow::bail!("segment_seconds must be positive");
+    }
+    
+    let window = job.params.get("window")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(6);
+    
+    let dvr_seconds = job.params.get("dvr_seconds")
+        .and_then(|v| v.as_f64());
+    
+    let keep_segments = job.params.get("keep_segments")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let low_latency = job.params.get("low_latency")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let part_seconds = job.params.get("part_seconds")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.5);
+    
+    let blocking_reload = job.params.get("blocking_reload")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let segment_type = job.params.get("segment_type")
+        .and_then(|v| v.as_str())
+        .unwrap_or(if low_latency { "fmp4" } else { "mpegts" });
+    
+    if low_latency && segment_type != "fmp4" {
+        anyhow::bail!("Low-latency HLS needs segment_type fmp4");
+    }
+    if low_latency && (part_seconds <= 0.0 || part_seconds > segment_seconds) {
+        anyhow::bail!("part_seconds must be positive and at most segment_seconds");
+    }
+    
+    let max_duration = job.params.get("max_duration")
+        .and_then(|v| v.as_f64())
+        .map(Duration::from_secs_f64);
+    
+    let encryption_key = match job.params.get("encryption_key").and_then(|v| v.as_str()) {
+        Some(key) => Some(
+            <[u8; 16]>::try_from(hex::decode(key).context("encryption_key must be hex")?.as_slice())
+                .map_err(|_| anyhow::anyhow!("encryption_key must be 16 bytes (32 hex digits)"))?,
+        ),
+        None => None,
+    };
+    
+    let encrypt = encryption_key.is_some() || job.params.get("encrypt")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let key_rotation = job.params.get("key_rotation")
+        .and_then(|v| v.as_u64())
+        .filter(|&segments| segments > 0);
+    
+    if encrypt && low_latency {
+        anyhow::bail!("Encryption is not supported with low_latency: parts are joined into segments after muxing");
+    }
+    if key_rotation.is_some() && encryption_key.is_some() {
+        anyhow::bail!("key_rotation needs generated keys; drop encryption_key");
+    }
+    
+    let copy_video = job.params.get("codec").and_then(|v| v.as_str()) == Some("copy");
+    
+    let audio_bitrate = parse_bitrate(
+        job.params.get("audio_bitrate")
+            .and_then(|v| v.as_str())
+            .unwrap_or("128k"),
+    )?;
+    
+    let size = match (
+        job.params.get("width").and_then(|v| v.as_u64()),
+        job.params.get("height").and_then(|v| v.as_u64()),
+    ) {
+        (Some(width), Some(height)) => Some((width as u32, height as u32)),
+        (None, None) => None,
+        _ => anyhow::bail!("width and height must be given together"),
+    };
+    
+    let segment_extension = match segment_type {
+        "mpegts" => "ts",
+        "fmp4" => "m4s",
+        other => anyhow::bail!("Unsupported HLS segment type: {}", other),
+    };
+    
+    let followed = match FollowOptions::from_params(&job.params, &job.input_path) {
+        Some(options) => Some(FollowedInput::open(&job.input_path, &options)?),
+        None => None,
+    };
+    let url = match &followed {
+        Some(followed) => followed.url().to_string(),
+        None => job.params.get("url")
+            .and_then(|v| v.as_str())
+            .unwrap_or(&job.input_path)
+            .to_string(),
+    };
+    
+    let mut input_options = ffmpeg::Dictionary::new();
+    if listen {
+        input_options.set("listen", "1");
+        input_options.set("timeout", &timeout.to_string());
+    }
+    input_options.set("rw_timeout", &(timeout * 1_000_000).to_string());
+    
+    let mut ictx = ffmpeg::format::input_with_dictionary(&url, input_options)
+        .context(format!("Failed to open live input: {}", url))?;
+    
+    let playlist = Path::new(&job.output_path);
+    let dir = playlist.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let stem = playlist.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    std::fs::create_dir_all(dir)?;
+    
+    // In low-latency mode the muxer writes parts to a private playlist we build the real one from
+    let muxer_playlist = if low_latency {
+        dir.join(format!("{}_parts.m3u8", stem)).to_string_lossy().to_string()
+    } else {
+        job.output_path.clone()
+    };
+    let mut octx = ffmpeg::format::output_as(&muxer_playlist, "hls")
+        .context("Failed to create HLS output")?;
+    
+    let mut keys = if encrypt {
+        let key_dir = job.params.get("key_dir")
+            .and_then(|v| v.as_str())
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(|| dir.to_path_buf());
+        std::fs::create_dir_all(&key_dir)?;
+        
+        let mut keys = HlsKeyRotation {
+            info_path: dir.join(format!("{}.keyinfo", stem)).to_string_lossy().to_string(),
+            key_dir,
+            stem: stem.clone(),
+            url_prefix: job.params.get("key_url_prefix")
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string(),
+            fixed_key: encryption_key,
+            rotate_seconds: key_rotation.map(|segments| segments as f64 * segment_seconds),
+            next_rotation: 0.0,
+            written: 0,
+        };
+        keys.write_key()?;
+        Some(keys)
+    } else {
+        None
+    };
+    
+    let mut outputs: Vec<(usize, StreamOutput)> = Vec::new();
+    let mut frame_seconds = 0.0;
+    
+    if let Some(stream) = ictx.streams().best(ffmpeg::media::Type::Video) {
+        frame_seconds = 1.0 / f64::from(stream_frame_rate(&stream));
+        let output = if copy_video {
+            StreamOutput::copy(&stream, &mut octx)?
+        } else {
+            let mut settings = VideoOutputSettings::from_params(&job.params)?;
+            let frame_rate = f64::from(stream_frame_rate(&stream));
+            settings.gop = Some((frame_rate * segment_seconds).round().max(1.0) as u32);
+            if settings.codec.is_none() && !settings.options.iter().any(|(key, _)| key == "preset") {
+                // The default H.264 preset cannot keep up with most live sources in real time
+                settings.options.push(("preset".to_string(), "veryfast".to_string()));
+            }
+            StreamOutput::Video(VideoTranscoder::open(&stream, &mut octx, &job.output_path, &settings, size)?)
+        };
+        outputs.push((stream.index(), output));
+    }
+    
+    if let Some(stream) = ictx.streams().best(ffmpeg::media::Type::Audio) {
+        let hls_audio = matches!(
+            stream.parameters().id(),
+            ffmpeg::codec::Id::AAC | ffmpeg::codec::Id::MP3 | ffmpeg::codec::Id::AC3 | ffmpeg::codec::Id::EAC3
+        );
+        let output = if hls_audio {
+            StreamOutput::copy(&stream, &mut octx)?
+        } else {
+            StreamOutput::Audio(AudioTranscoder::open(&stream, &mut octx, &job.output_path, Some("aac"), Some(audio_bitrate))?)
+        };
+        outputs.push((stream.index(), output));
+    }
+    
+    if outputs.is_empty() {
+        anyhow::bail!("Live input has no audio or video");
+    }
+    
+    // With DVR the playlist itself is the retention window
+    let list_size = match dvr_seconds {
+        Some(dvr) => (dvr / segment_seconds).ceil().max(1.0) as u64,
+        None => window,
+    };
+    
+    let mut options = ffmpeg::Dictionary::new();
+    let mut low_latency_playlist = None;
+    
+    if low_latency {
+        // Cutting a frame early keeps every part within PART-TARGET
+        let muxer_part_seconds = (part_seconds - frame_seconds).max(part_seconds / 2.0);
+        let parts_per_segment = (segment_seconds / part_seconds).ceil() as u64;
+        options.set("hls_time", &muxer_part_seconds.to_string());
+        // The muxer only deletes parts long after they have been copied into their segment
+        options.set("hls_list_size", &(parts_per_segment * 6 + 16).to_string());
+        options.set("hls_flags", "split_by_time+temp_file+delete_segments");
+        options.set(
+            "hls_segment_filename",
+            &dir.join(format!("{}_part%06d.m4s", stem)).to_string_lossy(),
+        );
+        
+        low_latency_playlist = Some(LowLatencyPlaylist {
+            dir: dir.to_path_buf(),
+            playlist_path: job.output_path.clone(),
+            parts_playlist: muxer_playlist.clone(),
+            stem: stem.clone(),
+            init_uri: format!("{}_init.mp4", stem),
+            part_target: part_seconds,
+            segment_seconds,
+            list_size: list_size as usize,
+            keep_segments,
+            blocking_reload,
+            // Video is added first, so it is track 1 of the fMP4 output
+            video_track: (frame_seconds > 0.0).then_some(1),
+            segments: std::collections::VecDeque::new(),
+            current: Vec::new(),
+            media_sequence: 0,
+            next_segment: 0,
+            last_part_sequence: None,
+            target_duration: segment_seconds.ceil(),
+            last_poll: Instant::now(),
+        });
+    } else {
+        let mut flags = vec!["independent_segments", "temp_file"];
+        if !keep_segments {
+            flags.push("delete_segments");
+        }
+        if dvr_seconds.is_some() {
+            flags.push("program_date_time");
+        }
+        if key_rotation.is_some() {
+            // Reread the key info file at every segment so rotated keys take effect
+            flags.push("periodic_rekey");
+        }
+        
+        options.set("hls_time", &segment_seconds.to_string());
+        options.set("hls_list_size", &list_size.to_string());
+        options.set("hls_flags", &flags.join("+"));
+        options.set(
+            "hls_segment_filename",
+            &dir.join(format!("{}_%05d.{}", stem, segment_extension)).to_string_lossy(),
+        );
+    }
+    
+    options.set("hls_segment_type", segment_type);
+    if let Some(keys) = &keys {
+        options.set("hls_key_info_file", &keys.info_path);
+    }
+    if segment_type == "fmp4" {
+        options.set("hls_fmp4_init_filename", &format!("{}_init.mp4", stem));
+    }
+    octx.write_header_with(options)?;
+    
+    info!(url = %url, segment_seconds, list_size, "Live HLS output started");
+    
+    let started = Instant::now();
+    let mut packets = 0u64;
+    let time_bases: Vec<ffmpeg::Rational> = ictx.streams().map(|stream| stream.time_base()).collect();
+    let mut first_timestamp: Option<f64> = None;
+    
+    loop {
+        if max_duration.map_or(false, |limit| started.elapsed() >= limit) {
+            info!("Reached max_duration, ending stream");
+            break;
+        }
+        
+        let mut packet = ffmpeg::Packet::empty();
+        match packet.read(&mut ictx) {
+            Ok(()) => {}
+            Err(ffmpeg::Error::Eof) => break,
+            Err(e) => {
+                // Close the playlist cleanly rather than leaving it without an end
+                warn!(error = %e, "Live input failed, ending stream");
+                break;
+            }
+        }
+        
+        if let Some((_, output)) = outputs.iter_mut().find(|(index, _)| *index == packet.stream()) {
+            if let (Some(keys), Some(timestamp)) = (keys.as_mut(), packet.dts().or(packet.pts())) {
+                let seconds = timestamp as f64 * f64::from(time_bases[packet.stream()]);
+                keys.rotate_at(seconds - *first_timestamp.get_or_insert(seconds))?;
+            }
+            output.write(packet, &mut octx)?;
+            if let Some(playlist) = low_latency_playlist.as_mut() {
+                playlist.poll()?;
+            }
+            packets += 1;
+            if packets % 5000 == 0 {
+                info!(packets, elapsed_secs = started.elapsed().as_secs(), "Streaming");
+            }
+        }
+    }
+    
+    for (_, output) in outputs.iter_mut() {
+        output.finish(&mut octx)?;
+    }
+    octx.write_trailer()?;
+    
+    if let Some(playlist) = low_latency_playlist.as_mut() {
+        playlist.finish()?;
+    }
+    if let Some(keys) = &keys {
+        let _ = std::fs::remove_file(&keys.info_path);
+    }
+    
+    if let Some(followed) = followed {
+        followed.finish()?;
+    }
+    
+    info!(packets, elapsed_secs = started.elapsed().as_secs(), "Live HLS output finished");
+    Ok(job.output_path.clone())
+}
+
+/// Push a file or live input to one or more RTMP(S) endpoints at once
+///
+/// `targets` lists endpoint URLs, or objects with a `url` plus per-target encoding: `codec`
+/// (default: `copy`), `bitrate`, `crf`, `preset`, `width`, `height` and `audio_bitrate`.
+/// Transcoded targets get a keyframe every `keyframe_seconds` (default: 2), which streaming
+/// platforms expect. Local files are sent at their native rate unless `realtime` is false.
+/// A target that fails is dropped and the others carry on; the job fails only when every
+/// target does. A JSON report of each target's outcome goes to `output_path`.
+pub async fn push_rtmp(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Pushing to RTMP endpoints using ffmpeg-next");
+    
+    let url = job.params.get("url")
+        .and_then(|v| v.as_str())
+        .unwrap_or(&job.input_path)
+        .to_string();
+    
+    let targets = job.params.get("targets")
+        .and_then(|v| v.as_array())
+        .filter(|targets| !targets.is_empty())
+        .context("targets must list at least one RTMP URL")?;
+    
+    let listen = job.params.get("listen")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let timeout = job.params.get("timeout")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(30);
+    
+    let realtime = job.params.get("realtime")
+        .and_then(|v| v.as_bool())
+        .unwrap_or_else(|| Path::new(&url).is_file());
+    
+    let keyframe_seconds = job.params.get("keyframe_seconds")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(2.0);
+    
+    let mut input_options = ffmpeg::Dictionary::new();
+    if listen {
+        input_options.set("listen", "1");
+        input_options.set("timeout", &timeout.to_string());
+    }
+    if !Path::new(&url).is_file() {
+        input_options.set("rw_timeout", &(timeout * 1_000_000).to_string());
+    }
+    
+    let mut ictx = ffmpeg::format::input_with_dictionary(&url, input_options)
+        .context(format!("Failed to open input: {}", url))?;
+    
+    let mut pushes = Vec::new();
+    for target in targets {
+        pushes.push(PushTarget::open(&ictx, target, keyframe_seconds)?);
+    }
+    
+    let time_bases: Vec<ffmpeg::Rational> = ictx.streams().map(|stream| stream.time_base()).collect();
+    let mut clock: Option<(Instant, f64)> = None;
+    
+    info!(url = %url, targets = pushes.len(), realtime, "Pushing stream");
+    
+    loop {
+        let mut packet = ffmpeg::Packet::empty();
+        match packet.read(&mut ictx) {
+            Ok(()) => {}
+            Err(ffmpeg::Error::Eof) => break,
+            Err(e) => {
+                warn!(error = %e, "Input failed, ending push");
+                break;
+            }
+        }
+        
+        // Hold packets back to the input's own pace, like `ffmpeg -re`
+        if realtime {
+            if let Some(timestamp) = packet.dts().or(packet.pts()) {
+                let seconds = timestamp as f64 * f64::from(time_bases[packet.stream()]);
+                let (start, first) = *clock.get_or_insert((Instant::now(), seconds));
+                let ahead = seconds - first - start.elapsed().as_secs_f64();
+                if ahead > 0.0 {
+                    tokio::time::sleep(Duration::from_secs_f64(ahead)).await;
+                }
+            }
+        }
+        
+        for push in pushes.iter_mut().filter(|push| push.error.is_none()) {
+            push.write(&packet);
+        }
+        
+        if pushes.iter().all(|push| push.error.is_some()) {
+            break;
+        }
+    }
+    
+    for push in pushes.iter_mut().filter(|push| push.error.is_none()) {
+        push.finish();
+    }
+    
+    let report: Vec<serde_json::Value> = pushes
+        .iter()
+        .map(|push| serde_json::json!({
+            "url": push.display_url,
+            "status": if push.error.is_none() { "completed" } else { "failed" },
+            "packets": push.packets,
+            "error": push.error,
+        }))
+        .collect();
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    if pushes.iter().all(|push| push.error.is_some()) {
+        anyhow::bail!("Every RTMP target failed; see {}", job.output_path);
+    }
+    
+    info!(
+        completed = pushes.iter().filter(|push| push.error.is_none()).count(),
+        of = pushes.len(),
+        "Push finished"
+    );
+    Ok(job.output_path.clone())
+}
+
+/// Keep a JPEG preview of a live input fresh at a stable path
+///
+/// Every `interval` seconds (default: 10) the next keyframe is decoded, scaled to `width`
+/// (default: 320, keeping the aspect ratio) and written to `output_path` through a temporary
+/// file and a rename, so readers never see a partial image. Only the keyframes that become
+/// previews are decoded. Runs until the source ends or for `max_duration` seconds, reopening
+/// a dropped input up to `reconnect_attempts` times `reconnect_delay` seconds apart.
+pub async fn live_thumbnails(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Capturing live thumbnails using ffmpeg-next");
+    
+    let url = job.params.get("url")
+        .and_then(|v| v.as_str())
+        .unwrap_or(&job.input_path)
+        .to_string();
+    
+    let listen = job.params.get("listen")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let timeout = job.params.get("timeout")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(30);
+    
+    let interval = Duration::from_secs_f64(
+        job.params.get("interval")
+            .and_then(|v| v.as_f64())
+            .unwrap_or(10.0),
+    );
+    
+    let width = job.params.get("width")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(320) as u32;
+    
+    let quality = job.params.get("quality")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(80)
+        .clamp(1, 100) as u32;
+    
+    let max_duration = job.params.get("max_duration")
+        .and_then(|v| v.as_f64())
+        .map(Duration::from_secs_f64);
+    
+    let reconnect_attempts = job.params.get("reconnect_attempts")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(5);
+    
+    let reconnect_delay = Duration::from_secs_f64(
+        job.params.get("reconnect_delay")
+            .and_then(|v| v.as_f64())
+            .unwrap_or(5.0),
+    );
+    
+    if let Some(parent) = Path::new(&job.output_path).parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    
+    let deadline = max_duration.map(|limit| Instant::now() + limit);
+    let mut thumbnailer = LiveThumbnailer {
+        path: job.output_path.clone(),
+        width,
+        quality,
+        interval,
+        encoder: None,
+        next_due: Instant::now(),
+        written: 0,
+    };
+    let mut failures = 0u64;
+    
+    loop {
+        let mut options = ffmpeg::Dictionary::new();
+        if listen {
+            options.set("listen", "1");
+            options.set("timeout", &timeout.to_string());
+        }
+        options.set("rw_timeout", &(timeout * 1_000_000).to_string());
+        
+        match ffmpeg::format::input_with_dictionary(&url, options) {
+            Ok(ictx) => {
+                failures = 0;
+                match thumbnailer.run(ictx, deadline) {
+                    Ok(true) => break,
+                    Ok(false) => warn!(url = %url, "Live input ended"),
+                    Err(e) => warn!(url = %url, error = %e, "Live input interrupted"),
+                }
+            }
+            Err(e) => warn!(url = %url, error = %e, "Failed to open live input"),
+        }
+        
+        failures += 1;
+        if failures > reconnect_attempts || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+        info!(attempt = failures, of = reconnect_attempts, "Reconnecting");
+        tokio::time::sleep(reconnect_delay).await;
+    }
+    
+    if thumbnailer.written == 0 {
+        anyhow::bail!("No thumbnail could be captured from {}", url);
+    }
+    
+    info!(thumbnails = thumbnailer.written, "Live thumbnails stopped");
+    Ok(job.output_path.clone())
+}
+
+/// Package a file as CENC-encrypted fMP4 with DASH and HLS manifests using Shaka Packager
+///
+/// Keys are given as `key_id` + `key` (32 hex digits each) or as a `keys` list of objects
+/// that also carry a `label` (Shaka's DRM label, e.g. SD/HD/AUDIO). `scheme` is `cenc`
+/// (default, Widevine/PlayReady) or `cbcs` (required for FairPlay). `systems` selects the
+/// PSSH/key systems written (default: widevine and playready; also fairplay and common);
+/// `pssh` adds license-server supplied PSSH boxes (hex) as is. Video and audio are packaged
+/// into `segment_duration` second segments under `output_dir` (default: `<output_path
+/// stem>_drm`) with `manifests` (default: dash and hls). `output_path` receives a JSON
+/// manifest of the produced assets and key ids; keys themselves are never written out.
+pub async fn package_drm(job: &JobPayload, config: &Config) -> Result<String> {
+    info!("Packaging with DRM using {}", config.drm.packager_binary);
+    
+    let scheme = job.params.get("scheme")
+        .and_then(|v| v.as_str())
+        .unwrap_or("cenc");
+    
+    if !matches!(scheme, "cenc" | "cbcs" | "cens" | "cbc1") {
+        anyhow::bail!("Unsupported protection scheme: {}", scheme);
+    }
+    
+    let systems: Vec<String> = match job.params.get("systems").and_then(|v| v.as_array()) {
+        Some(systems) => systems.iter().filter_map(|v| v.as_str()).map(|s| s.to_ascii_lowercase()).collect(),
+        None => vec!["widevine".to_string(), "playready".to_string()],
+    };
+    
+    let manifests: Vec<String> = match job.params.get("manifests").and_then(|v| v.as_array()) {
+        Some(manifests) => manifests.iter().filter_map(|v| v.as_str()).map(String::from).collect(),
+        None => vec!["dash".to_string(), "hls".to_string()],
+    };
+    
+    let segment_duration = job.params.get("segment_duration")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(6.0);
+    
+    let clear_lead = job.params.get("clear_lead")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.0);
+    
+    let keys: Vec<serde_json::Value> = match job.params.get("keys").and_then(|v| v.as_array()) {
+        Some(keys) => keys.clone(),
+        None => vec![serde_json::json!({
+            "label": "",
+            "key_id": job.params.get("key_id").and_then(|v| v.as_str()).context("key_id is required")?,
+            "key": job.params.get("key").and_then(|v| v.as_str()).context("key is required")?,
+        })],
+    };
+    
+    let mut key_args = Vec::new();
+    let mut key_ids = Vec::new();
+    for entry in &keys {
+        let label = entry.get("label").and_then(|v| v.as_str()).unwrap_or("");
+        let key_id = drm_hex_param(entry, "key_id", 16)?;
+        let key = drm_hex_param(entry, "key", 16)?;
+        key_args.push(format!("label={}:key_id={}:key={}", label, key_id, key));
+        key_ids.push(serde_json::json!({ "label": label, "key_id": key_id }));
+    }
+    
+    let mut protection_systems = Vec::new();
+    for system in &systems {
+        protection_systems.push(match system.as_str() {
+            "widevine" => "Widevine",
+            "playready" => "PlayReady",
+            "fairplay" => "FairPlay",
+            "common" => "CommonSystem",
+            other => anyhow::bail!("Unknown DRM system: {}", other),
+        });
+    }
+    if protection_systems.contains(&"FairPlay") && scheme != "cbcs" {
+        anyhow::bail!("FairPlay needs scheme cbcs");
+    }
+    
+    let output_dir = job.params.get("output_dir")
+        .and_then(|v| v.as_str())
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| {
+            let output = Path::new(&job.output_path);
+            output.with_file_name(format!(
+                "{}_drm",
+                output.file_stem().and_then(|s| s.to_str()).unwrap_or("output")
+            ))
+        });
+    std::fs::create_dir_all(&output_dir)?;
+    
+    // Package whichever of video and audio the input has
+    let (has_video, has_audio) = {
+        let ictx = ffmpeg::format::input(&job.input_path)
+            .context("Failed to open input file")?;
+        (
+            ictx.streams().best(ffmpeg::media::Type::Video).is_some(),
+            ictx.streams().best(ffmpeg::media::Type::Audio).is_some(),
+        )
+    };
+    
+    let mut args = Vec::new();
+    for (stream, present) in [("video", has_video), ("audio", has_audio)] {
+        if !present {
+            continue;
+        }
+        let stream_dir = output_dir.join(stream);
+        std::fs::create_dir_all(&stream_dir)?;
+        // Stream descriptors are comma-separated key=value fields with no escaping
+        let input = job.input_path.as_str();
+        let init_segment = stream_dir.join("init.mp4").to_string_lossy().to_string();
+        let segment_template = stream_dir.join("$Number$.m4s").to_string_lossy().to_string();
+        for path in [input, &init_segment, &segment_template] {
+            if path.contains(',') || path.contains('=') {
+                anyhow::bail!("The packager cannot take paths containing ',' or '=': {}", path);
+            }
+        }
+        args.push(format!(
+            "in={},stream={},init_segment={},segment_template={}",
+            input, stream, init_segment, segment_template,
+        ));
+    }
+    if args.is_empty() {
+        anyhow::bail!("Input has no audio or video to package");
+    }
+    
+    // Keys go through a private flag file; anything on the command line is readable by every
+    // local user through /proc
+    let scratch = ScratchDir::new(&config.scratch, "drm")?;
+    let flagfile = scratch.file("keys.flags");
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&flagfile)
+            .context("Failed to create packager flag file")?;
+        writeln!(file, "--enable_raw_key_encryption")?;
+        writeln!(file, "--keys={}", key_args.join(","))?;
+    }
+    args.push(format!("--flagfile={}", flagfile));
+    args.push("--protection_scheme".to_string());
+    args.push(scheme.to_string());
+    args.push("--protection_systems".to_string());
+    args.push(protection_systems.join(","));
+    args.push("--clear_lead".to_string());
+    args.push(clear_lead.to_string());
+    args.push("--segment_duration".to_string());
+    args.push(segment_duration.to_string());
+    
+    if let Some(pssh) = job.params.get("pssh").and_then(|v| v.as_str()) {
+        hex::decode(pssh).context("pssh must be hex-encoded PSSH boxes")?;
+        args.push("--pssh".to_string());
+        args.push(pssh.to_string());
+    }
+    if let Some(key_uri) = job.params.get("hls_key_uri").and_then(|v| v.as_str()) {
+        args.push("--hls_key_uri".to_string());
+        args.push(key_uri.to_string());
+    }
+    
+    let mut manifest_paths = serde_json::Map::new();
+    for manifest in &manifests {
+        let (flag, name) = match manifest.as_str() {
+            "dash" => ("--mpd_output", "manifest.mpd"),
+            "hls" => ("--hls_master_playlist_output", "master.m3u8"),
+            other => anyhow::bail!("Unknown manifest type: {}", other),
+        };
+        let path = output_dir.join(name);
+        args.push(flag.to_string());
+        args.push(path.to_string_lossy().to_string());
+        manifest_paths.insert(manifest.clone(), serde_json::json!(path.to_string_lossy()));
+    }
+    
+    let mut command = tokio::process::Command::new(&config.drm.packager_binary);
+    command.args(&args).kill_on_drop(true);
+    shutdown::check()?;
+    let child = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context(format!("Failed to execute {}", config.drm.packager_binary))?;
+    let _guard = child.id().map(shutdown::ChildGuard::register);
+    let output = tokio::time::timeout(Duration::from_secs(config.drm.timeout_seconds), child.wait_with_output())
+        .await
+        .map_err(|_| anyhow::anyhow!("Packager timed out after {} seconds", config.drm.timeout_seconds))?
+        .context(format!("Failed to execute {}", config.drm.packager_binary))?;
+    
+    if !output.status.success() {
+        anyhow::bail!("DRM packaging failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    
+    let mut assets = Vec::new();
+    collect_packaged_files(&output_dir, &output_dir, &mut assets)?;
+    assets.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+    
+    let manifest = serde_json::json!({
+        "input": job.input_path,
+        "output_dir": output_dir.to_string_lossy(),
+        "scheme": scheme,
+        "systems": systems,
+        "key_ids": key_ids,
+        "manifests": manifest_paths,
+        "assets": assets,
+    });
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&manifest)?)?;
+    
+    info!(assets = assets.len(), scheme, "DRM packaging complete");
+    Ok(job.output_path.clone())
+}
+
+/// Embed a per-recipient forensic watermark into the picture
+///
+/// `payload` (up to 8 bytes, e.g. a recipient id) is spread over time: every `chip_seconds`
+/// (default 0.5) one bit, scrambled by `key`, is added to the luma plane as a smooth
+/// low-frequency pattern of `strength` levels (default 2.0), so it survives re-encoding and
+/// scaling. The full payload repeats every 44 seconds at the default chip length; leaks should
+/// be at least that long for `detect_forensic_watermark` to recover it. Video is re-encoded
+/// with the usual `codec`/`bitrate`/`crf`/`preset` params; audio is copied.
+pub async fn watermark_forensic(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Embedding forensic watermark using ffmpeg-next");
+    
+    let payload = job.params.get("payload")
+        .and_then(|v| v.as_str())
+        .context("payload parameter required")?;
+    
+    let key = job.params.get("key")
+        .and_then(|v| v.as_str())
+        .context("key is required")?;
+    
+    let strength = job.params.get("strength")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(2.0);
+    
+    let chip_seconds = job.params.get("chip_seconds")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.5);
+    
+    if chip_seconds <= 0.0 {
+        anyhow::bail!("chip_seconds must be positive");
+    }
+    
+    let mut mark = ForensicMark::new(key, payload, strength as f32, chip_seconds)?;
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    let mut octx = ffmpeg::format::output(&job.output_path)?;
+    
+    let video_index = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .context("No video stream found")?
+        .index();
+    
+    let settings = VideoOutputSettings::from_params(&job.params)?;
+    let mut outputs: Vec<(usize, StreamOutput)> = Vec::new();
+    for stream in ictx.streams() {
+        let output = match stream.parameters().medium() {
+            ffmpeg::media::Type::Video if stream.index() == video_index => {
+                let transcoder = VideoTranscoder::open(&stream, &mut octx, &job.output_path, &settings, None)?;
+                if !matches!(
+                    transcoder.output.encoder.format(),
+                    ffmpeg::format::Pixel::YUV420P
+                        | ffmpeg::format::Pixel::YUVJ420P
+                        | ffmpeg::format::Pixel::YUV422P
+                        | ffmpeg::format::Pixel::YUV444P
+                        | ffmpeg::format::Pixel::NV12
+                ) {
+                    anyhow::bail!(
+                        "Forensic watermarking needs an 8-bit YUV output, not {:?}",
+                        transcoder.output.encoder.format()
+                    );
+                }
+                StreamOutput::Video(transcoder.with_frame_hook(move |frame, seconds| {
+                    let (width, height) = (frame.plane_width(0) as usize, frame.plane_height(0) as usize);
+                    let stride = frame.stride(0);
+                    mark.apply(frame.data_mut(0), stride, width, height, seconds);
+                }))
+            }
+            ffmpeg::media::Type::Audio => StreamOutput::copy(&stream, &mut octx)?,
+            _ => continue,
+        };
+        outputs.push((stream.index(), output));
+    }
+    
+    octx.write_header()?;
+    
+    for (stream, packet) in ictx.packets() {
+        if let Some((_, output)) = outputs.iter_mut().find(|(index, _)| *index == stream.index()) {
+            output.write(packet, &mut octx)?;
+        }
+    }
+    for (_, output) in &mut outputs {
+        output.finish(&mut octx)?;
+    }
+    
+    octx.write_trailer()?;
+    
+    info!(payload, "Forensic watermark embedded");
+    Ok(job.output_path.clone())
+}
+
+/// Recover the payload embedded by `watermark_forensic`
+///
+/// `key` and `chip_seconds` must match the embedding. With `search` (default true) the input
+/// may start anywhere in the bit cycle, as clips cut from a longer video do. A JSON report
+/// with `detected`, `payload`, `confidence` and `offset_seconds` goes to `output_path`; a
+/// payload only counts as detected when its CRC matches and `confidence` (sync bit strength
+/// relative to the average bit) reaches `min_confidence` (default 0.5).
+pub async fn detect_forensic_watermark(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Detecting forensic watermark using ffmpeg-next");
+    
+    let key = job.params.get("key")
+        .and_then(|v| v.as_str())
+        .context("key is required")?;
+    
+    let chip_seconds = job.params.get("chip_seconds")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.5);
+    
+    let search = job.params.get("search")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let min_confidence = job.params.get("min_confidence")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(0.5);
+    
+    if chip_seconds <= 0.0 {
+        anyhow::bail!("chip_seconds must be positive");
+    }
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    let input_stream = ictx
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .context("No video stream found")?;
+    
+    let video_stream_index = input_stream.index();
+    let time_base = f64::from(input_stream.time_base());
+    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
+    let mut decoder = context_decoder.decoder().video()?;
+    
+    // Cell means do not depend on resolution, so analyse a small grey copy
+    let (width, height) = (320, 180);
+    let mut scaler = ffmpeg::software::scaling::context::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::format::Pixel::GRAY8,
+        width,
+        height,
+        ffmpeg::software::scaling::flag::Flags::AREA,
+    )?;
+    
+    let mut frames: Vec<(f64, Vec<f32>)> = Vec::new();
+    let mut receive = |decoder: &mut ffmpeg::decoder::Video| -> Result<()> {
+        let mut decoded = ffmpeg::util::frame::video::Video::empty();
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let Some(timestamp) = decoded.timestamp() else {
+                continue;
+            };
+            let mut grey = ffmpeg::util::frame::video::Video::empty();
+            scaler.run(&decoded, &mut grey)?;
+            frames.push((
+                timestamp as f64 * time_base,
+                forensic_cells(grey.data(0), grey.stride(0), width as usize, height as usize),
+            ));
+        }
+        Ok(())
+    };
+    
+    for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
+        if stream.index() == video_stream_index {
+            decoder.send_packet(&packet)?;
+            receive(&mut decoder)?;
+        }
+    }
+    decoder.send_eof()?;
+    receive(&mut decoder)?;
+    
+    // Times are taken relative to the first frame, as a clip's timestamps may not start at zero
+    if let Some(start) = frames.first().map(|(seconds, _)| *seconds) {
+        for (seconds, _) in &mut frames {
+            *seconds -= start;
+        }
+    }
+    
+    let detection = detect_forensic(&frames, key, chip_seconds, search)
+        .context("Input too short to contain a watermark")?;
+    
+    let payload_bytes = crate::audio::WATERMARK_PAYLOAD_BYTES;
+    let mut bytes = vec![0u8; payload_bytes + 1];
+    for (bit, sum) in detection.sums[..(payload_bytes + 1) * 8].iter().enumerate() {
+        if *sum > 0.0 {
+            bytes[bit / 8] |= 0x80 >> (bit % 8);
+        }
+    }
+    let checksum_ok = crate::audio::crc8(&bytes[..payload_bytes]) == bytes[payload_bytes];
+    let detected = checksum_ok && detection.sync_strength >= min_confidence;
+    
+    let payload: Vec<u8> = bytes[..payload_bytes]
+        .iter()
+        .copied()
+        .take_while(|b| *b != 0)
+        .collect();
+    
+    let report = serde_json::json!({
+        "detected": detected,
+        "payload": if detected { Some(String::from_utf8_lossy(&payload).to_string()) } else { None },
+        "confidence": detection.sync_strength,
+        "offset_seconds": detection.offset_seconds,
+        "bit_slot_offset": detection.rotation,
+        "frames_analyzed": frames.len(),
+    });
+    
+    info!("Forensic watermark detected: {} (confidence {:.2})", detected, detection.sync_strength);
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    Ok(job.output_path.clone())
+}
+
+/// Render short muted preview clips across the timeline for hover-to-preview
+///
+/// `count` clips (default: 10) of `clip_duration` seconds (default: 2.5) are taken evenly
+/// across the video, each centred in its share of the timeline, scaled to `width` (default:
+/// 320) at `fps` (default: 15) and encoded at `bitrate` (default: 250k). `format` is `webm`
+/// (default, VP9) or `mp4` (H.264 with faststart); `codec` overrides the encoder. Clips go to
+/// `output_dir` (default: `<output_path stem>_previews`) and the JSON index listing each
+/// clip's file and source time range goes to `output_path`.
+pub async fn generate_hover_previews(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Generating hover previews using ffmpeg-next");
+    
+    let count = job.params.get("count")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(10)
+        .max(1);
+    
+    let clip_duration = job.params.get("clip_duration")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(2.5);
+    
+    let width = job.params.get("width")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(320) as u32;
+    
+    let fps = job.params.get("fps")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(15)
+        .max(1) as i32;
+    
+    let format = job.params.get("format")
+        .and_then(|v| v.as_str())
+        .unwrap_or("webm")
+        .to_ascii_lowercase();
+    
+    if format != "webm" && format != "mp4" {
+        anyhow::bail!("Unsupported preview format: {}", format);
+    }
+    if clip_duration <= 0.0 {
+        anyhow::bail!("clip_duration must be positive");
+    }
+    
+    let mut settings = VideoOutputSettings::from_params(&job.params)?;
+    if settings.bit_rate.is_none() {
+        settings.bit_rate = Some(250_000);
+    }
+    if format == "webm" && settings.codec.is_none() {
+        settings.codec = Some("libvpx-vp9".to_string());
+        // libvpx defaults to its slowest settings
+        settings.options.push(("deadline".to_string(), "good".to_string()));
+        settings.options.push(("cpu-used".to_string(), "4".to_string()));
+        settings.options.push(("row-mt".to_string(), "1".to_string()));
+    }
+    
+    let output_dir = job.params.get("output_dir")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(|| {
+            let output = Path::new(&job.output_path);
+            let stem = output.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            output.with_file_name(format!("{}_previews", stem)).to_string_lossy().to_string()
+        });
+    std::fs::create_dir_all(&output_dir)?;
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    
+    let duration = ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE);
+    if duration <= 0.0 {
+        anyhow::bail!("Input duration is unknown");
+    }
+    
+    let (video_stream_index, time_base, mut decoder) = {
+        let input_stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .context("No video stream found")?;
+        let decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?
+            .decoder()
+            .video()?;
+        (input_stream.index(), input_stream.time_base(), decoder)
+    };
+    
+    let height = (width as f64 * decoder.height() as f64 / decoder.width() as f64).round() as u32;
+    let (width, height) = (width.max(2) & !1, height.max(2) & !1);
+    
+    // Short inputs get fewer clips rather than overlapping ones
+    let clip_duration = clip_duration.min(duration);
+    let count = count.min((duration / clip_duration).floor().max(1.0) as u64);
+    
+    let mut previews = Vec::new();
+    for index in 0..count {
+        let centre = duration * (index as f64 + 0.5) / count as f64;
+        let start = (centre - clip_duration / 2.0).clamp(0.0, duration - clip_duration);
+        let end = start + clip_duration;
+        
+        let file_name = format!("preview_{:02}.{}", index, format);
+        let clip_path = Path::new(&output_dir).join(&file_name).to_string_lossy().to_string();
+        
+        let position = (start * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+        ictx.seek(position, ..position)?;
+        decoder.flush();
+        
+        let mut octx = ffmpeg::format::output_as(&clip_path, &format)
+            .context(format!("Failed to create {}", clip_path))?;
+        let (mut encoder, _) = open_video_encoder(&mut octx, &clip_path, &settings, width, height, (fps, 1).into())?;
+        let mut graph = build_video_filter_graph(
+            &decoder,
+            time_base,
+            &format!("fps={},scale={}:{}:flags=bicubic,setpts=PTS-STARTPTS", fps, width, height),
+            encoder.format(),
+        )?;
+        
+        let mut muxer_options = ffmpeg::Dictionary::new();
+        if format == "mp4" {
+            muxer_options.set("movflags", "+faststart");
+        }
+        octx.write_header_with(muxer_options)?;
+        let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
+        
+        let mut finished = false;
+        for (stream, packet) in ictx.packets() {
+            if stream.index() != video_stream_index {
+                continue;
+            }
+            decoder.send_packet(&packet)?;
+            finished = send_clip_frames(&mut decoder, &mut graph, time_base, start, end)?;
+            encode_filtered_video(&mut graph, &mut encoder, &mut octx, output_time_base)?;
+            if finished {
+                break;
+            }
+        }
+        if !finished {
+            decoder.send_eof()?;
+            send_clip_frames(&mut decoder, &mut graph, time_base, start, end)?;
+        }
+        
+        graph.get("in").context("Filter source missing")?.source().flush()?;
+        encode_filtered_video(&mut graph, &mut encoder, &mut octx, output_time_base)?;
+        encoder.send_eof()?;
+        write_encoded_packets(&mut encoder, &mut octx, output_time_base)?;
+        octx.write_trailer()?;
+        
+        previews.push(serde_json::json!({
+            "index": index,
+            "file": file_name,
+            "start": start,
+            "end": end,
+            "size_bytes": std::fs::metadata(&clip_path)?.len(),
+        }));
+    }
+    
+    let index = serde_json::json!({
+        "source": job.input_path,
+        "duration": duration,
+        "output_dir": output_dir,
+        "format": format,
+        "width": width,
+        "height": height,
+        "fps": fps,
+        "clip_duration": clip_duration,
+        "previews": previews,
+    });
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&index)?)?;
+    
+    info!("Generated {} hover previews in {}", previews.len(), output_dir);
+    Ok(job.output_path.clone())
+}
+
+/// Export a video for a social platform in one pass
+///
+/// `platform` is `tiktok`, `reels`, `shorts` or `x`, with an optional `variant` (reels:
+/// `reel`, `feed`, `square`; x: `landscape`, `square`, `portrait`) picking the frame size and
+/// length limit. The picture is fitted by `fit`: `crop` (default, fill and centre-crop), `pad`
+/// (letterbox) or `blur` (letterbox over a blurred fill). Output runs at `fps` (default: 30)
+/// and is cut at the platform limit (or a shorter `max_duration`) unless `trim` is false, in
+/// which case overlong inputs fail. Audio is normalised to `loudness` LUFS (default: -14)
+/// with a -1 dBFS limiter unless `normalize_audio` is false, and encoded at `audio_bitrate`
+/// (default: 128k). Sampled frames are checked for busy detail under the platform's
+/// (approximate) UI overlays. Warnings about trimming, upscaling, cropping and the safe area
+/// go to the JSON report at `report_path` (default: `<output_path>.json`).
+pub async fn export_social(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Exporting social media video using ffmpeg-next");
+    
+    let platform = job.params.get("platform")
+        .and_then(|v| v.as_str())
+        .context("platform parameter required")?
+        .to_ascii_lowercase();
+    
+    let variant = job.params.get("variant")
+        .and_then(|v| v.as_str())
+        .map(|variant| variant.to_ascii_lowercase());
+    
+    let preset = social_preset(&platform, variant.as_deref())?;
+    
+    let fit = job.params.get("fit")
+        .and_then(|v| v.as_str())
+        .unwrap_or("crop");
+    
+    let fps = job.params.get("fps")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(30)
+        .clamp(1, 60) as i32;
+    
+    let max_duration = job.params.get("max_duration")
+        .and_then(|v| v.as_f64())
+        .map_or(preset.max_duration, |limit| limit.min(preset.max_duration));
+    
+    let trim = job.params.get("trim")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let normalize_audio = job.params.get("normalize_audio")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    
+    let loudness = job.params.get("loudness")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(-14.0);
+    
+    let audio_bitrate = parse_bitrate(
+        job.params.get("audio_bitrate")
+            .and_then(|v| v.as_str())
+            .unwrap_or("128k"),
+    )?;
+    
+    let report_path = job.params.get("report_path")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}.json", job.output_path));
+    
+    let (width, height) = (preset.width, preset.height);
+    let mut warnings: Vec<String> = Vec::new();
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    
+    let duration = ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE);
+    if duration > max_duration {
+        if !trim {
+            anyhow::bail!(
+                "Input is {:.1}s, longer than the {:.0}s allowed on {}",
+                duration,
+                max_duration,
+                platform
+            );
+        }
+        warnings.push(format!("Trimmed from {:.1}s to the {:.0}s limit", duration, max_duration));
+    }
+    
+    let (video_stream_index, time_base, mut decoder) = {
+        let input_stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .context("No video stream found")?;
+        let decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?
+            .decoder()
+            .video()?;
+        (input_stream.index(), input_stream.time_base(), decoder)
+    };
+    
+    // Display size, honouring non-square pixels
+    let aspect = decoder.aspect_ratio();
+    let display_width = if aspect.numerator() > 0 && aspect.denominator() > 0 {
+        decoder.width() as f64 * f64::from(aspect)
+    } else {
+        decoder.width() as f64
+    };
+    let source_aspect = display_width / decoder.height() as f64;
+    let target_aspect = width as f64 / height as f64;
+    let kept = source_aspect.min(target_aspect) / source_aspect.max(target_aspect);
+    
+    let filters = match fit {
+        "crop" => {
+            if kept < 0.75 {
+                warnings.push(format!(
+                    "Cropping removes {:.0}% of the picture; check that subjects stay in frame",
+                    (1.0 - kept) * 100.0
+                ));
+            }
+            format!(
+                "scale={w}:{h}:force_original_aspect_ratio=increase:flags=lanczos,crop={w}:{h}",
+                w = width,
+                h = height
+            )
+        }
+        "pad" | "blur" => {
+            if kept < 0.6 {
+                warnings.push(format!(
+                    "The picture fills only {:.0}% of the frame; consider fit=crop",
+                    kept * 100.0
+                ));
+            }
+            let fitted = format!(
+                "scale={w}:{h}:force_original_aspect_ratio=decrease:force_divisible_by=2:flags=lanczos",
+                w = width,
+                h = height
+            );
+            if fit == "pad" {
+                format!("{},pad={}:{}:(ow-iw)/2:(oh-ih)/2:color=black", fitted, width, height)
+            } else {
+                format!(
+                    "split[background][foreground];\
+                     [background]scale={w}:{h}:force_original_aspect_ratio=increase,crop={w}:{h},\
+                     boxblur=luma_radius=40:luma_power=2[blurred];\
+                     [foreground]{fitted}[fitted];\
+                     [blurred][fitted]overlay=(W-w)/2:(H-h)/2",
+                    w = width,
+                    h = height,
+                    fitted = fitted
+                )
+            }
+        }
+        other => anyhow::bail!("Unknown fit mode: {}", other),
+    };
+    
+    // Upscaling only matters for the dimension that ends up filling the frame
+    let scale = match fit {
+        "crop" => (width as f64 / display_width).max(height as f64 / decoder.height() as f64),
+        _ => (width as f64 / display_width).min(height as f64 / decoder.height() as f64),
+    };
+    if scale > 1.25 {
+        warnings.push(format!(
+            "Source is {}x{}, upscaled {:.1}x; the export will look soft",
+            decoder.width(),
+            decoder.height(),
+            scale
+        ));
+    }
+    
+    let mut settings = VideoOutputSettings::from_params(&job.params)?;
+    if settings.bit_rate.is_none() && !settings.options.iter().any(|(key, _)| key == "crf") {
+        settings.options.push(("crf".to_string(), "20".to_string()));
+    }
+    
+    let mut octx = ffmpeg::format::output_as(&job.output_path, "mp4")
+        .context("Failed to create output")?;
+    let (mut encoder, _) = open_video_encoder(&mut octx, &job.output_path, &settings, width, height, (fps, 1).into())?;
+    let mut graph = build_video_filter_graph(
+        &decoder,
+        time_base,
+        &format!("{},setsar=1,fps={}", filters, fps),
+        encoder.format(),
+    )?;
+    
+    let mut audio = None;
+    if let Some(stream) = ictx.streams().best(ffmpeg::media::Type::Audio) {
+        let mut filter = "aresample=async=1".to_string();
+        if normalize_audio {
+            match crate::audio::measure_loudness(&job.input_path)?.integrated_lufs {
+                Some(integrated) => {
+                    filter.push_str(&format!(
+                        ",volume={:.2}dB,alimiter=limit={:.4}:level=0",
+                        loudness - integrated,
+                        10f64.powf(-1.0 / 20.0)
+                    ));
+                }
+                None => warnings.push("Audio is too quiet to measure; loudness left as is".to_string()),
+            }
+        }
+        let transcoder = AudioTranscoder::open_filtered(
+            &stream,
+            &mut octx,
+            &job.output_path,
+            Some("aac"),
+            Some(audio_bitrate),
+            &filter,
+        )?;
+        audio = Some((stream.index(), transcoder));
+    } else {
+        warnings.push("Input has no audio track".to_string());
+    }
+    
+    let mut muxer_options = ffmpeg::Dictionary::new();
+    muxer_options.set("movflags", "+faststart");
+    octx.write_header_with(muxer_options)?;
+    let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
+    
+    let mut safe_area = SafeAreaCheck::new(preset.safe_area, fps as u64);
+    let mut encode = |graph: &mut ffmpeg::filter::Graph,
+                      encoder: &mut ffmpeg::encoder::Video,
+                      octx: &mut ffmpeg::format::context::Output|
+     -> Result<()> {
+        let mut filtered = ffmpeg::util::frame::video::Video::empty();
+        while graph.get("out").context("Filter sink missing")?.sink().frame(&mut filtered).is_ok() {
+            safe_area.add(&filtered);
+            encoder.send_frame(&filtered)?;
+            write_encoded_packets(encoder, octx, output_time_base)?;
+        }
+        Ok(())
+    };
+    
+    let mut finished = false;
+    for (stream, packet) in ictx.packets() {
+        let seconds = packet.pts().map_or(0.0, |pts| pts as f64 * f64::from(stream.time_base()));
+        if stream.index() == video_stream_index {
+            decoder.send_packet(&packet)?;
+            finished = send_clip_frames(&mut decoder, &mut graph, time_base, f64::NEG_INFINITY, max_duration)?;
+            encode(&mut graph, &mut encoder, &mut octx)?;
+            if finished {
+                break;
+            }
+        } else if let Some((_, transcoder)) = audio.as_mut().filter(|(index, _)| *index == stream.index()) {
+            if seconds < max_duration {
+                transcoder.send_packet(&packet, &mut octx)?;
+            }
+        }
+    }
+    if !finished {
+        decoder.send_eof()?;
+        send_clip_frames(&mut decoder, &mut graph, time_base, f64::NEG_INFINITY, max_duration)?;
+    }
+    
+    graph.get("in").context("Filter source missing")?.source().flush()?;
+    encode(&mut graph, &mut encoder, &mut octx)?;
+    encoder.send_eof()?;
+    write_encoded_packets(&mut encoder, &mut octx, output_time_base)?;
+    if let Some((_, transcoder)) = &mut audio {
+        transcoder.finish(&mut octx)?;
+    }
+    
+    octx.write_trailer()?;
+    
+    warnings.extend(safe_area.warnings());
+    for warning in &warnings {
+        warn!(platform = %platform, "{}", warning);
+    }
+    
+    let report = serde_json::json!({
+        "output_path": job.output_path,
+        "platform": platform,
+        "variant": variant,
+        "width": width,
+        "height": height,
+        "fps": fps,
+        "fit": fit,
+        "max_duration": max_duration,
+        "duration": duration.min(max_duration),
+        "loudness_target": normalize_audio.then_some(loudness),
+        "safe_area": {
+            "top": preset.safe_area.0,
+            "bottom": preset.safe_area.1,
+            "left": preset.safe_area.2,
+            "right": preset.safe_area.3,
+        },
+        "frames_checked": safe_area.frames,
+        "warnings": warnings,
+    });
+    std::fs::write(&report_path, serde_json::to_string_pretty(&report)?)?;
+    
+    info!("Exported for {} with {} warnings", platform, warnings.len());
+    Ok(job.output_path.clone())
+}
+
+/// Join videos end to end, optionally with transitions between them
+///
+/// `input_files` (two or more) are normalised to a common `width`x`height` (default: the
+/// first input's size, letterboxed to keep each aspect ratio), `fps` (default: the first
+/// input's rate) and `sample_rate` (default: 48000, stereo), so inputs of different sizes,
+/// frame rates and sample rates can be mixed. Inputs without audio contribute silence.
+/// `transition` is `crossfade`, `fade_to_black` or `wipe` (`wipe_direction`: left (default),
+/// right, up or down), or any other xfade transition name, lasting `transition_duration`
+/// seconds (default: 1.0); audio is crossfaded over the same span. Without `transition`
+/// inputs are butted together. Video takes the usual `codec`/`bitrate`/`crf`/`preset`
+/// params, audio `audio_bitrate` (default: 128k).
+pub async fn concatenate_videos(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Concatenating videos using ffmpeg-next");
+    
+    let input_files: Vec<&str> = job.params.get("input_files")
+        .and_then(|v| v.as_array())
+        .context("input_files array parameter required")?
+        .iter()
+        .filter_map(|v| v.as_str())
+        .collect();
+    
+    if input_files.len() < 2 {
+        anyhow::bail!("At least two input files required for concatenation");
+    }
+    
+    let transition = concat_transition(&job.params)?;
+    
+    let mut inputs = Vec::with_capacity(input_files.len());
+    for (index, path) in input_files.iter().enumerate() {
+        let mut input = ConcatInput::open(path)?;
+        if index > 0 {
+            input.transition = transition.clone();
+        }
+        inputs.push(input);
+    }
+    
+    let total = write_concatenation(job, &mut inputs)?;
+    info!("Concatenated {} videos ({:.2}s)", inputs.len(), total);
+    Ok(job.output_path.clone())
+}
+
+/// Render an edit decision list: clips cut from source videos and joined into one output
+///
+/// `timeline` is an array of clips, or an object with a `clips` array; without it the input
+/// file is read as the timeline. Each clip has a `source` (default: the input file), `in` and
+/// `out` (or `duration`) points in seconds or as timestamps (defaults: the start and end of
+/// the source), a `gain_db` for its audio (default: 0) and optionally a `transition` into it
+/// from the previous clip, given and timed as for `concatenate_videos`; clips without one are
+/// cut together. Clips are trimmed to the frame and normalised like the inputs of
+/// `concatenate_videos`, and the output takes the same size, rate and encoding params.
+pub async fn render_edl(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Rendering edit decision list");
+    
+    let timeline = match job.params.get("timeline") {
+        Some(timeline) => timeline.clone(),
+        None => {
+            let text = std::fs::read_to_string(&job.input_path)
+                .context(format!("Failed to read timeline {}", job.input_path))?;
+            serde_json::from_str(&text).context("Timeline is not valid JSON")?
+        }
+    };
+    let clips = timeline
+        .as_array()
+        .or_else(|| timeline.get("clips").and_then(|v| v.as_array()))
+        .context("timeline must be an array of clips or an object with a clips array")?;
+    
+    if clips.is_empty() {
+        anyhow::bail!("The timeline has no clips");
+    }
+    
+    let mut inputs = Vec::with_capacity(clips.len());
+    for (index, clip) in clips.iter().enumerate() {
+        let source = match clip.get("source").and_then(|v| v.as_str()) {
+            Some(source) => source,
+            None if job.params.get("timeline").is_some() => job.input_path.as_str(),
+            None => anyhow::bail!("Clip {} has no source", index),
+        };
+        
+        let clip_in = crate::audio::param_seconds(clip, "in")?.unwrap_or(0.0);
+        let clip_out = match (
+            crate::audio::param_seconds(clip, "out")?,
+            crate::audio::param_seconds(clip, "duration")?,
+        ) {
+            (Some(_), Some(_)) => anyhow::bail!("Clip {} gives both out and duration", index),
+            (Some(out), None) => Some(out),
+            (None, Some(duration)) => Some(clip_in + duration),
+            (None, None) => None,
+        };
+        
+        let mut input = ConcatInput::open(source)?;
+        input.trim(clip_in, clip_out).context(format!("Clip {}", index))?;
+        input.gain_db = clip.get("gain_db")
+            .and_then(|v| v.as_f64())
+            .unwrap_or(0.0);
+        if index > 0 {
+            input.transition = concat_transition(clip).context(format!("Clip {}", index))?;
+        }
+        inputs.push(input);
+    }
+    
+    let total = write_concatenation(job, &mut inputs)?;
+    info!("Rendered {} clips ({:.2}s)", inputs.len(), total);
+    Ok(job.output_path.clone())
+}
+
+/// Transcode one source into an adaptive bitrate ladder, decoding it only once
+///
+/// `renditions` is an array of objects with `height` (required), and optionally `width`
+/// (default: from the source aspect ratio), `name` (default: `<height>p`), `bitrate`, `crf`,
+/// `preset` and `codec` (defaults: the job's own params). The default ladder is
+/// 1080p/720p/480p/360p at 5000k/2800k/1400k/800k. Renditions taller than the source are
+/// skipped unless `upscale` is true. Keyframes are placed every `keyframe_seconds` (default: 2)
+/// in every rendition so they can be switched between at segment boundaries. Audio is copied
+/// when it is AAC or MP3 and otherwise encoded to AAC at `audio_bitrate` (default: 128k).
+/// Renditions are written to `output_dir` (default: `<output_stem>_abr` next to the output) as
+/// `<name>.<container>` (`container` default: mp4) and summarised in JSON at `output_path`.
+pub async fn generate_abr_ladder(job: &JobPayload, _config: &Config) -> Result<String> {
+    info!("Generating ABR ladder using ffmpeg-next");
+    
+    let default_ladder = serde_json::json!([
+        {"height": 1080, "bitrate": "5000k"},
+        {"height": 720, "bitrate": "2800k"},
+        {"height": 480, "bitrate": "1400k"},
+        {"height": 360, "bitrate": "800k"},
+    ]);
+    let renditions = job.params.get("renditions")
+        .and_then(|v| v.as_array())
+        .unwrap_or_else(|| default_ladder.as_array().unwrap());
+    
+    if renditions.is_empty() {
+        anyhow::bail!("renditions must not be empty");
+    }
+    
+    let upscale = job.params.get("upscale")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    
+    let keyframe_seconds = job.params.get("keyframe_seconds")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(2.0);
+    
+    if keyframe_seconds <= 0.0 {
+        anyhow::bail!("keyframe_seconds must be positive");
+    }
+    
+    let container = job.params.get("container")
+        .and_then(|v| v.as_str())
+        .unwrap_or("mp4");
+    
+    let audio_bitrate = parse_bitrate(
+        job.params.get("audio_bitrate")
+            .and_then(|v| v.as_str())
+            .unwrap_or("128k"),
+    )?;
+    
+    let output_dir = job.params.get("output_dir")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(|| {
+            let output = Path::new(&job.output_path);
+            let stem = output.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            output.with_file_name(format!("{}_abr", stem)).to_string_lossy().to_string()
+        });
+    std::fs::create_dir_all(&output_dir)?;
+    
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    
+    let video_stream = ictx.streams()
+        .best(ffmpeg::media::Type::Video)
+        .context("No video stream found")?;
+    let video_index = video_stream.index();
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(video_stream.parameters())?
+        .decoder()
+        .video()?;
+    let (source_width, source_height) = (decoder.width(), decoder.height());
+    let frame_rate = f64::from(stream_frame_rate(&video_stream));
+    let audio_stream = ictx.streams().best(ffmpeg::media::Type::Audio);
+    
+    let mut ladder = Vec::new();
+    let mut skipped = Vec::new();
+    for rendition in renditions {
+        let height = rendition.get("height")
+            .and_then(|v| v.as_u64())
+            .context("Each rendition needs a height")? as u32;
+        let name = rendition.get("name")
+            .and_then(|v| v.as_str())
+            .map(String::from)
+            .unwrap_or_else(|| format!("{}p", height));
+        
+        if height > source_height && !upscale {
+            warn!("Skipping {} rendition taller than the {}p source", name, source_height);
+            skipped.push(name);
+            continue;
+        }
+        if ladder.iter().any(|rendition: &AbrRendition| rendition.name == name) {
+            anyhow::bail!("Duplicate rendition name: {}", name);
+        }
+        
+        let width = rendition.get("width")
+            .and_then(|v| v.as_u64())
+            .map(|width| width as u32)
+            .unwrap_or_else(|| {
+                (f64::from(source_width) * f64::from(height) / f64::from(source_height)).round() as u32
+            });
+        
+        // Rendition values override the job-wide encoding params
+        let mut params = job.params.clone();
+        if let (Some(params), Some(overrides)) = (params.as_object_mut(), rendition.as_object()) {
+            params.extend(overrides.clone());
+        }
+        let mut settings = VideoOutputSettings::from_params(&params)?;
+        settings.gop = Some((frame_rate * keyframe_seconds).round().max(1.0) as u32);
+        
+        let path = Path::new(&output_dir).join(format!("{}.{}", name, container));
+        let path = path.to_string_lossy().to_string();
+        let mut octx = ffmpeg::format::output(&path)
+            .context(format!("Failed to create output file {}", path))?;
+        
+        let encoder = VideoFrameEncoder::open(&video_stream, &mut octx, &path, &settings, (width, height))?;
+        let audio = match &audio_stream {
+            Some(stream) if matches!(stream.parameters().id(), ffmpeg::codec::Id::AAC | ffmpeg::codec::Id::MP3) => {
+                Some(StreamOutput::copy(stream, &mut octx)?)
+            }
+            Some(stream) => Some(StreamOutput::Audio(
+                AudioTranscoder::open(stream, &mut octx, &path, Some("aac"), Some(audio_bitrate))?,
+            )),
+            None => None,
+        };
+        
+        let muxer_options = mp4_muxer_options(&params, &octx.format())?;
+        octx.write_header_with(muxer_options)
+            .context(format!("Failed to write header for {}", path))?;
+        ladder.push(AbrRendition { name, path, params, bit_rate: settings.bit_rate, encoder, audio, octx });
+    }
+    
+    if ladder.is_empty() {
+        anyhow::bail!("Every rendition is taller than the {}p source; set upscale to keep them", source_height);
+    }
+    
+    let audio_index = audio_stream.as_ref().map(|stream| stream.index());
+    let mut decoded = ffmpeg::util::frame::video::Video::empty();
+    for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
+        if stream.index() == video_index {
+            if let Err(e) = decoder.send_packet(&packet) {
+                warn!(error = %e, "Dropping undecodable video packet");
+                continue;
+            }
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                for rendition in &mut ladder {
+                    rendition.encoder.send_frame(&decoded, &mut rendition.octx)?;
+                }
+            }
+        } else if Some(stream.index()) == audio_index {
+            for rendition in &mut ladder {
+                if let Some(audio) = &mut rendition.audio {
+                    audio.write(packet.clone(), &mut rendition.octx)?;
+                }
+            }
+        }
+    }
+    
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        for rendition in &mut ladder {
+            rendition.encoder.send_frame(&decoded, &mut rendition.octx)?;
+        }
+    }
+    
+    let mut summary = Vec::new();
+    for mut rendition in ladder {
+        rendition.encoder.finish(&mut rendition.octx)?;
+        if let Some(audio) = &mut rendition.audio {
+            audio.finish(&mut rendition.octx)?;
+        }
+        rendition.octx.write_trailer()?;
+        let media_segments = finish_mp4_output(&rendition.params, &rendition.path)?;
+        
+        summary.push(serde_json::json!({
+            "name": rendition.name,
+            "file": rendition.path,
+            "media_segments": media_segments,
+            "width": rendition.encoder.encoder.width(),
+            "height": rendition.encoder.encoder.height(),
+            "bitrate": rendition.bit_rate,
+            "size_bytes": std::fs::metadata(&rendition.path)?.len(),
+        }));
+    }
+    
+    let report = serde_json::json!({
+        "source": job.input_path,
+        "source_width": source_width,
+        "source_height": source_height,
+        "frame_rate": frame_rate,
+        "keyframe_seconds": keyframe_seconds,
+        "output_dir": output_dir,
+        "renditions": summary,
+        "skipped": skipped,
+    });
+    
+    std::fs::write(&job.output_path, serde_json::to_string_pretty(&report)?)?;
+    
+    info!("Generated {} renditions in {}", summary.len(), output_dir);
+    Ok(job.output_path.clone())
+}
+
+// Helper functions
+
+fn calculate_frame_difference(frame1: &ffmpeg::util::frame::video::Video, frame2: &ffmpeg::util::frame::video::Video) -> f64 {
+    // Simplified frame difference calculation
+    // In production, use more sophisticated methods (histogram, SSIM, etc.)
+    let data1 = frame1.data(0);
+    let data2 = frame2.data(0);
+    
+    let len = data1.len().min(data2.len());
+    if len == 0 {
+        return 0.0;
+    }
+    
+    let mut diff_sum: u64 = 0;
+    for i in 0..len {
+        diff_sum += (data1[i] as i32 - data2[i] as i32).abs() as u64;
+    }
+    
+    diff_sum as f64 / len as f64 / 255.0
+}
+
+pub(crate) fn parse_timestamp(timestamp: &str) -> Result<f64> {
+    // Parse HH:MM:SS or MM:SS or SS format
+    let parts: Vec<&str> = timestamp.split(':').collect();
+    
+    let seconds = match parts.len() {
+        1 => parts[0].parse::<f64>()?,
+        2 => {
+            let minutes = parts[0].parse::<f64>()?;
+            let secs = parts[1].parse::<f64>()?;
+            minutes * 60.0 + secs
+        }
+        3 => {
+            let hours = parts[0].parse::<f64>()?;
+            let minutes = parts[1].parse::<f64>()?;
+            let secs = parts[2].parse::<f64>()?;
+            hours * 3600.0 + minutes * 60.0 + secs
+        }
+        _ => anyhow::bail!("Invalid timestamp format: {}", timestamp),
+    };
+    
+    Ok(seconds)
+}
+
+/// Output encoder settings shared by the video tasks that encode
+#[derive(Default)]
+struct VideoOutputSettings {
+    codec: Option<String>,
+    bit_rate: Option<usize>,
+    pixel_format: Option<ffmpeg::format::Pixel>,
+    /// Keyframe interval in frames
+    gop: Option<u32>,
+    /// Private encoder options (`crf`, `preset`, ...)
+    options: Vec<(String, String)>,
+    /// Colour description to tag the stream with
+    color: Option<ColorTags>,
+}
+
+/// Colour primaries, transfer characteristics, matrix and range of a video stream
+#[derive(Clone, Copy, Debug)]
+struct ColorTags {
+    primaries: ffmpeg::ffi::AVColorPrimaries,
+    transfer: ffmpeg::ffi::AVColorTransferCharacteristic,
+    space: ffmpeg::ffi::AVColorSpace,
+    range: ffmpeg::ffi::AVColorRange,
+}
+
+impl ColorTags {
+    /// SDR HD video: BT.709 throughout, limited range
+    const BT709: ColorTags = ColorTags {
+        primaries: ffmpeg::ffi::AVColorPrimaries::AVCOL_PRI_BT709,
+        transfer: ffmpeg::ffi::AVColorTransferCharacteristic::AVCOL_TRC_BT709,
+        space: ffmpeg::ffi::AVColorSpace::AVCOL_SPC_BT709,
+        range: ffmpeg::ffi::AVColorRange::AVCOL_RANGE_MPEG,
+    };
+    
+    /// The tags of a stream as stored in its codec parameters
+    fn of_stream(stream: &ffmpeg::format::stream::Stream) -> Self {
+        unsafe {
+            let parameters = stream.parameters().as_ptr();
+            ColorTags {
+                primaries: (*parameters).color_primaries,
+                transfer: (*parameters).color_trc,
+                space: (*parameters).color_space,
+                range: (*parameters).color_range,
+            }
+        }
+    }
+    
+    /// Whether the transfer is one of the HDR ones (PQ or HLG)
+    fn is_hdr(&self) -> bool {
+        matches!(
+            self.transfer,
+            ffmpeg::ffi::AVColorTransferCharacteristic::AVCOL_TRC_SMPTE2084
+                | ffmpeg::ffi::AVColorTransferCharacteristic::AVCOL_TRC_ARIB_STD_B67
+        )
+    }
+    
+    /// Set the tags on an encoder before it is opened
+    fn apply(&self, encoder: &mut ffmpeg::encoder::video::Video) {
+        unsafe {
+            let context = encoder.as_mut_ptr();
+            (*context).color_primaries = self.primaries;
+            (*context).color_trc = self.transfer;
+            (*context).colorspace = self.space;
+            (*context).color_range = self.range;
+        }
+    }
+}
+
+impl VideoOutputSettings {
+    /// Read the common `codec`, `bitrate`, `crf`, `preset` (see `speed_options`) and
+    /// `pixel_format` params
+    fn from_params(params: &serde_json::Value) -> Result<Self> {
+        let bit_rate = match params.get("bitrate").and_then(|v| v.as_str()) {
+            Some(bitrate) => Some(parse_bitrate(bitrate)?),
+            None => None,
+        };
+        
+        let pixel_format = match params.get("pixel_format").and_then(|v| v.as_str()) {
+            Some(name) => Some(
+                name.parse::<ffmpeg::format::Pixel>()
+                    .map_err(|_| anyhow::anyhow!("Unknown pixel format: {}", name))?,
+            ),
+            None => None,
+        };
+        
+        let codec = params.get("codec").and_then(|v| v.as_str()).map(String::from);
+        let mut options = Vec::new();
+        if let Some(crf) = params.get("crf").and_then(|v| v.as_f64()) {
+            options.push(("crf".to_string(), crf.to_string()));
+            if bit_rate.is_none() && matches!(codec.as_deref(), Some("libvpx" | "libvpx-vp9" | "libaom-av1")) {
+                options.push(("b".to_string(), "0".to_string()));
+            }
+        }
+        options.extend(speed_options(params, codec.as_deref().unwrap_or_default())?);
+        
+        Ok(Self {
+            codec,
+            bit_rate,
+            pixel_format,
+            options,
+            ..Default::default()
+        })
+    }
+}
+
+/// Add a video stream to `octx` and open an encoder for it, returning the stream index
+///
+/// The codec defaults to the container's preferred video codec, the pixel format to yuv420p
+/// when the encoder takes it (the most widely playable choice).
+fn open_video_encoder(
+    octx: &mut ffmpeg::format::context::Output,
+    output_path: &str,
+    settings: &VideoOutputSettings,
+    width: u32,
+    height: u32,
+    frame_rate: ffmpeg::Rational,
+) -> Result<(ffmpeg::encoder::Video, usize)> {
+    let codec = match &settings.codec {
+        Some(name) => ffmpeg::encoder::find_by_name(name)
+            .context(format!("Codec {} not found", name))?,
+        None => ffmpeg::encoder::find(octx.format().codec(output_path, ffmpeg::media::Type::Video))
+            .context("No suitable video encoder found")?,
+    };
+    
+    let supported: Vec<ffmpeg::format::Pixel> = codec
+        .video()?
+        .formats()
+        .map(|formats| formats.collect())
+        .unwrap_or_default();
+    let pixel_format = match settings.pixel_format {
+        Some(format) if supported.is_empty() || supported.contains(&format) => format,
+        Some(format) => anyhow::bail!("Codec {} does not support pixel format {:?}", codec.name(), format),
+        None if supported.is_empty() || supported.contains(&ffmpeg::format::Pixel::YUV420P) => {
+            ffmpeg::format::Pixel::YUV420P
+        }
+        None => supported[0],
+    };
+    
+    let global_header = octx
+        .format()
+        .flags()
+        .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+    
+    let mut ost = octx.add_stream(codec)?;
+    let stream_index = ost.index();
+    let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(pixel_format);
+    encoder.set_time_base(frame_rate.invert());
+    encoder.set_frame_rate(Some(frame_rate));
+    if let Some(bit_rate) = settings.bit_rate {
+        encoder.set_bit_rate(bit_rate);
+    }
+    if let Some(gop) = settings.gop {
+        encoder.set_gop(gop);
+    }
+    if let Some(color) = &settings.color {
+        color.apply(&mut encoder);
+    }
+    if global_header {
+        encoder.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+    }
+    
+    let mut options = ffmpeg::Dictionary::new();
+    for (key, value) in &settings.options {
+        options.set(key, value);
+    }
+    
+    let encoder = encoder.open_as_with(codec, options)?;
+    ost.set_parameters(&encoder);
+    ost.set_time_base(frame_rate.invert());
+    ost.set_avg_frame_rate(frame_rate);
+    
+    Ok((encoder, stream_index))
+}
+
+/// Image files for a sequence: a directory's images, or the files matching a glob pattern
+fn list_image_sequence(input: &str) -> Result<Vec<std::path::PathBuf>> {
+    const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp", "tif", "tiff", "bmp", "tga", "exr", "gif"];
+    
+    let input_path = Path::new(input);
+    let (dir, matcher) = if input_path.is_dir() {
+        (input_path.to_path_buf(), None)
+    } else {
+        let dir = input_path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let pattern = input_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .context("Image sequence input needs a file name pattern")?;
+        let matcher = globset::Glob::new(&pattern)
+            .context(format!("Invalid glob: {}", pattern))?
+            .compile_matcher();
+        (dir.to_path_buf(), Some(matcher))
+    };
+    
+    let mut images: Vec<std::path::PathBuf> = std::fs::read_dir(&dir)
+        .context(format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            match &matcher {
+                Some(matcher) => matcher.is_match(&name),
+                None => path
+                    .extension()
+                    .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_string_lossy().to_ascii_lowercase().as_str()))
+                    .unwrap_or(false),
+            }
+        })
+        .collect();
+    
+    images.sort_by_cached_key(|path| natural_sort_key(&path.to_string_lossy()));
+    Ok(images)
+}
+
+/// Sort key comparing digit runs by value, so `frame_9` sorts before `frame_10`
+fn natural_sort_key(name: &str) -> Vec<(String, u128)> {
+    let mut key = Vec::new();
+    let mut text = String::new();
+    let mut digits = String::new();
+    
+    for c in name.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+        } else {
+            if !digits.is_empty() {
+                key.push((std::mem::take(&mut text), digits.parse().unwrap_or(u128::MAX)));
+                digits.clear();
+            }
+            text.push(c);
+        }
+    }
+    key.push((text, digits.parse().unwrap_or(0)));
+    key
+}
+
+/// Copies an audio stream from another file into an output, a packet at a time
+struct AudioPassthrough {
+    ictx: ffmpeg::format::context::Input,
+    input_index: usize,
+    input_time_base: ffmpeg::Rational,
+    output_index: usize,
+    pending: Option<ffmpeg::Packet>,
+    done: bool,
+}
+
+impl AudioPassthrough {
+    /// Open the best audio stream of `path` and add a stream-copy output for it to `octx`
+    fn open(path: &str, octx: &mut ffmpeg::format::context::Output) -> Result<Self> {
+        let ictx = ffmpeg::format::input(&path)
+            .context(format!("Failed to open audio input: {}", path))?;
+        let stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Audio)
+            .context(format!("No audio stream found in {}", path))?;
+        let input_index = stream.index();
+        let input_time_base = stream.time_base();
+        let codec_id = stream.parameters().id();
+        
+        let supported = unsafe {
+            ffmpeg::ffi::avformat_query_codec(
+                (*octx.as_ptr()).oformat,
+                codec_id.into(),
+                ffmpeg::ffi::FF_COMPLIANCE_NORMAL as i32,
+            ) == 1
+        };
+        if !supported {
+            anyhow::bail!(
+                "Audio codec {:?} cannot be copied into {}; use a compatible audio file or container",
+                codec_id,
+                octx.format().name()
+            );
+        }
+        
+        let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        ost.set_parameters(stream.parameters());
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        let output_index = ost.index();
+        
+        Ok(Self {
+            ictx,
+            input_index,
+            input_time_base,
+            output_index,
+            pending: None,
+            done: false,
+        })
+    }
+    
+    /// Copy packets that start before `until` seconds
+    fn write_until(&mut self, octx: &mut ffmpeg::format::context::Output, until: f64) -> Result<()> {
+        let output_time_base = octx
+            .stream(self.output_index)
+            .context("Output stream missing")?
+            .time_base();
+        
+        loop {
+            let packet = match self.pending.take() {
+                Some(packet) => packet,
+                None if self.done => return Ok(()),
+                None => {
+                    let mut packet = ffmpeg::Packet::empty();
+                    match packet.read(&mut self.ictx) {
+                        Ok(()) => {}
+                        Err(ffmpeg::Error::Eof) => {
+                            self.done = true;
+                            return Ok(());
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                    if packet.stream() != self.input_index {
+                        continue;
+                    }
+                    packet
+                }
+            };
+            
+            let start = packet.pts().or(packet.dts()).unwrap_or(0) as f64 * f64::from(self.input_time_base);
+            if start >= until {
+                // Held back for the next call; a final call with the video's end drops it
+                self.pending = Some(packet);
+                return Ok(());
+            }
+            self.write_packet(octx, packet, output_time_base)?;
+        }
+    }
+    
+    fn write_packet(
+        &self,
+        octx: &mut ffmpeg::format::context::Output,
+        mut packet: ffmpeg::Packet,
+        output_time_base: ffmpeg::Rational,
+    ) -> Result<()> {
+        packet.rescale_ts(self.input_time_base, output_time_base);
+        packet.set_position(-1);
+        packet.set_stream(self.output_index);
+        packet.write_interleaved(octx)?;
+        Ok(())
+    }
+}
+
+/// Open a single-image encoder: `png`, `jpeg`/`jpg` or `webp`, returning it and the file extension
+///
+/// `quality` (1-100) maps to the JPEG quantiser and to libwebp's quality; 100 makes WebP lossless.
+fn open_image_encoder(format: &str, quality: u32, width: u32, height: u32) -> Result<(ffmpeg::encoder::Video, &'static str)> {
+    let (codec, pixel_format, extension) = match format {
+        "png" => (ffmpeg::encoder::find(ffmpeg::codec::Id::PNG), ffmpeg::format::Pixel::RGB24, "png"),
+        "jpeg" | "jpg" => (ffmpeg::encoder::find(ffmpeg::codec::Id::MJPEG), ffmpeg::format::Pixel::YUVJ420P, "jpg"),
+        "webp" => (ffmpeg::encoder::find_by_name("libwebp"), ffmpeg::format::Pixel::YUV420P, "webp"),
+        other => anyhow::bail!("Unsupported image format: {}", other),
+    };
+    let codec = codec.context(format!("No {} encoder available", format))?;
+    
+    let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(pixel_format);
+    encoder.set_time_base((1, 25));
+    
+    let mut options = ffmpeg::Dictionary::new();
+    match extension {
+        "jpg" => {
+            // Quality 100 -> qscale 2 (best), 1 -> 31 (worst)
+            let qscale = 2 + (100 - quality) * 29 / 99;
+            encoder.set_flags(ffmpeg::codec::Flags::QSCALE);
+            encoder.set_global_quality((qscale * ffmpeg::ffi::FF_QP2LAMBDA as u32) as i32);
+        }
+        "webp" => {
+            options.set("quality", &quality.to_string());
+            if quality == 100 {
+                options.set("lossless", "1");
+            }
+        }
+        _ => {}
+    }
+    
+    let encoder = encoder.open_as_with(codec, options)?;
+    Ok((encoder, extension))
+}
+
+/// Selects, scales, encodes and names frames for `export_image_sequence`
+struct ImageSequenceExporter {
+    encoder: ffmpeg::encoder::Video,
+    scaler: ffmpeg::software::scaling::Context,
+    output_dir: String,
+    name_template: String,
+    extension: &'static str,
+    time_base: ffmpeg::Rational,
+    frame_rate: f64,
+    start: f64,
+    end: Option<f64>,
+    every_n: u64,
+    sample_fps: Option<f64>,
+    max_frames: u64,
+    frame_number: u64,
+    next_sample: f64,
+    entries: Vec<serde_json::Value>,
+}
+
+impl ImageSequenceExporter {
+    /// Handle one decoded frame; returns true once past `end` or `max_frames`
+    fn push(&mut self, decoded: &ffmpeg::util::frame::video::Video) -> Result<bool> {
+        let timestamp = decoded
+            .timestamp()
+            .or(decoded.pts())
+            .map(|ts| ts as f64 * f64::from(self.time_base))
+            .unwrap_or(self.start + self.frame_number as f64 / self.frame_rate);
+        
+        // Frames before the seek target are decoded from the preceding keyframe
+        if timestamp < self.start - 1e-6 {
+            return Ok(false);
+        }
+        if self.end.is_some_and(|end| timestamp >= end) || self.entries.len() as u64 >= self.max_frames {
+            return Ok(true);
+        }
+        
+        let frame = self.frame_number;
+        self.frame_number += 1;
+        
+        let selected = match self.sample_fps {
+            Some(fps) if timestamp + 1e-6 >= self.next_sample => {
+                self.next_sample = self.start + (((timestamp - self.start) * fps).floor() + 1.0) / fps;
+                true
+            }
+            Some(_) => false,
+            None => frame % self.every_n == 0,
+        };
+        if !selected {
+            return Ok(false);
+        }
+        
+        let index = self.entries.len() as u64;
+        let file_name = format!(
+            "{}.{}",
+            expand_frame_template(&self.name_template, &[
+                ("index", index),
+                ("frame", frame),
+                ("ms", (timestamp * 1000.0).round().max(0.0) as u64),
+            ]),
+            self.extension
+        );
+        let path = Path::new(&self.output_dir).join(&file_name);
+        
+        let mut scaled = ffmpeg::util::frame::video::Video::empty();
+        self.scaler.run(decoded, &mut scaled)?;
+        scaled.set_pts(Some(index as i64));
+        self.encoder.send_frame(&scaled)?;
+        
+        // Image encoders are intra-only: one frame in, one packet out
+        let mut packet = ffmpeg::Packet::empty();
+        self.encoder
+            .receive_packet(&mut packet)
+            .context(format!("Image encoder produced no output for frame {}", frame))?;
+        std::fs::write(&path, packet.data().unwrap_or_default())
+            .context(format!("Failed to write {}", path.display()))?;
+        
+        self.entries.push(serde_json::json!({
+            "index": index,
+            "frame": frame,
+            "timestamp": timestamp,
+            "file": file_name,
+        }));
+        Ok(false)
+    }
+}
+
+/// Expand `{name}` and zero-padded `{name:06}` placeholders
+fn expand_frame_template(template: &str, values: &[(&str, u64)]) -> String {
+    let mut output = String::with_capacity(template.len() + 8);
+    let mut rest = template;
+    
+    while let Some(open) = rest.find('{') {
+        output.push_str(&rest[..open]);
+        let Some(close) = rest[open..].find('}') else {
+            output.push_str(&rest[open..]);
+            return output;
+        };
+        let placeholder = &rest[open + 1..open + close];
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, width)) => (name, width.parse::<usize>().ok()),
+            None => (placeholder, None),
+        };
+        
+        match values.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => match width {
+                Some(width) => output.push_str(&format!("{:0width$}", value, width = width)),
+                None => output.push_str(&value.to_string()),
+            },
+            None => output.push_str(&rest[open..open + close + 1]),
+        }
+        rest = &rest[open + close + 1..];
+    }
+    
+    output.push_str(rest);
+    output
+}
+
+/// A stream's frame count from the container, else estimated from its duration (or the
+/// container's, in `AV_TIME_BASE` units) and frame rate
+fn expected_frame_count(stream: &ffmpeg::format::stream::Stream, container_duration: i64) -> Option<u64> {
+    if stream.frames() > 0 {
+        return Some(stream.frames() as u64);
+    }
+    let seconds = if stream.duration() > 0 {
+        stream.duration() as f64 * f64::from(stream.time_base())
+    } else {
+        container_duration as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)
+    };
+    (seconds > 0.0).then(|| (seconds * f64::from(stream_frame_rate(stream))).round() as u64)
+}
+
+/// A stream's average frame rate, falling back to its base rate and then 30 fps
+fn stream_frame_rate(stream: &ffmpeg::format::stream::Stream) -> ffmpeg::Rational {
+    [stream.avg_frame_rate(), stream.rate()]
+        .into_iter()
+        .find(|rate| rate.numerator() > 0 && rate.denominator() > 0)
+        .unwrap_or(ffmpeg::Rational(30, 1))
+}
+
+/// Side data of `kind` in a stream's codec parameters, read as a `T`
+fn stream_side_data<T: Copy>(
+    stream: &ffmpeg::format::stream::Stream,
+    kind: ffmpeg::ffi::AVPacketSideDataType,
+) -> Option<T> {
+    unsafe {
+        let parameters = (*stream.as_ptr()).codecpar;
+        let side_data = ffmpeg::ffi::av_packet_side_data_get(
+            (*parameters).coded_side_data,
+            (*parameters).nb_coded_side_data,
+            kind,
+        );
+        if side_data.is_null() || (*side_data).size < std::mem::size_of::<T>() {
+            return None;
+        }
+        Some(std::ptr::read_unaligned((*side_data).data as *const T))
+    }
+}
+
+/// Add side data of `kind` holding `value` to output stream `output_index`
+fn add_stream_side_data<T: Copy>(
+    octx: &mut ffmpeg::format::context::Output,
+    output_index: usize,
+    kind: ffmpeg::ffi::AVPacketSideDataType,
+    value: &T,
+) -> Result<()> {
+    let mut output = octx.stream_mut(output_index).context("Output stream missing")?;
+    unsafe {
+        let parameters = (*output.as_mut_ptr()).codecpar;
+        let side_data = ffmpeg::ffi::av_packet_side_data_new(
+            &mut (*parameters).coded_side_data,
+            &mut (*parameters).nb_coded_side_data,
+            kind,
+            std::mem::size_of::<T>(),
+            0,
+        );
+        if side_data.is_null() {
+            anyhow::bail!("Failed to add {:?} side data to output stream", kind);
+        }
+        std::ptr::write_unaligned((*side_data).data as *mut T, *value);
+    }
+    Ok(())
+}
+
+/// A stream's display matrix, which tells players how to rotate or flip its frames
+fn display_matrix(stream: &ffmpeg::format::stream::Stream) -> Option<[i32; 9]> {
+    stream_side_data(stream, ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX)
+}
+
+/// Clockwise rotation in degrees (0, 90, 180 or 270) players apply to a stream's frames, from
+/// its display matrix or, in older files, its `rotate` tag
+fn display_rotation(stream: &ffmpeg::format::stream::Stream) -> u32 {
+    // The matrix holds the counter-clockwise angle
+    let degrees = display_matrix(stream)
+        .map(|matrix| -unsafe { ffmpeg::ffi::av_display_rotation_get(matrix.as_ptr()) })
+        .filter(|degrees| degrees.is_finite())
+        .or_else(|| stream.metadata().get("rotate").and_then(|rotate| rotate.parse::<f64>().ok()))
+        .unwrap_or(0.0);
+    ((degrees / 90.0).round() as i64).rem_euclid(4) as u32 * 90
+}
+
+/// Give output stream `output_index` the display matrix of `stream`, for frames encoded as stored
+fn copy_display_matrix(
+    stream: &ffmpeg::format::stream::Stream,
+    octx: &mut ffmpeg::format::context::Output,
+    output_index: usize,
+) -> Result<()> {
+    match display_matrix(stream) {
+        Some(matrix) => add_stream_side_data(
+            octx,
+            output_index,
+            ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+            &matrix,
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Decodes one input video stream and re-encodes it into an output as packets arrive
+struct VideoTranscoder {
+    decoder: ffmpeg::decoder::Video,
+    output: VideoFrameEncoder,
+}
+
+impl VideoTranscoder {
+    /// Add an encoded copy of `stream` to `octx`, scaled to `size` (default: the source size)
+    fn open(
+        stream: &ffmpeg::format::stream::Stream,
+        octx: &mut ffmpeg::format::context::Output,
+        output_path: &str,
+        settings: &VideoOutputSettings,
+        size: Option<(u32, u32)>,
+    ) -> Result<Self> {
+        let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        let size = size.unwrap_or((decoder.width(), decoder.height()));
+        let output = VideoFrameEncoder::open(stream, octx, output_path, settings, size)?;
+        
+        Ok(Self { decoder, output })
+    }
+    
+    fn with_frame_hook(mut self, hook: impl FnMut(&mut ffmpeg::util::frame::video::Video, f64) + 'static) -> Self {
+        self.output.frame_hook = Some(Box::new(hook));
+        self
+    }
+    
+    fn send_packet(&mut self, packet: &ffmpeg::Packet, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        // A corrupt packet in a long-running input costs a frame or two, not the job
+        if let Err(e) = self.decoder.send_packet(packet) {
+            warn!(error = %e, "Dropping undecodable video packet");
+            return Ok(());
+        }
+        self.encode_decoded(octx)
+    }
+    
+    /// Flush the decoder and encoder
+    fn finish(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        self.decoder.send_eof()?;
+        self.encode_decoded(octx)?;
+        self.output.finish(octx)
+    }
+    
+    fn encode_decoded(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let mut decoded = ffmpeg::util::frame::video::Video::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            self.output.send_frame(&decoded, octx)?;
+        }
+        Ok(())
+    }
+}
+
+/// Scales decoded frames of one input stream and encodes them into an output stream; several
+/// can share a decoder to produce renditions of the same source
+struct VideoFrameEncoder {
+    encoder: ffmpeg::encoder::Video,
+    scaler: Option<((u32, u32, ffmpeg::format::Pixel), ffmpeg::software::scaling::Context)>,
+    input_time_base: ffmpeg::Rational,
+    output_index: usize,
+    last_pts: Option<i64>,
+    /// Called with each scaled frame and its time in seconds before it is encoded
+    frame_hook: Option<Box<dyn FnMut(&mut ffmpeg::util::frame::video::Video, f64)>>,
+}
+
+impl VideoFrameEncoder {
+    /// Add a video stream for frames of `stream` to `octx`, encoded at `size`
+    fn open(
+        stream: &ffmpeg::format::stream::Stream,
+        octx: &mut ffmpeg::format::context::Output,
+        output_path: &str,
+        settings: &VideoOutputSettings,
+        (width, height): (u32, u32),
+    ) -> Result<Self> {
+        let (encoder, output_index) = open_video_encoder(
+            octx,
+            output_path,
+            settings,
+            width & !1,
+            height & !1,
+            stream_frame_rate(stream),
+        )?;
+        
+        Ok(Self {
+            encoder,
+            scaler: None,
+            input_time_base: stream.time_base(),
+            output_index,
+            last_pts: None,
+            frame_hook: None,
+        })
+    }
+    
+    fn send_frame(
+        &mut self,
+        decoded: &ffmpeg::util::frame::video::Video,
+        octx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        use ffmpeg::Rescale;
+        
+        let pts = match decoded.timestamp() {
+            Some(timestamp) => timestamp.rescale(self.input_time_base, self.encoder.time_base()),
+            None => self.last_pts.map_or(0, |last| last + 1),
+        };
+        // Frames landing on a tick already used are dropped, holding the output to its frame rate
+        if self.last_pts.map_or(false, |last| pts <= last) {
+            return Ok(());
+        }
+        self.last_pts = Some(pts);
+        
+        // Sources may change size or format mid-stream, e.g. after an encoder restart
+        let format = (decoded.width(), decoded.height(), decoded.format());
+        let scaler = match &mut self.scaler {
+            Some((current, scaler)) if *current == format => scaler,
+            slot => {
+                let scaler = ffmpeg::software::scaling::context::Context::get(
+                    decoded.format(),
+                    decoded.width(),
+                    decoded.height(),
+                    self.encoder.format(),
+                    self.encoder.width(),
+                    self.encoder.height(),
+                    ffmpeg::software::scaling::flag::Flags::BICUBIC,
+                )?;
+                &mut slot.insert((format, scaler)).1
+            }
+        };
+        
+        let mut frame = ffmpeg::util::frame::video::Video::empty();
+        scaler.run(decoded, &mut frame)?;
+        frame.set_pts(Some(pts));
+        if let Some(hook) = &mut self.frame_hook {
+            hook(&mut frame, pts as f64 * f64::from(self.encoder.time_base()));
+        }
+        
+        self.encoder.send_frame(&frame)?;
+        metrics::add_frames(1);
+        self.write_encoded(octx)
+    }
+    
+    /// Flush the encoder
+    fn finish(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        self.encoder.send_eof()?;
+        self.write_encoded(octx)
+    }
+    
+    fn write_encoded(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let output_time_base = octx.stream(self.output_index).context("Output stream missing")?.time_base();
+        let mut encoded = ffmpeg::Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(self.output_index);
+            encoded.rescale_ts(self.encoder.time_base(), output_time_base);
+            encoded.write_interleaved(octx)?;
+        }
+        Ok(())
+    }
+}
+
+/// How an input stream reaches an output: copied as is or re-encoded
+enum StreamOutput {
+    Copy {
+        input_time_base: ffmpeg::Rational,
+        output_index: usize,
+    },
+    Video(VideoTranscoder),
+    Audio(AudioTranscoder),
+}
+
+impl StreamOutput {
+    /// Add a stream-copy output for `stream` to `octx`, keeping its tags (language, title)
+    /// and disposition (default, forced)
+    fn copy(stream: &ffmpeg::format::stream::Stream, octx: &mut ffmpeg::format::context::Output) -> Result<Self> {
+        let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        ost.set_parameters(stream.parameters());
+        ost.set_metadata(stream.metadata().to_owned());
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+            (*ost.as_mut_ptr()).disposition = (*stream.as_ptr()).disposition;
+        }
+        
+        Ok(StreamOutput::Copy {
+            input_time_base: stream.time_base(),
+            output_index: ost.index(),
+        })
+    }
+    
+    fn write(&mut self, mut packet: ffmpeg::Packet, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        shutdown::check()?;
+        match self {
+            StreamOutput::Copy { input_time_base, output_index } => {
+                let output_time_base = octx.stream(*output_index).context("Output stream missing")?.time_base();
+                packet.rescale_ts(*input_time_base, output_time_base);
+                packet.set_position(-1);
+                packet.set_stream(*output_index);
+                packet.write_interleaved(octx)?;
+                Ok(())
+            }
+            StreamOutput::Video(transcoder) => transcoder.send_packet(&packet, octx),
+            StreamOutput::Audio(transcoder) => transcoder.send_packet(&packet, octx),
+        }
+    }
+    
+    fn finish(&mut self, octx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        match self {
+            StreamOutput::Copy { .. } => Ok(()),
+            StreamOutput::Video(transcoder) => transcoder.finish(octx),
+            StreamOutput::Audio(transcoder) => transcoder.finish(octx),
+        }
+    }
+}
+
+/// One RTMP endpoint of `push_rtmp`, with its own output and per-stream copy or encode
+struct PushTarget {
+    /// The URL with its stream key redacted, for the report and logs
+    display_url: String,
+    octx: ffmpeg::format::context::Output,
+    outputs: Vec<(usize, StreamOutput)>,
+    packets: u64,
+    error: Option<String>,
+}
+
+impl PushTarget {
+    /// Connect to the endpoint and write the FLV header; `target` is a URL or an object with
+    /// `url` and encoding params
+    fn open(ictx: &ffmpeg::format::context::Input, target: &serde_json::Value, keyframe_seconds: f64) -> Result<Self> {
+        let url = target
+            .as_str()
+            .or_else(|| target.get("url").and_then(|v| v.as_str()))
+            .context("Each target needs a url")?
+            .to_string();
+        let display_url = redact_url(&url).unwrap_or_else(|| "<redacted>".to_string());
+        let copy_video = target.get("codec").and_then(|v| v.as_str()).map_or(true, |codec| codec == "copy");
+        
+        let audio_bitrate = parse_bitrate(
+            target.get("audio_bitrate")
+                .and_then(|v| v.as_str())
+                .unwrap_or("128k"),
+        )?;
+        
+        let size = match (
+            target.get("width").and_then(|v| v.as_u64()),
+            target.get("height").and_then(|v| v.as_u64()),
+        ) {
+            (Some(width), Some(height)) => Some((width as u32, height as u32)),
+            (None, None) => None,
+            _ => anyhow::bail!("width and height must be given together for {}", display_url),
+        };
+        
+        // RTMP carries FLV whatever the scheme
+        let mut octx = ffmpeg::format::output_as(&url, "flv")
+            .context(format!("Failed to connect to {}", display_url))?;
+        let mut outputs = Vec::new();
+        
+        if let Some(stream) = ictx.streams().best(ffmpeg::media::Type::Video) {
+            let output = if copy_video {
+                StreamOutput::copy(&stream, &mut octx)?
+            } else {
+                let mut settings = VideoOutputSettings::from_params(target)?;
+                let frame_rate = f64::from(stream_frame_rate(&stream));
+                settings.gop = Some((frame_rate * keyframe_seconds).round().max(1.0) as u32);
+                if !settings.options.iter().any(|(key, _)| key == "preset") && settings.codec.as_deref() == Some("libx264") {
+                    settings.options.push(("preset".to_string(), "veryfast".to_string()));
+                }
+                StreamOutput::Video(VideoTranscoder::open(&stream, &mut octx, &url, &settings, size)?)
+            };
+            outputs.push((stream.index(), output));
+        }
+        
+        if let Some(stream) = ictx.streams().best(ffmpeg::media::Type::Audio) {
+            let flv_audio = matches!(stream.parameters().id(), ffmpeg::codec::Id::AAC | ffmpeg::codec::Id::MP3);
+            let output = if flv_audio {
+                StreamOutput::copy(&stream, &mut octx)?
+            } else {
+                StreamOutput::Audio(AudioTranscoder::open(&stream, &mut octx, &url, Some("aac"), Some(audio_bitrate))?)
+            };
+            outputs.push((stream.index(), output));
+        }
+        
+        if outputs.is_empty() {
+            anyhow::bail!("Input has no audio or video to push");
+        }
+        
+        octx.write_header()
+            .context(format!("Failed to start stream to {}", display_url))?;
+        
+        Ok(Self {
+            display_url,
+            octx,
+            outputs,
+            packets: 0,
+            error: None,
+        })
+    }
+    
+    /// Send a packet, marking the target failed instead of returning the error
+    fn write(&mut self, packet: &ffmpeg::Packet) {
+        let stream = packet.stream();
+        let Some((_, output)) = self.outputs.iter_mut().find(|(index, _)| *index == stream) else {
+            return;
+        };
+        match output.write(packet.clone(), &mut self.octx) {
+            Ok(()) => self.packets += 1,
+            Err(e) => {
+                warn!(url = %self.display_url, error = %e, "RTMP target failed, dropping it");
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+    
+    fn finish(&mut self) {
+        let result = self
+            .outputs
+            .iter_mut()
+            .try_for_each(|(_, output)| output.finish(&mut self.octx))
+            .and_then(|()| self.octx.write_trailer().map_err(anyhow::Error::from));
+        if let Err(e) = result {
+            self.error = Some(e.to_string());
+        }
+    }
+}
+
+/// Decodes a keyframe every `interval` for `live_thumbnails` and replaces the preview image
+struct LiveThumbnailer {
+    path: String,
+    width: u32,
+    quality: u32,
+    interval: Duration,
+    /// JPEG encoder and scaler, rebuilt when the source size or format changes
+    encoder: Option<((u32, u32, ffmpeg::format::Pixel), ffmpeg::encoder::Video, ffmpeg::software::scaling::Context)>,
+    next_due: Instant,
+    written: u64,
+}
+
+impl LiveThumbnailer {
+    /// Capture from one connection; returns true when `deadline` stopped it
+    fn run(&mut self, mut ictx: ffmpeg::format::context::Input, deadline: Option<Instant>) -> Result<bool> {
+        let stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .context("Live input has no video")?;
+        let video_index = stream.index();
+        let mut decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        let mut decoding = false;
+        let mut decoded = ffmpeg::util::frame::video::Video::empty();
+        
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(true);
+            }
+            
+            let mut packet = ffmpeg::Packet::empty();
+            match packet.read(&mut ictx) {
+                Ok(()) => {}
+                Err(ffmpeg::Error::Eof) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            if packet.stream() != video_index {
+                continue;
+            }
+            
+            // Start decoding at the first keyframe once a preview is due
+            if !decoding {
+                if Instant::now() < self.next_due || !packet.is_key() {
+                    continue;
+                }
+                decoding = true;
+            }
+            
+            if let Err(e) = decoder.send_packet(&packet) {
+                warn!(error = %e, "Dropping undecodable video packet");
+                continue;
+            }
+            if decoder.receive_frame(&mut decoded).is_ok() {
+                self.write(&decoded)?;
+                // Discard the rest of the GOP until the next preview is due
+                decoder.flush();
+                decoding = false;
+                self.next_due = Instant::now() + self.interval;
+            }
+        }
+    }
+    
+    fn write(&mut self, decoded: &ffmpeg::util::frame::video::Video) -> Result<()> {
+        let format = (decoded.width(), decoded.height(), decoded.format());
+        let (encoder, scaler) = match &mut self.encoder {
+            Some((current, encoder, scaler)) if *current == format => (encoder, scaler),
+            slot => {
+                let width = self.width.min(decoded.width()).max(2) & !1;
+                let height = ((decoded.height() as u64 * width as u64 / decoded.width().max(1) as u64) as u32).max(2) & !1;
+                let (encoder, _) = open_image_encoder("jpeg", self.quality, width, height)?;
+                let scaler = ffmpeg::software::scaling::context::Context::get(
+                    decoded.format(),
+                    decoded.width(),
+                    decoded.height(),
+                    encoder.format(),
+                    width,
+                    height,
+                    ffmpeg::software::scaling::flag::Flags::BICUBIC,
+                )?;
+                let (_, encoder, scaler) = slot.insert((format, encoder, scaler));
+                (encoder, scaler)
+            }
+        };
+        
+        let mut scaled = ffmpeg::util::frame::video::Video::empty();
+        scaler.run(decoded, &mut scaled)?;
+        scaled.set_pts(Some(self.written as i64));
+        encoder.send_frame(&scaled)?;
+        
+        let mut packet = ffmpeg::Packet::empty();
+        encoder
+            .receive_packet(&mut packet)
+            .context("JPEG encoder produced no output")?;
+        
+        // Same directory as the target, so the rename is atomic
+        let temporary = format!("{}.tmp", self.path);
+        std::fs::write(&temporary, packet.data().unwrap_or_default())?;
+        std::fs::rename(&temporary, &self.path)?;
+        
+        self.written += 1;
+        if self.written == 1 {
+            info!(path = %self.path, "First live thumbnail written");
+        }
+        Ok(())
+    }
+}
+
+/// The type and body of each box in `data`, stopping at the first malformed one
+fn mp4_boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut boxes = Vec::new();
+    let mut pos = 0usize;
+    while pos + 8 <= data.len() {
+        let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+        let (size, header_len) = match u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) {
+            0 => (data.len() - pos, 8),
+            1 => match data.get(pos + 8..pos + 16) {
+                Some(size) => (u64::from_be_bytes([size[0], size[1], size[2], size[3], size[4], size[5], size[6], size[7]]) as usize, 16),
+                None => break,
+            },
+            size => (size as usize, 8),
+        };
+        if size < header_len || size > data.len() - pos {
+            break;
+        }
+        boxes.push((kind, &data[pos + header_len..pos + size]));
+        pos += size;
+    }
+    boxes
+}
+
+/// Body of the first `kind` box in `data`
+fn mp4_child_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    mp4_boxes(data).into_iter().find(|(box_kind, _)| box_kind == kind).map(|(_, body)| body)
+}
+
+/// Whether an fMP4 fragment starts with a sync sample of `track_id`
+///
+/// The first sample's flags come from the `trun`, else the `tfhd` defaults; a fragment
+/// without that track counts as independent.
+fn fmp4_fragment_starts_with_keyframe(data: &[u8], track_id: u32) -> bool {
+    const SAMPLE_IS_NON_SYNC: u32 = 0x0001_0000;
+    let read_u32 = |data: &[u8], offset: usize| {
+        data.get(offset..offset + 4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    
+    let Some(moof) = mp4_child_box(data, b"moof") else {
+        return false;
+    };
+    
+    for (kind, traf) in mp4_boxes(moof) {
+        if &kind != b"traf" {
+            continue;
+        }
+        let Some(tfhd) = mp4_child_box(traf, b"tfhd") else {
+            continue;
+        };
+        if read_u32(tfhd, 4) != Some(track_id) {
+            continue;
+        }
+        
+        let tfhd_flags = read_u32(tfhd, 0).unwrap_or(0) & 0x00ff_ffff;
+        let mut offset = 8;
+        for (flag, len) in [(0x01, 8), (0x02, 4), (0x08, 4), (0x10, 4)] {
+            if tfhd_flags & flag != 0 {
+                offset += len;
+            }
+        }
+        let default_flags = if tfhd_flags & 0x20 != 0 { read_u32(tfhd, offset) } else { None };
+        
+        let first_flags = mp4_child_box(traf, b"trun").and_then(|trun| {
+            let trun_flags = read_u32(trun, 0)? & 0x00ff_ffff;
+            let mut offset = if trun_flags & 0x01 != 0 { 12 } else { 8 };
+            if trun_flags & 0x04 != 0 {
+                return read_u32(trun, offset);
+            }
+            if trun_flags & 0x400 == 0 {
+                return None;
+            }
+            for flag in [0x100, 0x200] {
+                if trun_flags & flag != 0 {
+                    offset += 4;
+                }
+            }
+            read_u32(trun, offset)
+        });
+        
+        return first_flags.or(default_flags).is_some_and(|flags| flags & SAMPLE_IS_NON_SYNC == 0);
+    }
+    true
+}
+
+/// One LL-HLS partial segment
+struct LowLatencyPart {
+    uri: String,
+    duration: f64,
+    independent: bool,
+}
+
+/// A full segment, made by concatenating its parts once the next segment starts
+struct LowLatencySegment {
+    uri: String,
+    duration: f64,
+    parts: Vec<LowLatencyPart>,
+}
+
+/// Builds an LL-HLS media playlist from the short fMP4 parts FFmpeg's HLS muxer writes
+///
+/// The muxer runs with `hls_time` set to the part duration and cuts between keyframes, into
+/// a private playlist. Each new part is checked for a leading keyframe; a segment closes at
+/// the first independent part once it reaches `segment_seconds`, and its parts are
+/// concatenated into the segment file. The public playlist lists full segments, with
+/// `EXT-X-PART` entries for the most recent ones, and a preload hint for the next part.
+struct LowLatencyPlaylist {
+    dir: std::path::PathBuf,
+    playlist_path: String,
+    parts_playlist: String,
+    stem: String,
+    init_uri: String,
+    part_target: f64,
+    segment_seconds: f64,
+    list_size: usize,
+    keep_segments: bool,
+    blocking_reload: bool,
+    video_track: Option<u32>,
+    segments: std::collections::VecDeque<LowLatencySegment>,
+    current: Vec<LowLatencyPart>,
+    media_sequence: u64,
+    next_segment: u64,
+    last_part_sequence: Option<u64>,
+    target_duration: f64,
+    last_poll: Instant,
+}
+
+impl LowLatencyPlaylist {
+    /// Pick up parts the muxer has finished since the last call, at most every 50 ms
+    fn poll(&mut self) -> Result<()> {
+        if self.last_poll.elapsed() < Duration::from_millis(50) {
+            return Ok(());
+        }
+        self.last_poll = Instant::now();
+        
+        if self.read_new_parts()? {
+            self.write_playlist(false)?;
+        }
+        Ok(())
+    }
+    
+    /// Take the last parts, close the final segment and end the playlist
+    ///
+    /// The ended playlist lists whole segments only, so the remaining parts are removed.
+    fn finish(&mut self) -> Result<()> {
+        self.read_new_parts()?;
+        if !self.current.is_empty() {
+            self.close_segment()?;
+        }
+        self.write_playlist(true)?;
+        
+        let parts = std::fs::read_to_string(&self.parts_playlist).unwrap_or_default();
+        for uri in parts.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let _ = std::fs::remove_file(self.dir.join(uri));
+        }
+        let _ = std::fs::remove_file(&self.parts_playlist);
+        Ok(())
+    }
+    
+    /// Returns true when a part was added
+    fn read_new_parts(&mut self) -> Result<bool> {
+        let playlist = match std::fs::read_to_string(&self.parts_playlist) {
+            Ok(playlist) => playlist,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        
+        let mut sequence = 0u64;
+        let mut duration = None;
+        let mut added = false;
+        
+        for line in playlist.lines().map(str::trim) {
+            if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+                sequence = value.parse().unwrap_or(0);
+            } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+                duration = value.split(',').next().and_then(|d| d.parse::<f64>().ok());
+            } else if !line.is_empty() && !line.starts_with('#') {
+                let part_sequence = sequence;
+                sequence += 1;
+                let Some(duration) = duration.take() else {
+                    continue;
+                };
+                if self.last_part_sequence.is_some_and(|last| part_sequence <= last) {
+                    continue;
+                }
+                self.last_part_sequence = Some(part_sequence);
+                
+                let independent = match self.video_track {
+                    Some(track) => std::fs::read(self.dir.join(line))
+                        .map(|data| fmp4_fragment_starts_with_keyframe(&data, track))
+                        .unwrap_or(false),
+                    None => true,
+                };
+                
+                let current_duration: f64 = self.current.iter().map(|part| part.duration).sum();
+                if independent && !self.current.is_empty() && current_duration + 1e-3 >= self.segment_seconds {
+                    self.close_segment()?;
+                }
+                self.current.push(LowLatencyPart {
+                    uri: line.to_string(),
+                    duration,
+                    independent,
+                });
+                added = true;
+            }
+        }
+        Ok(added)
+    }
+    
+    fn close_segment(&mut self) -> Result<()> {
+        let uri = format!("{}_{:05}.m4s", self.stem, self.next_segment);
+        self.next_segment += 1;
+        
+        let mut segment = std::fs::File::create(self.dir.join(&uri))?;
+        for part in &self.current {
+            let mut data = std::fs::File::open(self.dir.join(&part.uri))
+                .context(format!("Part {} was removed before its segment closed", part.uri))?;
+            std::io::copy(&mut data, &mut segment)?;
+        }
+        
+        let parts = std::mem::take(&mut self.current);
+        let duration = parts.iter().map(|part| part.duration).sum::<f64>();
+        self.target_duration = self.target_duration.max(duration.round());
+        self.segments.push_back(LowLatencySegment { uri, duration, parts });
+        
+        while self.list_size > 0 && self.segments.len() > self.list_size {
+            if let Some(old) = self.segments.pop_front() {
+                self.media_sequence += 1;
+                if !self.keep_segments {
+                    let _ = std::fs::remove_file(self.dir.join(&old.uri));
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    fn write_playlist(&self, ended: bool) -> Result<()> {
+        use std::fmt::Write as _;
+        
+        let mut playlist = String::new();
+        writeln!(playlist, "#EXTM3U")?;
+        writeln!(playlist, "#EXT-X-VERSION:9")?;
+        writeln!(playlist, "#EXT-X-TARGETDURATION:{}", self.target_duration.ceil() as u64)?;
+        let mut server_control = format!("PART-HOLD-BACK={:.3}", self.part_target * 3.0);
+        if self.blocking_reload {
+            server_control.insert_str(0, "CAN-BLOCK-RELOAD=YES,");
+        }
+        writeln!(playlist, "#EXT-X-SERVER-CONTROL:{}", server_control)?;
+        writeln!(playlist, "#EXT-X-PART-INF:PART-TARGET={:.3}", self.part_target)?;
+        writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", self.media_sequence)?;
+        writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS")?;
+        writeln!(playlist, "#EXT-X-MAP:URI=\"{}\"", self.init_uri)?;
+        
+        // Parts are only listed for roughly the last three target durations
+        let part_segments = (3.0 * self.target_duration / self.segment_seconds).ceil() as usize;
+        let with_parts = self.segments.len().saturating_sub(part_segments);
+        
+        let write_parts = |playlist: &mut String, parts: &[LowLatencyPart]| -> std::fmt::Result {
+            for part in parts {
+                write!(playlist, "#EXT-X-PART:DURATION={:.5},URI=\"{}\"", part.duration, part.uri)?;
+                if part.independent {
+                    write!(playlist, ",INDEPENDENT=YES")?;
+                }
+                writeln!(playlist)?;
+            }
+            Ok(())
+        };
+        
+        for (index, segment) in self.segments.iter().enumerate() {
+            if index >= with_parts && !ended {
+                write_parts(&mut playlist, &segment.parts)?;
+            }
+            writeln!(playlist, "#EXTINF:{:.5},", segment.duration)?;
+            writeln!(playlist, "{}", segment.uri)?;
+        }
+        
+        if ended {
+            writeln!(playlist, "#EXT-X-ENDLIST")?;
+        } else {
+            write_parts(&mut playlist, &self.current)?;
+            if let Some(next) = self.last_part_sequence.map(|last| self.part_uri(last + 1)) {
+                writeln!(playlist, "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\"", next)?;
+            }
+        }
+        
+        let temporary = format!("{}.tmp", self.playlist_path);
+        std::fs::write(&temporary, playlist)?;
+        std::fs::rename(&temporary, &self.playlist_path)?;
+        Ok(())
+    }
+    
+    /// Name the muxer gives part `sequence` (its `hls_segment_filename` pattern)
+    fn part_uri(&self, sequence: u64) -> String {
+        format!("{}_part{:06}.m4s", self.stem, sequence)
+    }
+}
+
+/// AES-128 keys for `stream_to_hls`, published through the key info file the HLS muxer reads
+struct HlsKeyRotation {
+    info_path: String,
+    key_dir: std::path::PathBuf,
+    stem: String,
+    url_prefix: String,
+    fixed_key: Option<[u8; 16]>,
+    rotate_seconds: Option<f64>,
+    next_rotation: f64,
+    written: u64,
+}
+
+impl HlsKeyRotation {
+    /// Switch to a new key once stream time reaches the next rotation point
+    fn rotate_at(&mut self, seconds: f64) -> Result<()> {
+        match self.rotate_seconds {
+            Some(interval) if seconds >= self.next_rotation => {
+                if seconds > 0.0 {
+                    self.write_key()?;
+                }
+                self.next_rotation = (seconds / interval).floor() * interval + interval;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+    
+    /// Write the next key file and point the key info file (URI, key path, IV) at it
+    fn write_key(&mut self) -> Result<()> {
+        let mut key = [0u8; 16];
+        let mut iv = [0u8; 16];
+        match self.fixed_key {
+            Some(fixed) => key = fixed,
+            None => rand::rngs::OsRng.fill_bytes(&mut key),
+        }
+        rand::rngs::OsRng.fill_bytes(&mut iv);
+        
+        let name = format!("{}_{:05}.key", self.stem, self.written);
+        let key_path = self.key_dir.join(&name);
+        std::fs::write(&key_path, key)?;
+        
+        // Replaced atomically: the muxer may reread it at any segment boundary
+        let info = format!("{}{}\n{}\n{}\n", self.url_prefix, name, key_path.display(), hex::encode(iv));
+        let temporary = format!("{}.tmp", self.info_path);
+        std::fs::write(&temporary, info)?;
+        std::fs::rename(&temporary, &self.info_path)?;
+        
+        self.written += 1;
+        info!(key = %name, "HLS encryption key written");
+        Ok(())
+    }
+}
+
+/// A hex param of exactly `len` bytes, lowercased
+fn drm_hex_param(params: &serde_json::Value, key: &str, len: usize) -> Result<String> {
+    let value = params.get(key)
+        .and_then(|v| v.as_str())
+        .context(format!("{} is required", key))?;
+    let bytes = hex::decode(value).context(format!("{} must be hex", key))?;
+    if bytes.len() != len {
+        anyhow::bail!("{} must be {} bytes ({} hex digits)", key, len, len * 2);
+    }
+    Ok(hex::encode(bytes))
+}
+
+/// Files under `dir` with their paths relative to `root` and sizes
+fn collect_packaged_files(root: &Path, dir: &Path, files: &mut Vec<serde_json::Value>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_packaged_files(root, &path, files)?;
+        } else {
+            files.push(serde_json::json!({
+                "path": path.strip_prefix(root).unwrap_or(&path).to_string_lossy(),
+                "size_bytes": entry.metadata()?.len(),
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Cells across and down the frame that the forensic watermark pattern is defined on
+const FORENSIC_GRID: (usize, usize) = (16, 9);
+
+/// Always-set bit slots after the payload and CRC, used to find where the bit cycle starts
+const FORENSIC_SYNC_BITS: usize = 16;
+
+/// Key-derived pieces of the forensic watermark: a ±1 pattern over the grid cells and a ±1
+/// scrambling chip per bit slot
+fn forensic_pattern(key: &str) -> (Vec<f32>, Vec<f32>) {
+    let cells = FORENSIC_GRID.0 * FORENSIC_GRID.1;
+    let sequence = crate::audio::watermark_sequence(key);
+    let slots = crate::audio::WATERMARK_PAYLOAD_BYTES * 8 + 8 + FORENSIC_SYNC_BITS;
+    (sequence[..cells].to_vec(), sequence[cells..cells + slots].to_vec())
+}
+
+/// Embeds the forensic watermark into the luma plane, one payload bit per `chip_seconds`
+struct ForensicMark {
+    bits: Vec<f32>,
+    pattern: Vec<f32>,
+    chips: Vec<f32>,
+    strength: f32,
+    chip_seconds: f64,
+    /// The cell pattern interpolated to pixels, for the last frame size seen
+    mask: Option<((usize, usize), Vec<f32>)>,
+}
+
+impl ForensicMark {
+    fn new(key: &str, payload: &str, strength: f32, chip_seconds: f64) -> Result<Self> {
+        let (pattern, chips) = forensic_pattern(key);
+        let mut bits = crate::audio::watermark_bits(payload)?;
+        bits.extend([1.0; FORENSIC_SYNC_BITS]);
+        Ok(Self {
+            bits,
+            pattern,
+            chips,
+            strength,
+            chip_seconds,
+            mask: None,
+        })
+    }
+    
+    /// Add the mark for time `seconds` to an 8-bit luma plane
+    fn apply(&mut self, luma: &mut [u8], stride: usize, width: usize, height: usize, seconds: f64) {
+        let position = (seconds / self.chip_seconds).max(0.0);
+        let slot = position.floor() as usize % self.bits.len();
+        // Each chip is split into opposite halves so the mark averages out over time
+        let half = if position.fract() < 0.5 { 1.0 } else { -1.0 };
+        let amplitude = self.strength * self.bits[slot] * self.chips[slot] * half;
+        
+        let mask = match &mut self.mask {
+            Some((size, mask)) if *size == (width, height) => mask,
+            cached => &mut cached.insert(((width, height), forensic_mask(&self.pattern, width, height))).1,
+        };
+        
+        for y in 0..height {
+            let row = &mut luma[y * stride..y * stride + width];
+            for (pixel, weight) in row.iter_mut().zip(&mask[y * width..(y + 1) * width]) {
+                *pixel = (*pixel as f32 + amplitude * weight).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// The cell pattern bilinearly interpolated between cell centres, so it has no visible edges
+fn forensic_mask(pattern: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let (columns, rows) = FORENSIC_GRID;
+    let sample = |x: f32, limit: usize| {
+        let position = (x - 0.5).clamp(0.0, (limit - 1) as f32);
+        let low = position.floor() as usize;
+        (low, (low + 1).min(limit - 1), position - low as f32)
+    };
+    
+    let mut mask = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let (row0, row1, fy) = sample((y as f32 + 0.5) * rows as f32 / height as f32, rows);
+        for x in 0..width {
+            let (col0, col1, fx) = sample((x as f32 + 0.5) * columns as f32 / width as f32, columns);
+            let top = pattern[row0 * columns + col0] * (1.0 - fx) + pattern[row0 * columns + col1] * fx;
+            let bottom = pattern[row1 * columns + col0] * (1.0 - fx) + pattern[row1 * columns + col1] * fx;
+            mask.push(top * (1.0 - fy) + bottom * fy);
+        }
+    }
+    mask
+}
+
+/// Mean luma of each grid cell of an 8-bit plane
+fn forensic_cells(luma: &[u8], stride: usize, width: usize, height: usize) -> Vec<f32> {
+    let (columns, rows) = FORENSIC_GRID;
+    let mut sums = vec![0f64; columns * rows];
+    let mut counts = vec![0u32; columns * rows];
+    for y in 0..height {
+        let row = (y * rows / height) * columns;
+        for (x, pixel) in luma[y * stride..y * stride + width].iter().enumerate() {
+            let cell = row + x * columns / width;
+            sums[cell] += *pixel as f64;
+            counts[cell] += 1;
+        }
+    }
+    sums.iter().zip(&counts).map(|(sum, count)| (*sum / (*count).max(1) as f64) as f32).collect()
+}
+
+/// Result of correlating per-frame cell means against the forensic watermark
+struct ForensicDetection {
+    /// Correlation sum per bit slot (positive = 1), payload and CRC first
+    sums: Vec<f64>,
+    /// Mean correlation of the sync slots, relative to the noise across all slots
+    sync_strength: f64,
+    offset_seconds: f64,
+    rotation: usize,
+}
+
+/// Correlate `(time, cell means)` per frame with the watermark of `key`
+///
+/// Each chip's second half carries the inverse mark, so differencing the halves cancels static
+/// picture content and slow fades. With `search`, sub-chip offsets and bit slot rotations are
+/// scanned for the strongest sync bits, so trimmed copies are still found.
+fn detect_forensic(frames: &[(f64, Vec<f32>)], key: &str, chip_seconds: f64, search: bool) -> Option<ForensicDetection> {
+    let (pattern, chips) = forensic_pattern(key);
+    let slots = chips.len();
+    let phases = if search { 4 } else { 1 };
+    let rotations = if search { slots } else { 1 };
+    let mut best: Option<(f64, ForensicDetection)> = None;
+    
+    for phase in 0..phases {
+        let offset = phase as f64 * chip_seconds / phases as f64;
+        
+        // Mean cell vector per half chip
+        let mut half_sums: std::collections::BTreeMap<(i64, bool), (Vec<f64>, u32)> = std::collections::BTreeMap::new();
+        for (seconds, cells) in frames {
+            let position = (seconds + offset) / chip_seconds;
+            let entry = half_sums
+                .entry((position.floor() as i64, position.fract() < 0.5))
+                .or_insert_with(|| (vec![0.0; cells.len()], 0));
+            for (sum, cell) in entry.0.iter_mut().zip(cells) {
+                *sum += *cell as f64;
+            }
+            entry.1 += 1;
+        }
+        let mean = |key: &(i64, bool)| {
+            half_sums.get(key).map(|(sums, count)| sums.iter().map(|sum| sum / *count as f64).collect::<Vec<_>>())
+        };
+        
+        // Normalised correlation of each chip's first-minus-second half with the pattern
+        let mut correlations = Vec::new();
+        for &(index, first) in half_sums.keys() {
+            if !first {
+                continue;
+            }
+            let (Some(first), Some(second)) = (mean(&(index, true)), mean(&(index, false))) else {
+                continue;
+            };
+            let detail: Vec<f64> = first.iter().zip(&second).map(|(first, second)| first - second).collect();
+            let average = detail.iter().sum::<f64>() / detail.len() as f64;
+            let energy = detail.iter().map(|d| (d - average).powi(2)).sum::<f64>().sqrt();
+            if energy <= f64::EPSILON {
+                continue;
+            }
+            let correlation: f64 = detail.iter().zip(&pattern).map(|(d, p)| (d - average) * *p as f64).sum();
+            correlations.push((index, correlation / energy));
+        }
+        
+        for rotation in 0..rotations {
+            let mut sums = vec![0f64; slots];
+            for (index, correlation) in &correlations {
+                let slot = (index + rotation as i64).rem_euclid(slots as i64) as usize;
+                sums[slot] += correlation * chips[slot] as f64;
+            }
+            let score: f64 = sums[slots - FORENSIC_SYNC_BITS..].iter().sum();
+            if best.as_ref().map_or(true, |(best_score, _)| score > *best_score) {
+                best = Some((score, ForensicDetection { sums, sync_strength: 0.0, offset_seconds: offset, rotation }));
+            }
+        }
+    }
+    
+    best.map(|(score, mut detection)| {
+        let spread = detection.sums.iter().map(|sum| sum.abs()).sum::<f64>() / slots as f64;
+        detection.sync_strength = if spread > 0.0 { score / FORENSIC_SYNC_BITS as f64 / spread } else { 0.0 };
+        detection
+    })
+}
+
+/// Decode the video stream through the analysis filters `filter_spec` and hand each frame
+/// that comes out, with the metadata the filters attached, to `on_frame`, returning the
+/// duration in seconds
+///
+/// Frames are retimed to microseconds from the first frame, so timestamps the filters record
+/// are seconds from the start of the video. They come out in `sink_format` (default: the
+/// decoder's).
+fn analyze_video(
+    path: &str,
+    video_stream: Option<usize>,
+    filter_spec: &str,
+    sink_format: Option<ffmpeg::format::Pixel>,
+    mut on_frame: impl FnMut(&ffmpeg::util::frame::video::Video) -> Result<()>,
+) -> Result<f64> {
+    let mut ictx = ffmpeg::format::input(&path).context(format!("Failed to open {}", path))?;
+    let video_stream_index = select_video_stream(&ictx, video_stream)?;
+    let input_stream = ictx.stream(video_stream_index).context("No video stream found")?;
+    let time_base = input_stream.time_base();
+    let frame_duration = input_stream.avg_frame_rate()
+        .filter(|rate| rate.numerator() > 0 && rate.denominator() > 0)
+        .map_or(0.0, |rate| 1.0 / f64::from(rate));
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?
+        .decoder()
+        .video()?;
+    
+    let filter_spec = format!("settb=AVTB,setpts=PTS-STARTPTS,{}", filter_spec);
+    let sink_format = sink_format.unwrap_or(decoder.format());
+    let mut graph = build_video_filter_graph(&decoder, time_base, &filter_spec, sink_format)?;
+    
+    let mut end = 0.0f64;
+    let mut receive = |graph: &mut ffmpeg::filter::Graph| -> Result<()> {
+        let mut filtered = ffmpeg::util::frame::video::Video::empty();
+        while graph.get("out").context("Filter sink missing")?.sink().frame(&mut filtered).is_ok() {
+            let seconds = filtered.timestamp().map_or(0.0, |pts| pts as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE));
+            end = end.max(seconds + frame_duration);
+            on_frame(&filtered)?;
+        }
+        Ok(())
+    };
+    
+    for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
+        if stream.index() == video_stream_index {
+            decoder.send_packet(&packet)?;
+            send_decoded_video(&mut decoder, &mut graph, "in")?;
+            receive(&mut graph)?;
+        }
+    }
+    decoder.send_eof()?;
+    send_decoded_video(&mut decoder, &mut graph, "in")?;
+    graph.get("in").context("Filter source missing")?.source().flush()?;
+    receive(&mut graph)?;
+    
+    Ok(end)
+}
+
+/// Seconds per step of the envelopes `measure_av_sync` correlates
+const SYNC_HOP: f64 = 0.01;
+
+/// Pearson correlation of two equally long series, 0 when either is flat
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let count = a.len().min(b.len()) as f64;
+    if count == 0.0 {
+        return 0.0;
+    }
+    let mean_a = a.iter().sum::<f64>() / count;
+    let mean_b = b.iter().sum::<f64>() / count;
+    let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a).powi(2);
+        variance_b += (y - mean_b).powi(2);
+    }
+    if variance_a <= 0.0 || variance_b <= 0.0 {
+        return 0.0;
+    }
+    covariance / (variance_a * variance_b).sqrt()
+}
+
+/// Rules of a `run_qc_report` profile; a rule left out is not checked
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QcProfile {
+    require_video: Option<bool>,
+    require_audio: Option<bool>,
+    video_codecs: Option<Vec<String>>,
+    audio_codecs: Option<Vec<String>>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    frame_rates: Option<Vec<f64>>,
+    pixel_formats: Option<Vec<String>>,
+    allow_interlaced: Option<bool>,
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+    /// Longest run of black picture allowed, in seconds
+    max_black: Option<f64>,
+    /// Longest run of frozen picture allowed, in seconds
+    max_freeze: Option<f64>,
+    /// Longest silence allowed, in seconds
+    max_silence: Option<f64>,
+    silence_threshold: Option<f64>,
+    /// Integrated loudness target, in LUFS
+    loudness: Option<f64>,
+    loudness_tolerance: Option<f64>,
+    /// Highest sample peak allowed, in dBFS
+    max_peak: Option<f64>,
+    sample_rates: Option<Vec<u32>>,
+    channels: Option<Vec<u16>>,
+}
+
+/// Built-in `run_qc_report` profiles
+fn builtin_qc_profile(name: &str) -> Result<serde_json::Value> {
+    Ok(match name {
+        // Streaming delivery: web codecs, progressive, loudness for phones and laptops
+        "web" => serde_json::json!({
+            "require_video": true,
+            "video_codecs": ["h264", "hevc", "vp9", "av1"],
+            "audio_codecs": ["aac", "opus"],
+            "allow_interlaced": false,
+            "max_black": 5.0,
+            "max_freeze": 5.0,
+            "max_silence": 10.0,
+            "loudness": -16.0,
+            "loudness_tolerance": 2.0,
+            "max_peak": -1.0,
+        }),
+        // EBU R128 programme delivery in HD
+        "broadcast" => serde_json::json!({
+            "require_video": true,
+            "require_audio": true,
+            "min_width": 1920,
+            "min_height": 1080,
+            "frame_rates": [25.0, 50.0, 29.97, 59.94],
+            "sample_rates": [48000],
+            "max_black": 2.0,
+            "max_freeze": 2.0,
+            "max_silence": 2.0,
+            "loudness": -23.0,
+            "loudness_tolerance": 1.0,
+            "max_peak": -1.0,
+        }),
+        name => anyhow::bail!("Unknown QC profile: {} (expected web or broadcast)", name),
+    })
+}
+
+/// The name and rules of the `profile` param of `run_qc_report` (default: web)
+fn qc_profile(param: Option<&serde_json::Value>) -> Result<(String, QcProfile)> {
+    let (name, rules) = match param {
+        None => ("web".to_string(), builtin_qc_profile("web")?),
+        Some(serde_json::Value::String(name)) => (name.clone(), builtin_qc_profile(name)?),
+        Some(serde_json::Value::Object(overrides)) => {
+            let mut overrides = overrides.clone();
+            match overrides.remove("base") {
+                Some(base) => {
+                    let base = base.as_str().context("profile base must be a profile name")?.to_string();
+                    let mut rules = builtin_qc_profile(&base)?;
+                    if let Some(rules) = rules.as_object_mut() {
+                        rules.extend(overrides);
+                    }
+                    (format!("{} (customised)", base), rules)
+                }
+                None => ("custom".to_string(), serde_json::Value::Object(overrides)),
+            }
+        }
+        Some(_) => anyhow::bail!("profile must be a profile name or an object of rules"),
+    };
+    let profile = serde_json::from_value(rules).context("Invalid QC profile")?;
+    Ok((name, profile))
+}
+
+/// Intervals an analysis filter marks with `<prefix>_start` and `<prefix>_end` metadata on
+/// the frames where they begin and end
+#[derive(Debug)]
+struct DetectedIntervals {
+    /// The filter to run
+    filter: String,
+    prefix: &'static str,
+    min_duration: f64,
+    start: Option<f64>,
+    intervals: Vec<(f64, f64)>,
+}
+
+impl DetectedIntervals {
+    /// Frozen picture (`freezedetect`): frames within `noise_db` of each other for at least
+    /// `min_duration` seconds
+    fn freezes(noise_db: f64, min_duration: f64) -> Self {
+        Self::new(format!("freezedetect=n={}dB:d={}", noise_db, min_duration), "lavfi.freezedetect.freeze", min_duration)
+    }
+    
+    /// Black picture (`blackdetect`) for at least `min_duration` seconds, counting pixels
+    /// below `pixel_threshold` of the luma range as black
+    fn black(pixel_threshold: f64, min_duration: f64) -> Self {
+        Self::new(format!("blackdetect=d=0:pix_th={}", pixel_threshold), "lavfi.black", min_duration)
+    }
+    
+    fn new(filter: String, prefix: &'static str, min_duration: f64) -> Self {
+        Self { filter, prefix, min_duration, start: None, intervals: Vec::new() }
+    }
+    
+    fn frame(&mut self, frame: &ffmpeg::util::frame::video::Video) {
+        let metadata = frame.metadata();
+        let value = |suffix: &str| {
+            metadata.get(&format!("{}_{}", self.prefix, suffix)).and_then(|value| value.parse::<f64>().ok())
+        };
+        let (start, end) = (value("start"), value("end"));
+        if let (Some(start), Some(end)) = (self.start, end) {
+            self.intervals.push((start, end));
+            self.start = None;
+        }
+        if start.is_some() {
+            self.start = start;
+        }
+    }
+    
+    /// The intervals of at least `min_duration`, with one still running at the end closed
+    /// at `duration`
+    fn finish(mut self, duration: f64) -> Vec<(f64, f64)> {
+        if let Some(start) = self.start.take() {
+            self.intervals.push((start, duration.max(start)));
+        }
+        let min_duration = self.min_duration;
+        self.intervals.retain(|(start, end)| end - start >= min_duration);
+        self.intervals
+    }
+}
+
+/// Frame and repeated-field counts from the metadata `idet` attaches to frames
+#[derive(Debug, Default)]
+struct FieldCounts {
+    tff: u64,
+    bff: u64,
+    progressive: u64,
+    undetermined: u64,
+    repeated_neither: u64,
+    repeated_top: u64,
+    repeated_bottom: u64,
+}
+
+impl FieldCounts {
+    const FILTER: &'static str = "idet";
+    
+    /// Take the running totals `idet` keeps on each frame
+    fn frame(&mut self, frame: &ffmpeg::util::frame::video::Video) {
+        let metadata = frame.metadata();
+        let count = |key: &str, current: u64| {
+            metadata.get(&format!("lavfi.idet.{}", key)).and_then(|value| value.parse().ok()).unwrap_or(current)
+        };
+        self.tff = count("multiple.tff", self.tff);
+        self.bff = count("multiple.bff", self.bff);
+        self.progressive = count("multiple.progressive", self.progressive);
+        self.undetermined = count("multiple.undetermined", self.undetermined);
+        self.repeated_neither = count("repeated.neither", self.repeated_neither);
+        self.repeated_top = count("repeated.top", self.repeated_top);
+        self.repeated_bottom = count("repeated.bottom", self.repeated_bottom);
+    }
+    
+    /// Scan type (progressive/interlaced/telecined) and field order (tff/bff, or progressive)
+    fn classify(&self, threshold: f64) -> (&'static str, &'static str) {
+        let interlaced = self.tff + self.bff;
+        let decided = interlaced + self.progressive;
+        let repeated = self.repeated_top + self.repeated_bottom;
+        let fields = repeated + self.repeated_neither;
+        let field_order = if self.tff >= self.bff { "tff" } else { "bff" };
+        
+        if decided == 0 {
+            return ("progressive", "progressive");
+        }
+        // 3:2 pulldown repeats a field in two frames of every five
+        let repeated_ratio = if fields > 0 { repeated as f64 / fields as f64 } else { 0.0 };
+        if (0.3..=0.5).contains(&repeated_ratio) {
+            return ("telecined", field_order);
+        }
+        if interlaced as f64 / decided as f64 > threshold {
+            return ("interlaced", field_order);
+        }
+        ("progressive", "progressive")
+    }
+}
+
+/// Name of a stream's declared field order: progressive, tff, bff (fields stored and shown in
+/// the same order), tb or bt (stored in one order, shown in the other), or unknown
+fn field_order_name(field_order: ffmpeg::ffi::AVFieldOrder) -> &'static str {
+    use ffmpeg::ffi::AVFieldOrder::*;
+    
+    match field_order {
+        AV_FIELD_PROGRESSIVE => "progressive",
+        AV_FIELD_TT => "tff",
+        AV_FIELD_BB => "bff",
+        AV_FIELD_TB => "tb",
+        AV_FIELD_BT => "bt",
+        _ => "unknown",
+    }
+}
+
+/// Build a video filter graph fed by a `buffer` source "in" matching `decoder`, ending in a
+/// `buffersink` "out" that produces `sink_format`
+fn build_video_filter_graph(
+    decoder: &ffmpeg::decoder::Video,
+    time_base: ffmpeg::Rational,
+    filter_spec: &str,
+    sink_format: ffmpeg::format::Pixel,
+) -> Result<ffmpeg::filter::Graph> {
+    build_multi_video_filter_graph(&[(decoder, time_base)], filter_spec, sink_format)
+}
+
+/// Like `build_video_filter_graph` with one `buffer` source per decoder; multiple sources are
+/// named "in0", "in1", ... and must be referenced by label in `filter_spec`
+fn build_multi_video_filter_graph(
+    sources: &[(&ffmpeg::decoder::Video, ffmpeg::Rational)],
+    filter_spec: &str,
+    sink_format: ffmpeg::format::Pixel,
+) -> Result<ffmpeg::filter::Graph> {
+    let mut graph = ffmpeg::filter::Graph::new();
     
-    // For watermarking, we'll use a simple approach
-    // In production, you'd want more sophisticated overlay logic
+    let buffer = ffmpeg::filter::find("buffer").context("buffer filter not available")?;
+    let buffersink = ffmpeg::filter::find("buffersink").context("buffersink filter not available")?;
     
-    let mut ictx = ffmpeg::format::input(&job.input_path)?;
-    let input_stream = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .context("No video stream found")?;
+    let names = video_source_names(sources.len());
+    for ((decoder, time_base), name) in sources.iter().zip(&names) {
+        let aspect = decoder.aspect_ratio();
+        let args = format!(
+            "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
+            decoder.width(),
+            decoder.height(),
+            ffmpeg::ffi::AVPixelFormat::from(decoder.format()) as i32,
+            time_base.numerator(),
+            time_base.denominator(),
+            aspect.numerator().max(1),
+            if aspect.numerator() > 0 { aspect.denominator() } else { 1 },
+        );
+        graph.add(&buffer, name, &args)?;
+    }
+    graph.add(&buffersink, "out", "")?;
+    graph
+        .get("out")
+        .context("Filter sink missing")?
+        .set_pixel_format(sink_format);
     
-    let video_stream_index = input_stream.index();
-    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
-    let mut decoder = context_decoder.decoder().video()?;
+    let mut parser = ffmpeg::filter::graph::Parser::new(&mut graph);
+    for name in &names {
+        parser = parser.output(name, 0)?;
+    }
+    parser
+        .input("out", 0)?
+        .parse(filter_spec)
+        .context(format!("Invalid video filter: {}", filter_spec))?;
+    graph.validate()?;
     
-    // Load watermark image
-    let watermark_img = image::open(watermark_path)
-        .context("Failed to open watermark image")?;
+    Ok(graph)
+}
+
+fn video_source_names(count: usize) -> Vec<String> {
+    if count == 1 {
+        vec!["in".to_string()]
+    } else {
+        (0..count).map(|i| format!("in{}", i)).collect()
+    }
+}
+
+/// Push the decoder's ready frames within `[start, end)` seconds into the graph's "in" source,
+/// returning true once a frame at or past `end` shows up
+fn send_clip_frames(
+    decoder: &mut ffmpeg::decoder::Video,
+    graph: &mut ffmpeg::filter::Graph,
+    time_base: ffmpeg::Rational,
+    start: f64,
+    end: f64,
+) -> Result<bool> {
+    let mut decoded = ffmpeg::util::frame::video::Video::empty();
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        let seconds = decoded.timestamp().map_or(start, |pts| pts as f64 * f64::from(time_base));
+        if seconds < start {
+            continue;
+        }
+        if seconds >= end {
+            return Ok(true);
+        }
+        let timestamp = decoded.timestamp();
+        decoded.set_pts(timestamp);
+        graph.get("in").context("Filter source missing")?.source().add(&decoded)?;
+    }
+    Ok(false)
+}
+
+/// Drain an encoder's packets into stream 0 of `octx`
+fn write_encoded_packets(
+    encoder: &mut ffmpeg::encoder::Video,
+    octx: &mut ffmpeg::format::context::Output,
+    output_time_base: ffmpeg::Rational,
+) -> Result<()> {
+    let mut encoded = ffmpeg::Packet::empty();
+    while encoder.receive_packet(&mut encoded).is_ok() {
+        encoded.set_stream(0);
+        encoded.rescale_ts(encoder.time_base(), output_time_base);
+        encoded.write_interleaved(octx)?;
+    }
+    Ok(())
+}
+
+/// Encode every frame waiting at the graph's "out" sink
+fn encode_filtered_video(
+    graph: &mut ffmpeg::filter::Graph,
+    encoder: &mut ffmpeg::encoder::Video,
+    octx: &mut ffmpeg::format::context::Output,
+    output_time_base: ffmpeg::Rational,
+) -> Result<usize> {
+    let mut filtered = ffmpeg::util::frame::video::Video::empty();
+    let mut count = 0;
+    while graph.get("out").context("Filter sink missing")?.sink().frame(&mut filtered).is_ok() {
+        encoder.send_frame(&filtered)?;
+        write_encoded_packets(encoder, octx, output_time_base)?;
+        count += 1;
+    }
+    metrics::add_frames(count as u64);
+    Ok(count)
+}
+
+/// Run the job's video stream through the filter chain `filter_spec` builds for its display
+/// size and encode the result, returning the number of frames encoded
+///
+/// Pictures stored sideways or upside down are turned upright first, following the stream's
+/// display matrix (or `rotate` tag), and the output carries no rotation; with `auto_rotate`
+/// false they are left as stored and the output keeps the display matrix instead. The output
+/// takes its size from the end of the chain and runs at `frame_rate` (default: the source's
+/// rate). Video is encoded with `settings`; the input's other streams are carried over as
+/// `passthrough` says, and MP4 outputs take the MP4 output params.
+fn filter_video(
+    job: &JobPayload,
+    settings: &VideoOutputSettings,
+    frame_rate: Option<ffmpeg::Rational>,
+    passthrough: &StreamPassthrough,
+    filter_spec: impl FnOnce((f64, f64)) -> Result<String>,
+) -> Result<usize> {
+    let auto_rotate = job.params.get("auto_rotate")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
     
-    let mut octx = ffmpeg::format::output(&job.output_path)?;
-    let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::H264)?;
+    let mut ictx = ffmpeg::format::input(&job.input_path)
+        .context("Failed to open input file")?;
+    let video_stream_index = select_video_stream(&ictx, video_stream_param(&job.params))?;
+    let (time_base, source_rate, total_frames, rotation, mut decoder) = {
+        let stream = ictx.stream(video_stream_index).context("No video stream found")?;
+        let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        let rotation = if auto_rotate { display_rotation(&stream) } else { 0 };
+        (stream.time_base(), stream_frame_rate(&stream), expected_frame_count(&stream, ictx.duration()), rotation, decoder)
+    };
+    let frame_rate = frame_rate.unwrap_or(source_rate);
     
-    let mut ost = octx.add_stream(codec)?;
-    let mut encoder = ost.codec().encoder().video()?;
+    let sample_aspect = decoder.aspect_ratio();
+    let display_width = if sample_aspect.numerator() > 0 && sample_aspect.denominator() > 0 {
+        decoder.width() as f64 * f64::from(sample_aspect)
+    } else {
+        decoder.width() as f64
+    };
+    let mut display_size = (display_width, decoder.height() as f64);
+    let mut chain = Vec::new();
+    match rotation {
+        90 => chain.push("transpose=clock".to_string()),
+        180 => chain.push("hflip,vflip".to_string()),
+        270 => chain.push("transpose=cclock".to_string()),
+        _ => {}
+    }
+    if rotation % 180 == 90 {
+        display_size = (display_size.1, display_size.0);
+        info!(rotation, "Turning the picture upright");
+    }
+    chain.push(filter_spec(display_size)?);
+    chain.push(format!("fps={}/{}", frame_rate.numerator(), frame_rate.denominator()));
+    let filter_spec = chain.join(",");
     
-    encoder.set_width(decoder.width());
-    encoder.set_height(decoder.height());
-    encoder.set_format(decoder.format());
-    encoder.set_time_base(input_stream.time_base());
-    encoder.set_bit_rate(decoder.bit_rate());
+    // The encoder needs the chain's output size and the chain the encoder's pixel format,
+    // so the chain is built once to read the size and again for the encoder
+    let (width, height) = {
+        let graph = build_video_filter_graph(&decoder, time_base, &filter_spec, decoder.format())?;
+        let sink = graph.get("out").context("Filter sink missing")?;
+        unsafe {
+            (
+                ffmpeg::ffi::av_buffersink_get_w(sink.as_ptr()) as u32,
+                ffmpeg::ffi::av_buffersink_get_h(sink.as_ptr()) as u32,
+            )
+        }
+    };
     
-    if let Some(frame_rate) = input_stream.avg_frame_rate() {
-        encoder.set_frame_rate(Some(frame_rate));
+    let mut octx = ffmpeg::format::output(&job.output_path)
+        .context("Failed to create output file")?;
+    let (mut encoder, _) = open_video_encoder(&mut octx, &job.output_path, settings, width, height, frame_rate)?;
+    if !auto_rotate {
+        copy_display_matrix(&ictx.stream(video_stream_index).context("No video stream found")?, &mut octx, 0)?;
     }
+    let mut graph = build_video_filter_graph(&decoder, time_base, &filter_spec, encoder.format())?;
+    let mut passthrough_outputs = passthrough.open(&ictx, &mut octx, &job.output_path)?;
     
-    let encoder = encoder.open_as(codec)?;
-    ost.set_parameters(&encoder);
+    let muxer_options = mp4_muxer_options(&job.params, &octx.format())?;
+    octx.write_header_with(muxer_options)?;
+    let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
     
-    octx.write_header()?;
+    let mut progress = ProgressReporter::from_params(&job.task, &job.params)?;
+    if let Some(progress) = &mut progress {
+        let ratio = f64::from(frame_rate) / f64::from(source_rate);
+        progress.set_total_frames(total_frames.map(|frames| (frames as f64 * ratio).round() as u64));
+    }
     
+    let _span = tracing::info_span!("encode").entered();
     let mut frame_count = 0;
-    
     for (stream, packet) in ictx.packets() {
+        shutdown::check()?;
         if stream.index() == video_stream_index {
             decoder.send_packet(&packet)?;
-            
-            let mut decoded = ffmpeg::util::frame::video::Video::empty();
-            while decoder.receive_frame(&mut decoded).is_ok() {
-                // Note: Actual watermark overlay would require pixel manipulation
-                // This is a simplified version
-                
-                encoder.send_frame(&decoded)?;
-                
-                let mut encoded = ffmpeg::Packet::empty();
-                while encoder.receive_packet(&mut encoded).is_ok() {
-                    encoded.set_stream(0);
-                    encoded.rescale_ts(input_stream.time_base(), ost.time_base());
-                    encoded.write_interleaved(&mut octx)?;
-                }
-                
-                frame_count += 1;
+            send_decoded_video(&mut decoder, &mut graph, "in")?;
+            frame_count += encode_filtered_video(&mut graph, &mut encoder, &mut octx, output_time_base)?;
+            if let Some(progress) = &mut progress {
+                progress.update(frame_count as u64);
             }
+        } else if let Some(output) = passthrough_outputs.get_mut(&stream.index()) {
+            output.write(packet, &mut octx)?;
         }
     }
     
+    decoder.send_eof()?;
+    send_decoded_video(&mut decoder, &mut graph, "in")?;
+    graph.get("in").context("Filter source missing")?.source().flush()?;
+    frame_count += encode_filtered_video(&mut graph, &mut encoder, &mut octx, output_time_base)?;
     encoder.send_eof()?;
-    let mut encoded = ffmpeg::Packet::empty();
-    while encoder.receive_packet(&mut encoded).is_ok() {
-        encoded.set_stream(0);
-        encoded.write_interleaved(&mut octx)?;
+    write_encoded_packets(&mut encoder, &mut octx, output_time_base)?;
+    for output in passthrough_outputs.values_mut() {
+        output.finish(&mut octx)?;
     }
     
     octx.write_trailer()?;
+    finish_mp4_output(&job.params, &job.output_path)?;
     
-    info!("Applied watermark to {} frames", frame_count);
-    Ok(job.output_path.clone())
+    if let Some(progress) = &mut progress {
+        progress.finish(frame_count as u64);
+    }
+    
+    Ok(frame_count)
 }
 
-/// Extract a single key frame
-pub async fn extract_key_frame(job: &JobPayload, _config: &Config) -> Result<String> {
-    info!("Extracting key frame");
+/// Frame size, length limit and UI-covered margins of a social platform format
+struct SocialPreset {
+    width: u32,
+    height: u32,
+    max_duration: f64,
+    /// Fractions of the frame covered by platform UI: top, bottom, left, right
+    safe_area: (f64, f64, f64, f64),
+}
+
+fn social_preset(platform: &str, variant: Option<&str>) -> Result<SocialPreset> {
+    let preset = |width, height, max_duration, safe_area| SocialPreset { width, height, max_duration, safe_area };
     
-    let timestamp = job.params.get("timestamp")
-        .and_then(|v| v.as_str())
-        .unwrap_or("00:00:01");
+    Ok(match (platform, variant) {
+        ("tiktok", None | Some("default")) => preset(1080, 1920, 600.0, (0.08, 0.20, 0.05, 0.12)),
+        ("reels", None | Some("reel")) => preset(1080, 1920, 90.0, (0.13, 0.18, 0.05, 0.10)),
+        ("reels", Some("feed")) => preset(1080, 1350, 90.0, (0.05, 0.08, 0.03, 0.03)),
+        ("reels", Some("square")) => preset(1080, 1080, 90.0, (0.05, 0.08, 0.03, 0.03)),
+        ("shorts", None | Some("default")) => preset(1080, 1920, 180.0, (0.10, 0.20, 0.05, 0.14)),
+        ("x", None | Some("landscape")) => preset(1280, 720, 140.0, (0.05, 0.12, 0.04, 0.04)),
+        ("x", Some("square")) => preset(720, 720, 140.0, (0.05, 0.12, 0.04, 0.04)),
+        ("x", Some("portrait")) => preset(720, 1280, 140.0, (0.05, 0.10, 0.04, 0.04)),
+        (platform, Some(variant)) => anyhow::bail!("Unknown {} variant: {}", platform, variant),
+        (platform, None) => anyhow::bail!("Unknown social platform: {}", platform),
+    })
+}
+
+/// Compares luma detail under the platform UI margins with the rest of the frame, about
+/// once a second; lots of edges under an overlay usually means captions or graphics there
+struct SafeAreaCheck {
+    margins: (f64, f64, f64, f64),
+    every: u64,
+    seen: u64,
+    frames: u64,
+    /// Mean absolute luma gradient sums: top, bottom, left, right, safe area
+    detail: [f64; 5],
+}
+
+impl SafeAreaCheck {
+    fn new(margins: (f64, f64, f64, f64), every: u64) -> Self {
+        Self {
+            margins,
+            every: every.max(1),
+            seen: 0,
+            frames: 0,
+            detail: [0.0; 5],
+        }
+    }
     
-    // Parse timestamp to seconds
-    let seconds = parse_timestamp(timestamp)?;
+    fn add(&mut self, frame: &ffmpeg::util::frame::video::Video) {
+        self.seen += 1;
+        if (self.seen - 1) % self.every != 0 {
+            return;
+        }
+        
+        let (width, height) = (frame.plane_width(0) as usize, frame.plane_height(0) as usize);
+        let stride = frame.stride(0);
+        let luma = frame.data(0);
+        let (top, bottom, left, right) = self.margins;
+        let top = (top * height as f64) as usize;
+        let bottom = height - (bottom * height as f64) as usize;
+        let left = (left * width as f64) as usize;
+        let right = width - (right * width as f64) as usize;
+        
+        let mut sums = [0f64; 5];
+        let mut counts = [0u64; 5];
+        for y in (0..height.saturating_sub(1)).step_by(2) {
+            for x in (0..width.saturating_sub(1)).step_by(2) {
+                let pixel = luma[y * stride + x] as i32;
+                let gradient = (luma[y * stride + x + 1] as i32 - pixel).abs()
+                    + (luma[(y + 1) * stride + x] as i32 - pixel).abs();
+                let region = if y < top {
+                    0
+                } else if y >= bottom {
+                    1
+                } else if x < left {
+                    2
+                } else if x >= right {
+                    3
+                } else {
+                    4
+                };
+                sums[region] += gradient as f64;
+                counts[region] += 1;
+            }
+        }
+        
+        for (region, (sum, count)) in sums.iter().zip(&counts).enumerate() {
+            if *count > 0 {
+                self.detail[region] += sum / *count as f64;
+            }
+        }
+        self.frames += 1;
+    }
     
-    let mut ictx = ffmpeg::format::input(&job.input_path)?;
+    fn warnings(&self) -> Vec<String> {
+        if self.frames == 0 {
+            return Vec::new();
+        }
+        let frames = self.frames as f64;
+        let safe = self.detail[4] / frames;
+        
+        ["top", "bottom", "left", "right"]
+            .iter()
+            .zip(&self.detail)
+            .filter_map(|(side, detail)| {
+                let detail = detail / frames;
+                // Sharp text on a plain background gives far more edges than the picture around it
+                (detail > 6.0 && detail > safe * 1.5).then(|| {
+                    format!(
+                        "Busy detail under the {} UI overlay ({:.1} vs {:.1} in the safe area); captions or graphics may be hidden",
+                        side, detail, safe
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// One input of `concatenate_videos` with its decoders
+struct ConcatInput {
+    path: String,
+    ictx: ffmpeg::format::context::Input,
+    duration: f64,
+    video_index: usize,
+    video_time_base: ffmpeg::Rational,
+    video_decoder: ffmpeg::decoder::Video,
+    frame_rate: ffmpeg::Rational,
+    audio: Option<ConcatAudio>,
+    /// Span of the input that is used, in its own timestamps; all of it when `None`
+    trim: Option<(f64, f64)>,
+    /// Gain applied to the input's audio, in dB
+    gain_db: f64,
+    /// xfade transition from the previous input into this one, and its length in seconds
+    transition: Option<(String, f64)>,
+}
+
+struct ConcatAudio {
+    index: usize,
+    time_base: ffmpeg::Rational,
+    decoder: ffmpeg::decoder::Audio,
+    /// Filter graph source this input's audio is sent to
+    source: String,
+}
+
+impl ConcatInput {
+    fn open(path: &str) -> Result<Self> {
+        let ictx = ffmpeg::format::input(&path)
+            .context(format!("Failed to open {}", path))?;
+        
+        let duration = ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE);
+        if duration <= 0.0 {
+            anyhow::bail!("Duration of {} is unknown", path);
+        }
+        
+        let stream = ictx
+            .streams()
+            .best(ffmpeg::media::Type::Video)
+            .context(format!("No video stream in {}", path))?;
+        let video_decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        let (video_index, video_time_base, frame_rate) = (stream.index(), stream.time_base(), stream_frame_rate(&stream));
+        
+        let audio = match ictx.streams().best(ffmpeg::media::Type::Audio) {
+            Some(stream) => Some(ConcatAudio {
+                index: stream.index(),
+                time_base: stream.time_base(),
+                decoder: ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+                    .decoder()
+                    .audio()?,
+                source: String::new(),
+            }),
+            None => None,
+        };
+        
+        Ok(Self {
+            path: path.to_string(),
+            ictx,
+            duration,
+            video_index,
+            video_time_base,
+            video_decoder,
+            frame_rate,
+            audio,
+            trim: None,
+            gain_db: 0.0,
+            transition: None,
+        })
+    }
     
-    // Seek to timestamp
-    ictx.seek(seconds as i64 * 1000, ..)?;
+    /// Use only `start` to `end` (default: the end of the input)
+    fn trim(&mut self, start: f64, end: Option<f64>) -> Result<()> {
+        let end = end.unwrap_or(self.duration);
+        if start < 0.0 || end <= start {
+            anyhow::bail!("The clip must start at or after 0 and end after it starts");
+        }
+        if start >= self.duration {
+            anyhow::bail!("{} ends at {:.2}s, before the clip starts", self.path, self.duration);
+        }
+        self.duration = end.min(self.duration) - start;
+        self.trim = Some((start, end));
+        Ok(())
+    }
+}
+
+/// The `transition` (and `wipe_direction`, `transition_duration`) in a set of params, as an
+/// xfade transition name and length
+fn concat_transition(params: &serde_json::Value) -> Result<Option<(String, f64)>> {
+    let transition = match params.get("transition").and_then(|v| v.as_str()) {
+        None | Some("none") => return Ok(None),
+        Some("crossfade") => "fade".to_string(),
+        Some("fade_to_black") => "fadeblack".to_string(),
+        Some("wipe") => {
+            let direction = params.get("wipe_direction")
+                .and_then(|v| v.as_str())
+                .unwrap_or("left");
+            if !["left", "right", "up", "down"].contains(&direction) {
+                anyhow::bail!("Unknown wipe direction: {}", direction);
+            }
+            format!("wipe{}", direction)
+        }
+        Some(other) if other.chars().all(|c| c.is_ascii_alphanumeric()) => other.to_string(),
+        Some(other) => anyhow::bail!("Unknown transition: {}", other),
+    };
     
-    let input_stream = ictx
-        .streams()
-        .best(ffmpeg::media::Type::Video)
-        .context("No video stream found")?;
+    let duration = params.get("transition_duration")
+        .and_then(|v| v.as_f64())
+        .unwrap_or(1.0);
+    if duration <= 0.0 {
+        anyhow::bail!("transition_duration must be positive");
+    }
     
-    let video_stream_index = input_stream.index();
-    let context_decoder = ffmpeg::codec::context::Context::from_parameters(input_stream.parameters())?;
-    let mut decoder = context_decoder.decoder().video()?;
+    Ok(Some((transition, duration)))
+}
+
+/// Normalise `inputs` and write them one after another to the job's output, joined by cuts
+/// or by each input's transition, returning the output's length in seconds
+///
+/// Takes the `width`, `height`, `fps`, `sample_rate`, `audio_bitrate` and video encoding
+/// params of `concatenate_videos`.
+fn write_concatenation(job: &JobPayload, inputs: &mut [ConcatInput]) -> Result<f64> {
+    let sample_rate = job.params.get("sample_rate")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(48000) as u32;
     
-    let mut scaler = ffmpeg::software::scaling::context::Context::get(
-        decoder.format(),
-        decoder.width(),
-        decoder.height(),
-        ffmpeg::format::Pixel::RGB24,
-        decoder.width(),
-        decoder.height(),
-        ffmpeg::software::scaling::flag::Flags::BILINEAR,
+    let audio_bitrate = parse_bitrate(
+        job.params.get("audio_bitrate")
+            .and_then(|v| v.as_str())
+            .unwrap_or("128k"),
     )?;
     
-    for (stream, packet) in ictx.packets() {
-        if stream.index() == video_stream_index {
-            decoder.send_packet(&packet)?;
-            
-            let mut decoded = ffmpeg::util::frame::video::Video::empty();
-            if decoder.receive_frame(&mut decoded).is_ok() {
-                let mut rgb_frame = ffmpeg::util::frame::video::Video::empty();
-                scaler.run(&decoded, &mut rgb_frame)?;
-                
-                save_frame_as_jpeg(&rgb_frame, &job.output_path)?;
-                break;
+    for (index, input) in inputs.iter().enumerate() {
+        let transitions = [
+            input.transition.as_ref(),
+            inputs.get(index + 1).and_then(|next| next.transition.as_ref()),
+        ];
+        if let Some((_, duration)) = transitions.into_iter().flatten().find(|(_, duration)| input.duration <= *duration) {
+            anyhow::bail!(
+                "{} ({:.2}s) is shorter than the {:.2}s transition",
+                input.path,
+                input.duration,
+                duration
+            );
+        }
+    }
+    
+    let first = &inputs[0];
+    let (width, height) = match (
+        job.params.get("width").and_then(|v| v.as_u64()),
+        job.params.get("height").and_then(|v| v.as_u64()),
+    ) {
+        (Some(width), Some(height)) => (width as u32, height as u32),
+        _ => (first.video_decoder.width(), first.video_decoder.height()),
+    };
+    let (width, height) = (width.max(2) & !1, height.max(2) & !1);
+    
+    let frame_rate = match job.params.get("fps").and_then(|v| v.as_f64()) {
+        Some(fps) if fps > 0.0 => ffmpeg::Rational::new((fps * 1000.0).round() as i32, 1000).reduce(),
+        _ => first.frame_rate,
+    };
+    
+    let mut octx = ffmpeg::format::output(&job.output_path)?;
+    let settings = VideoOutputSettings::from_params(&job.params)?;
+    let (mut video_encoder, _) = open_video_encoder(&mut octx, &job.output_path, &settings, width, height, frame_rate)?;
+    let pixel_format = video_encoder.format();
+    let pixel_format_name = pixel_format.descriptor().map_or("yuv420p", |descriptor| descriptor.name());
+    
+    // Every input is cut to its span and brought to the same size, rate, format and a zero
+    // start time, which concat and xfade both require
+    let video_names = video_source_names(inputs.len());
+    let mut video_spec = String::new();
+    for (index, (name, input)) in video_names.iter().zip(inputs.iter()).enumerate() {
+        let trim = input.trim.map_or(String::new(), |(start, end)| {
+            format!("trim=start={:.6}:end={:.6},", start, end)
+        });
+        video_spec.push_str(&format!(
+            "[{}]{}setpts=PTS-STARTPTS,fps={}/{},\
+             scale={w}:{h}:force_original_aspect_ratio=decrease:force_divisible_by=2,\
+             pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1,format={}[v{}];",
+            name,
+            trim,
+            frame_rate.numerator(),
+            frame_rate.denominator(),
+            pixel_format_name,
+            index,
+            w = width,
+            h = height,
+        ));
+    }
+    
+    let has_audio = inputs.iter().any(|input| input.audio.is_some());
+    let audio_count = inputs.iter().filter(|input| input.audio.is_some()).count();
+    let audio_names = crate::audio::FilteredAudioEncoder::source_names(audio_count);
+    let mut audio_spec = String::new();
+    let mut audio_sources = Vec::new();
+    for (index, input) in inputs.iter_mut().enumerate() {
+        // Audio is padded or cut to the video's length so both tracks stay aligned
+        match &mut input.audio {
+            Some(audio) => {
+                audio.source = audio_names[audio_sources.len()].clone();
+                let trim = input.trim.map_or(String::new(), |(start, end)| {
+                    format!("atrim=start={:.6}:end={:.6},asetpts=PTS-STARTPTS,", start, end)
+                });
+                let gain = if input.gain_db != 0.0 {
+                    format!("volume={}dB,", input.gain_db)
+                } else {
+                    String::new()
+                };
+                audio_spec.push_str(&format!(
+                    "[{}]{}aresample={}:async=1,{}apad,atrim=0:{},asetpts=PTS-STARTPTS[a{}];",
+                    audio.source, trim, sample_rate, gain, input.duration, index
+                ));
+                audio_sources.push(index);
             }
+            None => audio_spec.push_str(&format!(
+                "anullsrc=r={}:cl=stereo,atrim=0:{}[a{}];",
+                sample_rate, input.duration, index
+            )),
         }
     }
     
-    Ok(job.output_path.clone())
-}
-
-// Helper functions
-
-fn calculate_frame_difference(frame1: &ffmpeg::util::frame::video::Video, frame2: &ffmpeg::util::frame::video::Video) -> f64 {
-    // Simplified frame difference calculation
-    // In production, use more sophisticated methods (histogram, SSIM, etc.)
-    let data1 = frame1.data(0);
-    let data2 = frame2.data(0);
+    let mut total = inputs[0].duration;
+    if inputs.iter().all(|input| input.transition.is_none()) {
+        let labels: String = (0..inputs.len()).map(|index| format!("[v{}]", index)).collect();
+        video_spec.push_str(&format!("{}concat=n={}:v=1:a=0[out]", labels, inputs.len()));
+        let labels: String = (0..inputs.len()).map(|index| format!("[a{}]", index)).collect();
+        audio_spec.push_str(&format!("{}concat=n={}:v=0:a=1[out]", labels, inputs.len()));
+        total = inputs.iter().map(|input| input.duration).sum();
+    } else {
+        // Each transition starts its own length before the end of everything so far; inputs
+        // without one are cut on with a two-input concat
+        let mut video_label = "v0".to_string();
+        let mut audio_label = "a0".to_string();
+        for index in 1..inputs.len() {
+            let last = index + 1 == inputs.len();
+            let next_video = if last { "out".to_string() } else { format!("vx{}", index) };
+            let next_audio = if last { "out".to_string() } else { format!("ax{}", index) };
+            match &inputs[index].transition {
+                Some((transition, duration)) => {
+                    video_spec.push_str(&format!(
+                        "[{}][v{}]xfade=transition={}:duration={}:offset={:.6}[{}];",
+                        video_label, index, transition, duration, total - duration, next_video
+                    ));
+                    audio_spec.push_str(&format!(
+                        "[{}][a{}]acrossfade=d={}[{}];",
+                        audio_label, index, duration, next_audio
+                    ));
+                    total += inputs[index].duration - duration;
+                }
+                None => {
+                    video_spec.push_str(&format!(
+                        "[{}][v{}]concat=n=2:v=1:a=0[{}];",
+                        video_label, index, next_video
+                    ));
+                    audio_spec.push_str(&format!(
+                        "[{}][a{}]concat=n=2:v=0:a=1[{}];",
+                        audio_label, index, next_audio
+                    ));
+                    total += inputs[index].duration;
+                }
+            }
+            video_label = next_video;
+            audio_label = next_audio;
+        }
+        video_spec.pop();
+        audio_spec.pop();
+    }
     
-    let len = data1.len().min(data2.len());
-    if len == 0 {
-        return 0.0;
+    let mut video_graph = build_multi_video_filter_graph(
+        &inputs.iter().map(|input| (&input.video_decoder, input.video_time_base)).collect::<Vec<_>>(),
+        &video_spec,
+        pixel_format,
+    )?;
+    
+    let mut audio_encoder = if has_audio {
+        let sources: Vec<(&ffmpeg::decoder::Audio, ffmpeg::Rational)> = audio_sources
+            .iter()
+            .filter_map(|index| inputs[*index].audio.as_ref())
+            .map(|audio| (&audio.decoder, audio.time_base))
+            .collect();
+        Some(crate::audio::FilteredAudioEncoder::open(
+            &mut octx,
+            &job.output_path,
+            None,
+            Some(audio_bitrate),
+            ffmpeg::ChannelLayout::STEREO,
+            sample_rate,
+            &sources,
+            &audio_spec,
+        )?)
+    } else {
+        None
+    };
+    
+    octx.write_header()?;
+    let output_time_base = octx.stream(0).context("Output stream missing")?.time_base();
+    
+    // Inputs are fed one after another; filters hold back what they need for transitions
+    for (input, video_source) in inputs.iter_mut().zip(&video_names) {
+        info!("Appending {}", input.path);
+        
+        if let Some((start, _)) = input.trim {
+            // Lands on the keyframe before the in point; the trim filters drop what comes before
+            let position = (start * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+            input.ictx.seek(position, ..position)?;
+        }
+        
+        for (stream, packet) in input.ictx.packets() {
+            // Packets well past the out point cannot hold frames inside it, even reordered
+            if let (Some((_, end)), Some(dts)) = (input.trim, packet.dts()) {
+                if dts as f64 * f64::from(stream.time_base()) > end + 1.0 {
+                    break;
+                }
+            }
+            
+            if stream.index() == input.video_index {
+                input.video_decoder.send_packet(&packet)?;
+                send_decoded_video(&mut input.video_decoder, &mut video_graph, video_source)?;
+                encode_filtered_video(&mut video_graph, &mut video_encoder, &mut octx, output_time_base)?;
+            } else if let (Some(audio), Some(encoder)) = (&mut input.audio, &mut audio_encoder) {
+                if stream.index() == audio.index {
+                    audio.decoder.send_packet(&packet)?;
+                    encoder.send_decoded(&mut audio.decoder, &audio.source, &mut octx)?;
+                }
+            }
+        }
+        
+        input.video_decoder.send_eof()?;
+        send_decoded_video(&mut input.video_decoder, &mut video_graph, video_source)?;
+        video_graph.get(video_source).context("Filter source missing")?.source().flush()?;
+        encode_filtered_video(&mut video_graph, &mut video_encoder, &mut octx, output_time_base)?;
+        
+        if let (Some(audio), Some(encoder)) = (&mut input.audio, &mut audio_encoder) {
+            audio.decoder.send_eof()?;
+            encoder.send_decoded(&mut audio.decoder, &audio.source, &mut octx)?;
+            encoder.close_source(&audio.source, &mut octx)?;
+        }
     }
     
-    let mut diff_sum: u64 = 0;
-    for i in 0..len {
-        diff_sum += (data1[i] as i32 - data2[i] as i32).abs() as u64;
+    encode_filtered_video(&mut video_graph, &mut video_encoder, &mut octx, output_time_base)?;
+    video_encoder.send_eof()?;
+    write_encoded_packets(&mut video_encoder, &mut octx, output_time_base)?;
+    if let Some(encoder) = &mut audio_encoder {
+        encoder.finish(&mut octx)?;
     }
     
-    diff_sum as f64 / len as f64 / 255.0
+    octx.write_trailer()?;
+    
+    Ok(total)
 }
 
-fn parse_timestamp(timestamp: &str) -> Result<f64> {
-    // Parse HH:MM:SS or MM:SS or SS format
-    let parts: Vec<&str> = timestamp.split(':').collect();
+/// Push every frame the decoder has ready into the named filter source
+fn send_decoded_video(
+    decoder: &mut ffmpeg::decoder::Video,
+    graph: &mut ffmpeg::filter::Graph,
+    source: &str,
+) -> Result<()> {
+    let mut decoded = ffmpeg::util::frame::video::Video::empty();
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        let timestamp = decoded.timestamp();
+        decoded.set_pts(timestamp);
+        graph.get(source).context("Filter source missing")?.source().add(&decoded)?;
+    }
+    Ok(())
+}
+
+/// One output of `generate_abr_ladder`, fed frames from the shared decoder
+struct AbrRendition {
+    name: String,
+    path: String,
+    /// The job's params with the rendition's overrides
+    params: serde_json::Value,
+    bit_rate: Option<usize>,
+    encoder: VideoFrameEncoder,
+    audio: Option<StreamOutput>,
+    octx: ffmpeg::format::context::Output,
+}
+
+/// Presets understood by libx264 and libx265, fastest first
+const X264_PRESETS: [&str; 10] = [
+    "ultrafast", "superfast", "veryfast", "faster", "fast", "medium", "slow", "slower", "veryslow", "placebo",
+];
+
+/// SVT-AV1 presets (13 fastest) for each of `X264_PRESETS`
+const SVTAV1_PRESETS: [u32; 10] = [12, 11, 10, 9, 8, 7, 5, 4, 2, 0];
+
+/// libaom `cpu-used` (8 fastest) for each of `X264_PRESETS`
+const AOM_CPU_USED: [i64; 10] = [8, 7, 6, 5, 5, 4, 3, 2, 1, 0];
+
+/// libvpx `deadline` and `cpu-used` for each of `X264_PRESETS`
+const VPX_SPEEDS: [(&str, i64); 10] = [
+    ("realtime", 8), ("realtime", 6), ("good", 5), ("good", 4), ("good", 3),
+    ("good", 2), ("good", 1), ("good", 0), ("best", 0), ("best", 0),
+];
+
+/// Encoder options for constant-quality rate control
+///
+/// `crf` is the software encoders' constant rate factor, `cq` the hardware encoders'
+/// constant quality; each falls back to the other's option where the encoder has only one.
+fn constant_quality_options(codec_name: &str, mode: &str, quality: Option<f64>) -> Result<Vec<(String, String)>> {
+    if mode != "crf" && mode != "cq" {
+        anyhow::bail!("Unknown quality_mode: {} (expected bitrate, crf or cq)", mode);
+    }
     
-    let seconds = match parts.len() {
-        1 => parts[0].parse::<f64>()?,
-        2 => {
-            let minutes = parts[0].parse::<f64>()?;
-            let secs = parts[1].parse::<f64>()?;
-            minutes * 60.0 + secs
-        }
-        3 => {
-            let hours = parts[0].parse::<f64>()?;
-            let minutes = parts[1].parse::<f64>()?;
-            let secs = parts[2].parse::<f64>()?;
-            hours * 3600.0 + minutes * 60.0 + secs
-        }
-        _ => anyhow::bail!("Invalid timestamp format: {}", timestamp),
+    let (options, default_quality, range): (&[&str], f64, (f64, f64)) = match codec_name {
+        "libx264" => (&["crf"], 23.0, (0.0, 51.0)),
+        "libx265" => (&["crf"], 28.0, (0.0, 51.0)),
+        // libvpx and libaom only hold a constant quality with no target bitrate
+        "libvpx" | "libvpx-vp9" => (&["crf", "b=0"], 31.0, (0.0, 63.0)),
+        "libaom-av1" => (&["crf", "b=0"], 35.0, (0.0, 63.0)),
+        "libsvtav1" => (&["crf"], 35.0, (0.0, 63.0)),
+        name if name.ends_with("_nvenc") => (&["rc=vbr", "cq"], 23.0, (0.0, 51.0)),
+        name if name.ends_with("_qsv") => (&["global_quality"], 23.0, (1.0, 51.0)),
+        name if name.ends_with("_videotoolbox") => (&["q"], 65.0, (1.0, 100.0)),
+        name => anyhow::bail!("quality_mode {} is not supported for codec {}", mode, name),
     };
     
-    Ok(seconds)
+    let quality = quality.unwrap_or(default_quality);
+    if quality < range.0 || quality > range.1 {
+        anyhow::bail!("quality {} is outside {}..={} for {}", quality, range.0, range.1, codec_name);
+    }
+    
+    Ok(options
+        .iter()
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (option.to_string(), quality.to_string()),
+        })
+        .collect())
 }
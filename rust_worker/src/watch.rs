@@ -0,0 +1,403 @@
+use anyhow::{Context, Result};
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{Config, WatchFolderConfig},
+    execute_job, shutdown, JobPayload,
+};
+
+/// Run the watch-folder daemon until the process is stopped
+///
+/// Every file that appears in a configured folder (including files already there at startup)
+/// is processed once it has been stable for `stable_seconds` and no process holds it open for
+/// writing, by running the folder's pipeline steps in order. Files under an `output_dir` or
+/// `move_processed_to` are never inputs, a file is not started again while its pipeline runs,
+/// and files processed successfully are kept in `watch.ledger_path` so that they are skipped,
+/// unless they change, after a restart.
+pub async fn run(config: Config) -> Result<()> {
+    if config.watch.folders.is_empty() {
+        anyhow::bail!("No [[watch.folders]] configured");
+    }
+    for folder in &config.watch.folders {
+        let root = canonical(Path::new(&folder.path));
+        if result_dirs(folder).any(|dir| canonical(Path::new(dir)) == root) {
+            anyhow::bail!("Watch folder {} cannot also be its output_dir or move_processed_to", folder.path);
+        }
+    }
+    
+    let ledger = Arc::new(Mutex::new(Ledger::load(&config.watch.ledger_path)?));
+    let running: Arc<Mutex<HashSet<PathBuf>>> = Arc::default();
+    let config = Arc::new(config);
+    let slots = Arc::new(tokio::sync::Semaphore::new(config.processing.max_workers.max(1)));
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PathBuf>();
+    
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!(error = %e, "Watch error"),
+        }
+    })
+    .context("Failed to start file watcher")?;
+    
+    let mut pending: HashMap<PathBuf, PendingFile> = HashMap::new();
+    
+    for (index, folder) in config.watch.folders.iter().enumerate() {
+        fs::create_dir_all(&folder.path)
+            .context(format!("Failed to create watch folder {}", folder.path))?;
+        let mode = if folder.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+        watcher.watch(Path::new(&folder.path), mode)
+            .context(format!("Failed to watch {}", folder.path))?;
+        
+        for path in existing_files(Path::new(&folder.path), folder.recursive)? {
+            if !is_result(&config, &path) {
+                pending.entry(canonical(&path)).or_insert_with(|| PendingFile::new(index));
+            }
+        }
+        
+        info!(folder = %folder.path, steps = folder.pipeline.len(), "Watching folder");
+    }
+    
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    
+    loop {
+        tokio::select! {
+            Some(path) = rx.recv() => {
+                if let Some(index) = folder_for(&config, &path) {
+                    pending.entry(canonical(&path)).or_insert_with(|| PendingFile::new(index));
+                }
+            }
+            _ = tick.tick() => {
+                if shutdown::requested() {
+                    // Files not yet started are picked up again at the next start
+                    info!("Waiting for running pipelines before shutting down");
+                    let _ = slots.acquire_many(config.processing.max_workers.max(1) as u32).await;
+                    return Ok(());
+                }
+                
+                let ready: Vec<(PathBuf, usize)> = pending
+                    .iter_mut()
+                    .filter_map(|(path, file)| file.poll(path, &config).then(|| (path.clone(), file.folder)))
+                    .collect();
+                
+                for (path, index) in ready {
+                    // Changes seen while its pipeline runs are looked at once it has finished
+                    if running.lock().unwrap().contains(&path) {
+                        continue;
+                    }
+                    pending.remove(&path);
+                    let Some(fingerprint) = Fingerprint::of(&path) else {
+                        continue;
+                    };
+                    if ledger.lock().unwrap().contains(&path, fingerprint) {
+                        continue;
+                    }
+                    running.lock().unwrap().insert(path.clone());
+                    
+                    let config = config.clone();
+                    let slots = slots.clone();
+                    let ledger = ledger.clone();
+                    let running = running.clone();
+                    tokio::spawn(async move {
+                        let permit = slots.acquire_owned().await;
+                        if shutdown::requested() {
+                            return;
+                        }
+                        // Tasks do blocking media work, so each pipeline gets its own thread
+                        let handle = tokio::runtime::Handle::current();
+                        let file = path.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            let _permit = permit;
+                            let folder = &config.watch.folders[index];
+                            handle.block_on(run_pipeline(&file, folder, &config))
+                        })
+                        .await;
+                        match result {
+                            Ok(Ok(())) => {
+                                if let Err(e) = ledger.lock().unwrap().record(&path, fingerprint) {
+                                    warn!(error = %e, "Failed to update the watch ledger");
+                                }
+                            }
+                            Ok(Err(e)) => {
+                                error!(file = %path.display(), error = %e, "Watch pipeline failed");
+                            }
+                            Err(e) => error!(error = %e, "Watch pipeline panicked"),
+                        }
+                        running.lock().unwrap().remove(&path);
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Size/mtime observations for a file waiting to settle
+struct PendingFile {
+    folder: usize,
+    last_seen: Option<(u64, SystemTime)>,
+    stable_since: Instant,
+}
+
+impl PendingFile {
+    fn new(folder: usize) -> Self {
+        Self {
+            folder,
+            last_seen: None,
+            stable_since: Instant::now(),
+        }
+    }
+    
+    /// True once the file is unchanged for the folder's `stable_seconds` and not being written
+    fn poll(&mut self, path: &Path, config: &Config) -> bool {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return false,
+        };
+        let current = (metadata.len(), metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+        
+        if self.last_seen != Some(current) {
+            self.last_seen = Some(current);
+            self.stable_since = Instant::now();
+            return false;
+        }
+        
+        let stable_for = Duration::from_secs(config.watch.folders[self.folder].stable_seconds);
+        self.stable_since.elapsed() >= stable_for && !is_open_for_writing(path)
+    }
+}
+
+/// Size and mtime of a file, which identify the version of it that was processed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Fingerprint {
+    size: u64,
+    modified_ms: u64,
+}
+
+impl Fingerprint {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified_ms: modified.as_millis() as u64,
+        })
+    }
+}
+
+/// Files whose pipeline succeeded, as they were when it ran, saved after every change
+struct Ledger {
+    path: PathBuf,
+    processed: HashMap<PathBuf, Fingerprint>,
+}
+
+impl Ledger {
+    fn load(path: &str) -> Result<Self> {
+        let processed = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .context(format!("Invalid watch ledger {}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).context(format!("Failed to read watch ledger {}", path)),
+        };
+        Ok(Self { path: PathBuf::from(path), processed })
+    }
+    
+    fn contains(&self, path: &Path, fingerprint: Fingerprint) -> bool {
+        self.processed.get(path) == Some(&fingerprint)
+    }
+    
+    fn record(&mut self, path: &Path, fingerprint: Fingerprint) -> Result<()> {
+        self.processed.insert(path.to_path_buf(), fingerprint);
+        // Files moved or deleted since need no entry
+        self.processed.retain(|path, _| path.exists());
+        
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Replaced whole, so a crash mid-write cannot leave it unreadable
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_string_pretty(&self.processed)?)?;
+        fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Run each pipeline step, expanding `{input}`, `{name}`, `{stem}`, `{ext}`, `{output_dir}`
+/// and `{previous}` (the previous step's output) in step inputs, outputs and string params
+async fn run_pipeline(path: &Path, folder: &WatchFolderConfig, config: &Config) -> Result<()> {
+    let input = path.to_string_lossy().to_string();
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = path.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or_default();
+    
+    if !folder.include.is_empty() && !folder.include.iter().any(|suffix| name.ends_with(suffix.as_str())) {
+        return Ok(());
+    }
+    
+    fs::create_dir_all(&folder.output_dir)?;
+    info!(file = %input, "Processing watched file");
+    
+    let mut previous = input.clone();
+    for step in &folder.pipeline {
+        let expand = |template: &str| {
+            template
+                .replace("{input}", &input)
+                .replace("{name}", &name)
+                .replace("{stem}", &stem)
+                .replace("{ext}", &ext)
+                .replace("{output_dir}", &folder.output_dir)
+                .replace("{previous}", &previous)
+        };
+        
+        let job = JobPayload {
+            job_id: None,
+            traceparent: None,
+            task: step.task.clone(),
+            input_path: expand(&step.input),
+            output_path: expand(&step.output),
+            params: expand_params(&step.params, &expand),
+        };
+        
+        shutdown::check()?;
+        let start = Instant::now();
+        let output = execute_job(&job, config)
+            .await
+            .context(format!("Step {} failed for {}", step.task, input))?;
+        info!(
+            task = %step.task,
+            output = %output,
+            duration_ms = start.elapsed().as_millis() as u64,
+            "Watch step completed"
+        );
+        previous = output;
+    }
+    
+    if let Some(done_dir) = &folder.move_processed_to {
+        fs::create_dir_all(done_dir)?;
+        let destination = Path::new(done_dir).join(&name);
+        if fs::rename(path, &destination).is_err() {
+            fs::copy(path, &destination)?;
+            fs::remove_file(path)?;
+        }
+    }
+    
+    Ok(())
+}
+
+pub(crate) fn expand_params(value: &serde_json::Value, expand: &dyn Fn(&str) -> String) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(s) => serde_json::Value::String(expand(s)),
+        serde_json::Value::Array(items) => items.iter().map(|v| expand_params(v, expand)).collect(),
+        serde_json::Value::Object(map) => map
+            .iter()
+            .map(|(k, v)| (k.clone(), expand_params(v, expand)))
+            .collect(),
+        other => other.clone(),
+    }
+}
+
+/// Index of the configured folder containing `path`, unless it is a result of one
+fn folder_for(config: &Config, path: &Path) -> Option<usize> {
+    if is_result(config, path) {
+        return None;
+    }
+    config.watch.folders.iter().position(|folder| {
+        let root = Path::new(&folder.path);
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let parent = path.parent().map(|p| p.canonicalize().unwrap_or_else(|_| p.to_path_buf()));
+        match parent {
+            Some(parent) if folder.recursive => parent.starts_with(&root),
+            Some(parent) => parent == root,
+            None => false,
+        }
+    })
+}
+
+/// Whether `path` is under any folder's `output_dir` or `move_processed_to`
+fn is_result(config: &Config, path: &Path) -> bool {
+    let path = canonical(path);
+    config
+        .watch
+        .folders
+        .iter()
+        .flat_map(result_dirs)
+        .any(|dir| path.starts_with(canonical(Path::new(dir))))
+}
+
+fn result_dirs(folder: &WatchFolderConfig) -> impl Iterator<Item = &String> {
+    std::iter::once(&folder.output_dir).chain(&folder.move_processed_to)
+}
+
+/// `path` made absolute when it exists, so that the same file always has the same key
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn existing_files(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_file() {
+            files.push(entry.path());
+        } else if file_type.is_dir() && recursive {
+            files.extend(existing_files(&entry.path(), true)?);
+        }
+    }
+    Ok(files)
+}
+
+/// Whether any process has `path` open for writing, from `/proc/*/fdinfo`
+#[cfg(target_os = "linux")]
+fn is_open_for_writing(path: &Path) -> bool {
+    let target = match path.canonicalize() {
+        Ok(target) => target,
+        Err(_) => return false,
+    };
+    let processes = match fs::read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(_) => return false,
+    };
+    
+    for process in processes.flatten() {
+        let fd_dir = process.path().join("fd");
+        let fds = match fs::read_dir(&fd_dir) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            if fs::read_link(fd.path()).ok().as_deref() != Some(target.as_path()) {
+                continue;
+            }
+            let info_path = process.path().join("fdinfo").join(fd.file_name());
+            let flags = fs::read_to_string(info_path)
+                .ok()
+                .and_then(|info| {
+                    info.lines()
+                        .find_map(|line| line.strip_prefix("flags:"))
+                        .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+                })
+                .unwrap_or(0);
+            // O_WRONLY or O_RDWR
+            if flags & 0o3 != 0 {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_open_for_writing(_path: &Path) -> bool {
+    false
+}